@@ -12,7 +12,7 @@ use anyhow::{ensure, Context};
 use concordium_node::{
     common::PeerType,
     consensus_ffi::blockchain_types::BlockHash,
-    p2p::maintenance::{attempt_bootstrap, spawn, P2PNode},
+    p2p::maintenance::{attempt_bootstrap, spawn, P2PNode, ThreadPoolSize},
     stats_export_service::instantiate_stats_export_engine,
     utils::get_config_and_logging_setup,
 };
@@ -20,11 +20,17 @@ use concordium_node::{
 use std::{env, process::Command, thread, time::Duration};
 
 fn main() -> anyhow::Result<()> {
-    let (mut conf, app_prefs) = get_config_and_logging_setup()?;
+    let (mut conf, app_prefs) = match get_config_and_logging_setup()? {
+        Some(setup) => setup,
+        None => {
+            println!("The configuration is valid.");
+            return Ok(());
+        }
+    };
     let data_dir_path = app_prefs.get_user_app_dir();
 
     conf.connection.max_allowed_nodes = Some(0);
-    conf.connection.thread_pool_size = 1;
+    conf.connection.thread_pool_size = ThreadPoolSize::Fixed(1);
     conf.connection.require_dnssec = false;
     let pager_duty_token = env::var("PD_TOKEN")?;
     let pager_duty_email = env::var("PD_EMAIL")?;
@@ -12,7 +12,13 @@ use anyhow::{ensure, Context};
 use concordium_node::{
     common::PeerType,
     consensus_ffi::blockchain_types::BlockHash,
-    p2p::{maintenance::spawn, *},
+    p2p::{
+        maintenance::{
+            install_ban_reload_handler, install_shutdown_handlers, install_state_dump_handler,
+            spawn,
+        },
+        *,
+    },
     stats_export_service::instantiate_stats_export_engine,
     utils::get_config_and_logging_setup,
 };
@@ -21,7 +27,13 @@ use concordium_node::{
 use concordium_node::stats_export_service::start_push_gateway;
 
 fn main() -> anyhow::Result<()> {
-    let (mut conf, app_prefs) = get_config_and_logging_setup()?;
+    let (mut conf, app_prefs) = match get_config_and_logging_setup()? {
+        Some(setup) => setup,
+        None => {
+            println!("The configuration is valid.");
+            return Ok(());
+        }
+    };
     conf.connection.max_allowed_nodes = Some(conf.bootstrapper.max_nodes);
     let data_dir_path = app_prefs.get_user_app_dir();
 
@@ -53,9 +65,12 @@ fn main() -> anyhow::Result<()> {
     .context("Failed to create the network node.")?;
 
     #[cfg(feature = "instrumentation")]
-    start_push_gateway(&conf.prometheus, &node.stats, node.id());
+    start_push_gateway(&conf.prometheus, &node.stats, node.id())?;
 
     spawn(&node, poll, None);
+    install_shutdown_handlers(&node)?;
+    install_ban_reload_handler(&node)?;
+    install_state_dump_handler(&node)?;
 
     node.join().expect("Node thread panicked!");
 
@@ -9,7 +9,7 @@ static A: System = System;
 
 use anyhow::Context;
 use concordium_node::{
-    common::PeerType,
+    common::{DiscoverySource, PeerType},
     configuration as config,
     consensus_ffi::{
         blockchain_types::BlockHash,
@@ -23,7 +23,10 @@ use concordium_node::{
     },
     p2p::{
         connectivity::connect,
-        maintenance::{attempt_bootstrap, spawn},
+        maintenance::{
+            attempt_bootstrap, install_ban_reload_handler, install_shutdown_handlers,
+            install_state_dump_handler, spawn,
+        },
         *,
     },
     plugins::{self, consensus::*},
@@ -36,13 +39,7 @@ use concordium_node::{
 use mio::Poll;
 use parking_lot::Mutex as ParkingMutex;
 use rand::Rng;
-use std::{
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
-    thread::JoinHandle,
-};
+use std::{sync::Arc, thread::JoinHandle};
 
 #[cfg(feature = "instrumentation")]
 use concordium_node::stats_export_service::start_push_gateway;
@@ -51,8 +48,13 @@ use std::net::{IpAddr, SocketAddr};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let (conf, mut app_prefs) = get_config_and_logging_setup()?;
-    let shutdown_handler_state = Arc::new(AtomicBool::new(false));
+    let (conf, mut app_prefs) = match get_config_and_logging_setup()? {
+        Some(setup) => setup,
+        None => {
+            println!("The configuration is valid.");
+            return Ok(());
+        }
+    };
 
     let stats_export_service = instantiate_stats_export_engine(&conf)?;
     let regenesis_arc = Arc::new(RwLock::new(vec![]));
@@ -62,48 +64,26 @@ async fn main() -> anyhow::Result<()> {
         instantiate_node(&conf, &mut app_prefs, stats_export_service, regenesis_arc.clone())
             .context("Failed to create the node.")?;
 
-    // Signal handling closure. so we shut down cleanly
-    let signal_closure = |signal_handler_node: &Arc<P2PNode>,
-                          shutdown_handler_state: &Arc<AtomicBool>| {
-        match shutdown_handler_state.compare_exchange(
-            false,
-            true,
-            Ordering::SeqCst,
-            Ordering::SeqCst,
-        ) {
-            Ok(false) => {
-                info!("Signal received attempting to shutdown node cleanly");
-                if !signal_handler_node.close() {
-                    error!("Can't shutdown node properly!");
-                    std::process::exit(1);
-                }
-            }
-            Err(true) => {
-                info!(
-                    "Signal received to shutdown node cleanly, but an attempt to do so is already \
-                     in progress."
-                );
-            }
-            _ => info!("Could not handle signal to shut down. Try again."),
-        }
-    };
+    // Shut down cleanly on SIGTERM and SIGINT / ^C
+    install_shutdown_handlers(&node)?;
+    // Re-read the bans on SIGHUP
+    install_ban_reload_handler(&node)?;
+    // Log the state of the node on SIGUSR1
+    install_state_dump_handler(&node)?;
 
-    // Register a SIGTERM handler for a POSIX.1-2001 system
-    #[cfg(not(windows))]
+    #[cfg(feature = "network_dump")]
     {
-        let sigterm_shutdown_handler_state = shutdown_handler_state.clone();
-        let signal_hook_node = node.clone();
-        unsafe {
-            signal_hook::low_level::register(signal_hook::consts::SIGTERM, move || {
-                signal_closure(&signal_hook_node, &sigterm_shutdown_handler_state)
-            })
-        }?;
+        if let Some(ref dir) = conf.common.network_dump {
+            node.activate_dump(
+                dir,
+                true,
+                conf.common.network_dump_format,
+                conf.common.network_dump_append,
+            )
+            .context("Can't start the network dump")?;
+        }
     }
 
-    // Register a safe handler for SIGINT / ^C
-    let ctrlc_node = node.clone();
-    ctrlc::set_handler(move || signal_closure(&ctrlc_node, &shutdown_handler_state))?;
-
     #[cfg(feature = "instrumentation")]
     {
         let stats = node.stats.clone();
@@ -122,7 +102,7 @@ async fn main() -> anyhow::Result<()> {
 
     #[cfg(feature = "instrumentation")]
     // The push gateway to Prometheus thread
-    start_push_gateway(&conf.prometheus, &node.stats, node.id());
+    start_push_gateway(&conf.prometheus, &node.stats, node.id())?;
 
     let (gen_data, priv_data) = get_baker_data(&app_prefs, &conf.cli.baker)
         .context("Can't get genesis data or private data. Aborting")?;
@@ -144,7 +124,9 @@ async fn main() -> anyhow::Result<()> {
     let mut database_directory = data_dir_path.to_path_buf();
     database_directory.push(concordium_node::configuration::DATABASE_SUB_DIRECTORY_NAME);
     if !database_directory.exists() {
-        std::fs::create_dir_all(&database_directory)?;
+        std::fs::create_dir_all(&database_directory).with_context(|| {
+            format!("Could not create the database directory {}.", database_directory.display())
+        })?;
     }
 
     info!("Starting consensus layer");
@@ -227,6 +209,12 @@ fn instantiate_node(
     stats_export_service: Arc<StatsExportService>,
     regenesis_arc: Arc<RwLock<Vec<BlockHash>>>,
 ) -> anyhow::Result<(Arc<P2PNode>, Poll)> {
+    // If the node id is derived from the noise key or a seed there is nothing to
+    // look up or persist, the node will compute it itself.
+    if conf.common.id_from_noise_key || conf.common.id_seed.is_some() {
+        return P2PNode::new(None, &conf, PeerType::Node, stats_export_service, regenesis_arc);
+    }
+
     // If the node id is supplied on the command line (in the conf argument) use it.
     // Otherwise try to look it up from the persistent config.
     let node_id = match conf.common.id {
@@ -272,7 +260,9 @@ fn connect_to_config_nodes(node: &Arc<P2PNode>) {
     // connected network of given addresses. Warnings should suffice to detect
     // configuration mistakes.
     for &given_addr in conns.iter() {
-        if let Err(e) = connect(node, PeerType::Node, given_addr, None, false) {
+        if let Err(e) =
+            connect(node, PeerType::Node, given_addr, None, false, DiscoverySource::Given)
+        {
             warn!("Could not connect to a given address {}: {}", given_addr, e);
         }
     }
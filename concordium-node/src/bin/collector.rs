@@ -2,7 +2,7 @@
 use concordium_node::{
     common::{collector_utils::NodeInfo, grpc_api},
     req_with_auth,
-    utils::setup_logger_env,
+    utils::{setup_logger_env, LogFormat},
 };
 use env_logger::Env;
 use serde_json::Value;
@@ -137,7 +137,7 @@ async fn main() {
         Env::default().filter_or("LOG_LEVEL", "warn")
     };
 
-    setup_logger_env(env, conf.no_log_timestamp);
+    setup_logger_env(env, conf.no_log_timestamp, LogFormat::Text);
 
     if conf.print_config {
         info!("{:?}", conf);
@@ -4,7 +4,7 @@ extern crate gotham_derive;
 use anyhow::anyhow;
 use concordium_node::{
     common::{collector_utils::*, get_current_stamp},
-    utils::setup_logger_env,
+    utils::{setup_logger_env, LogFormat},
 };
 use env_logger::Env;
 use structopt::StructOpt;
@@ -233,7 +233,7 @@ pub fn main() -> anyhow::Result<()> {
         Env::default().filter_or("LOG_LEVEL", "warn")
     };
 
-    setup_logger_env(env, conf.no_log_timestamp);
+    setup_logger_env(env, conf.no_log_timestamp, LogFormat::Text);
 
     if conf.print_config {
         info!("{:?}", conf);
@@ -9,7 +9,7 @@ static A: System = System;
 
 use anyhow::{bail, Context};
 use concordium_node::{
-    common::PeerType,
+    common::{DiscoverySource, PeerType},
     consensus_ffi::helpers::PacketType,
     network::NetworkId,
     p2p::{
@@ -29,7 +29,13 @@ use std::{
 };
 
 fn main() -> anyhow::Result<()> {
-    let (mut conf, _app_prefs) = utils::get_config_and_logging_setup()?;
+    let (mut conf, _app_prefs) = match utils::get_config_and_logging_setup()? {
+        Some(setup) => setup,
+        None => {
+            println!("The configuration is valid.");
+            return Ok(());
+        }
+    };
 
     conf.connection.require_dnssec = false;
     conf.connection.no_bootstrap_dns = true;
@@ -49,15 +55,12 @@ fn main() -> anyhow::Result<()> {
     spawn(&node, poll, None);
 
     conf.connection.connect_to.iter().for_each(|host: &String| {
-        match utils::parse_host_port(
-            &host,
-            &node.config.dns_resolvers,
-            conf.connection.require_dnssec,
-        ) {
+        match utils::parse_host_port(&host, &node.config.dns_resolvers, node.config.dns_options) {
             Ok(addrs) => {
                 for addr in addrs {
-                    let _ = connect(&node, PeerType::Node, addr, None, false)
-                        .map_err(|e| error!("{}", e));
+                    let _ =
+                        connect(&node, PeerType::Node, addr, None, false, DiscoverySource::Given)
+                            .map_err(|e| error!("{}", e));
                 }
             }
             Err(err) => error!("Can't parse configured addresses to connect to: {}", err),
@@ -7,7 +7,7 @@ use rand::{thread_rng, Rng};
 
 use concordium_node::{
     common::PeerType,
-    connection::Connection,
+    connection::{Connection, MessageKind},
     network::NetworkId,
     p2p::{connectivity::send_broadcast_message, P2PNode},
     test_utils::{
@@ -128,12 +128,12 @@ fn send_fuzzed_packet(source: &P2PNode, min: usize, max: usize) {
 fn send_fuzzed_message(source: &P2PNode, min: usize, max: usize) {
     let filter = |_: &Connection| true;
     let msg = generate_random_data(thread_rng().gen_range(min, max));
-    source.send_over_all_connections(&msg, &filter);
+    source.send_over_all_connections(&msg, MessageKind::Other, &filter);
 }
 
 /// Sends a broadcast with an empty payload (which the low-level network layer
 /// prepends with a zero as the buffer size).
 fn send_zeroes(source: &P2PNode) {
     let filter = |_: &Connection| true;
-    source.send_over_all_connections(&[], &filter);
+    source.send_over_all_connections(&[], MessageKind::Other, &filter);
 }
@@ -16,5 +16,5 @@ pub fn get_current_stamp() -> u64 { Utc::now().timestamp_millis() as u64 }
 
 pub use self::{
     p2p_node_id::P2PNodeId,
-    p2p_peer::{P2PPeer, PeerStats, PeerType, RemotePeer},
+    p2p_peer::{ConnectionInfo, DiscoverySource, P2PPeer, PeerStats, PeerType, RemotePeer},
 };
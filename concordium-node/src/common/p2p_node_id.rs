@@ -4,7 +4,12 @@ use anyhow::bail;
 use byteorder::{ReadBytesExt, WriteBytesExt};
 use crypto_common::{Buffer, Deserial, Serial};
 use rand::distributions::{Distribution, Standard, Uniform};
-use std::fmt;
+use sha2::{Digest, Sha256};
+use std::{
+    convert::TryInto,
+    fmt,
+    net::{IpAddr, SocketAddr},
+};
 
 pub type PeerId = u64;
 
@@ -15,9 +20,10 @@ pub type PeerId = u64;
 #[repr(transparent)]
 pub struct P2PNodeId(pub PeerId);
 
+/// Random ids are never `P2PNodeId::UNKNOWN`.
 impl Distribution<P2PNodeId> for Standard {
     fn sample<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> P2PNodeId {
-        P2PNodeId(Uniform::new(0, PeerId::max_value()).sample(rng))
+        P2PNodeId(Uniform::new(1, PeerId::max_value()).sample(rng))
     }
 }
 
@@ -57,6 +63,35 @@ impl Deserial for P2PNodeId {
 }
 
 impl P2PNodeId {
+    /// The id advertised in peer lists for peers whose id isn't known, e.g.,
+    /// the seed peers of a bootstrapper. Their real id is only learned in the
+    /// handshake.
+    pub const UNKNOWN: P2PNodeId = P2PNodeId(0);
+
     /// Obtain the integer behind the node id.
     pub fn as_raw(self) -> PeerId { self.0 }
+
+    /// Derive a node id from a static public key by truncating its SHA-256
+    /// hash, so that the id is bound to the key.
+    pub fn from_public_key(public_key: &[u8]) -> Self {
+        let hash = Sha256::digest(public_key);
+        // the hash is 32 bytes long, so taking the first 8 cannot fail
+        P2PNodeId(PeerId::from_be_bytes(hash[..8].try_into().unwrap()))
+    }
+
+    /// Derive a node id from a cluster seed and the address the node listens
+    /// on, so that the nodes of a test cluster get stable and distinct ids
+    /// across runs.
+    pub fn from_seed_and_address(seed: u64, addr: SocketAddr) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(seed.to_be_bytes());
+        match addr.ip() {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.update(addr.port().to_be_bytes());
+        let hash = hasher.finalize();
+        // the hash is 32 bytes long, so taking the first 8 cannot fail
+        P2PNodeId(PeerId::from_be_bytes(hash[..8].try_into().unwrap()))
+    }
 }
@@ -1,6 +1,11 @@
 //! Types related to identifying peers.
 
-use crate::{common::P2PNodeId, connection::ConnectionStats};
+use crate::{
+    common::{get_current_stamp, P2PNodeId},
+    connection::{Connection, LatencySummary},
+    network::Networks,
+    p2p::geo::PeerLocation,
+};
 use anyhow::bail;
 use byteorder::{ReadBytesExt, WriteBytesExt};
 use crypto_common::{Buffer, Deserial, Serial};
@@ -8,6 +13,7 @@ use rand::{
     distributions::{Standard, Uniform},
     prelude::Distribution,
 };
+use semver::Version;
 use std::{
     fmt::{self, Display},
     hash::{Hash, Hasher},
@@ -32,6 +38,30 @@ impl fmt::Display for PeerType {
     }
 }
 
+/// Describes how the node learned about the peer of a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DiscoverySource {
+    /// The peer connected to us.
+    Incoming,
+    /// The address was given in the configuration or via RPC.
+    Given,
+    /// The address is that of a bootstrapper, or was advertised by one.
+    Bootstrap,
+    /// The peer was advertised in a PeerList by another node.
+    PeerList,
+}
+
+impl fmt::Display for DiscoverySource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match self {
+            DiscoverySource::Incoming => "incoming",
+            DiscoverySource::Given => "given",
+            DiscoverySource::Bootstrap => "bootstrap",
+            DiscoverySource::PeerList => "peer list",
+        })
+    }
+}
+
 /// Identifier we assign to a peer when they connect. This is a purely local
 /// identifier that is never transmitted over the network, but it is used
 /// internally to keep track of peers (e.g., during catchup) and to ban them.
@@ -198,6 +228,15 @@ impl P2PPeer {
 
     /// Get the peer's port.
     pub fn port(&self) -> u16 { self.addr.port() }
+
+    /// Get the peer's id, unless it was advertised as unknown.
+    pub fn known_id(&self) -> Option<P2PNodeId> {
+        if self.id == P2PNodeId::UNKNOWN {
+            None
+        } else {
+            Some(self.id)
+        }
+    }
 }
 
 impl Display for P2PPeer {
@@ -210,40 +249,69 @@ impl Display for P2PPeer {
 #[derive(Debug)]
 pub struct PeerStats {
     /// The peer's self identifier. Only used for reporting.
-    pub self_id:        P2PNodeId,
-    pub addr:           SocketAddr,
-    pub external_port:  u16,
+    pub self_id:            P2PNodeId,
+    pub addr:               SocketAddr,
+    pub external_port:      u16,
     /// Our identifier for the remote peer.
-    pub local_id:       RemotePeerId,
-    pub peer_type:      PeerType,
-    pub latency:        u64,
-    pub msgs_sent:      u64,
-    pub msgs_received:  u64,
-    pub bytes_sent:     u64,
-    pub bytes_received: u64,
+    pub local_id:           RemotePeerId,
+    pub peer_type:          PeerType,
+    /// How the node learned about the peer.
+    pub discovery_source:   DiscoverySource,
+    pub latency:            u64,
+    /// The latencies measured at the peer's most recent pongs.
+    pub latency_summary:    LatencySummary,
+    pub msgs_sent:          u64,
+    pub msgs_received:      u64,
+    pub bytes_sent:         u64,
+    pub bytes_received:     u64,
+    /// Bytes per second received from the peer over the last housekeeping
+    /// window.
+    pub bps_in:             u64,
+    /// Bytes per second sent to the peer over the last housekeeping window.
+    pub bps_out:            u64,
+    /// The number of bytes waiting to be written to the peer's socket.
+    pub output_queue_bytes: u64,
+    /// The client version the peer announced in its handshake.
+    pub node_version:       Version,
+    /// Time (in ms) since the handshake with the peer was completed, i.e., its
+    /// uptime as far as we can observe it.
+    pub uptime:             u64,
+    /// The peer's score; it is quarantined once the score drops below the
+    /// configured threshold.
+    pub score:              u64,
+    /// The networks the peer participates in.
+    pub networks:           Networks,
+    /// The region and autonomous system of the peer's address, if known.
+    pub location:           Option<PeerLocation>,
 }
 
 impl PeerStats {
-    /// Creates a new peer stats object.
-    pub fn new(
-        local_id: RemotePeerId,
-        self_id: P2PNodeId,
-        addr: SocketAddr,
-        external_port: u16,
-        peer_type: PeerType,
-        conn_stats: &ConnectionStats,
-    ) -> PeerStats {
+    /// Creates a peer stats object describing a post-handshake connection.
+    pub fn new(self_id: P2PNodeId, conn: &Connection) -> PeerStats {
+        let conn_stats = &conn.stats;
+        let (bps_in, bps_out) = conn_stats.get_rates();
         PeerStats {
-            local_id,
+            local_id: conn.remote_peer.local_id,
             self_id,
-            addr,
-            external_port,
-            peer_type,
+            addr: conn.remote_addr(),
+            external_port: conn.remote_peer_external_port(),
+            peer_type: conn.remote_peer_type(),
+            discovery_source: conn.discovery_source,
             latency: conn_stats.get_latency(),
+            latency_summary: conn_stats.get_latency_summary(),
             msgs_sent: conn_stats.messages_sent.load(AtomicOrdering::Relaxed),
             msgs_received: conn_stats.messages_received.load(AtomicOrdering::Relaxed),
             bytes_sent: conn_stats.bytes_sent.load(AtomicOrdering::Relaxed),
             bytes_received: conn_stats.bytes_received.load(AtomicOrdering::Relaxed),
+            bps_in,
+            bps_out,
+            output_queue_bytes: conn.output_queue_bytes() as u64,
+            // safe - always available post-handshake
+            node_version: conn.remote_version.clone().unwrap(),
+            uptime: get_current_stamp().saturating_sub(conn.handshake_completed),
+            score: conn_stats.get_score(),
+            networks: conn.remote_end_networks.clone(),
+            location: conn.location.location().cloned(),
         }
     }
 
@@ -255,3 +323,40 @@ impl PeerStats {
         SocketAddr::new(self.addr.ip(), self.external_port)
     }
 }
+
+/// A snapshot of the live state of a single connection. Unlike `PeerStats`,
+/// it is also available for connections that haven't completed the handshake,
+/// in which case the information about the peer is partial.
+#[derive(Debug)]
+pub struct ConnectionInfo {
+    /// Our identifier for the remote peer, i.e., the connection's token.
+    pub local_id:           RemotePeerId,
+    /// The peer's self identifier; only known after the handshake.
+    pub remote_id:          Option<P2PNodeId>,
+    pub addr:               SocketAddr,
+    /// The type of the peer; only reliable after the handshake.
+    pub peer_type:          PeerType,
+    pub discovery_source:   DiscoverySource,
+    pub handshake_complete: bool,
+    /// Timestamp of the connection's creation.
+    pub created:            u64,
+    /// Timestamp of the last message received from the peer.
+    pub last_seen:          u64,
+    pub latency:            u64,
+    pub msgs_sent:          u64,
+    pub msgs_received:      u64,
+    pub bytes_sent:         u64,
+    pub bytes_received:     u64,
+    /// The number of bytes waiting to be written to the peer's socket.
+    pub output_queue_bytes: u64,
+    /// The client version of the peer; only known after the handshake.
+    pub node_version:       Option<Version>,
+    /// Time (in ms) since the handshake with the peer was completed; 0 before
+    /// the handshake.
+    pub uptime:             u64,
+    /// The networks the peer participates in, including the ones it joined or
+    /// left since the handshake; empty before the handshake.
+    pub networks:           Networks,
+    /// The region and autonomous system of the peer's address, if known.
+    pub location:           Option<PeerLocation>,
+}
@@ -1,10 +1,18 @@
 use crate::concordium_dns::sys::*;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+use thiserror::Error;
 
 const DNS_ANCHOR_1: &str = ". IN DNSKEY 257 3 8 AwEAAaz/tAm8yTn4Mfeh5eyI96WSVexTBAvkMgJzkKTOiW1vkIbzxeF3+/4RgWOq7HrxRixHlFlExOLAJr5emLvN7SWXgnLh4+B5xQlNVz8Og8kvArMtNROxVQuCaSnIDdD5LKyWbRd2n9WGe2R8PzgCmr3EgVLrjyBxWezF0jLHwVN8efS3rCj/EWgvIWgb9tarpVUDK/b58Da+sqqls3eNbuv7pr+eoZG+SrDK6nWeL3c6H5Apxz7LjVc1uTIdsIXxuOLYA4/ilBmSVIzuDWfdRUfhHdY6+cn8HFRm+2hM8AnXGXws9555KrUB5qihylGa8subX2Nn6UwNR1AkUTV74bU=";
 const DNS_ANCHOR_2: &str = ". IN DNSKEY 256 3 8 AwEAAYvxrQOOujKdZz+37P+oL4l7e35/0diH/mZITGjlp4f81ZGQK42HNxSfkiSahinPR3t0YQhjC393NX4TorSiTJy76TBWddNOkC/IaGqcb4erU+nQ75k2Lf0oIpA7qTCk3UkzYBqhKDHHAr2UditE7uFLDcoX4nBLCoaH5FtfxhUqyTlRu0RBXAEuKO+rORTFP0XgA5vlzVmXtwCkb9G8GknHuO1jVAwu3syPRVHErIbaXs1+jahvWWL+Do4wd+lA+TL3+pUk+zKTD2ncq7ZbJBZddo9T7PZjvntWJUzIHIMWZRFAjpi+V7pgh0o1KYXZgDUbiA1s9oLAL1KLSdmoIYM=";
 const DNS_ANCHOR_3: &str = ". IN DNSKEY 257 3 8 AwEAAagAIKlVZrpC6Ia7gEzahOR+9W29euxhJhVVLOyQbSEW0O8gcCjFFVQUTf6v58fLjwBd0YI0EzrAcQqBGCzh/RStIoO8g0NfnfL2MTJRkxoXbfDaUeVPQuYEhg37NZWAJQ9VnMVDxP/VHL496M/QZxkjf5/Efucp2gaDX6RS6CXpoY68LsvPVjR0ZSwzz1apAzvN9dlzEheX7ICJBBtuA6G3LQpzW5hOA2hzCTMjJPJ8LbqF6dsV6DoBQzgul0sGIcGOYl7OyQdXfZ57relSQageu+ipAdTTJ25AsRTAoub8ONGcLmqrAmRLKBP1dfwhYB4N7knNnulqQxA+Uk1ihz0=";
 
+/// The port DNS resolvers listen on unless specified otherwise.
+pub const DNS_PORT: u16 = 53;
+
 #[derive(Copy, Clone, Debug)]
 // We disable this lint for this specific type since A, AAAA, and TXT are standard acronyms.
 #[allow(clippy::upper_case_acronyms)]
@@ -14,72 +22,137 @@ enum LookupType {
     TXT  = 16,
 }
 
+/// How DNS queries are performed.
+#[derive(Copy, Clone, Debug)]
+pub struct QueryOptions {
+    /// Reject answers that can't be validated with DNSSEC.
+    pub require_dnssec: bool,
+    /// How long to wait for the answer to a single query.
+    pub timeout:        Duration,
+    /// How many times a query that timed out or failed is retried.
+    pub retries:        u32,
+}
+
+#[derive(Debug, Error)]
+pub enum DnsError {
+    #[error("No answer from the DNS resolvers within {0:?}")]
+    Timeout(Duration),
+    #[error("The domain {0} does not exist")]
+    NxDomain(String),
+    #[error("DNSSEC validation failed")]
+    DnssecFailed,
+    #[error("DNS resolution failed: {0}")]
+    Failed(String),
+}
+
+/// Parse the address of a DNS resolver, given either as a bare IP address
+/// (in which case the default DNS port is used) or as an `ip:port` pair (with
+/// IPv6 addresses in brackets).
+pub fn parse_resolver(input: &str) -> Option<SocketAddr> {
+    SocketAddr::from_str(input)
+        .or_else(|_| IpAddr::from_str(input).map(|ip| SocketAddr::new(ip, DNS_PORT)))
+        .ok()
+}
+
 pub fn resolve_dns_txt_record(
     entry: &str,
-    dns_servers: &[IpAddr],
-    require_dnssec: bool,
-) -> Result<Vec<String>, String> {
+    dns_servers: &[SocketAddr],
+    options: QueryOptions,
+) -> Result<Vec<String>, DnsError> {
     debug!("Attempting to resolve TXT record {} using DNS server {:?}", entry, dns_servers);
-    resolve_dns_record(entry, dns_servers, require_dnssec, LookupType::TXT)
+    resolve_dns_record(entry, dns_servers, options, LookupType::TXT)
 }
 
 pub fn resolve_dns_a_record(
     entry: &str,
-    dns_servers: &[IpAddr],
-    require_dnssec: bool,
-) -> Result<Vec<String>, String> {
+    dns_servers: &[SocketAddr],
+    options: QueryOptions,
+) -> Result<Vec<String>, DnsError> {
     debug!("Attempting to resolve A record {} using DNS server {:?}", entry, dns_servers);
-    resolve_dns_record(entry, dns_servers, require_dnssec, LookupType::A)
+    resolve_dns_record(entry, dns_servers, options, LookupType::A)
 }
 
 pub fn resolve_dns_aaaa_record(
     entry: &str,
-    dns_servers: &[IpAddr],
-    require_dnssec: bool,
-) -> Result<Vec<String>, String> {
+    dns_servers: &[SocketAddr],
+    options: QueryOptions,
+) -> Result<Vec<String>, DnsError> {
     debug!("Attempting to resolve AAAA record {} using DNS server {:?}", entry, dns_servers);
-    resolve_dns_record(entry, dns_servers, require_dnssec, LookupType::AAAA)
+    resolve_dns_record(entry, dns_servers, options, LookupType::AAAA)
 }
 
+/// Resolve the record, retrying queries that time out or fail for a reason
+/// other than the domain not existing or the DNSSEC validation failing.
 fn resolve_dns_record(
     entry: &str,
-    dns_servers: &[IpAddr],
-    require_dnssec: bool,
+    dns_servers: &[SocketAddr],
+    options: QueryOptions,
     record_type: LookupType,
-) -> Result<Vec<String>, String> {
+) -> Result<Vec<String>, DnsError> {
+    let mut attempt = 0;
+    loop {
+        match query_dns_record(entry, dns_servers, options, record_type) {
+            Err(err @ DnsError::Timeout(_)) | Err(err @ DnsError::Failed(_))
+                if attempt < options.retries =>
+            {
+                attempt += 1;
+                warn!(
+                    "Resolving {} failed ({}); retrying ({}/{})",
+                    entry, err, attempt, options.retries
+                );
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Run a single query, which is given up on if it isn't answered within the
+/// timeout of the options.
+fn query_dns_record(
+    entry: &str,
+    dns_servers: &[SocketAddr],
+    options: QueryOptions,
+    record_type: LookupType,
+) -> Result<Vec<String>, DnsError> {
     let mut res = vec![];
 
-    let ctx = Context::new().unwrap();
+    let ctx = Context::new()
+        .map_err(|_| DnsError::Failed("Can't create the resolver context!".to_string()))?;
 
     if let Err(err) = ctx.add_ta(DNS_ANCHOR_1) {
         error!("error adding key 1: {}", err);
-        return Err("Error adding key 1!".to_string());
+        return Err(DnsError::Failed("Error adding key 1!".to_string()));
     }
 
     if let Err(err) = ctx.add_ta(DNS_ANCHOR_2) {
         error!("error adding key 2: {}", err);
-        return Err("Error adding key 2!".to_string());
+        return Err(DnsError::Failed("Error adding key 2!".to_string()));
     }
 
     if let Err(err) = ctx.add_ta(DNS_ANCHOR_3) {
         error!("error adding key 3: {}", err);
-        return Err("Error adding key 3!".to_string());
+        return Err(DnsError::Failed("Error adding key 3!".to_string()));
     }
 
     // Add forward resolvers
-    for ip in dns_servers {
-        debug!("Using DNS resolver: {}", ip);
-        if let Err(err) = ctx.set_fwd(ip) {
+    for &addr in dns_servers {
+        debug!("Using DNS resolver: {}", addr);
+        if let Err(err) = ctx.set_fwd(addr) {
             error!("error adding forwarder: {}", err);
-            return Err("Error adding forwarder!".to_string());
+            return Err(DnsError::Failed("Error adding forwarder!".to_string()));
         }
     }
 
-    match ctx.resolve(entry, record_type as u16, 1) {
-        Ok(ans) => {
-            if require_dnssec && !ans.secure() {
+    match ctx.resolve(entry, record_type as u16, 1, options.timeout) {
+        Ok(None) => return Err(DnsError::Timeout(options.timeout)),
+        Ok(Some(ans)) => {
+            if ans.nxdomain() {
+                return Err(DnsError::NxDomain(entry.to_owned()));
+            }
+
+            if options.require_dnssec && !ans.secure() {
                 error!("DNSSEC validation failed!");
-                return Err("DNSSEC validation failed!".to_string());
+                return Err(DnsError::DnssecFailed);
             }
 
             match record_type {
@@ -109,7 +182,7 @@ fn resolve_dns_record(
         }
         Err(err) => {
             error!("resolve error: {}", err);
-            return Err("Couldn't resolve!".to_string());
+            return Err(DnsError::Failed(err.to_string()));
         }
     }
 
@@ -145,12 +218,32 @@ fn data_to_ipv6(data: &[u8]) -> Ipv6Addr {
 #[cfg(test)]
 mod tests {
     use crate::concordium_dns::dns::*;
-    use std::str::FromStr;
+
+    fn resolver(ip: &str) -> [SocketAddr; 1] {
+        [SocketAddr::new(IpAddr::from_str(ip).unwrap(), DNS_PORT)]
+    }
+
+    fn options(require_dnssec: bool) -> QueryOptions {
+        QueryOptions {
+            require_dnssec,
+            timeout: Duration::from_secs(10),
+            retries: 0,
+        }
+    }
+
+    #[test]
+    pub fn test_parse_resolver() {
+        assert_eq!(parse_resolver("8.8.8.8"), Some("8.8.8.8:53".parse().unwrap()));
+        assert_eq!(parse_resolver("127.0.0.1:5353"), Some("127.0.0.1:5353".parse().unwrap()));
+        assert_eq!(parse_resolver("::1"), Some("[::1]:53".parse().unwrap()));
+        assert_eq!(parse_resolver("[::1]:5353"), Some("[::1]:5353".parse().unwrap()));
+        assert_eq!(parse_resolver("8.8.8.8:dns"), None);
+        assert_eq!(parse_resolver("dns.google"), None);
+    }
 
     #[test]
     pub fn test_googledns_resolve_dns() {
-        let res =
-            resolve_dns_txt_record("concordium.com", &[IpAddr::from_str("8.8.8.8").unwrap()], true);
+        let res = resolve_dns_txt_record("concordium.com", &resolver("8.8.8.8"), options(true));
         match res {
             Ok(ref resps) => assert_eq!(resps.len(), 4),
             Err(e) => panic!("{}", e),
@@ -159,11 +252,8 @@ mod tests {
 
     #[test]
     pub fn test_googledns_resolve_dns_fail() {
-        let res = resolve_dns_txt_record(
-            "www.dnssec-failed.org",
-            &[IpAddr::from_str("8.8.8.8").unwrap()],
-            true,
-        );
+        let res =
+            resolve_dns_txt_record("www.dnssec-failed.org", &resolver("8.8.8.8"), options(true));
         if res.is_ok() {
             panic!("This shouldn't happen - we got a valid response");
         }
@@ -172,11 +262,8 @@ mod tests {
     #[test]
     #[ignore]
     pub fn _test_quadnine_resolve_dns_fail() {
-        let res = resolve_dns_txt_record(
-            "www.dnssec-failed.org",
-            &[IpAddr::from_str("9.9.9.9").unwrap()],
-            true,
-        );
+        let res =
+            resolve_dns_txt_record("www.dnssec-failed.org", &resolver("9.9.9.9"), options(true));
         if res.is_ok() {
             panic!("This shouldn't happen - we got a valid response");
         }
@@ -185,11 +272,8 @@ mod tests {
     #[test]
     #[ignore]
     pub fn _test_norton_resolve_dns() {
-        let res = resolve_dns_txt_record(
-            "concordium.com",
-            &[IpAddr::from_str("199.85.126.20").unwrap()],
-            true,
-        );
+        let res =
+            resolve_dns_txt_record("concordium.com", &resolver("199.85.126.20"), options(true));
         match res {
             Ok(ref resps) => assert_eq!(resps.len(), 4),
             Err(e) => panic!("{}", e),
@@ -201,8 +285,8 @@ mod tests {
     pub fn _test_norton_resolve_dns_fail() {
         let res = resolve_dns_txt_record(
             "www.dnssec-failed.org",
-            &[IpAddr::from_str("199.85.126.20").unwrap()],
-            true,
+            &resolver("199.85.126.20"),
+            options(true),
         );
         if res.is_ok() {
             panic!("This shouldn't happen - we got a valid response");
@@ -212,8 +296,7 @@ mod tests {
     #[test]
     #[ignore]
     pub fn _test_quadnine_resolve_dns() {
-        let res =
-            resolve_dns_txt_record("concordium.com", &[IpAddr::from_str("9.9.9.9").unwrap()], true);
+        let res = resolve_dns_txt_record("concordium.com", &resolver("9.9.9.9"), options(true));
         match res {
             Ok(ref resps) => assert_eq!(resps.len(), 4),
             Err(e) => panic!("{}", e),
@@ -222,11 +305,8 @@ mod tests {
 
     #[test]
     pub fn test_cloudflare_resolve_dns_fail() {
-        let res = resolve_dns_txt_record(
-            "www.dnssec-failed.org",
-            &[IpAddr::from_str("1.1.1.1").unwrap()],
-            true,
-        );
+        let res =
+            resolve_dns_txt_record("www.dnssec-failed.org", &resolver("1.1.1.1"), options(true));
         if res.is_ok() {
             panic!("This shouldn't happen - we got a valid response");
         }
@@ -234,8 +314,7 @@ mod tests {
 
     #[test]
     pub fn test_cloudflare_resolve_dns() {
-        let res =
-            resolve_dns_txt_record("concordium.com", &[IpAddr::from_str("8.8.8.8").unwrap()], true);
+        let res = resolve_dns_txt_record("concordium.com", &resolver("8.8.8.8"), options(true));
         match res {
             Ok(ref resps) => assert_eq!(resps.len(), 4),
             Err(e) => panic!("{}", e),
@@ -245,11 +324,8 @@ mod tests {
     #[test]
     #[ignore]
     pub fn _test_comodo_resolve_dns_fail() {
-        let res = resolve_dns_txt_record(
-            "www.dnssec-failed.org",
-            &[IpAddr::from_str("8.26.56.26").unwrap()],
-            true,
-        );
+        let res =
+            resolve_dns_txt_record("www.dnssec-failed.org", &resolver("8.26.56.26"), options(true));
         if res.is_ok() {
             panic!("This shouldn't happen - we got a valid response");
         }
@@ -258,11 +334,8 @@ mod tests {
     #[test]
     #[ignore]
     pub fn _test_cleanbrowsing_resolve_dns() {
-        let res = resolve_dns_txt_record(
-            "concordium.com",
-            &[IpAddr::from_str("185.228.168.168").unwrap()],
-            true,
-        );
+        let res =
+            resolve_dns_txt_record("concordium.com", &resolver("185.228.168.168"), options(true));
         match res {
             Ok(ref resps) => assert_eq!(resps.len(), 4),
             Err(e) => panic!("{}", e),
@@ -272,11 +345,7 @@ mod tests {
     #[test]
     #[ignore]
     pub fn _test_comodo_resolve_dns() {
-        let res = resolve_dns_txt_record(
-            "concordium.com",
-            &[IpAddr::from_str("8.26.56.26").unwrap()],
-            true,
-        );
+        let res = resolve_dns_txt_record("concordium.com", &resolver("8.26.56.26"), options(true));
         match res {
             Ok(ref resps) => assert_eq!(resps.len(), 4),
             Err(e) => panic!("{}", e),
@@ -287,8 +356,8 @@ mod tests {
     pub fn test_cleanbrowsing_resolve_dns_fail() {
         let res = resolve_dns_txt_record(
             "www.dnssec-failed.org",
-            &[IpAddr::from_str("185.228.168.168").unwrap()],
-            true,
+            &resolver("185.228.168.168"),
+            options(true),
         );
         if res.is_ok() {
             panic!("This shouldn't happen - we got a valid response");
@@ -297,11 +366,8 @@ mod tests {
 
     #[test]
     pub fn test_opendns_resolve_nodnssec_fail() {
-        let res = resolve_dns_txt_record(
-            "concordium.com",
-            &[IpAddr::from_str("208.67.220.220").unwrap()],
-            true,
-        );
+        let res =
+            resolve_dns_txt_record("concordium.com", &resolver("208.67.220.220"), options(true));
         match res {
             Ok(ref resps) => assert_eq!(resps.len(), 4),
             Err(e) => panic!("{}", e),
@@ -310,11 +376,7 @@ mod tests {
 
     #[test]
     pub fn test_yandex_resolve_nodnssec_fail() {
-        let res = resolve_dns_txt_record(
-            "concordium.com",
-            &[IpAddr::from_str("77.88.8.7").unwrap()],
-            true,
-        );
+        let res = resolve_dns_txt_record("concordium.com", &resolver("77.88.8.7"), options(true));
         if res.is_ok() {
             panic!("This shouldn't happen - we got a valid response");
         }
@@ -322,11 +384,7 @@ mod tests {
 
     #[test]
     pub fn test_yandex_resolve_nodnssec_fail_nodnssec_test() {
-        let res = resolve_dns_txt_record(
-            "concordium.com",
-            &[IpAddr::from_str("77.88.8.7").unwrap()],
-            false,
-        );
+        let res = resolve_dns_txt_record("concordium.com", &resolver("77.88.8.7"), options(false));
         match res {
             Ok(ref resps) => assert_eq!(resps.len(), 4),
             Err(e) => panic!("{}", e),
@@ -335,8 +393,7 @@ mod tests {
 
     #[test]
     pub fn test_googledns_resolve_a_record() {
-        let res =
-            resolve_dns_a_record("google.com", &[IpAddr::from_str("8.8.8.8").unwrap()], false);
+        let res = resolve_dns_a_record("google.com", &resolver("8.8.8.8"), options(false));
         match res {
             Ok(ref resps) => assert!(!resps.is_empty()),
             Err(e) => panic!("{}", e),
@@ -345,8 +402,7 @@ mod tests {
 
     #[test]
     pub fn test_googledns_resolve_aaaa_record() {
-        let res =
-            resolve_dns_aaaa_record("google.com", &[IpAddr::from_str("8.8.8.8").unwrap()], false);
+        let res = resolve_dns_aaaa_record("google.com", &resolver("8.8.8.8"), options(false));
         match res {
             Ok(ref resps) => assert!(!resps.is_empty()),
             Err(e) => panic!("{}", e),
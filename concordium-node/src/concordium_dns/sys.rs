@@ -1,16 +1,12 @@
 use crate::concordium_dns::sys_c as sys;
-use libc::c_int;
+use libc::{c_int, c_void};
 use std::{
-    borrow::Borrow,
     ffi::{CStr, CString, NulError},
-    fmt,
-    io::{self, Write},
-    net, ptr,
+    fmt, net, thread,
+    time::{Duration, Instant},
 };
 use thiserror::Error;
 
-const IP_CSTR_MAX: usize = 40;
-
 #[derive(Debug, Error)]
 #[error("Argument contains null byte.")]
 pub struct NullByteError;
@@ -62,6 +58,9 @@ impl Answer {
         }
     }
 
+    /// True if the queried domain does not exist.
+    pub fn nxdomain(&self) -> bool { unsafe { (*self.0).nxdomain != 0 } }
+
     /// True if result is secure.
     pub fn secure(&self) -> bool { unsafe { (*self.0).secure != 0 } }
 }
@@ -92,24 +91,10 @@ impl Context {
         }
     }
 
-    pub fn set_fwd<T: Borrow<net::IpAddr>>(&self, ip: T) -> anyhow::Result<()> {
-        match *ip.borrow() {
-            net::IpAddr::V4(ref ip) => self.set_fwd4(ip),
-            net::IpAddr::V6(ref ip) => self.set_fwd6(ip),
-        }
-    }
-
-    /// Forward queries to an IPv4 host.
-    pub fn set_fwd4<T: Borrow<net::Ipv4Addr>>(&self, ip: T) -> anyhow::Result<()> {
-        let mut buf = [0u8; IP_CSTR_MAX];
-        let target = ipv4_to_cstr(ip.borrow(), &mut buf);
-        unsafe { into_result!(sys::ub_ctx_set_fwd(self.ub_ctx, target.as_ptr())) }
-    }
-
-    /// Forward queries to an IPv6 host.
-    pub fn set_fwd6<T: Borrow<net::Ipv6Addr>>(&self, ip: T) -> anyhow::Result<()> {
-        let mut buf = [0u8; IP_CSTR_MAX];
-        let target = ipv6_to_cstr(ip.borrow(), &mut buf);
+    /// Forward queries to the host at the given address.
+    pub fn set_fwd(&self, addr: net::SocketAddr) -> anyhow::Result<()> {
+        // unbound expects `ip@port`, and no brackets around IPv6 addresses
+        let target = CString::new(format!("{}@{}", addr.ip(), addr.port()))?;
         unsafe { into_result!(sys::ub_ctx_set_fwd(self.ub_ctx, target.as_ptr())) }
     }
 
@@ -120,25 +105,69 @@ impl Context {
         unsafe { into_result!(sys::ub_ctx_add_ta(self.ub_ctx, ta.as_ptr())) }
     }
 
-    /// Resolve and validate a query.
-    pub fn resolve(&self, name: &str, rrtype: u16, class: u16) -> anyhow::Result<Answer> {
-        let mut result: *mut sys::ub_result = ptr::null_mut();
+    /// Resolve and validate a query, giving up on it once the timeout has
+    /// passed. Returns `None` if there was no answer in time. The query runs
+    /// on the context's own background thread, which is stopped along with
+    /// the context, so a timed out query doesn't leave anything behind.
+    pub fn resolve(
+        &self,
+        name: &str,
+        rrtype: u16,
+        class: u16,
+        timeout: Duration,
+    ) -> anyhow::Result<Option<Answer>> {
         let name = CString::new(name)?;
+        // set by `store_outcome` once the query completes
+        let mut outcome: Option<(c_int, *mut sys::ub_result)> = None;
+        let mut async_id: c_int = 0;
         unsafe {
-            into_result!(
-                sys::ub_resolve(
-                    self.ub_ctx,
-                    name.as_ptr(),
-                    i32::from(rrtype),
-                    i32::from(class),
-                    &mut result
-                ),
-                Answer(result)
-            )
+            let threaded: anyhow::Result<()> = into_result!(sys::ub_ctx_async(self.ub_ctx, 1));
+            threaded?;
+            let started: anyhow::Result<()> = into_result!(sys::ub_resolve_async(
+                self.ub_ctx,
+                name.as_ptr(),
+                i32::from(rrtype),
+                i32::from(class),
+                &mut outcome as *mut _ as *mut c_void,
+                Some(store_outcome),
+                &mut async_id
+            ));
+            started?;
+        }
+
+        let deadline = Instant::now() + timeout;
+        while outcome.is_none() {
+            if unsafe { sys::ub_poll(self.ub_ctx) } != 0 {
+                // runs the callback of the completed query
+                let processed: anyhow::Result<()> =
+                    unsafe { into_result!(sys::ub_process(self.ub_ctx)) };
+                processed?;
+            } else if Instant::now() >= deadline {
+                // the callback is never called for a cancelled query, so `outcome`
+                // isn't written to after it goes out of scope
+                unsafe { sys::ub_cancel(self.ub_ctx, async_id) };
+                return Ok(None);
+            } else {
+                thread::sleep(RESOLVE_POLL_INTERVAL);
+            }
         }
+
+        // safe - the loop only ends once the outcome is set
+        let (err, result) = outcome.unwrap();
+        into_result!(err, Some(Answer(result)))
     }
 }
 
+/// How often a pending query is checked for an answer.
+const RESOLVE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The callback of an asynchronous query, storing its outcome in the
+/// `Option<(c_int, *mut ub_result)>` that `mydata` points to.
+unsafe extern "C" fn store_outcome(mydata: *mut c_void, err: c_int, result: *mut sys::ub_result) {
+    let outcome = mydata as *mut Option<(c_int, *mut sys::ub_result)>;
+    *outcome = Some((err, result));
+}
+
 /// An iterator over the datas of an [Answer](struct.Answer.html).
 pub struct DataIter<'a> {
     index:  isize,
@@ -164,21 +193,3 @@ impl<'a> std::iter::Iterator for DataIter<'a> {
         item
     }
 }
-
-fn ipv4_to_cstr<'a>(ip: &net::Ipv4Addr, buf: &'a mut [u8; IP_CSTR_MAX]) -> &'a CStr {
-    let len = {
-        let mut w = io::BufWriter::new(&mut buf[..]);
-        w.write_fmt(format_args!("{}", &ip)).expect("write_fmt ipv4");
-        IP_CSTR_MAX + 1 - w.into_inner().expect("into_inner ipv4").len()
-    };
-    CStr::from_bytes_with_nul(&buf[..len]).expect("valid ipv4 c str")
-}
-
-fn ipv6_to_cstr<'a>(ip: &net::Ipv6Addr, buf: &'a mut [u8; IP_CSTR_MAX]) -> &'a CStr {
-    let len = {
-        let mut w = io::BufWriter::new(&mut buf[..]);
-        w.write_fmt(format_args!("{}", &ip)).expect("write_fmt ipv6");
-        IP_CSTR_MAX + 1 - w.into_inner().expect("into_inner ipv6").len()
-    };
-    CStr::from_bytes_with_nul(&buf[..len]).expect("valid ipv6 c str")
-}
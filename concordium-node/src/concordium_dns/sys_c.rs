@@ -20,9 +20,15 @@ pub struct ub_result {
     pub why_bogus:     *mut ::libc::c_char,
     pub ttl:           ::libc::c_int,
 }
+pub type ub_callback_type = ::std::option::Option<
+    unsafe extern "C" fn(mydata: *mut ::libc::c_void, err: ::libc::c_int, result: *mut ub_result),
+>;
 extern "C" {
     pub fn ub_ctx_create() -> *mut ub_ctx;
 }
+extern "C" {
+    pub fn ub_ctx_async(ctx: *mut ub_ctx, dothread: ::libc::c_int) -> ::libc::c_int;
+}
 extern "C" {
     pub fn ub_ctx_set_fwd(ctx: *mut ub_ctx, addr: *const ::libc::c_char) -> ::libc::c_int;
 }
@@ -38,6 +44,26 @@ extern "C" {
         result: *mut *mut ub_result,
     ) -> ::libc::c_int;
 }
+extern "C" {
+    pub fn ub_resolve_async(
+        ctx: *mut ub_ctx,
+        name: *const ::libc::c_char,
+        rrtype: ::libc::c_int,
+        rrclass: ::libc::c_int,
+        mydata: *mut ::libc::c_void,
+        callback: ub_callback_type,
+        async_id: *mut ::libc::c_int,
+    ) -> ::libc::c_int;
+}
+extern "C" {
+    pub fn ub_poll(ctx: *mut ub_ctx) -> ::libc::c_int;
+}
+extern "C" {
+    pub fn ub_process(ctx: *mut ub_ctx) -> ::libc::c_int;
+}
+extern "C" {
+    pub fn ub_cancel(ctx: *mut ub_ctx, async_id: ::libc::c_int) -> ::libc::c_int;
+}
 extern "C" {
     pub fn ub_ctx_delete(ctx: *mut ub_ctx);
 }
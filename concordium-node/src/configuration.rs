@@ -2,19 +2,33 @@
 
 use crate::{
     common::P2PNodeId,
-    connection::DeduplicationHashAlgorithm,
-    network::{WireProtocolVersion, WIRE_PROTOCOL_VERSION},
+    concordium_dns::dns,
+    connection::{DeduplicationHashAlgorithm, HANDSHAKE_SIZE_LIMIT},
+    consensus_ffi::consensus::{CONSENSUS_QUEUE_DEPTH_IN_HI, CONSENSUS_QUEUE_DEPTH_IN_LO},
+    network::{
+        serialization::fbs::PEER_LIST_SIZE_LIMIT, WireProtocolVersion, WIRE_PROTOCOL_VERSION,
+    },
+    p2p::{
+        connectivity::{AllowlistEntry, RelayStrategy},
+        maintenance::ThreadPoolSize,
+    },
+    utils::LogFormat,
 };
-use anyhow::{ensure, Context};
+#[cfg(feature = "network_dump")]
+use crate::{consensus_ffi::helpers::PacketType, dumper::DumpFormat};
+use anyhow::{bail, ensure, Context};
 use app_dirs2::*;
 use preferences::{Preferences, PreferencesMap};
 use std::{
     fs::{File, OpenOptions},
     io::{BufReader, BufWriter, Write},
+    net::IpAddr,
     path::{Path, PathBuf},
     str::FromStr,
+    time::Duration,
 };
 use structopt::{clap::AppSettings, StructOpt};
+use thiserror::Error;
 
 /// Client's details for local directory setup purposes.
 pub const APP_INFO: AppInfo = AppInfo {
@@ -61,8 +75,9 @@ pub const DUMP_QUEUE_DEPTH: usize = 100;
 pub const DUMP_SWITCH_QUEUE_DEPTH: usize = 0;
 
 // connection-related consts
-/// Maximum time (in s) a node's connection can remain unreachable.
-pub const UNREACHABLE_EXPIRATION_SECS: u64 = 86_400;
+/// Time (in s) for which a node doesn't reconnect to a peer whose connection
+/// was closed for outliving the maximum connection lifetime.
+pub const ROTATED_PEER_EXPIRATION_SECS: u64 = 600;
 /// Maximum time (in ms) a bootstrapper can hold a connection to a node.
 pub const MAX_BOOTSTRAPPER_KEEP_ALIVE: u64 = 20_000;
 /// Maximum time (in ms) a node can hold an inactive connection to a peer.
@@ -70,8 +85,22 @@ pub const MAX_NORMAL_KEEP_ALIVE: u64 = 1_200_000;
 /// Maximum time (in ms) a connection can be kept without concluding a
 /// handshake.
 pub const MAX_PREHANDSHAKE_KEEP_ALIVE: u64 = 10_000;
+/// Time (in s) during which the nonce of a completed noise handshake is
+/// remembered, and a handshake reusing it is rejected as a replay.
+pub const HANDSHAKE_REPLAY_WINDOW_SECS: u64 = 600;
+/// Maximum number of handshake nonces remembered within the replay window.
+pub const MAX_RECENT_HANDSHAKES: usize = 100_000;
 /// Maximum time (in s) a soft ban is in force.
 pub const SOFT_BAN_DURATION_SECS: u64 = 300;
+/// The score of a fresh connection, which is also the highest possible one.
+pub const MAX_PEER_SCORE: u64 = 100;
+/// The score a peer loses for every invalid message it sent.
+pub const INVALID_MESSAGE_PENALTY: u64 = 10;
+/// The score a peer loses at every housekeeping round its latency is high.
+pub const HIGH_LATENCY_PENALTY: u64 = 5;
+/// The number of latencies needed for their 95th percentile to differ from
+/// their maximum.
+pub const MIN_P95_LATENCY_SAMPLES: usize = 20;
 /// Maximum number of networks a peer can share
 pub const MAX_PEER_NETWORKS: usize = 20;
 /// Database subdirectory name
@@ -178,6 +207,18 @@ pub struct PrometheusConfig {
         env = "CONCORDIUM_NODE_PROMETHEUS_PUSH_GATEWAY_INTERVAL"
     )]
     pub prometheus_push_interval: u64,
+    #[structopt(
+        long = "prometheus-push-gateway-tls",
+        help = "Use HTTPS for the push gateway unless its address specifies a scheme",
+        env = "CONCORDIUM_NODE_PROMETHEUS_PUSH_GATEWAY_TLS"
+    )]
+    pub prometheus_push_tls:      bool,
+    #[structopt(
+        long = "prometheus-push-gateway-proxy",
+        help = "Proxy to reach the push gateway through, e.g. http://proxy:3128",
+        env = "CONCORDIUM_NODE_PROMETHEUS_PUSH_GATEWAY_PROXY"
+    )]
+    pub prometheus_push_proxy:    Option<String>,
 }
 
 #[derive(StructOpt, Debug)]
@@ -284,6 +325,13 @@ pub struct BakerConfig {
         env = "CONCORDIUM_NODE_CONSENSUS_MAX_EXPIRY_DURATION"
     )]
     pub max_time_to_expiry: u64,
+    #[structopt(
+        long = "genesis-data-file",
+        help = "Path to the genesis data file; if not set, genesis.dat in the data directory is \
+                used",
+        env = "CONCORDIUM_NODE_GENESIS_DATA_FILE"
+    )]
+    pub genesis_data_file: Option<PathBuf>,
     #[structopt(
         long = "baker-credentials-file",
         help = "Path to the baker credentials file",
@@ -307,21 +355,26 @@ pub struct RpcCliConfig {
         help = "Disable the built-in RPC server",
         env = "CONCORDIUM_NODE_DISABLE_RPC_SERVER"
     )]
-    pub no_rpc_server:    bool,
+    pub no_rpc_server:     bool,
     #[structopt(
         long = "rpc-server-port",
-        help = "RPC server port",
-        default_value = "10000",
+        help = "RPC server port [default: 10000]",
         env = "CONCORDIUM_NODE_RPC_SERVER_PORT"
     )]
-    pub rpc_server_port:  u16,
+    pub rpc_server_port:   Option<u16>,
     #[structopt(
         long = "rpc-server-addr",
-        help = "RPC server listen address",
-        default_value = "127.0.0.1",
+        help = "RPC server listen address [default: 127.0.0.1]",
         env = "CONCORDIUM_NODE_RPC_SERVER_ADDR"
     )]
-    pub rpc_server_addr:  String,
+    pub rpc_server_addr:   Option<String>,
+    #[structopt(
+        long = "rpc-server-socket",
+        help = "Path of a Unix domain socket to serve RPC on instead of TCP; access to it is \
+                controlled by the file permissions, and no access token is required",
+        env = "CONCORDIUM_NODE_RPC_SERVER_SOCKET"
+    )]
+    pub rpc_server_socket: Option<PathBuf>,
     #[structopt(
         long = "rpc-server-token",
         help = "RPC server access token",
@@ -329,9 +382,15 @@ pub struct RpcCliConfig {
         env = "CONCORDIUM_NODE_RPC_SERVER_TOKEN",
         hide_env_values = true
     )]
-    pub rpc_server_token: String,
+    pub rpc_server_token:  String,
 }
 
+/// The address the RPC server listens on unless configured otherwise.
+pub const DEFAULT_RPC_SERVER_ADDR: &str = "127.0.0.1";
+
+/// The port the RPC server listens on unless configured otherwise.
+pub const DEFAULT_RPC_SERVER_PORT: u16 = 10000;
+
 #[derive(StructOpt, Debug)]
 // Parameters related to connections.
 pub struct ConnectionConfig {
@@ -356,6 +415,44 @@ pub struct ConnectionConfig {
         env = "CONCORDIUM_NODE_CONNECTION_MAX_ALLOWED_NODES_PERCENTAGE"
     )]
     pub max_allowed_nodes_percentage: u16,
+    #[structopt(
+        long = "no-drop-excess-peers",
+        help = "Do not drop established connections when the number of peers exceeds the maximum. \
+                Instead refuse new connections until the number of peers drops below it.",
+        env = "CONCORDIUM_NODE_CONNECTION_NO_DROP_EXCESS_PEERS"
+    )]
+    pub no_drop_excess_peers: bool,
+    #[structopt(
+        long = "no-handshake-capabilities",
+        help = "Do not announce the node's capabilities (supported serialization formats, \
+                compression and maximum message size) in handshakes",
+        env = "CONCORDIUM_NODE_CONNECTION_NO_HANDSHAKE_CAPABILITIES"
+    )]
+    pub no_handshake_capabilities: bool,
+    #[structopt(
+        long = "max-connection-lifetime",
+        help = "Time (in s) after which a connection to a peer is closed to make room for a fresh \
+                one; connections are rotated one at a time and given peers are kept. Disabled if \
+                not set",
+        env = "CONCORDIUM_NODE_CONNECTION_MAX_CONNECTION_LIFETIME"
+    )]
+    pub max_connection_lifetime: Option<u64>,
+    #[structopt(
+        long = "shutdown-drain-timeout",
+        help = "Time (in s) the node waits on shutdown for its connections to send what is queued \
+                for them before closing them forcibly",
+        default_value = "10",
+        env = "CONCORDIUM_NODE_CONNECTION_SHUTDOWN_DRAIN_TIMEOUT"
+    )]
+    pub shutdown_drain_timeout: u64,
+    #[structopt(
+        long = "max-peer-list-size",
+        help = "The maximum number of peers a node shares in a PeerList response; if it has more, \
+                a random selection of them is shared",
+        default_value = "100",
+        env = "CONCORDIUM_NODE_CONNECTION_MAX_PEER_LIST_SIZE"
+    )]
+    pub max_peer_list_size: usize,
     #[structopt(
         long = "no-bootstrap",
         help = "Do not bootstrap via DNS",
@@ -368,6 +465,20 @@ pub struct ConnectionConfig {
         env = "CONCORDIUM_NODE_CONNECTION_NO_CLEAR_BANS"
     )]
     pub no_clear_bans: bool,
+    #[structopt(
+        long = "in-memory-bans",
+        help = "Keep the bans in memory only instead of persisting them in the data directory",
+        env = "CONCORDIUM_NODE_CONNECTION_IN_MEMORY_BANS"
+    )]
+    pub in_memory_bans: bool,
+    #[structopt(
+        long = "peer-location-subnets",
+        help = "Annotate the peers with the region and autonomous system of their IP address, as \
+                given by the subnets listed in this file (one `<subnet>,<region>[,<asn>]` per \
+                line)",
+        env = "CONCORDIUM_NODE_CONNECTION_PEER_LOCATION_SUBNETS"
+    )]
+    pub peer_location_subnets: Option<PathBuf>,
     #[structopt(
         long = "relay-broadcast-percentage",
         help = "The percentage of peers to relay broadcasted messages to",
@@ -375,6 +486,30 @@ pub struct ConnectionConfig {
         env = "CONCORDIUM_NODE_CONNECTION_RELAY_BROADCAST_PERCENTAGE"
     )]
     pub relay_broadcast_percentage: f64,
+    #[structopt(
+        long = "relay-strategy",
+        help = "How to choose the peers to relay broadcasted messages to: a random percentage of \
+                them, a random square root of their number, or the percentage with the lowest \
+                latency [percentage|sqrt|low-latency]",
+        default_value = "percentage",
+        env = "CONCORDIUM_NODE_CONNECTION_RELAY_STRATEGY"
+    )]
+    pub relay_strategy: RelayStrategy,
+    #[structopt(
+        long = "relay-dampening-window",
+        help = "Time (in ms) during which identical copies of a relayed broadcast are neither \
+                relayed nor processed again (disabled if not set)",
+        env = "CONCORDIUM_NODE_CONNECTION_RELAY_DAMPENING_WINDOW"
+    )]
+    pub relay_dampening_window: Option<u64>,
+    #[structopt(
+        long = "broadcast-hop-limit",
+        help = "The number of times the broadcasts originating from this node may be relayed; \
+                received broadcasts are relayed at most as often",
+        default_value = "32",
+        env = "CONCORDIUM_NODE_CONNECTION_BROADCAST_HOP_LIMIT"
+    )]
+    pub broadcast_hop_limit: u8,
     #[structopt(
         long = "connect-to",
         short = "c",
@@ -383,6 +518,15 @@ pub struct ConnectionConfig {
         env = "CONCORDIUM_NODE_CONNECTION_CONNECT_TO"
     )]
     pub connect_to: Vec<String>,
+    #[structopt(
+        long = "connect-to-resolve-interval",
+        help = "Interval (in s) at which the hostnames of the --connect-to peers that are not \
+                connected are resolved again, so that the node reconnects to them if their IP \
+                changed. 0 disables the re-resolution",
+        default_value = "300",
+        env = "CONCORDIUM_NODE_CONNECTION_CONNECT_TO_RESOLVE_INTERVAL"
+    )]
+    pub connect_to_resolve_interval: u64,
     #[structopt(
         long = "require-dnssec",
         help = "Perform DNSsec tests for lookups",
@@ -395,17 +539,48 @@ pub struct ConnectionConfig {
         env = "CONCORDIUM_NODE_CONNECTION_DISALLOW_MULTIPLE_PEERS_ON_SAME_IP"
     )]
     pub disallow_multiple_peers_on_ip: bool,
+    #[structopt(
+        long = "max-connections-per-ip",
+        help = "Maximum number of incoming connections from a single IP address",
+        env = "CONCORDIUM_NODE_CONNECTION_MAX_CONNECTIONS_PER_IP"
+    )]
+    pub max_connections_per_ip: Option<u16>,
+    #[structopt(
+        long = "allowlist",
+        help = "Only accept connections from, and connect to, the given peers (IP addresses or \
+                node ids); bootstrappers must be on the list as well",
+        use_delimiter = true,
+        env = "CONCORDIUM_NODE_CONNECTION_ALLOWLIST"
+    )]
+    pub allowlist: Vec<AllowlistEntry>,
     #[structopt(
         long = "dns-resolver",
-        help = "DNS resolver to use",
+        help = "DNS resolver to use, as an IP address optionally followed by a port (ip:port, \
+                with IPv6 addresses in brackets)",
         env = "CONCORDIUM_NODE_CONNECTION_DNS_RESOLVER",
         use_delimiter = true
     )]
     pub dns_resolver: Vec<String>,
+    #[structopt(
+        long = "dns-timeout-ms",
+        help = "How long to wait for the answer to a single DNS query (in ms)",
+        default_value = "5000",
+        env = "CONCORDIUM_NODE_CONNECTION_DNS_TIMEOUT_MS"
+    )]
+    pub dns_timeout_ms: u64,
+    #[structopt(
+        long = "dns-retries",
+        help = "How many times a DNS query that timed out or failed is retried",
+        default_value = "2",
+        env = "CONCORDIUM_NODE_CONNECTION_DNS_RETRIES"
+    )]
+    pub dns_retries: u32,
     #[structopt(
         name = "bootstrap-node",
         long = "bootstrap-node",
-        help = "Bootstrap nodes to use upon startup host/ip:port (this disables DNS bootstrapping)",
+        help = "Bootstrap nodes to use upon startup host/ip:port (this disables DNS \
+                bootstrapping). A #dnssec or #no-dnssec suffix overrides --require-dnssec for \
+                that entry",
         env = "CONCORDIUM_NODE_CONNECTION_BOOTSTRAP_NODES",
         use_delimiter = true
     )]
@@ -431,12 +606,127 @@ pub struct ConnectionConfig {
         env = "CONCORDIUM_NODE_CONNECTION_BOOTSTRAPPING_INTERVAL"
     )]
     pub bootstrapping_interval: u64,
+    #[structopt(
+        long = "bootstrap-deadline",
+        help = "Time in seconds within which bootstrapping must produce at least one peer. If it \
+                does not, the failure is reported and further attempts are delayed with an \
+                exponential backoff",
+        env = "CONCORDIUM_NODE_CONNECTION_BOOTSTRAP_DEADLINE"
+    )]
+    pub bootstrap_deadline: Option<u64>,
+    #[structopt(
+        long = "bootstrap-strict",
+        help = "Shut down the node if bootstrapping fails within the bootstrap deadline",
+        env = "CONCORDIUM_NODE_CONNECTION_BOOTSTRAP_STRICT"
+    )]
+    pub bootstrap_strict: bool,
+    #[structopt(
+        long = "bootstrap-backoff-max",
+        help = "Maximum time in seconds by which a bootstrap attempt is delayed after \
+                bootstrapping failed within the bootstrap deadline",
+        default_value = "86400",
+        env = "CONCORDIUM_NODE_CONNECTION_BOOTSTRAP_BACKOFF_MAX"
+    )]
+    pub bootstrap_backoff_max: u64,
+    #[structopt(
+        long = "bootstrap-probe-timeout",
+        help = "Before connecting to a bootstrapper, check that it accepts TCP connections within \
+                this many ms and skip it if it doesn't",
+        env = "CONCORDIUM_NODE_CONNECTION_BOOTSTRAP_PROBE_TIMEOUT"
+    )]
+    pub bootstrap_probe_timeout: Option<u64>,
+    #[structopt(
+        long = "reconnect-backoff-base",
+        help = "Time in seconds after which an address that could not be connected to is retried \
+                for the first time. Each further consecutive failure doubles the delay, which is \
+                randomized by up to a half",
+        default_value = "10",
+        env = "CONCORDIUM_NODE_CONNECTION_RECONNECT_BACKOFF_BASE"
+    )]
+    pub reconnect_backoff_base: u64,
+    #[structopt(
+        long = "reconnect-backoff-max",
+        help = "Maximum time in seconds before an address that could not be connected to is \
+                retried",
+        default_value = "86400",
+        env = "CONCORDIUM_NODE_CONNECTION_RECONNECT_BACKOFF_MAX"
+    )]
+    pub reconnect_backoff_max: u64,
+    #[structopt(
+        long = "bootstrap-probe-cache-ttl",
+        help = "Time in seconds for which the result of probing a bootstrapper is reused instead \
+                of probing it again. 0 disables the caching",
+        default_value = "60",
+        env = "CONCORDIUM_NODE_CONNECTION_BOOTSTRAP_PROBE_CACHE_TTL"
+    )]
+    pub bootstrap_probe_cache_ttl: u64,
+    #[structopt(
+        long = "bootstrap-cache-ttl",
+        help = "Time in seconds for which the last resolved addresses of a bootstrap node, cached \
+                in the data directory, are used when resolving the node fails. 0 disables the \
+                caching",
+        default_value = "86400",
+        env = "CONCORDIUM_NODE_CONNECTION_BOOTSTRAP_CACHE_TTL"
+    )]
+    pub bootstrap_cache_ttl: u64,
     #[structopt(
         long = "max-latency",
         help = "The maximum allowed connection latency in ms",
         env = "CONCORDIUM_NODE_CONNECTION_MAX_LATENCY"
     )]
     pub max_latency: Option<u64>,
+    #[structopt(
+        long = "max-latency-use-p95",
+        help = "Compare the 95th percentile of a connection's recent latencies against \
+                --max-latency, instead of the last measured one. Connections are only compared \
+                once their latency window is full; the window must hold at least 20 latencies",
+        env = "CONCORDIUM_NODE_CONNECTION_MAX_LATENCY_USE_P95"
+    )]
+    pub max_latency_use_p95: bool,
+    #[structopt(
+        long = "latency-window",
+        help = "The number of recent ping round-trip times kept for every connection",
+        default_value = "20",
+        env = "CONCORDIUM_NODE_CONNECTION_LATENCY_WINDOW"
+    )]
+    pub latency_window: usize,
+    #[structopt(
+        long = "peer-score-threshold",
+        help = "Quarantine peers whose score drops below this value. Peers start with a score of \
+                100, lose points for invalid messages, dropped packets and high latency, and \
+                recover over time",
+        env = "CONCORDIUM_NODE_CONNECTION_PEER_SCORE_THRESHOLD"
+    )]
+    pub peer_score_threshold: Option<u64>,
+    #[structopt(
+        long = "peer-score-recovery",
+        help = "The score a peer recovers at every housekeeping round without a penalty",
+        default_value = "1",
+        env = "CONCORDIUM_NODE_CONNECTION_PEER_SCORE_RECOVERY"
+    )]
+    pub peer_score_recovery: u64,
+    #[structopt(
+        long = "peer-score-latency",
+        help = "The latency (in ms) above which a peer's score is decreased",
+        default_value = "2000",
+        env = "CONCORDIUM_NODE_CONNECTION_PEER_SCORE_LATENCY"
+    )]
+    pub peer_score_latency: u64,
+    #[structopt(
+        long = "peer-quarantine-duration",
+        help = "The time (in s) for which a peer is banned once its score drops below \
+                --peer-score-threshold",
+        default_value = "600",
+        env = "CONCORDIUM_NODE_CONNECTION_PEER_QUARANTINE_DURATION"
+    )]
+    pub peer_quarantine_duration: u64,
+    #[structopt(
+        long = "message-timestamp-tolerance",
+        help = "If set, drop incoming messages whose timestamp differs from the local time by \
+                more than this many seconds in either direction",
+        env = "CONCORDIUM_NODE_CONNECTION_MESSAGE_TIMESTAMP_TOLERANCE"
+    )]
+    pub message_timestamp_tolerance: Option<u64>,
     #[structopt(
         long = "hard-connection-limit",
         help = "Maximum connections to keep open at any time",
@@ -452,6 +742,21 @@ pub struct ConnectionConfig {
         env = "CONCORDIUM_NODE_CONNECTION_REQUESTS_BATCH_LIMIT"
     )]
     pub conn_requests_batch_limit: u16,
+    #[structopt(
+        long = "accept-rate-limit",
+        help = "If set, the maximum average number of incoming connections accepted per second; \
+                connections above the limit are closed right away",
+        env = "CONCORDIUM_NODE_CONNECTION_ACCEPT_RATE_LIMIT"
+    )]
+    pub accept_rate_limit: Option<u32>,
+    #[structopt(
+        long = "accept-burst",
+        help = "The number of incoming connections that can be accepted at once despite \
+                --accept-rate-limit",
+        default_value = "16",
+        env = "CONCORDIUM_NODE_CONNECTION_ACCEPT_BURST"
+    )]
+    pub accept_burst: u32,
     #[structopt(
         long = "catch-up-batch-limit",
         help = "The maximum batch size for a catch-up round.",
@@ -461,11 +766,19 @@ pub struct ConnectionConfig {
     pub catch_up_batch_limit: i64,
     #[structopt(
         long = "thread-pool-size",
-        help = "The size of the threadpool processing connection events in parallel",
+        help = "The size of the threadpool processing connection events in parallel, or 'auto' to \
+                use the available cores minus those reserved for the poll loop and consensus",
         default_value = "4",
         env = "CONCORDIUM_NODE_CONNECTION_THREAD_POOL_SIZE"
     )]
-    pub thread_pool_size: usize,
+    pub thread_pool_size: ThreadPoolSize,
+    #[structopt(
+        long = "available-cores",
+        help = "The number of cores available to the node, used with '--thread-pool-size auto' \
+                instead of the detected number, e.g., if the node is pinned to some of them",
+        env = "CONCORDIUM_NODE_CONNECTION_AVAILABLE_CORES"
+    )]
+    pub available_cores: Option<usize>,
     #[structopt(
         long = "dedup-size-long",
         help = "The size of the long deduplication queues",
@@ -500,6 +813,35 @@ pub struct ConnectionConfig {
         env = "CONCORDIUM_NODE_CONNECTION_SOCKET_SO_LINGER"
     )]
     pub socket_so_linger: Option<u16>,
+    #[structopt(
+        long = "tcp-keepalive-idle",
+        help = "Enable TCP keepalive, sending the first probe after a socket has been idle for \
+                this many seconds",
+        env = "CONCORDIUM_NODE_CONNECTION_TCP_KEEPALIVE_IDLE"
+    )]
+    pub tcp_keepalive_idle: Option<u32>,
+    #[structopt(
+        long = "tcp-keepalive-interval",
+        help = "Seconds between unanswered TCP keepalive probes",
+        default_value = "10",
+        env = "CONCORDIUM_NODE_CONNECTION_TCP_KEEPALIVE_INTERVAL"
+    )]
+    pub tcp_keepalive_interval: u32,
+    #[structopt(
+        long = "tcp-keepalive-probes",
+        help = "The number of unanswered TCP keepalive probes after which a connection is dropped",
+        default_value = "3",
+        env = "CONCORDIUM_NODE_CONNECTION_TCP_KEEPALIVE_PROBES"
+    )]
+    pub tcp_keepalive_probes: u32,
+    #[structopt(
+        long = "max-send-rate",
+        help = "The maximum number of bytes per second the node writes to all its sockets, split \
+                evenly among the connections with bytes to write; the rest stays queued \
+                (unlimited if not set)",
+        env = "CONCORDIUM_NODE_CONNECTION_MAX_SEND_RATE"
+    )]
+    pub max_send_rate: Option<u64>,
     #[structopt(
         long = "events-queue-size",
         help = "Events queue size per poll iteration",
@@ -507,6 +849,103 @@ pub struct ConnectionConfig {
         env = "CONCORDIUM_NODE_CONNECTION_EVENTS_QUEUE_SIZE"
     )]
     pub events_queue_size: usize,
+    #[structopt(
+        long = "max-pending-messages",
+        help = "Maximum number of outbound messages queued for a single connection. When it is \
+                exceeded the oldest low priority message is dropped, or the new message if only \
+                high priority messages are queued.",
+        default_value = "10000",
+        env = "CONCORDIUM_NODE_CONNECTION_MAX_PENDING_MESSAGES"
+    )]
+    pub max_pending_messages: usize,
+    #[structopt(
+        long = "max-output-queue-bytes",
+        help = "Maximum number of bytes waiting to be written to the socket of a single \
+                connection. A connection to a peer that does not read fast enough to stay below \
+                it is dropped.",
+        default_value = "67108864",
+        env = "CONCORDIUM_NODE_CONNECTION_MAX_OUTPUT_QUEUE_BYTES"
+    )]
+    pub max_output_queue_bytes: usize,
+    #[structopt(
+        long = "handshake-timeout",
+        help = "Time (in ms) within which a new connection must complete the handshake before it \
+                is dropped",
+        default_value = "5000",
+        env = "CONCORDIUM_NODE_CONNECTION_HANDSHAKE_TIMEOUT"
+    )]
+    pub handshake_timeout: u64,
+    #[structopt(
+        long = "read-stall-timeout",
+        help = "Time (in ms) after which a connection is dropped if its peer sent part of a \
+                message but none of the rest",
+        default_value = "10000",
+        env = "CONCORDIUM_NODE_CONNECTION_READ_STALL_TIMEOUT"
+    )]
+    pub read_stall_timeout: u64,
+    #[structopt(
+        long = "get-peers-min-interval",
+        help = "Minimum time (in ms) between two GetPeers requests of a peer that are answered; \
+                requests arriving sooner are ignored",
+        default_value = "5000",
+        env = "CONCORDIUM_NODE_CONNECTION_GET_PEERS_MIN_INTERVAL"
+    )]
+    pub get_peers_min_interval: u64,
+    #[structopt(
+        long = "node-max-message-size",
+        help = "Maximum size (in bytes) of a message accepted from a node",
+        default_value = "20971520",
+        env = "CONCORDIUM_NODE_CONNECTION_NODE_MAX_MESSAGE_SIZE"
+    )]
+    pub node_max_message_size: u32,
+    #[structopt(
+        long = "bootstrapper-max-message-size",
+        help = "Maximum size (in bytes) of a message accepted over a connection to or from a \
+                bootstrapper",
+        default_value = "1048576",
+        env = "CONCORDIUM_NODE_CONNECTION_BOOTSTRAPPER_MAX_MESSAGE_SIZE"
+    )]
+    pub bootstrapper_max_message_size: u32,
+    #[structopt(
+        long = "packet-compression-threshold",
+        help = "Compress the payloads of network packets larger than this many bytes. Packets are \
+                only compressed for peers that announce support for it in their handshake.",
+        env = "CONCORDIUM_NODE_CONNECTION_PACKET_COMPRESSION_THRESHOLD"
+    )]
+    pub packet_compression_threshold: Option<usize>,
+    #[structopt(
+        long = "ingestion-pause-queue-size",
+        help = "Pause reading from a peer while the high priority inbound consensus queue holds \
+                at least this many messages and at least an even share of them came from the \
+                peer, so that peers sending blocks faster than consensus can process them are \
+                paced instead of having their messages dropped",
+        env = "CONCORDIUM_NODE_CONNECTION_INGESTION_PAUSE_QUEUE_SIZE"
+    )]
+    pub ingestion_pause_queue_size: Option<usize>,
+    #[structopt(
+        long = "queue-breaker-threshold",
+        help = "Stop reading from the peers whose messages are dropped once an inbound consensus \
+                queue has been full for this long (in ms), instead of decrypting and discarding \
+                their messages. Disabled if not set",
+        env = "CONCORDIUM_NODE_CONNECTION_QUEUE_BREAKER_THRESHOLD"
+    )]
+    pub queue_breaker_threshold: Option<u64>,
+    #[structopt(
+        long = "queue-breaker-cooldown",
+        help = "The time (in ms) for which reading from a peer stops once the inbound consensus \
+                queue breaker trips",
+        default_value = "5000",
+        env = "CONCORDIUM_NODE_CONNECTION_QUEUE_BREAKER_COOLDOWN"
+    )]
+    pub queue_breaker_cooldown: u64,
+    #[structopt(
+        long = "memory-soft-limit",
+        help = "Shed load while the resident memory of the node exceeds this many MiB: queued low \
+                priority messages are dropped, transactions received from peers are not passed to \
+                consensus and no new connections are accepted. Disabled if not set",
+        env = "CONCORDIUM_NODE_CONNECTION_MEMORY_SOFT_LIMIT"
+    )]
+    pub memory_soft_limit: Option<u64>,
     #[structopt(
         long = "deduplication-hashing-algorithm",
         help = "Hash algorithm used for deduplication [xxhash64|sha256]",
@@ -533,6 +972,35 @@ pub struct CommonConfig {
         env = "CONCORDIUM_NODE_ID"
     )]
     pub id: Option<P2PNodeId>,
+    #[structopt(
+        long = "id-from-noise-key",
+        help = "Derive the node id from the node's static noise public key instead of choosing a \
+                random one. This is announced in the handshake, so that peers can check the id \
+                against the key",
+        env = "CONCORDIUM_NODE_ID_FROM_NOISE_KEY"
+    )]
+    pub id_from_noise_key: bool,
+    #[structopt(
+        long = "id-seed",
+        help = "Derive the node id deterministically from the listen address and port and the \
+                given cluster seed; meant for testing only",
+        env = "CONCORDIUM_NODE_ID_SEED"
+    )]
+    pub id_seed: Option<u64>,
+    #[structopt(
+        long = "rng-seed",
+        help = "Seed the random choices of peers to relay to, drop and connect to, making them \
+                reproducible; meant for testing only",
+        env = "CONCORDIUM_NODE_RNG_SEED"
+    )]
+    pub rng_seed: Option<u64>,
+    #[structopt(
+        long = "regenerate-noise-key",
+        help = "Replace the static noise key stored in the data directory with a newly generated \
+                one",
+        env = "CONCORDIUM_NODE_REGENERATE_NOISE_KEY"
+    )]
+    pub regenerate_noise_key: bool,
     #[structopt(
         long = "listen-port",
         short = "p",
@@ -548,6 +1016,20 @@ pub struct CommonConfig {
         env = "CONCORDIUM_NODE_LISTEN_ADDRESS"
     )]
     pub listen_address: Option<String>,
+    #[structopt(
+        long = "bind-address",
+        help = "Local address to bind outgoing connections to, selecting the interface they use; \
+                only peers of the same address family can be connected to",
+        env = "CONCORDIUM_NODE_BIND_ADDRESS"
+    )]
+    pub bind_address: Option<IpAddr>,
+    #[structopt(
+        long = "prefer-ipv6",
+        help = "When discovering the node's own address, prefer an IPv6 address over an IPv4 one \
+                if both are available",
+        env = "CONCORDIUM_NODE_PREFER_IPV6"
+    )]
+    pub prefer_ipv6: bool,
     #[structopt(
         long = "debug",
         short = "d",
@@ -600,6 +1082,14 @@ pub struct CommonConfig {
         env = "CONCORDIUM_NODE_NO_LOG_TIMESTAMP"
     )]
     pub no_log_timestamp: bool,
+    #[structopt(
+        long = "log-format",
+        help = "The format of the log output: human-readable lines, or a JSON object per line \
+                with the level, target, timestamp, thread id and message [text|json]",
+        default_value = "text",
+        env = "CONCORDIUM_NODE_LOG_FORMAT"
+    )]
+    pub log_format: LogFormat,
     #[structopt(
         long = "minimum-peers-bucket",
         help = "Minimum peers to keep in each bucket always",
@@ -613,6 +1103,12 @@ pub struct CommonConfig {
         env = "CONCORDIUM_NODE_PRINT_CONFIG"
     )]
     pub print_config: bool,
+    #[structopt(
+        long = "check-config",
+        help = "Validate the configuration and exit without starting the node",
+        env = "CONCORDIUM_NODE_CHECK_CONFIG"
+    )]
+    pub check_config: bool,
     #[structopt(
         long = "bucket-cleanup-interval",
         help = "Try to timeout entries in the buckets every set interval (in ms)",
@@ -620,6 +1116,58 @@ pub struct CommonConfig {
         env = "CONCORDIUM_NODE_BUCKET_CLEANUP_INTERVAL"
     )]
     pub bucket_cleanup_interval: u64,
+    #[structopt(
+        long = "max-bucket-size",
+        help = "Maximum number of peers to keep in each bucket. When a bucket is full, the peer \
+                with the worst combination of latency and uptime is evicted if the new peer \
+                scores better, and the new peer isn't added otherwise",
+        env = "CONCORDIUM_NODE_MAX_BUCKET_SIZE"
+    )]
+    pub max_bucket_size: Option<usize>,
+    #[cfg(feature = "network_dump")]
+    #[structopt(
+        long = "network-dump",
+        help = "Start dumping the network messages into this directory (relative to the data \
+                directory) when the node starts",
+        env = "CONCORDIUM_NODE_NETWORK_DUMP"
+    )]
+    pub network_dump: Option<String>,
+    #[cfg(feature = "network_dump")]
+    #[structopt(
+        long = "network-dump-format",
+        help = "The format of the raw messages of the dump started with --network-dump: a file \
+                per message, or a single pcap file [raw|pcap]",
+        default_value = "raw",
+        env = "CONCORDIUM_NODE_NETWORK_DUMP_FORMAT"
+    )]
+    pub network_dump_format: DumpFormat,
+    #[cfg(feature = "network_dump")]
+    #[structopt(
+        long = "network-dump-append",
+        help = "Continue an existing dump in the --network-dump directory, e.g., to keep a \
+                capture across restarts, instead of replacing it",
+        env = "CONCORDIUM_NODE_NETWORK_DUMP_APPEND"
+    )]
+    pub network_dump_append: bool,
+    #[cfg(feature = "network_dump")]
+    #[structopt(
+        long = "network-dump-packet-types",
+        help = "Only dump the consensus packets of these types \
+                [block|transaction|finalization-record|finalization-message|catch-up-status]; all \
+                the messages are dumped if none are given",
+        env = "CONCORDIUM_NODE_NETWORK_DUMP_PACKET_TYPES",
+        use_delimiter = true
+    )]
+    pub network_dump_packet_types: Vec<PacketType>,
+    #[cfg(feature = "network_dump")]
+    #[structopt(
+        long = "network-dump-peers",
+        help = "Only dump the messages exchanged with the peers with these ids; the messages of \
+                all the peers are dumped if none are given",
+        env = "CONCORDIUM_NODE_NETWORK_DUMP_PEERS",
+        use_delimiter = true
+    )]
+    pub network_dump_peers: Vec<P2PNodeId>,
 }
 
 // Client's parameters.
@@ -657,6 +1205,20 @@ pub struct CliConfig {
         env = "CONCORDIUM_NODE_DROP_REBROADCSAT_PROBABILITY"
     )]
     pub drop_rebroadcast_probability: Option<f64>,
+    #[structopt(
+        long = "transaction-shedding-queue-size",
+        help = "Shed transaction rebroadcasts while the low priority inbound consensus queue \
+                holds at least this many messages. Blocks and finalization messages are never shed",
+        env = "CONCORDIUM_NODE_TRANSACTION_SHEDDING_QUEUE_SIZE"
+    )]
+    pub transaction_shedding_queue_size: Option<usize>,
+    #[structopt(
+        long = "transaction-shedding-probability",
+        help = "Probability with which a transaction is not rebroadcast while shedding",
+        default_value = "0.5",
+        env = "CONCORDIUM_NODE_TRANSACTION_SHEDDING_PROBABILITY"
+    )]
+    pub transaction_shedding_probability: f64,
     #[structopt(
         long = "transaction-outcome-logging",
         help = "Enable transaction outcome logging",
@@ -733,6 +1295,14 @@ pub struct BootstrapperConfig {
         env = "CONCORDIUM_NODE_BOOTSTRAPPER_PEER_LIST_SIZE"
     )]
     pub peer_list_size: usize,
+    #[structopt(
+        long = "seed-peer",
+        help = "Peer (host/ip:port) that is always included in the PeerList responses of the \
+                bootstrapper, even if it is not connected to it",
+        use_delimiter = true,
+        env = "CONCORDIUM_NODE_BOOTSTRAPPER_SEED_PEERS"
+    )]
+    pub seed_peers: Vec<String>,
     #[structopt(
         long = "regenesis-block-hashes-file",
         help = "Path to a file that contains a json array of regenesis hashes.",
@@ -761,6 +1331,11 @@ pub struct Config {
     pub database_emitter: DatabaseEmitterConfig,
 }
 
+/// A problem with the configuration found by `Config::validate`.
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct ConfigError(String);
+
 impl Config {
     pub fn add_options(
         mut self,
@@ -775,6 +1350,320 @@ impl Config {
         self.common.min_peers_bucket = min_peers_bucket;
         self
     }
+
+    /// Check the consistency of the configuration, e.g. of options that
+    /// depend on or conflict with each other. All the problems found are
+    /// returned, not just the first one.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        macro_rules! check {
+            ($cond:expr, $($arg:tt)+) => {
+                if !$cond {
+                    errors.push(ConfigError(format!($($arg)+)));
+                }
+            };
+        }
+
+        check!(
+            self.connection.max_allowed_nodes_percentage >= 100,
+            "Can't provide a lower percentage than 100, as that would limit the maximum amount of \
+             nodes to less than the desired nodes is set to"
+        );
+
+        if let Some(max_allowed_nodes) = self.connection.max_allowed_nodes {
+            check!(
+                max_allowed_nodes >= self.connection.desired_nodes,
+                "Desired nodes set to {}, but max allowed nodes is set to {}. Max allowed nodes \
+                 must be greater or equal to desired amount of nodes",
+                self.connection.desired_nodes,
+                max_allowed_nodes
+            );
+        }
+
+        check!(
+            self.connection.hard_connection_limit >= self.connection.desired_nodes,
+            "Hard connection limit can't be less than what desired nodes is set to"
+        );
+
+        check!(
+            self.connection.relay_broadcast_percentage >= 0.0
+                && self.connection.relay_broadcast_percentage <= 1.0,
+            "Percentage of peers to relay broadcasted packets to, must be between 0.0 and 1.0"
+        );
+
+        check!(
+            self.cli.baker.maximum_block_size <= 4_000_000_000
+                && ((f64::from(self.cli.baker.maximum_block_size) * 0.9).ceil()) as u32
+                    <= PROTOCOL_MAX_MESSAGE_SIZE,
+            "Maximum block size set higher than 90% of network protocol max size ({})",
+            PROTOCOL_MAX_MESSAGE_SIZE
+        );
+
+        check!(self.connection.latency_window > 0, "The latency window can't be empty");
+
+        check!(
+            !self.connection.max_latency_use_p95
+                || self.connection.latency_window >= MIN_P95_LATENCY_SAMPLES,
+            "The latency window must hold at least {} latencies to use their 95th percentile",
+            MIN_P95_LATENCY_SAMPLES
+        );
+
+        if let Some(threshold) = self.connection.peer_score_threshold {
+            check!(
+                threshold > 0 && threshold <= MAX_PEER_SCORE,
+                "The peer score threshold must be between 1 and {}",
+                MAX_PEER_SCORE
+            );
+        }
+        check!(
+            self.connection.peer_quarantine_duration > 0,
+            "The peer quarantine duration must be positive"
+        );
+
+        check!(
+            self.connection.max_connection_lifetime != Some(0),
+            "The maximum connection lifetime must be positive"
+        );
+
+        for entry in &self.connection.bootstrap_nodes {
+            if let Err(e) = crate::utils::parse_bootstrap_entry(entry) {
+                errors.push(ConfigError(e.to_string()));
+            }
+        }
+
+        check!(
+            self.connection.accept_rate_limit != Some(0),
+            "The accept rate limit must be positive"
+        );
+
+        check!(self.connection.accept_burst > 0, "The accept burst must be positive");
+
+        check!(
+            self.connection.available_cores != Some(0),
+            "The number of available cores must be positive"
+        );
+
+        check!(
+            self.connection.message_timestamp_tolerance != Some(0),
+            "The message timestamp tolerance must be positive"
+        );
+
+        check!(self.connection.dns_timeout_ms > 0, "The DNS timeout must be positive");
+
+        for resolver in &self.connection.dns_resolver {
+            check!(
+                dns::parse_resolver(resolver).is_some(),
+                "Invalid DNS resolver {}; expected an IP address, optionally followed by a port",
+                resolver
+            );
+        }
+
+        let rpc = &self.cli.rpc;
+        check!(
+            rpc.rpc_server_socket.is_none()
+                || (rpc.rpc_server_addr.is_none() && rpc.rpc_server_port.is_none()),
+            "The RPC server can listen either on a TCP address or on a Unix socket, not both"
+        );
+        #[cfg(not(unix))]
+        check!(rpc.rpc_server_socket.is_none(), "Unix sockets are not supported on this platform");
+
+        for max_message_size in
+            &[self.connection.node_max_message_size, self.connection.bootstrapper_max_message_size]
+        {
+            check!(
+                *max_message_size as usize >= HANDSHAKE_SIZE_LIMIT
+                    && *max_message_size <= PROTOCOL_MAX_MESSAGE_SIZE,
+                "The maximum message sizes must be between {} and {} bytes",
+                HANDSHAKE_SIZE_LIMIT,
+                PROTOCOL_MAX_MESSAGE_SIZE
+            );
+        }
+
+        check!(
+            self.connection.socket_read_size >= 65535,
+            "Socket read size must be set to at least 65535"
+        );
+
+        check!(
+            self.connection.socket_read_size >= self.connection.socket_write_size,
+            "Socket read size must be greater or equal to the write size"
+        );
+
+        check!(
+            self.common.id.is_none() || !self.common.id_from_noise_key,
+            "The node id can't be both set explicitly and derived from the noise key"
+        );
+
+        check!(
+            self.common.id_seed.is_none()
+                || (self.common.id.is_none() && !self.common.id_from_noise_key),
+            "The node id can't be derived from a seed if it is set explicitly or derived from the \
+             noise key"
+        );
+
+        check!(
+            !self.connection.bootstrap_strict || self.connection.bootstrap_deadline.is_some(),
+            "Strict bootstrapping requires a bootstrap deadline to be set"
+        );
+
+        check!(
+            self.connection
+                .bootstrap_deadline
+                .map_or(true, |deadline| deadline <= self.connection.bootstrap_backoff_max),
+            "The bootstrap deadline can't exceed the maximum bootstrap backoff"
+        );
+
+        check!(
+            self.connection.reconnect_backoff_base > 0
+                && self.connection.reconnect_backoff_base <= self.connection.reconnect_backoff_max,
+            "The reconnect backoff base must be positive and can't exceed its maximum"
+        );
+
+        check!(
+            self.connection.max_connections_per_ip != Some(0),
+            "The maximum number of connections per IP must be positive"
+        );
+
+        check!(
+            self.connection.max_pending_messages > 0,
+            "The maximum number of pending messages must be positive"
+        );
+
+        check!(
+            self.connection.max_output_queue_bytes > 0,
+            "The maximum size of the output queue must be positive"
+        );
+
+        check!(self.connection.handshake_timeout > 0, "The handshake timeout must be positive");
+        check!(self.connection.read_stall_timeout > 0, "The read stall timeout must be positive");
+
+        check!(
+            self.connection.relay_dampening_window != Some(0),
+            "The relay dampening window must be positive"
+        );
+
+        check!(self.connection.broadcast_hop_limit > 0, "The broadcast hop limit must be positive");
+
+        check!(
+            self.connection.tcp_keepalive_idle != Some(0),
+            "The TCP keepalive idle time must be positive"
+        );
+        check!(
+            self.connection.tcp_keepalive_interval > 0,
+            "The TCP keepalive interval must be positive"
+        );
+        check!(
+            self.connection.tcp_keepalive_probes > 0,
+            "The number of TCP keepalive probes must be positive"
+        );
+
+        check!(self.connection.max_send_rate != Some(0), "The maximum send rate must be positive");
+
+        check!(self.common.max_bucket_size != Some(0), "The maximum bucket size must be positive");
+
+        check!(
+            self.connection.bootstrap_probe_timeout != Some(0),
+            "The bootstrapper probe timeout must be positive"
+        );
+
+        #[cfg(feature = "network_dump")]
+        check!(
+            self.common.network_dump.is_some() || !self.common.network_dump_append,
+            "Appending to a network dump requires its directory to be set with --network-dump"
+        );
+
+        if let Some(queue_size) = self.connection.ingestion_pause_queue_size {
+            check!(
+                queue_size > 0 && queue_size <= CONSENSUS_QUEUE_DEPTH_IN_HI,
+                "The ingestion pause queue size must be between 1 and {}",
+                CONSENSUS_QUEUE_DEPTH_IN_HI
+            );
+        }
+
+        check!(
+            self.connection.queue_breaker_cooldown > 0,
+            "The queue breaker cooldown must be positive"
+        );
+
+        if let Some(limit) = self.connection.memory_soft_limit {
+            check!(limit > 0, "The memory soft limit must be positive");
+        }
+
+        if let Some(queue_size) = self.cli.transaction_shedding_queue_size {
+            check!(
+                queue_size > 0 && queue_size <= CONSENSUS_QUEUE_DEPTH_IN_LO,
+                "The transaction shedding queue size must be between 1 and {}",
+                CONSENSUS_QUEUE_DEPTH_IN_LO
+            );
+        }
+
+        check!(
+            (0.0..=1.0).contains(&self.cli.transaction_shedding_probability),
+            "The transaction shedding probability must be between 0 and 1"
+        );
+
+        check!(
+            self.bootstrapper.wait_until_minimum_nodes as usize <= self.bootstrapper.peer_list_size,
+            "wait-until-minimum-nodes must be lower than or equal to peer-list-size"
+        );
+
+        for peer_list_size in
+            &[self.connection.max_peer_list_size, self.bootstrapper.peer_list_size]
+        {
+            check!(
+                (1..=PEER_LIST_SIZE_LIMIT).contains(peer_list_size),
+                "The size of PeerList responses must be between 1 and {}",
+                PEER_LIST_SIZE_LIMIT
+            );
+        }
+
+        #[cfg(feature = "instrumentation")]
+        {
+            let prometheus = &self.prometheus;
+            check!(
+                prometheus.prometheus_server || prometheus.prometheus_push_gateway.is_some(),
+                "The instrumentation feature requires either prometheus-server or \
+                 prometheus-push-gateway argument to be set"
+            );
+            check!(
+                !prometheus.prometheus_server
+                    || IpAddr::from_str(&prometheus.prometheus_listen_addr).is_ok(),
+                "The Prometheus listen address {} is not an IP address",
+                prometheus.prometheus_listen_addr
+            );
+        }
+
+        if let Some(listen_address) = &self.common.listen_address {
+            check!(
+                IpAddr::from_str(listen_address).is_ok(),
+                "The listen address {} is not an IP address",
+                listen_address
+            );
+        }
+
+        check!(
+            !self.cli.baker.decrypt_baker_credentials
+                || self.cli.baker.baker_credentials_file.is_some(),
+            "Decrypting the baker credentials requires a baker credentials file"
+        );
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl ConnectionConfig {
+    /// The way DNS queries should be performed.
+    pub fn dns_query_options(&self) -> dns::QueryOptions {
+        dns::QueryOptions {
+            require_dnssec: self.require_dnssec,
+            timeout:        Duration::from_millis(self.dns_timeout_ms),
+            retries:        self.dns_retries,
+        }
+    }
 }
 
 /// Verifies the validity of the configuration.
@@ -787,68 +1676,27 @@ pub fn parse_config() -> anyhow::Result<Config> {
         Config::from_clap(&app.get_matches())
     };
 
-    ensure!(
-        conf.connection.max_allowed_nodes_percentage >= 100,
-        "Can't provide a lower percentage than 100, as that would limit the maximum amount of \
-         nodes to less than the desired nodes is set to"
-    );
-
-    if let Some(max_allowed_nodes) = conf.connection.max_allowed_nodes {
-        ensure!(
-            max_allowed_nodes >= conf.connection.desired_nodes,
-            "Desired nodes set to {}, but max allowed nodes is set to {}. Max allowed nodes must \
-             be greater or equal to desired amount of nodes",
-            conf.connection.desired_nodes,
-            max_allowed_nodes
-        );
-    }
-
-    ensure!(
-        conf.connection.hard_connection_limit >= conf.connection.desired_nodes,
-        "Hard connection limit can't be less than what desired nodes is set to"
-    );
-
-    ensure!(
-        conf.connection.relay_broadcast_percentage >= 0.0
-            && conf.connection.relay_broadcast_percentage <= 1.0,
-        "Percentage of peers to relay broadcasted packets to, must be between 0.0 and 1.0"
-    );
-
-    ensure!(
-        conf.cli.baker.maximum_block_size <= 4_000_000_000
-            && ((f64::from(conf.cli.baker.maximum_block_size) * 0.9).ceil()) as u32
-                <= PROTOCOL_MAX_MESSAGE_SIZE,
-        "Maximum block size set higher than 90% of network protocol max size ({})",
-        PROTOCOL_MAX_MESSAGE_SIZE
-    );
-
-    ensure!(
-        conf.connection.socket_read_size >= 65535,
-        "Socket read size must be set to at least 65535"
-    );
-
-    ensure!(
-        conf.connection.socket_read_size >= conf.connection.socket_write_size,
-        "Socket read size must be greater or equal to the write size"
-    );
-
-    ensure!(
-        conf.bootstrapper.wait_until_minimum_nodes as usize <= conf.bootstrapper.peer_list_size,
-        "wait-until-minimum-nodes must be lower than or equal to peer-list-size"
-    );
-
-    #[cfg(feature = "instrumentation")]
-    {
-        ensure!(
-            conf.prometheus.prometheus_server || conf.prometheus.prometheus_push_gateway.is_some(),
-            "The instrumentation feature requires either prometheus-server or \
-             prometheus-push-gateway argument to be set"
-        );
+    if let Err(errors) = conf.validate() {
+        let errors = errors.iter().map(|e| format!("- {}", e)).collect::<Vec<_>>();
+        bail!("Invalid configuration:\n{}", errors.join("\n"));
     }
 
     Ok(conf)
 }
 
+/// Make sure that the given directory exists and that the node can write to
+/// it. The `kind` is used to describe the directory in the error message.
+fn ensure_writable_dir(dir: &Path, kind: &str) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Could not create the {} directory {}.", kind, dir.display()))?;
+    let probe_path = dir.join(".write_test");
+    File::create(&probe_path)
+        .with_context(|| format!("The {} directory {} is not writable.", kind, dir.display()))?;
+    // failing to clean up the probe file is harmless
+    let _ = std::fs::remove_file(&probe_path);
+    Ok(())
+}
+
 /// Handles the configuration data.
 #[derive(Debug)]
 pub struct AppPreferences {
@@ -858,8 +1706,13 @@ pub struct AppPreferences {
 }
 
 impl AppPreferences {
-    /// Creates an `AppPreferences` object.
-    pub fn new(override_conf: PathBuf, override_data: PathBuf) -> Self {
+    /// Creates an `AppPreferences` object. Fails if the config or data
+    /// directories cannot be created or are not writable, or if the config
+    /// file cannot be written.
+    pub fn new(override_conf: PathBuf, override_data: PathBuf) -> anyhow::Result<Self> {
+        ensure_writable_dir(&override_conf, "config")?;
+        ensure_writable_dir(&override_data, "data")?;
+
         let file_path = Self::calculate_config_file_path(&override_conf, APP_PREFERENCES_MAIN);
         let mut new_prefs = match OpenOptions::new().read(true).write(true).open(&file_path) {
             Ok(file) => {
@@ -873,20 +1726,23 @@ impl AppPreferences {
                     override_config_dir: override_conf,
                 }
             }
-            _ => match File::create(&file_path) {
-                Ok(_) => {
-                    let prefs = PreferencesMap::<String>::new();
-                    AppPreferences {
-                        preferences_map:     prefs,
-                        override_data_dir:   override_data,
-                        override_config_dir: override_conf,
-                    }
+            _ => {
+                File::create(&file_path).with_context(|| {
+                    format!("Could not create the config file {}.", file_path.display())
+                })?;
+                AppPreferences {
+                    preferences_map:     PreferencesMap::<String>::new(),
+                    override_data_dir:   override_data,
+                    override_config_dir: override_conf,
                 }
-                _ => panic!("Can't write to config file!"),
-            },
+            }
         };
-        new_prefs.set_config(APP_PREFERENCES_KEY_VERSION, Some(super::VERSION));
-        new_prefs
+        ensure!(
+            new_prefs.set_config(APP_PREFERENCES_KEY_VERSION, Some(super::VERSION)),
+            "Could not write to the config file {}. Make sure it is writable.",
+            file_path.display()
+        );
+        Ok(new_prefs)
     }
 
     fn calculate_config_file_path(config_path: &Path, key: &str) -> PathBuf {
@@ -944,3 +1800,24 @@ impl AppPreferences {
     /// Returns the path to the config directory.
     pub fn get_user_config_dir(&self) -> &Path { &self.override_config_dir }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::get_test_config;
+
+    #[test]
+    fn test_validate_config() {
+        let mut config = get_test_config(8888, vec![100]);
+        #[cfg(feature = "instrumentation")]
+        {
+            config.prometheus.prometheus_server = true;
+        }
+        assert!(config.validate().is_ok());
+
+        // all the problems are reported at once
+        config.connection.max_allowed_nodes = Some(config.connection.desired_nodes - 1);
+        config.cli.baker.decrypt_baker_credentials = true;
+        config.common.listen_address = Some("localhost".to_owned());
+        assert_eq!(config.validate().unwrap_err().len(), 3);
+    }
+}
@@ -5,18 +5,27 @@ use mio::net::TcpStream;
 use noiseexplorer_xx::{
     consts::{DHLEN, MAC_LENGTH},
     noisesession::NoiseSession,
-    types::Keypair,
+    types::{Keypair, PublicKey},
+};
+use thiserror::Error;
+
+use crate::{
+    common::get_current_stamp,
+    connection::MessageKind,
+    p2p::{
+        connectivity::{NonceRejection, SendShare, SendThrottle},
+        maintenance::P2PNode,
+    },
 };
-
-use crate::{configuration::PROTOCOL_MAX_MESSAGE_SIZE, p2p::maintenance::P2PNode};
 
 use std::{
     cmp,
     collections::VecDeque,
     convert::TryInto,
-    io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write},
+    io::{self, Cursor, ErrorKind, Read, Seek, SeekFrom, Write},
     mem,
     sync::{Arc, Weak},
+    time::Instant,
 };
 
 /// The size of the noise message payload.
@@ -27,6 +36,16 @@ pub const NOISE_MAX_MESSAGE_LEN: usize = 64 * 1024 - 1; // 65535
 const NOISE_AUTH_TAG_LEN: usize = 16;
 pub const NOISE_MAX_PAYLOAD_LEN: usize = NOISE_MAX_MESSAGE_LEN - NOISE_AUTH_TAG_LEN;
 pub const HANDSHAKE_SIZE_LIMIT: usize = 1024;
+/// The largest "high-level" handshake payload that still fits in the handshake
+/// message B, the one with the most noise overhead, once the size check is
+/// applied to it.
+pub const MAX_HANDSHAKE_PAYLOAD_LEN: usize =
+    HANDSHAKE_SIZE_LIMIT - 1 - (DHLEN * 2 + MAC_LENGTH) - MAC_LENGTH;
+/// The ephemeral public key a peer uses in the noise handshake. It is freshly
+/// generated for every handshake, so it serves as the handshake's nonce.
+pub type HandshakeNonce = [u8; DHLEN];
+/// The static public key of a peer in the noise handshake.
+pub type HandshakeKey = [u8; DHLEN];
 /// Not really a PSK, but serves a PSK-like function
 pub const PSK: &[u8] = b"b6461bd246843f70ac1328401405b2b4e725994d7d144a75bff1a04a247d64b7";
 /// The size of the initial socket write queue allocation.
@@ -42,6 +61,13 @@ struct IncomingMessage {
     pending_bytes: usize,
     /// The encrypted message currently being read.
     message:       Vec<u8>,
+    /// Timestamp of the last read that received bytes from the socket.
+    last_progress: u64,
+}
+
+impl IncomingMessage {
+    /// Whether a part, but not the whole, of a message has been read.
+    fn is_partial(&self) -> bool { !self.size_bytes.is_empty() || self.pending_bytes != 0 }
 }
 
 /// A buffer used to handle reads/writes to the socket.
@@ -103,26 +129,68 @@ pub enum ReadResult {
     Closed,
 }
 
+/// The error raised when a peer does not read the data sent to it fast enough
+/// and the connection's output queue exceeds its limit.
+#[derive(Debug, Error)]
+#[error("The output queue holds more than {limit} bytes")]
+pub struct OutputQueueFull {
+    pub limit: usize,
+}
+
+/// The error raised when a received frame fails authentication during its
+/// decryption, i.e., it was corrupted or tampered with.
+#[derive(Debug, Error)]
+#[error("A received frame failed authentication: {reason}")]
+pub struct FrameAuthenticationFailed {
+    pub reason: String,
+}
+
 /// The `Connection`'s socket, noise session and some helper objects.
 pub struct ConnectionLowLevel {
     /// A reference to the node.
-    pub handler:    Weak<P2PNode>,
+    pub handler:            Weak<P2PNode>,
     /// The socket associated with the connection.
-    pub socket:     TcpStream,
-    noise_session:  NoiseSession,
-    noise_buffer:   Box<[u8]>,
-    socket_buffer:  SocketBuffer,
-    incoming_msg:   IncomingMessage,
+    pub socket:             TcpStream,
+    noise_session:          NoiseSession,
+    noise_buffer:           Box<[u8]>,
+    socket_buffer:          SocketBuffer,
+    incoming_msg:           IncomingMessage,
     /// A priority queue for bytes waiting to be written to the socket.
-    output_queue:   VecDeque<u8>,
+    output_queue:           VecDeque<u8>,
+    /// The maximum number of bytes in the output queue before no more
+    /// messages are accepted.
+    max_output_queue_bytes: usize,
     /// The desired size of a single write to the socket.
-    write_size:     usize,
+    write_size:             usize,
     /// Whether the socket is writable.
-    is_writable:    bool,
+    is_writable:            bool,
     /// Whether the socket has been initialized
-    is_initialized: bool,
+    is_initialized:         bool,
     /// If specified, the linger value to set for the socket
-    so_linger:      Option<u16>,
+    so_linger:              Option<u16>,
+    /// If specified, the TCP keepalive parameters to set for the socket
+    keepalive:              Option<TcpKeepalive>,
+    /// The node-wide accounting and cap of the bytes written to sockets
+    send_throttle:          Arc<SendThrottle>,
+    /// The connection's share of the capped send rate.
+    send_share:             SendShare,
+    /// Whether the connection counts towards the ones the capped send rate is
+    /// split among, i.e., whether it has bytes waiting to be written.
+    is_sending:             bool,
+    /// The ephemeral key the peer sent in the first message of the handshake,
+    /// kept until its static key is known to check it for a replay.
+    handshake_nonce:        Option<HandshakeNonce>,
+}
+
+/// The parameters of the TCP keepalive probes sent on idle sockets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    /// Seconds a connection is idle before the first probe is sent.
+    pub idle:     u32,
+    /// Seconds between unanswered probes.
+    pub interval: u32,
+    /// The number of unanswered probes after which the connection is dropped.
+    pub probes:   u32,
 }
 
 macro_rules! recv_xx_msg {
@@ -148,6 +216,9 @@ macro_rules! send_xx_msg {
         $self.noise_session.send_message(&mut msg[PAYLOAD_SIZE..])?;
         // queue and send the message
         trace!("Sending message {} with size {}", $idx, msg.len());
+        if let Some(node) = $self.handler.upgrade() {
+            node.stats.msg_sent_by_kind_inc(MessageKind::Handshake, msg.len());
+        }
         $self.output_queue.extend(msg);
         $self.flush_socket()?;
     };
@@ -180,15 +251,25 @@ impl ConnectionLowLevel {
         ConnectionLowLevel {
             handler: Arc::downgrade(handler),
             socket,
-            noise_session: NoiseSession::init_session(is_initiator, PROLOGUE, Keypair::default()),
+            noise_session: NoiseSession::init_session(
+                is_initiator,
+                PROLOGUE,
+                handler.noise_keypair.clone(),
+            ),
             noise_buffer: vec![0u8; NOISE_MAX_MESSAGE_LEN].into_boxed_slice(),
             socket_buffer: SocketBuffer::new(read_size),
             incoming_msg: IncomingMessage::default(),
             output_queue: VecDeque::with_capacity(WRITE_QUEUE_ALLOC),
+            max_output_queue_bytes: handler.config.max_output_queue_bytes,
             write_size,
             is_writable: false,
             is_initialized: false,
             so_linger,
+            keepalive: handler.config.socket_keepalive,
+            send_throttle: Arc::clone(&handler.connection_handler.send_throttle),
+            send_share: SendShare::new(Instant::now()),
+            is_sending: false,
+            handshake_nonce: None,
         }
     }
 
@@ -259,6 +340,72 @@ impl ConnectionLowLevel {
         }
     }
 
+    #[cfg(unix)]
+    fn set_keepalive(&self, keepalive: TcpKeepalive) -> io::Result<()> {
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        use libc::TCP_KEEPALIVE as TCP_KEEPIDLE;
+        #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+        use libc::TCP_KEEPIDLE;
+        use libc::{
+            c_int, c_void, setsockopt, socklen_t, IPPROTO_TCP, SOL_SOCKET, SO_KEEPALIVE,
+            TCP_KEEPCNT, TCP_KEEPINTVL,
+        };
+        use std::os::unix::io::AsRawFd;
+
+        let fd = self.socket.as_raw_fd();
+        let set_option = |level: c_int, name: c_int, value: u32| {
+            let value = value as c_int;
+            let res = unsafe {
+                setsockopt(
+                    fd,
+                    level,
+                    name,
+                    &value as *const c_int as *const c_void,
+                    mem::size_of::<c_int>() as socklen_t,
+                )
+            };
+            if res != 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        };
+
+        set_option(SOL_SOCKET, SO_KEEPALIVE, 1)?;
+        set_option(IPPROTO_TCP, TCP_KEEPIDLE, keepalive.idle)?;
+        set_option(IPPROTO_TCP, TCP_KEEPINTVL, keepalive.interval)?;
+        set_option(IPPROTO_TCP, TCP_KEEPCNT, keepalive.probes)
+    }
+
+    /// On Windows only keepalive itself is enabled; the timing of the probes
+    /// is left to the system settings.
+    #[cfg(windows)]
+    fn set_keepalive(&self, _keepalive: TcpKeepalive) -> io::Result<()> {
+        use libc::{c_int, setsockopt};
+        use std::os::windows::io::AsRawSocket;
+
+        // The constants SOL_SOCKET and SO_KEEPALIVE are currently not provided
+        // by libc on Windows.
+        const SOL_SOCKET: c_int = 0xffff;
+        const SO_KEEPALIVE: c_int = 0x0008;
+
+        let enabled: c_int = 1;
+        let res = unsafe {
+            setsockopt(
+                self.socket.as_raw_socket() as libc::SOCKET,
+                SOL_SOCKET,
+                SO_KEEPALIVE,
+                &enabled as *const c_int as *const i8,
+                mem::size_of::<c_int>() as c_int,
+            )
+        };
+        if res != 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
     /// Initialization
     fn initialize(&mut self) {
         // Set linger time if requested
@@ -266,6 +413,12 @@ impl ConnectionLowLevel {
             self.set_linger(true, linger as u16);
         }
 
+        if let Some(keepalive) = self.keepalive {
+            if let Err(e) = self.set_keepalive(keepalive) {
+                error!("Could not enable TCP keepalive due to {}", e);
+            }
+        }
+
         if let Err(e) = self.socket.set_nodelay(true) {
             error!("Could not set TCP_NODELAY due to {}", e);
         }
@@ -282,8 +435,35 @@ impl ConnectionLowLevel {
         Ok(())
     }
 
+    /// The remote ephemeral key, which is always the first element of the
+    /// first handshake message received from the peer.
+    fn read_handshake_nonce(&self, len: usize) -> anyhow::Result<HandshakeNonce> {
+        Ok(self.socket_buffer.slice(len)[..DHLEN].try_into()?)
+    }
+
+    /// Rejects the handshake if the peer already used the same ephemeral key
+    /// with the same static key recently, i.e., if the handshake is replayed.
+    /// It may only be called once the peer's static key was received.
+    fn check_handshake_replay(&self, nonce: HandshakeNonce) -> anyhow::Result<()> {
+        let mut remote_key: HandshakeKey = [0u8; DHLEN];
+        remote_key.copy_from_slice(&self.remote_public_key().as_bytes()[..]);
+        let node = self.handler.upgrade().unwrap(); // safe
+        match node.connection_handler.register_handshake_nonce(remote_key, nonce) {
+            Ok(()) => Ok(()),
+            Err(NonceRejection::Replayed) => {
+                node.stats.handshake_replays_inc();
+                bail!("Rejecting a replayed handshake")
+            }
+            Err(NonceRejection::Full) => {
+                bail!("Rejecting a handshake: too many handshakes within the replay window")
+            }
+        }
+    }
+
     fn process_msg_a(&mut self, len: usize) -> anyhow::Result<Vec<u8>> {
         recv_xx_msg!(self, len, "A");
+        // the peer's static key only arrives with message C
+        self.handshake_nonce = Some(self.read_handshake_nonce(len)?);
         let pad = 16;
         let payload_in = self.socket_buffer.slice(len)[DHLEN..][..len - DHLEN - pad].try_into()?;
         let payload_out = self.handler.upgrade().unwrap().produce_handshake_request()?; // safe
@@ -294,6 +474,7 @@ impl ConnectionLowLevel {
 
     fn process_msg_b(&mut self, len: usize) -> anyhow::Result<Vec<u8>> {
         recv_xx_msg!(self, len, "B");
+        self.check_handshake_replay(self.read_handshake_nonce(len)?)?;
         let payload_in = self.socket_buffer.slice(len)[DHLEN * 2 + MAC_LENGTH..]
             [..len - DHLEN * 2 - MAC_LENGTH * 2]
             .try_into()?;
@@ -305,6 +486,10 @@ impl ConnectionLowLevel {
 
     fn process_msg_c(&mut self, len: usize) -> anyhow::Result<Vec<u8>> {
         recv_xx_msg!(self, len, "C");
+        match self.handshake_nonce.take() {
+            Some(nonce) => self.check_handshake_replay(nonce)?,
+            None => bail!("Received the last handshake message before the first one"),
+        }
         let payload = self.socket_buffer.slice(len)[DHLEN + MAC_LENGTH..]
             [..len - DHLEN - MAC_LENGTH * 2]
             .try_into()?;
@@ -324,9 +509,10 @@ impl ConnectionLowLevel {
 
     // input
 
-    /// Attempts to read a complete message from the socket.
+    /// Attempts to read a complete message from the socket. Messages larger
+    /// than `max_message_size` are rejected once the handshake is complete.
     #[inline]
-    pub fn read_from_socket(&mut self) -> anyhow::Result<ReadResult> {
+    pub fn read_from_socket(&mut self, max_message_size: u32) -> anyhow::Result<ReadResult> {
         if self.socket_buffer.is_exhausted() {
             self.socket_buffer.reset();
         }
@@ -342,6 +528,7 @@ impl ConnectionLowLevel {
                     //     ByteSize(num_bytes as u64).to_string_as(true)
                     // );
                     self.socket_buffer.remaining = num_bytes;
+                    self.incoming_msg.last_progress = get_current_stamp();
                 }
                 Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(ReadResult::WouldBlock),
                 Err(e) => return Err(e.into()),
@@ -351,7 +538,7 @@ impl ConnectionLowLevel {
         // if we don't know the length of the incoming message, read it from the
         // collected bytes; that number of bytes needs to be accounted for later
         if self.incoming_msg.pending_bytes == 0 {
-            self.attempt_to_read_length()?;
+            self.attempt_to_read_length(max_message_size)?;
         }
 
         // check if we know the size of the message now
@@ -362,9 +549,15 @@ impl ConnectionLowLevel {
         }
     }
 
+    /// Whether a message was only partially read and no more of it has been
+    /// received since the `deadline` timestamp.
+    pub fn is_read_stalled(&self, deadline: u64) -> bool {
+        self.incoming_msg.is_partial() && self.incoming_msg.last_progress < deadline
+    }
+
     /// Attempt to discover the length of the incoming encrypted message.
     #[inline]
-    fn attempt_to_read_length(&mut self) -> anyhow::Result<()> {
+    fn attempt_to_read_length(&mut self, max_message_size: u32) -> anyhow::Result<()> {
         let read_size = cmp::min(
             self.socket_buffer.remaining,
             PAYLOAD_SIZE - self.incoming_msg.size_bytes.len(),
@@ -389,12 +582,12 @@ impl ConnectionLowLevel {
                 );
             }
 
-            // check if the expected size doesn't exceed the protocol limit
-            if expected_size > PROTOCOL_MAX_MESSAGE_SIZE {
+            // check if the expected size doesn't exceed the limit for the peer
+            if expected_size > max_message_size {
                 bail!(
-                    "expected message size ({}) exceeds the maximum protocol size ({})",
+                    "expected message size ({}) exceeds the maximum message size ({})",
                     ByteSize(expected_size as u64).to_string_as(true),
-                    ByteSize(PROTOCOL_MAX_MESSAGE_SIZE as u64).to_string_as(true)
+                    ByteSize(max_message_size as u64).to_string_as(true)
                 );
             }
 
@@ -492,7 +685,10 @@ impl ConnectionLowLevel {
         msg.seek(SeekFrom::Start((offset_mul * NOISE_MAX_PAYLOAD_LEN) as u64))?;
 
         if let Err(err) = self.noise_session.recv_message(&mut self.noise_buffer[..read_size]) {
-            Err(err.into())
+            Err(FrameAuthenticationFailed {
+                reason: err.to_string(),
+            }
+            .into())
         } else {
             msg.write_all(&self.noise_buffer[..read_size - MAC_LENGTH])?;
             Ok(())
@@ -512,16 +708,38 @@ impl ConnectionLowLevel {
         // trace!("Connection became writable. {:?}", self.socket);
     }
 
-    /// Enqueue a message to be written to the socket.
+    /// Enqueue a message to be written to the socket. Fails if the output
+    /// queue already exceeds its limit, i.e., if the peer is not reading.
     #[inline]
     pub fn write_to_socket(&mut self, input: Arc<[u8]>) -> anyhow::Result<()> {
+        if self.output_queue.len() > self.max_output_queue_bytes {
+            return Err(OutputQueueFull {
+                limit: self.max_output_queue_bytes,
+            }
+            .into());
+        }
         self.encrypt_and_enqueue(&input)
     }
 
+    /// Check whether there are enqueued bytes that are yet to be written.
+    #[inline]
+    pub fn has_pending_output(&self) -> bool { !self.output_queue.is_empty() }
+
+    /// The number of enqueued bytes that are yet to be written.
+    #[inline]
+    pub fn output_queue_len(&self) -> usize { self.output_queue.len() }
+
+    /// Whether the output queue has drained enough for the next message to be
+    /// encrypted and enqueued. Holding messages back until then allows ones of
+    /// higher priority to overtake them.
+    #[inline]
+    pub fn accepts_output(&self) -> bool { self.output_queue.len() < self.write_size() }
+
     /// Writes enequeued bytes to the socket until the queue is exhausted
     /// or the write would be blocking.
     #[inline]
     pub fn flush_socket(&mut self) -> anyhow::Result<()> {
+        self.update_sending();
         if self.is_writable {
             while !self.output_queue.is_empty() {
                 match self.flush_socket_once() {
@@ -531,21 +749,40 @@ impl ConnectionLowLevel {
                 }
             }
         }
+        self.update_sending();
 
         Ok(())
     }
 
+    /// Let the send throttle know whether the connection has bytes waiting to
+    /// be written, so that the capped rate is split among the ones that do.
+    /// Handshake messages are not subject to the cap.
+    fn update_sending(&mut self) {
+        let is_sending = self.is_post_handshake() && !self.output_queue.is_empty();
+        if is_sending != self.is_sending {
+            self.send_throttle.set_sending(is_sending);
+            self.is_sending = is_sending;
+        }
+    }
+
     /// Writes a single batch of enqueued bytes to the socket.
     #[inline]
     fn flush_socket_once(&mut self) -> anyhow::Result<usize> {
         // Always ignore max write buffer when we're handshaking, as we need to ensure
         // we won't chunk the handshake messages, which can cause issues for the
-        // noise protocol
-        let write_size = if !self.is_post_handshake() {
-            cmp::min(4_096, self.output_queue.len())
+        // noise protocol. Nor is the handshake subject to the cap on the send rate;
+        // once the cap is reached, the rest of the queue is written in a later poll
+        // iteration.
+        let (write_size, taken) = if !self.is_post_handshake() {
+            (cmp::min(4_096, self.output_queue.len()), 0)
         } else {
-            cmp::min(self.write_size(), self.output_queue.len())
+            let wanted = cmp::min(self.write_size(), self.output_queue.len());
+            let allowed = self.send_throttle.take(&mut self.send_share, wanted, Instant::now());
+            (allowed, allowed)
         };
+        if write_size == 0 {
+            return Ok(0);
+        }
 
         let (front, back) = self.output_queue.as_slices();
 
@@ -560,6 +797,7 @@ impl ConnectionLowLevel {
         let written = match self.socket.write(&self.socket_buffer.buf[..write_size]) {
             Ok(num_bytes) => num_bytes,
             Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                self.send_throttle.record(&mut self.send_share, 0, taken);
                 self.is_writable = false;
                 debug!("Sending would block (setting non-writable). {:?}", self.socket);
                 return Ok(0);
@@ -567,6 +805,7 @@ impl ConnectionLowLevel {
             Err(e) => return Err(e.into()),
         };
 
+        self.send_throttle.record(&mut self.send_share, written, taken);
         self.output_queue.drain(..written);
 
         // trace!(
@@ -608,6 +847,13 @@ impl ConnectionLowLevel {
         Ok(())
     }
 
+    /// The static public key the peer authenticated itself with in the noise
+    /// handshake. It is only known once the peer sent it, i.e., by the time
+    /// the high-level handshake is received.
+    pub fn remote_public_key(&self) -> PublicKey {
+        self.noise_session.get_remote_static_public_key()
+    }
+
     /// Produces and enqueues a single noise message from `input`, potentially
     /// squeezing it with the previously enqueued chunk.
     #[inline]
@@ -632,3 +878,12 @@ impl ConnectionLowLevel {
     #[inline]
     fn write_size(&self) -> usize { self.write_size }
 }
+
+impl Drop for ConnectionLowLevel {
+    fn drop(&mut self) {
+        // the capped send rate is no longer shared with this connection
+        if self.is_sending {
+            self.send_throttle.set_sending(false);
+        }
+    }
+}
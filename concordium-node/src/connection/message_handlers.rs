@@ -2,19 +2,21 @@
 
 use crate::{
     common::{
+        get_current_stamp,
         p2p_peer::{PeerStats, RemotePeerId},
-        PeerType,
+        DiscoverySource, P2PNodeId, PeerType,
     },
     configuration::{is_compatible_version, is_compatible_wire_version, MAX_PEER_NETWORKS},
     connection::{ConnChange, Connection},
     network::{
         Handshake, NetworkMessage, NetworkPacket, NetworkPayload, NetworkRequest, NetworkResponse,
-        PacketDestination,
+        Networks, PacketDestination, WIRE_FRAMING_VERSION,
     },
     plugins::consensus::*,
-    read_or_die,
+    read_or_die, write_or_die,
 };
 use anyhow::{bail, ensure};
+use std::time::Instant;
 
 impl Connection {
     /// Processes a network message based on its type.
@@ -53,11 +55,16 @@ impl Connection {
             }
             NetworkPayload::NetworkRequest(NetworkRequest::GetPeers(networks), ..) => {
                 debug!("Got a GetPeers request from peer {}", peer_id);
-                self.send_peer_list_resp(networks, conn_stats)
+                self.handle_get_peers_req(networks, conn_stats)
             }
             NetworkPayload::NetworkResponse(NetworkResponse::PeerList(peers), ..) => {
                 debug!("Got a PeerList ({} peers) from peer {}", peers.len(), peer_id);
-                self.handler.register_conn_change(ConnChange::NewPeers(peers));
+                // peers advertised by bootstrappers count as discovered by bootstrapping
+                let source = match self.remote_peer.peer_type {
+                    PeerType::Bootstrapper => DiscoverySource::Bootstrap,
+                    PeerType::Node => DiscoverySource::PeerList,
+                };
+                self.handler.register_conn_change(ConnChange::NewPeers(peers, source));
                 Ok(())
             }
             NetworkPayload::NetworkRequest(NetworkRequest::JoinNetwork(network), ..) => {
@@ -75,13 +82,43 @@ impl Connection {
         }
     }
 
-    fn handle_handshake_req(
+    pub(crate) fn handle_handshake_req(
         &mut self,
         handshake: Handshake,
         conn_stats: &[PeerStats],
     ) -> anyhow::Result<()> {
         debug!("Got a Handshake request from peer {}", handshake.remote_id);
 
+        // The node can be reachable under several addresses, so a connection to
+        // itself can get past the checks done before connecting. Only a peer with
+        // the node's own noise key is the node itself, though; one merely claiming
+        // its id mustn't get its address excluded from future connections.
+        if handshake.remote_id == self.handler.self_peer.id {
+            let own_key = self.handler.noise_keypair.get_public_key();
+            if self.low_level.remote_public_key().as_bytes() != own_key.as_bytes() {
+                bail!("Rejecting handshake: the peer claims the id of this node.");
+            }
+            if self.discovery_source != DiscoverySource::Incoming {
+                warn!("{} leads back to this node; not connecting to it again", self.remote_addr());
+                write_or_die!(self.handler.connection_handler.self_addresses)
+                    .insert(self.remote_addr());
+            }
+            bail!("Rejecting handshake: the connection loops back to this node.");
+        }
+
+        // a peer announcing a key-derived id can't claim an id that isn't its own;
+        // other peers choose their ids freely
+        if handshake.capabilities.as_ref().map_or(false, |caps| caps.key_derived_id) {
+            let key_id = P2PNodeId::from_public_key(&self.low_level.remote_public_key().as_bytes());
+            if handshake.remote_id != key_id {
+                bail!(
+                    "Rejecting handshake: the id {} isn't derived from the peer's noise key ({}).",
+                    handshake.remote_id,
+                    key_id
+                );
+            }
+        }
+
         if !is_compatible_version(&handshake.node_version) {
             bail!("Rejecting handshake: incompatible client ({}).", handshake.node_version);
         }
@@ -98,6 +135,13 @@ impl Connection {
                 );
             }
         }
+        if handshake.framing_version != WIRE_FRAMING_VERSION {
+            bail!(
+                "Rejecting handshake: incompatible wire framing version ({}, ours is {}).",
+                handshake.framing_version,
+                WIRE_FRAMING_VERSION
+            );
+        }
         if handshake.networks.len() > MAX_PEER_NETWORKS {
             bail!("Rejecting handshake: too many networks.");
         }
@@ -122,10 +166,20 @@ impl Connection {
             }
         }
 
+        if !self.handler.is_allowed(self.remote_addr().ip(), Some(handshake.remote_id)) {
+            bail!("Rejecting handshake: peer {} is not on the allowlist.", handshake.remote_id);
+        }
+
+        self.remote_proof = handshake.proof;
+        if let Some(ref capabilities) = handshake.capabilities {
+            debug!("Peer {} announced capabilities {:?}", handshake.remote_id, capabilities);
+        }
+        self.remote_capabilities = handshake.capabilities;
         self.promote_to_post_handshake(
             handshake.remote_id,
             handshake.remote_port,
             &handshake.networks,
+            handshake.node_version,
         );
 
         if self.handler.peer_type() == PeerType::Bootstrapper {
@@ -141,6 +195,26 @@ impl Connection {
 
     fn handle_pong(&self) -> anyhow::Result<()> { self.stats.notify_pong() }
 
+    /// Answer a GetPeers request, unless the peer already got an answer less
+    /// than the minimum interval ago; this bounds the cost of a peer spamming
+    /// the request without banning it.
+    fn handle_get_peers_req(
+        &mut self,
+        networks: Networks,
+        conn_stats: &[PeerStats],
+    ) -> anyhow::Result<()> {
+        let now = get_current_stamp();
+        if self.last_get_peers != 0
+            && now.saturating_sub(self.last_get_peers) < self.handler.config.get_peers_min_interval
+        {
+            debug!("Ignoring a GetPeers request from {}, as it arrived too soon", self);
+            self.handler.stats.get_peers_ignored_inc();
+            return Ok(());
+        }
+        self.last_get_peers = now;
+        self.send_peer_list_resp(networks, conn_stats)
+    }
+
     fn handle_incoming_packet(
         &self,
         pac: NetworkPacket,
@@ -148,7 +222,24 @@ impl Connection {
     ) -> anyhow::Result<()> {
         let is_broadcast = matches!(pac.destination, PacketDestination::Broadcast(..));
 
+        // copies of a broadcast that was just relayed are not processed again
+        let recently_relayed = &self.handler.connection_handler.recently_relayed;
+        if is_broadcast
+            && self.handler.config.relay_dampening_window.is_some()
+            && recently_relayed.contains(&pac.message, Instant::now())
+        {
+            self.handler.stats.relays_suppressed_inc();
+            return Ok(());
+        }
+
         // Ignore the deserialized p2p node ids to be excluded from the wire.
-        handle_pkt_out(&self.handler, vec![peer_id], peer_id, pac.message, is_broadcast)
+        handle_pkt_out(
+            &self.handler,
+            vec![peer_id],
+            peer_id,
+            pac.message,
+            is_broadcast,
+            pac.hop_limit,
+        )
     }
 }
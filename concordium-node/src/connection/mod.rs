@@ -9,28 +9,34 @@ use anyhow::{bail, ensure};
 use bytesize::ByteSize;
 use circular_queue::CircularQueue;
 use low_level::ConnectionLowLevel;
+pub use low_level::{
+    FrameAuthenticationFailed, HandshakeKey, HandshakeNonce, OutputQueueFull, TcpKeepalive,
+    HANDSHAKE_SIZE_LIMIT, MAX_HANDSHAKE_PAYLOAD_LEN,
+};
 use mio::{net::TcpStream, Interest, Token};
+use rand::seq::IteratorRandom;
+use semver::Version;
 
 #[cfg(feature = "network_dump")]
 use crate::dumper::DumpItem;
 use crate::{
     common::{
         get_current_stamp,
-        p2p_peer::{P2PPeer, PeerStats},
-        P2PNodeId, PeerType, RemotePeer,
+        p2p_peer::{P2PPeer, PeerStats, RemotePeerId},
+        DiscoverySource, P2PNodeId, PeerType, RemotePeer,
     },
-    configuration::MAX_PEER_NETWORKS,
+    configuration::{MAX_PEER_NETWORKS, MAX_PEER_SCORE, MIN_P95_LATENCY_SAMPLES},
     connection::low_level::ReadResult,
-    netmsg,
+    lock_or_die, netmsg,
     network::{
-        NetworkId, NetworkMessage, NetworkPacket, NetworkPayload, NetworkRequest, NetworkResponse,
-        Networks,
+        Capabilities, NetworkId, NetworkMessage, NetworkPacket, NetworkPayload, NetworkRequest,
+        NetworkResponse, Networks,
     },
-    p2p::P2PNode,
+    p2p::{geo::LocationLookup, peers::calculate_average_throughput, P2PNode},
     read_or_die, write_or_die,
 };
 
-use crate::consensus_ffi::helpers::PacketType;
+use crate::consensus_ffi::{consensus::CALLBACK_QUEUE, helpers::PacketType};
 
 use std::{
     collections::VecDeque,
@@ -41,8 +47,9 @@ use std::{
     str::FromStr,
     sync::{
         atomic::{AtomicI64, AtomicU64, Ordering},
-        Arc, RwLock,
+        Arc, Mutex, RwLock,
     },
+    time::Instant,
 };
 
 /// Designates the sending priority of outgoing messages.
@@ -56,6 +63,56 @@ pub enum MessageSendingPriority {
     High,
 }
 
+/// The kind of a message sent to a peer, used to break down the statistics of
+/// the sent messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    /// A noise handshake message.
+    Handshake,
+    /// A ping or a pong.
+    Ping,
+    /// A request for peers or a list of peers.
+    PeerList,
+    /// A request to join or leave a network.
+    Network,
+    /// A consensus packet, along with its type if it is a known one.
+    Packet(Option<PacketType>),
+    /// Anything else, e.g. raw payloads sent by the test tools.
+    Other,
+}
+
+impl MessageKind {
+    /// Determines the kind of a packet from its consensus payload, which
+    /// starts with the packet type.
+    pub fn of_packet(payload: &[u8]) -> Self {
+        Self::Packet(payload.first().and_then(|&tag| PacketType::try_from(tag).ok()))
+    }
+
+    /// The label of the kind in the exported statistics.
+    pub fn label(self) -> &'static str {
+        match self {
+            MessageKind::Handshake => "handshake",
+            MessageKind::Ping => "ping",
+            MessageKind::PeerList => "peerlist",
+            MessageKind::Network => "network",
+            MessageKind::Packet(Some(PacketType::Block)) => "packet_block",
+            MessageKind::Packet(Some(PacketType::Transaction)) => "packet_transaction",
+            MessageKind::Packet(Some(PacketType::FinalizationRecord)) => {
+                "packet_finalization_record"
+            }
+            MessageKind::Packet(Some(PacketType::FinalizationMessage)) => {
+                "packet_finalization_message"
+            }
+            MessageKind::Packet(Some(PacketType::CatchUpStatus)) => "packet_catch_up_status",
+            MessageKind::Packet(None) => "packet_unknown",
+            MessageKind::Other => "other",
+        }
+    }
+}
+
+/// A message queued for sending, along with its kind.
+pub type QueuedMessage = (MessageKind, Arc<[u8]>);
+
 /// This enum defines the hashing algorithms we support for deduplication
 #[derive(Debug, Clone, Copy)]
 pub enum DeduplicationHashAlgorithm {
@@ -211,6 +268,37 @@ impl DeduplicationQueues {
     }
 }
 
+/// A summary of the latencies measured at the most recent pongs of a
+/// connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencySummary {
+    /// The number of latencies summarized.
+    pub samples: usize,
+    pub min:     u64,
+    pub avg:     u64,
+    /// The 95th percentile, using the nearest-rank method. It is only
+    /// distinct from the maximum with at least `MIN_P95_LATENCY_SAMPLES`
+    /// samples.
+    pub p95:     u64,
+}
+
+impl LatencySummary {
+    fn new(samples: &CircularQueue<u64>) -> Self {
+        let mut samples = samples.iter().copied().collect::<Vec<u64>>();
+        if samples.is_empty() {
+            return LatencySummary::default();
+        }
+        samples.sort_unstable();
+        let p95_rank = (samples.len() * 95 + 99) / 100;
+        LatencySummary {
+            samples: samples.len(),
+            min:     samples[0],
+            avg:     samples.iter().sum::<u64>() / samples.len() as u64,
+            p95:     samples[p95_rank - 1],
+        }
+    }
+}
+
 /// Contains all the statistics of a connection.
 pub struct ConnectionStats {
     /// Timestamp of connection creation.
@@ -227,6 +315,9 @@ pub struct ConnectionStats {
     pending_pongs:         AtomicI64,
     /// Latency measured at last received pong
     last_latency:          AtomicU64,
+    /// Latencies measured at the most recent pongs, along with their summary,
+    /// which is updated at every pong
+    latency_samples:       Mutex<(CircularQueue<u64>, LatencySummary)>,
     /// Number of messages sent.
     pub messages_sent:     AtomicU64,
     /// Number of messages received.
@@ -235,10 +326,21 @@ pub struct ConnectionStats {
     pub bytes_received:    AtomicU64,
     /// Number of bytes sent.
     pub bytes_sent:        AtomicU64,
+    /// The peer's score, between 0 and `MAX_PEER_SCORE`.
+    score:                 AtomicU64,
+    /// The timestamp and the received and sent byte counters at the start of
+    /// the current throughput window.
+    rate_window:           Mutex<(u64, u64, u64)>,
+    /// Bytes per second received over the last complete throughput window.
+    bps_in:                AtomicU64,
+    /// Bytes per second sent over the last complete throughput window.
+    bps_out:               AtomicU64,
 }
 
 impl ConnectionStats {
-    pub fn new(timestamp: u64) -> Self {
+    /// Creates the statistics of a connection, keeping the latencies of the
+    /// `latency_window` most recent pongs.
+    pub fn new(timestamp: u64, latency_window: usize) -> Self {
         ConnectionStats {
             created:            timestamp,
             last_seen:          AtomicU64::new(timestamp),
@@ -246,17 +348,42 @@ impl ConnectionStats {
             last_ping_interval: AtomicU64::new(0),
             pending_pongs:      AtomicI64::new(0),
             last_latency:       AtomicU64::new(0),
+            latency_samples:    Mutex::new((
+                CircularQueue::with_capacity(latency_window),
+                LatencySummary::default(),
+            )),
             messages_sent:      AtomicU64::new(0),
             messages_received:  AtomicU64::new(0),
             bytes_received:     AtomicU64::new(0),
             bytes_sent:         AtomicU64::new(0),
+            score:              AtomicU64::new(MAX_PEER_SCORE),
+            rate_window:        Mutex::new((timestamp, 0, 0)),
+            bps_in:             AtomicU64::new(0),
+            bps_out:            AtomicU64::new(0),
         }
     }
 
+    /// Get the peer's current score.
+    pub fn get_score(&self) -> u64 { self.score.load(Ordering::Relaxed) }
+
+    /// Decrease the peer's score by `penalty`, down to 0.
+    pub fn penalize(&self, penalty: u64) {
+        let _ = self.score.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |score| {
+            Some(score.saturating_sub(penalty))
+        });
+    }
+
+    /// Let the peer's score recover by `amount`, up to `MAX_PEER_SCORE`.
+    pub fn recover_score(&self, amount: u64) {
+        let _ = self.score.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |score| {
+            Some(score.saturating_add(amount).min(MAX_PEER_SCORE))
+        });
+    }
+
     pub fn notify_ping(&self) {
         let now = get_current_stamp();
         let previous_ping = self.last_ping.swap(now, Ordering::AcqRel);
-        self.last_ping_interval.store(now - previous_ping, Ordering::Release);
+        self.last_ping_interval.store(now.saturating_sub(previous_ping), Ordering::Release);
         self.pending_pongs.fetch_add(1, Ordering::SeqCst);
     }
 
@@ -275,18 +402,95 @@ impl ConnectionStats {
             // intervals. At that point, this loss of accuracy is not expected
             // to be so significant.)
             let extra_delay = if old_pending_pongs > 1 {
-                (old_pending_pongs as u64 - 1) * self.last_ping_interval.load(Ordering::Acquire)
+                (old_pending_pongs as u64 - 1)
+                    .saturating_mul(self.last_ping_interval.load(Ordering::Acquire))
             } else {
                 0
             };
-            let measured_latency = now - self.last_ping.load(Ordering::Acquire) + extra_delay;
-            self.last_latency.store(measured_latency, Ordering::Relaxed);
+            // The clock is not guaranteed to be monotonic, so guard against the
+            // last ping appearing to have been sent in the future.
+            let measured_latency = now
+                .saturating_sub(self.last_ping.load(Ordering::Acquire))
+                .saturating_add(extra_delay);
+            self.record_latency(measured_latency);
             Ok(())
         }
     }
 
+    /// Records a measured latency as the latest one.
+    pub(crate) fn record_latency(&self, latency: u64) {
+        self.last_latency.store(latency, Ordering::Relaxed);
+        let mut latency_samples = lock_or_die!(self.latency_samples);
+        let (samples, summary) = &mut *latency_samples;
+        samples.push(latency);
+        *summary = LatencySummary::new(samples);
+    }
+
     #[inline]
     pub fn get_latency(&self) -> u64 { self.last_latency.load(Ordering::Relaxed) }
+
+    /// Summarizes the latencies in the window of recent pongs; all the values
+    /// are 0 if no pong was received yet.
+    pub fn get_latency_summary(&self) -> LatencySummary { lock_or_die!(self.latency_samples).1 }
+
+    /// The latency a connection is judged by: the 95th percentile of the
+    /// recent latencies if `use_p95` is set, or the last latency otherwise.
+    /// The percentile is only used once there are enough samples for it to
+    /// differ from the maximum, so `None` is returned before that.
+    pub fn get_judged_latency(&self, use_p95: bool) -> Option<u64> {
+        if use_p95 {
+            let summary = self.get_latency_summary();
+            if summary.samples >= MIN_P95_LATENCY_SAMPLES {
+                Some(summary.p95)
+            } else {
+                None
+            }
+        } else {
+            Some(self.get_latency())
+        }
+    }
+
+    /// The total number of bytes sent and received over the connection.
+    #[inline]
+    pub fn bytes_handled(&self) -> u64 {
+        self.bytes_sent
+            .load(Ordering::Relaxed)
+            .saturating_add(self.bytes_received.load(Ordering::Relaxed))
+    }
+
+    /// Closes the current throughput window at `now` and updates the rates
+    /// with the traffic observed in it. A window that hasn't elapsed yet is
+    /// left open, and if the byte counters went down (i.e., they were reset or
+    /// wrapped around) the rates are reported as 0 and a new window starts
+    /// from the current counters.
+    pub(crate) fn update_rates(&self, now: u64) {
+        let bytes_received = self.bytes_received.load(Ordering::Relaxed);
+        let bytes_sent = self.bytes_sent.load(Ordering::Relaxed);
+        let mut window = lock_or_die!(self.rate_window);
+        let (window_start, prev_bytes_received, prev_bytes_sent) = *window;
+        if now <= window_start {
+            return;
+        }
+        let (bps_in, bps_out) = calculate_average_throughput(
+            window_start as i64,
+            now as i64,
+            prev_bytes_received,
+            bytes_received,
+            prev_bytes_sent,
+            bytes_sent,
+        )
+        .unwrap_or((0, 0));
+        *window = (now, bytes_received, bytes_sent);
+        self.bps_in.store(bps_in, Ordering::Relaxed);
+        self.bps_out.store(bps_out, Ordering::Relaxed);
+    }
+
+    /// The bytes per second received and sent over the last complete
+    /// throughput window.
+    #[inline]
+    pub fn get_rates(&self) -> (u64, u64) {
+        (self.bps_in.load(Ordering::Relaxed), self.bps_out.load(Ordering::Relaxed))
+    }
 }
 
 /// Specifies the type of change to be applied to the list of connections.
@@ -302,8 +506,9 @@ pub enum ConnChange {
         /// whether the connection was given or discovered
         given:     bool,
     },
-    /// Prospect peers to possibly connect to.
-    NewPeers(Vec<P2PPeer>),
+    /// Prospect peers to possibly connect to, along with how they were
+    /// discovered.
+    NewPeers(Vec<P2PPeer>, DiscoverySource),
     /// Promotion to post-handshake.
     Promotion(Token),
     /// To be removed from the list of connections.
@@ -313,12 +518,16 @@ pub enum ConnChange {
 
 /// Message queues, indexed by priority.
 pub struct MessageQueues {
-    pub low:  VecDeque<Arc<[u8]>>,
-    pub high: VecDeque<Arc<[u8]>>,
+    pub low:  VecDeque<QueuedMessage>,
+    pub high: VecDeque<QueuedMessage>,
+    /// The maximum number of messages queued across both priorities.
+    max_len:  usize,
+    /// The total size of the queued messages.
+    bytes:    usize,
 }
 
 impl Index<MessageSendingPriority> for MessageQueues {
-    type Output = VecDeque<Arc<[u8]>>;
+    type Output = VecDeque<QueuedMessage>;
 
     fn index(&self, priority: MessageSendingPriority) -> &Self::Output {
         match priority {
@@ -338,25 +547,60 @@ impl IndexMut<MessageSendingPriority> for MessageQueues {
 }
 
 impl MessageQueues {
-    /// Create queues with the specified initial capacities.
-    pub fn new(low_capacity: usize, high_capacity: usize) -> Self {
+    /// Create queues with the specified initial capacities, holding at most
+    /// `max_len` messages in total.
+    pub fn new(low_capacity: usize, high_capacity: usize, max_len: usize) -> Self {
         Self {
-            low:  VecDeque::with_capacity(low_capacity),
+            low: VecDeque::with_capacity(low_capacity),
             high: VecDeque::with_capacity(high_capacity),
+            max_len,
+            bytes: 0,
         }
     }
 
-    /// Add a message to the queue with the appropriate priority.
-    pub fn enqueue(&mut self, priority: MessageSendingPriority, message: Arc<[u8]>) {
+    /// Add a message to the queue with the appropriate priority. If the queues
+    /// are full the oldest low priority message is dropped to make room for
+    /// it; if only high priority messages are queued, the new message is
+    /// dropped instead. Returns `true` if a message was dropped.
+    pub fn enqueue(&mut self, priority: MessageSendingPriority, message: QueuedMessage) -> bool {
+        let dropped = if self.len() >= self.max_len {
+            match self.low.pop_front() {
+                Some((_, oldest)) => {
+                    self.bytes -= oldest.len();
+                    true
+                }
+                // queued high priority messages are never evicted
+                None => return true,
+            }
+        } else {
+            false
+        };
+        self.bytes += message.1.len();
         self[priority].push_back(message);
+        dropped
+    }
+
+    /// The total number of queued messages.
+    pub fn len(&self) -> usize { self.low.len() + self.high.len() }
+
+    /// Whether there are no queued messages.
+    pub fn is_empty(&self) -> bool { self.low.is_empty() && self.high.is_empty() }
+
+    /// The total size of the queued messages in bytes.
+    pub fn bytes(&self) -> usize { self.bytes }
+
+    /// Drop all the queued low priority messages, returning their number.
+    pub fn drop_low_priority(&mut self) -> usize {
+        let dropped = self.low.len();
+        self.bytes -= self.low.drain(..).map(|(_, message)| message.len()).sum::<usize>();
+        dropped
     }
 
     /// Dequeue a message, taking from the high priority queue first.
-    pub fn dequeue(&mut self) -> Option<Arc<[u8]>> {
-        match self.high.pop_front() {
-            Some(message) => Some(message),
-            None => self.low.pop_front(),
-        }
+    pub fn dequeue(&mut self) -> Option<QueuedMessage> {
+        let message = self.high.pop_front().or_else(|| self.low.pop_front())?;
+        self.bytes -= message.1.len();
+        Some(message)
     }
 }
 
@@ -373,6 +617,26 @@ pub struct Connection {
     pub stats:               ConnectionStats,
     /// The queue of messages to be sent to the connection.
     pub pending_messages:    MessageQueues,
+    /// How the node learned about the peer of the connection.
+    pub discovery_source:    DiscoverySource,
+    /// Whether reading from the socket was paused while data might still be
+    /// available, in which case reading must be resumed without waiting for
+    /// a new readable event.
+    pub read_paused:         bool,
+    /// Timestamp of the last GetPeers request of the peer that was answered,
+    /// or 0 if there was none.
+    pub last_get_peers:      u64,
+    /// The client version the peer announced in its handshake.
+    pub remote_version:      Option<Version>,
+    /// Timestamp of the completion of the handshake, or 0 before it.
+    pub handshake_completed: u64,
+    /// The zero knowledge proof the peer sent in its handshake; it is kept
+    /// for its later verification.
+    pub remote_proof:        Vec<u8>,
+    /// The features the peer announced in its handshake, if any.
+    pub remote_capabilities: Option<Capabilities>,
+    /// The location of the peer, once it was looked up.
+    pub location:            LocationLookup,
 }
 
 impl PartialEq for Connection {
@@ -392,6 +656,33 @@ impl fmt::Display for Connection {
     }
 }
 
+/// Checks whether `stamp` differs from `now` by at most `tolerance` (all in
+/// ms). Since clocks can be skewed either way, the window is symmetric.
+pub(crate) fn is_within_tolerance(stamp: u64, now: u64, tolerance: u64) -> bool {
+    stamp >= now.saturating_sub(tolerance) && stamp <= now.saturating_add(tolerance)
+}
+
+/// Select the peers to share with the `requestor` in a PeerList response out
+/// of the node's `peers`: the other ones that participate in any of the
+/// requested `networks`, at most `limit` of them chosen at random. If none of
+/// the peers is in those networks, no peers are selected.
+pub(crate) fn select_peer_list(
+    peers: &[PeerStats],
+    requestor: RemotePeerId,
+    networks: &Networks,
+    limit: usize,
+) -> Vec<P2PPeer> {
+    peers
+        .iter()
+        .filter(|stat| stat.local_id != requestor && !stat.networks.is_disjoint(networks))
+        .map(|stat| P2PPeer {
+            id:        stat.self_id,
+            addr:      stat.external_address(),
+            peer_type: stat.peer_type,
+        })
+        .choose_multiple(&mut rand::thread_rng(), limit)
+}
+
 impl Connection {
     /// Create a new connection object.
     /// This registers the given socket with the handler's poll registry.
@@ -401,6 +692,7 @@ impl Connection {
         token: Token,
         remote_peer: RemotePeer,
         is_initiator: bool,
+        discovery_source: DiscoverySource,
     ) -> anyhow::Result<Self> {
         let curr_stamp = get_current_stamp();
 
@@ -412,7 +704,7 @@ impl Connection {
             handler.config.socket_write_size,
         );
 
-        let stats = ConnectionStats::new(curr_stamp);
+        let stats = ConnectionStats::new(curr_stamp, handler.config.latency_window);
 
         // Register the connection's socket with the handler's poll registry.
         handler.poll_registry.register(
@@ -427,7 +719,15 @@ impl Connection {
             low_level,
             remote_end_networks: Default::default(),
             stats,
-            pending_messages: MessageQueues::new(1024, 128),
+            pending_messages: MessageQueues::new(1024, 128, handler.config.max_pending_messages),
+            read_paused: false,
+            discovery_source,
+            last_get_peers: 0,
+            remote_version: None,
+            handshake_completed: 0,
+            remote_proof: Vec::new(),
+            remote_capabilities: None,
+            location: LocationLookup::NotLooked,
         })
     }
 
@@ -438,6 +738,9 @@ impl Connection {
     /// Obtain the connection's latency.
     pub fn get_latency(&self) -> u64 { self.stats.get_latency() }
 
+    /// Obtain the summary of the connection's recent latencies.
+    pub fn get_latency_summary(&self) -> LatencySummary { self.stats.get_latency_summary() }
+
     /// Obtain the node id related to the connection, if available.
     pub fn remote_id(&self) -> Option<P2PNodeId> { self.remote_peer.self_id }
 
@@ -468,23 +771,35 @@ impl Connection {
 
         let deduplication_queues = &self.handler.connection_handler.deduplication_queues;
 
-        let is_duplicate = match packet_type {
-            PacketType::FinalizationMessage => dedup_with(
-                &packet.message,
-                &mut **write_or_die!(deduplication_queues.finalizations),
-            )?,
-            PacketType::Transaction => dedup_with(
-                &packet.message,
-                &mut **write_or_die!(deduplication_queues.transactions),
-            )?,
-            PacketType::Block => {
-                dedup_with(&packet.message, &mut **write_or_die!(deduplication_queues.blocks))?
-            }
-            PacketType::FinalizationRecord => {
-                dedup_with(&packet.message, &mut **write_or_die!(deduplication_queues.fin_records))?
-            }
-            _ => false,
+        let (is_duplicate, is_long_queue) = match packet_type {
+            PacketType::FinalizationMessage => (
+                dedup_with(
+                    &packet.message,
+                    &mut **write_or_die!(deduplication_queues.finalizations),
+                )?,
+                true,
+            ),
+            PacketType::Transaction => (
+                dedup_with(
+                    &packet.message,
+                    &mut **write_or_die!(deduplication_queues.transactions),
+                )?,
+                true,
+            ),
+            PacketType::Block => (
+                dedup_with(&packet.message, &mut **write_or_die!(deduplication_queues.blocks))?,
+                false,
+            ),
+            PacketType::FinalizationRecord => (
+                dedup_with(
+                    &packet.message,
+                    &mut **write_or_die!(deduplication_queues.fin_records),
+                )?,
+                false,
+            ),
+            _ => return Ok(false),
         };
+        self.handler.stats.dedup_check_inc(is_long_queue, is_duplicate);
 
         Ok(is_duplicate)
     }
@@ -495,7 +810,15 @@ impl Connection {
     #[inline]
     pub fn read_stream(&mut self, conn_stats: &[PeerStats]) -> anyhow::Result<bool> {
         loop {
-            match self.low_level.read_from_socket()? {
+            if self.is_ingestion_paused() {
+                if !self.read_paused {
+                    self.handler.stats.ingestion_pauses_inc();
+                }
+                self.read_paused = true;
+                return Ok(true);
+            }
+            self.read_paused = false;
+            match self.low_level.read_from_socket(self.max_message_size())? {
                 ReadResult::Complete(msg) => self.process_message(Arc::from(msg), conn_stats)?,
                 ReadResult::Incomplete => {}
                 ReadResult::WouldBlock => return Ok(true),
@@ -504,6 +827,53 @@ impl Connection {
         }
     }
 
+    /// The maximum size of a message accepted from the peer. Connections to or
+    /// from a bootstrapper only carry small control messages, so they use a
+    /// much lower limit than the ones between nodes.
+    fn max_message_size(&self) -> u32 {
+        if self.handler.self_peer.peer_type == PeerType::Bootstrapper
+            || self.remote_peer.peer_type == PeerType::Bootstrapper
+        {
+            self.handler.config.bootstrapper_max_message_size
+        } else {
+            self.handler.config.node_max_message_size
+        }
+    }
+
+    /// Check whether reading from the connection should be paused because
+    /// consensus is not keeping up with processing the peer's messages, or
+    /// because a queue breaker paused the peer. Handshakes are never paused.
+    fn is_ingestion_paused(&self) -> bool {
+        if !self.is_post_handshake() {
+            return false;
+        }
+        if let Some(limit) = self.handler.config.ingestion_pause_queue_size {
+            let queue_len = CALLBACK_QUEUE.inbound.sender_high_priority.len();
+            let backlog = &self.handler.connection_handler.ingestion_backlog;
+            if backlog.is_paused(self.remote_peer.local_id, queue_len, limit) {
+                return true;
+            }
+        }
+        if self.handler.config.queue_breaker_threshold.is_some() {
+            let handler = &self.handler.connection_handler;
+            let (peer, now) = (self.remote_peer.local_id, Instant::now());
+            return handler.high_queue_breaker.is_paused(peer, now)
+                || handler.low_queue_breaker.is_paused(peer, now);
+        }
+        false
+    }
+
+    /// Checks whether a message timestamp is within the configured tolerance
+    /// of the local time.
+    fn is_timestamp_tolerable(&self, created: u64) -> bool {
+        match self.handler.config.message_timestamp_tolerance {
+            Some(tolerance) => {
+                is_within_tolerance(created, get_current_stamp(), tolerance.saturating_mul(1000))
+            }
+            None => true,
+        }
+    }
+
     #[inline]
     fn process_message(
         &mut self,
@@ -523,6 +893,15 @@ impl Connection {
 
         let mut message = NetworkMessage::deserialize(&bytes)?;
 
+        if !self.is_timestamp_tolerable(message.created) {
+            self.handler.stats.stale_messages_dropped_inc();
+            debug!(
+                "Dropping a message from peer {} with an out-of-range timestamp ({})",
+                self.remote_peer.local_id, message.created
+            );
+            return Ok(());
+        }
+
         if let NetworkPayload::NetworkPacket(ref mut packet) = message.payload {
             // disregard packets when in bootstrapper mode
             if self.handler.self_peer.peer_type == PeerType::Bootstrapper {
@@ -539,9 +918,17 @@ impl Connection {
     }
 
     /// Concludes the connection's handshake process.
-    pub fn promote_to_post_handshake(&mut self, id: P2PNodeId, peer_port: u16, nets: &Networks) {
+    pub fn promote_to_post_handshake(
+        &mut self,
+        id: P2PNodeId,
+        peer_port: u16,
+        nets: &Networks,
+        version: Version,
+    ) {
         self.remote_peer.self_id = Some(id);
         self.remote_peer.external_port = peer_port;
+        self.remote_version = Some(version);
+        self.handshake_completed = get_current_stamp();
         self.handler.stats.peers_inc();
         if self.remote_peer.peer_type == PeerType::Bootstrapper {
             self.handler.update_last_bootstrap();
@@ -551,10 +938,17 @@ impl Connection {
         debug!("Concluded handshake with peer {}(their id {})", self.remote_peer.local_id, id);
     }
 
-    /// Queues a message to be sent to the connection.
+    /// Queues a message of the given kind to be sent to the connection.
     #[inline]
-    pub fn async_send(&mut self, message: Arc<[u8]>, priority: MessageSendingPriority) {
-        self.pending_messages.enqueue(priority, message);
+    pub fn async_send(
+        &mut self,
+        message: Arc<[u8]>,
+        priority: MessageSendingPriority,
+        kind: MessageKind,
+    ) {
+        if self.pending_messages.enqueue(priority, (kind, message)) {
+            self.handler.stats.pending_messages_dropped_inc();
+        }
     }
 
     /// Update the timestamp of when the connection was seen last.
@@ -570,7 +964,17 @@ impl Connection {
         self.remote_end_networks.extend(networks.iter());
 
         if self.remote_peer.peer_type != PeerType::Bootstrapper {
-            write_or_die!(self.handler.buckets()).insert_into_bucket(peer, networks.to_owned());
+            let mut buckets = write_or_die!(self.handler.buckets());
+            if let Some(capacity) = self.handler.config.max_bucket_size {
+                buckets.insert_with_scoring(
+                    peer,
+                    networks.to_owned(),
+                    self.get_latency(),
+                    capacity,
+                );
+            } else {
+                buckets.insert_into_bucket(peer, networks.to_owned());
+            }
         }
     }
 
@@ -601,7 +1005,7 @@ impl Connection {
     #[cfg(feature = "network_dump")]
     fn send_to_dump(&self, buf: Arc<[u8]>, inbound: bool) {
         if let Some(ref sender) = &*read_or_die!(self.handler.connection_handler.log_dumper) {
-            let di = DumpItem::new(inbound, self.remote_peer.addr.ip(), buf);
+            let di = DumpItem::new(inbound, self.remote_peer.addr.ip(), self.remote_id(), buf);
             let _ = sender.send(di);
         }
     }
@@ -617,7 +1021,7 @@ impl Connection {
         ping.serialize(&mut serialized)?;
         self.stats.notify_ping();
 
-        self.async_send(Arc::from(serialized), MessageSendingPriority::High);
+        self.async_send(Arc::from(serialized), MessageSendingPriority::High, MessageKind::Ping);
 
         Ok(())
     }
@@ -629,7 +1033,7 @@ impl Connection {
         let pong = netmsg!(NetworkResponse, NetworkResponse::Pong);
         let mut serialized = Vec::with_capacity(56);
         pong.serialize(&mut serialized)?;
-        self.async_send(Arc::from(serialized), MessageSendingPriority::High);
+        self.async_send(Arc::from(serialized), MessageSendingPriority::High, MessageKind::Ping);
 
         Ok(())
     }
@@ -644,17 +1048,33 @@ impl Connection {
 
         let peer_list_resp = match self.handler.peer_type() {
             PeerType::Bootstrapper => {
+                let peer_list_size = self.handler.config.bootstrapper_peer_list_size;
                 // select random nodes that are post-handshake
-                let random_nodes = read_or_die!(self.handler.buckets())
-                    .get_random_nodes(
-                        requestor,
-                        self.handler.config.bootstrapper_peer_list_size,
-                        &nets,
-                    )
+                let mut random_nodes = read_or_die!(self.handler.buckets())
+                    .get_random_nodes(requestor, peer_list_size, &nets)
                     .iter()
                     .filter_map(RemotePeer::peer)
                     .collect::<Vec<_>>();
 
+                // fill up the remaining space with the configured seed peers, so that
+                // the first nodes of a network can find each other
+                let requestor_addr = self.remote_peer.external_addr();
+                let seed_peers = self
+                    .handler
+                    .config
+                    .bootstrapper_seed_peers
+                    .iter()
+                    .filter(|seed| {
+                        seed.addr != requestor_addr
+                            && !random_nodes.iter().any(|node| node.addr == seed.addr)
+                    })
+                    .copied()
+                    .choose_multiple(
+                        &mut rand::thread_rng(),
+                        peer_list_size.saturating_sub(random_nodes.len()),
+                    );
+                random_nodes.extend(seed_peers);
+
                 if !random_nodes.is_empty()
                     && random_nodes.len()
                         >= usize::from(self.handler.config.bootstrapper_wait_minimum_peers)
@@ -665,15 +1085,14 @@ impl Connection {
                 }
             }
             PeerType::Node => {
-                let nodes = conn_stats
-                    .iter()
-                    .filter(|stat| stat.local_id != requestor)
-                    .map(|stat| P2PPeer {
-                        id:        stat.self_id,
-                        addr:      stat.external_address(),
-                        peer_type: stat.peer_type,
-                    })
-                    .collect::<Vec<_>>();
+                // share a random selection of the peers in the requested networks if
+                // there are too many of them
+                let nodes = select_peer_list(
+                    conn_stats,
+                    requestor,
+                    &nets,
+                    self.handler.config.max_peer_list_size,
+                );
 
                 if !nodes.is_empty() {
                     Some(netmsg!(NetworkResponse, NetworkResponse::PeerList(nodes)))
@@ -688,7 +1107,11 @@ impl Connection {
 
             let mut serialized = Vec::with_capacity(256);
             resp.serialize(&mut serialized)?;
-            self.async_send(Arc::from(serialized), MessageSendingPriority::Normal);
+            self.async_send(
+                Arc::from(serialized),
+                MessageSendingPriority::Normal,
+                MessageKind::PeerList,
+            );
 
             Ok(())
         } else {
@@ -697,30 +1120,67 @@ impl Connection {
         }
     }
 
+    /// Check whether there are messages or bytes that are yet to be written to
+    /// the socket.
+    pub fn has_pending_output(&self) -> bool {
+        !self.pending_messages.is_empty() || self.low_level.has_pending_output()
+    }
+
+    /// The number of bytes waiting to be sent: the queued messages plus the
+    /// encrypted bytes that are yet to be written to the socket.
+    pub fn output_queue_bytes(&self) -> usize {
+        self.pending_messages.bytes() + self.low_level.output_queue_len()
+    }
+
     /// Processes a queue with pending messages, writing them to the socket.
+    ///
+    /// Messages are only encrypted once the socket's output queue has (nearly)
+    /// drained, so that high-priority messages like pings and pongs overtake
+    /// the bulk messages that are still queued. A message that is already
+    /// being written can't be overtaken, as the noise stream must be written
+    /// in the order it was encrypted.
     #[inline]
     pub fn send_pending_messages(&mut self) -> anyhow::Result<()> {
-        while let Some(msg) = self.pending_messages.dequeue() {
-            trace!(
-                "Attempting to send {} to {}",
-                ByteSize(msg.len() as u64).to_string_as(true),
-                self
-            );
-
-            self.low_level.write_to_socket(msg.clone())?;
+        let limit = self.handler.config.max_output_queue_bytes;
+        if self.output_queue_bytes() > limit {
+            return Err(OutputQueueFull {
+                limit,
+            }
+            .into());
+        }
 
-            self.handler.connection_handler.total_sent.fetch_add(1, Ordering::Relaxed);
-            self.handler.stats.pkt_sent_inc();
-            self.stats.messages_sent.fetch_add(1, Ordering::Relaxed);
-            self.stats.bytes_sent.fetch_add(msg.len() as u64, Ordering::Relaxed);
+        loop {
+            while self.low_level.accepts_output() {
+                let (kind, msg) = match self.pending_messages.dequeue() {
+                    Some(queued) => queued,
+                    None => return Ok(()),
+                };
+                trace!(
+                    "Attempting to send {} to {}",
+                    ByteSize(msg.len() as u64).to_string_as(true),
+                    self
+                );
+
+                self.low_level.write_to_socket(msg.clone())?;
+
+                self.handler.connection_handler.total_sent.fetch_add(1, Ordering::Relaxed);
+                self.handler.stats.pkt_sent_inc();
+                self.stats.messages_sent.fetch_add(1, Ordering::Relaxed);
+                self.stats.bytes_sent.fetch_add(msg.len() as u64, Ordering::Relaxed);
+                self.handler.stats.msg_sent_by_kind_inc(kind, msg.len());
+
+                #[cfg(feature = "network_dump")]
+                {
+                    self.send_to_dump(msg, false);
+                }
+            }
 
-            #[cfg(feature = "network_dump")]
-            {
-                self.send_to_dump(msg, false);
+            // continue with the next messages only if the socket took the queued bytes
+            self.low_level.flush_socket()?;
+            if !self.low_level.accepts_output() || self.pending_messages.is_empty() {
+                return Ok(());
             }
         }
-
-        Ok(())
     }
 }
 
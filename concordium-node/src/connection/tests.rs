@@ -1,17 +1,38 @@
 use itertools::Itertools;
 
 use crate::{
-    common::PeerType,
+    common::{p2p_peer::RemotePeerId, DiscoverySource, P2PNodeId, P2PPeer, PeerType},
+    configuration::{MAX_PEER_SCORE, MIN_P95_LATENCY_SAMPLES},
+    connection::{
+        is_within_tolerance, select_peer_list, ConnChange, ConnectionStats, LatencySummary,
+        MessageKind, MessageQueues, MessageSendingPriority,
+    },
     consensus_ffi::helpers::PacketType,
-    network::NetworkId,
-    p2p::connectivity::send_broadcast_message,
+    network::{Handshake, NetworkId, WIRE_FRAMING_VERSION, WIRE_PROTOCOL_VERSION},
+    p2p::{
+        bans::PersistedBanId,
+        connectivity::{
+            self, connection_housekeeping, relay_broadcast_message, relayed_hop_limit,
+            send_broadcast_message, AllowlistEntry, ConnectError,
+        },
+        geo::PeerLocation,
+        P2PNode,
+    },
     test_utils::{
-        await_handshakes, connect, dummy_regenesis_blocks, make_node_and_sync, next_available_port,
-        stop_node_delete_dirs,
+        await_handshakes, connect, create_random_packet, dummy_regenesis_blocks,
+        generate_random_data, make_node_and_sync, make_node_and_sync_with, next_available_port,
+        stop_node_delete_dirs, wait_node_delete_dirs,
     },
 };
 
-use std::sync::Arc;
+use semver::Version;
+use std::{
+    io::Write,
+    net::{IpAddr, SocketAddr, TcpStream},
+    sync::{atomic::Ordering, Arc},
+    thread,
+    time::{Duration, Instant},
+};
 
 const NID: u16 = 100;
 const NODE_COUNT: usize = 10;
@@ -71,3 +92,927 @@ fn basic_connectivity() {
         stop_node_delete_dirs(dp, node);
     }
 }
+
+#[test]
+fn pending_messages_limit() {
+    let msg =
+        |byte: u8| -> (MessageKind, Arc<[u8]>) { (MessageKind::Other, Arc::from(vec![byte])) };
+    let mut queues = MessageQueues::new(4, 4, 3);
+
+    assert!(!queues.enqueue(MessageSendingPriority::Normal, msg(0)));
+    assert!(!queues.enqueue(MessageSendingPriority::High, msg(1)));
+    assert!(!queues.enqueue(MessageSendingPriority::Normal, msg(2)));
+    assert_eq!(queues.len(), 3);
+    assert_eq!(queues.bytes(), 3);
+
+    // the oldest low priority message is dropped first
+    assert!(queues.enqueue(MessageSendingPriority::High, msg(3)));
+    assert_eq!(queues.len(), 3);
+
+    // then the remaining low priority one
+    assert!(queues.enqueue(MessageSendingPriority::High, msg(4)));
+    // once only high priority messages are queued, new messages are dropped
+    assert!(queues.enqueue(MessageSendingPriority::Normal, msg(5)));
+    assert!(queues.enqueue(MessageSendingPriority::High, msg(6)));
+    assert_eq!(queues.len(), 3);
+    assert_eq!(queues.bytes(), 3);
+
+    let remaining = std::iter::from_fn(|| queues.dequeue()).map(|(_, m)| m[0]).collect::<Vec<_>>();
+    assert_eq!(remaining, vec![1, 3, 4]);
+    assert!(queues.is_empty());
+    assert_eq!(queues.bytes(), 0);
+}
+
+#[test]
+fn pending_messages_drop_low_priority() {
+    let msg =
+        |len: usize| -> (MessageKind, Arc<[u8]>) { (MessageKind::Other, Arc::from(vec![0; len])) };
+    let mut queues = MessageQueues::new(4, 4, 10);
+
+    queues.enqueue(MessageSendingPriority::Normal, msg(1));
+    queues.enqueue(MessageSendingPriority::High, msg(2));
+    queues.enqueue(MessageSendingPriority::Normal, msg(4));
+    assert_eq!(queues.drop_low_priority(), 2);
+
+    // only the high priority message is left
+    assert_eq!(queues.len(), 1);
+    assert_eq!(queues.bytes(), 2);
+    assert_eq!(queues.drop_low_priority(), 0);
+}
+
+#[test]
+fn control_messages_overtake_bulk() {
+    let (node_1, dp_1) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    let (node_2, dp_2) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    connect(&node_1, &node_2);
+    await_handshakes(&node_1);
+    await_handshakes(&node_2);
+
+    // larger than what the socket buffers can hold, so it can't be written at once
+    let mut block = vec![PacketType::Block as u8];
+    block.extend(generate_random_data(16 * 1024 * 1024));
+    let block: Arc<[u8]> = Arc::from(block);
+
+    {
+        // the second node doesn't read from its sockets while its connections are
+        // locked, and the first one doesn't send while the test holds its
+        // connections
+        let _stalled = node_2.connections().write().unwrap();
+        let mut connections = node_1.connections().write().unwrap();
+        let conn = connections.values_mut().next().unwrap();
+
+        let kind = MessageKind::of_packet(&block);
+        conn.async_send(Arc::clone(&block), MessageSendingPriority::Normal, kind);
+        conn.async_send(Arc::clone(&block), MessageSendingPriority::Normal, kind);
+        conn.send_pending_messages().unwrap();
+        // only the first block is being written; the second one is held back
+        assert!(conn.low_level.has_pending_output());
+        assert_eq!(conn.pending_messages.len(), 1);
+
+        conn.send_ping().unwrap();
+        conn.send_pending_messages().unwrap();
+        // the ping is written as soon as the first block is, ahead of the second one
+        assert_eq!(conn.pending_messages.len(), 2);
+        assert!(conn.pending_messages.dequeue().unwrap().1.len() < block.len());
+    }
+
+    stop_node_delete_dirs(dp_1, node_1);
+    stop_node_delete_dirs(dp_2, node_2);
+}
+
+#[test]
+fn graceful_shutdown_drains_connections() {
+    let (node_1, dp_1) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    let (node_2, dp_2) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    connect(&node_1, &node_2);
+    await_handshakes(&node_1);
+
+    // queue a large block just before shutting down
+    let mut block = vec![PacketType::Block as u8];
+    block.extend(generate_random_data(1024 * 1024));
+    send_broadcast_message(&node_1, vec![], NetworkId::from(NID), Arc::from(block));
+
+    assert_eq!(node_1.close_gracefully(Duration::from_secs(10)).unwrap(), (1, 0));
+    assert!(node_1.connections().read().unwrap().is_empty());
+
+    wait_node_delete_dirs(dp_1, node_1);
+    stop_node_delete_dirs(dp_2, node_2);
+}
+
+#[test]
+fn broadcast_hop_limit() {
+    let (node_1, dp_1) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    let (node_2, dp_2) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    connect(&node_1, &node_2);
+    await_handshakes(&node_1);
+
+    let block: Arc<[u8]> = Arc::from(vec![PacketType::Block as u8, 0, 0]);
+    // a broadcast that used up its hops isn't relayed
+    assert_eq!(relay_broadcast_message(&node_1, vec![], NetworkId::from(NID), block.clone(), 0), 0);
+    // while one with a hop left still is
+    assert_eq!(relay_broadcast_message(&node_1, vec![], NetworkId::from(NID), block, 1), 1);
+
+    // a peer can't raise the hop limit above the node's own one
+    assert_eq!(relayed_hop_limit(255, 32), Some(31));
+    assert_eq!(relayed_hop_limit(5, 32), Some(4));
+    assert_eq!(relayed_hop_limit(1, 32), Some(0));
+    assert_eq!(relayed_hop_limit(0, 32), None);
+
+    stop_node_delete_dirs(dp_1, node_1);
+    stop_node_delete_dirs(dp_2, node_2);
+}
+
+#[test]
+fn output_queue_limit() {
+    let (node_1, dp_1) =
+        make_node_and_sync_with(next_available_port(), vec![NID], PeerType::Node, vec![], |conf| {
+            conf.connection.max_output_queue_bytes = 1024 * 1024;
+        })
+        .unwrap();
+    let (node_2, dp_2) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    connect(&node_1, &node_2);
+    await_handshakes(&node_1);
+    await_handshakes(&node_2);
+
+    let mut block = vec![PacketType::Block as u8];
+    block.extend(generate_random_data(1024 * 1024));
+    let block: Arc<[u8]> = Arc::from(block);
+
+    {
+        // the second node doesn't read from its sockets while its connections are
+        // locked
+        let _connections = node_2.connections().write().unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while !node_1.connections().read().unwrap().is_empty() {
+            assert!(Instant::now() < deadline, "The connection to a stalled peer wasn't dropped");
+            send_broadcast_message(&node_1, vec![], NetworkId::from(NID), Arc::clone(&block));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    stop_node_delete_dirs(dp_1, node_1);
+    stop_node_delete_dirs(dp_2, node_2);
+}
+
+#[test]
+fn connection_info() {
+    let (node_1, dp_1) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    let (node_2, dp_2) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    connect(&node_1, &node_2);
+    await_handshakes(&node_1);
+
+    // a connection that stays before the handshake
+    let _stream = TcpStream::connect(node_1.internal_addr()).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while node_1.conn_candidates().lock().unwrap().is_empty() {
+        assert!(Instant::now() < deadline, "The incoming connection wasn't accepted");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let info = node_1.get_connection_info();
+    assert_eq!(info.len(), 2);
+    let (complete, partial): (Vec<_>, Vec<_>) =
+        info.into_iter().partition(|conn| conn.handshake_complete);
+    assert_eq!(complete[0].remote_id, Some(node_2.id()));
+    let version = complete[0].node_version.as_ref().map(|version| version.to_string());
+    assert_eq!(version.as_deref(), Some(crate::VERSION));
+    assert_eq!(partial[0].remote_id, None);
+    assert_eq!(partial[0].node_version, None);
+    assert_eq!(partial[0].uptime, 0);
+    // what SIGUSR1 logs
+    node_1.log_state();
+
+    stop_node_delete_dirs(dp_1, node_1);
+    stop_node_delete_dirs(dp_2, node_2);
+}
+
+#[test]
+fn peer_location() {
+    let dir = tempfile::tempdir().unwrap();
+    let subnets = dir.path().join("subnets.csv");
+    std::fs::write(&subnets, "127.0.0.0/8,DK,64512\n").unwrap();
+    let (node_1, dp_1) =
+        make_node_and_sync_with(next_available_port(), vec![NID], PeerType::Node, vec![], |conf| {
+            conf.connection.peer_location_subnets = Some(subnets);
+        })
+        .unwrap();
+    let (node_2, dp_2) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    connect(&node_1, &node_2);
+    await_handshakes(&node_1);
+
+    connection_housekeeping(&node_1);
+    let expected = Some(PeerLocation {
+        region: "DK".to_owned(),
+        asn:    Some(64512),
+    });
+    assert_eq!(node_1.get_peer_stats(None)[0].location, expected);
+    assert_eq!(node_1.get_connection_info()[0].location, expected);
+    // a node without subnets doesn't locate its peers
+    assert_eq!(node_2.get_peer_stats(None)[0].location, None);
+
+    stop_node_delete_dirs(dp_1, node_1);
+    stop_node_delete_dirs(dp_2, node_2);
+}
+
+#[test]
+fn peer_networks() {
+    let (node_1, dp_1) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    let (node_2, dp_2) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    connect(&node_1, &node_2);
+    await_handshakes(&node_1);
+    await_handshakes(&node_2);
+
+    let peer_networks = |node: &P2PNode, id: P2PNodeId| {
+        node.get_connection_info()
+            .into_iter()
+            .find(|conn| conn.remote_id == Some(id))
+            .map(|conn| conn.networks)
+    };
+    let is_in_network = |network: u16| {
+        peer_networks(&node_2, node_1.id()).unwrap().contains(&NetworkId::from(network))
+    };
+    let await_networks = |joined: u16, left: u16| {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !is_in_network(joined) || is_in_network(left) {
+            assert!(Instant::now() < deadline, "The network change wasn't registered");
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+    assert_eq!(peer_networks(&node_2, node_1.id()).unwrap().len(), 1);
+    assert!(is_in_network(NID));
+    // only connected peers have known networks
+    assert_eq!(peer_networks(&node_2, node_2.id()), None);
+
+    // changes announced by the peer are reflected
+    node_1.join_network(NetworkId::from(NID + 1));
+    await_networks(NID + 1, NID + 2);
+    node_1.leave_network(NetworkId::from(NID));
+    await_networks(NID + 1, NID);
+
+    stop_node_delete_dirs(dp_1, node_1);
+    stop_node_delete_dirs(dp_2, node_2);
+}
+
+#[test]
+fn peer_list_network_filtering() {
+    let (node_1, dp_1) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    let (node_2, dp_2) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    let (node_3, dp_3) =
+        make_node_and_sync(next_available_port(), vec![NID + 1], PeerType::Node, vec![]).unwrap();
+    connect(&node_2, &node_1);
+    connect(&node_3, &node_1);
+    await_handshakes(&node_1);
+    await_handshakes(&node_2);
+    await_handshakes(&node_3);
+
+    let peers = node_1.get_peer_stats(None);
+    assert_eq!(peers.len(), 2);
+    let requestor = RemotePeerId::from(usize::MAX);
+    let networks = |ids: &[u16]| ids.iter().copied().map(NetworkId::from).collect();
+    let selected_ids = |ids: &[u16]| {
+        select_peer_list(&peers, requestor, &networks(ids), 10)
+            .into_iter()
+            .map(|peer| peer.id)
+            .sorted()
+            .collect::<Vec<_>>()
+    };
+
+    // only the peers in the requested networks are shared
+    assert_eq!(selected_ids(&[NID]), vec![node_2.id()]);
+    assert_eq!(selected_ids(&[NID + 1]), vec![node_3.id()]);
+    let mut both = vec![node_2.id(), node_3.id()];
+    both.sort();
+    assert_eq!(selected_ids(&[NID, NID + 1]), both);
+    // and none if no peer is in them
+    assert!(selected_ids(&[NID + 2]).is_empty());
+    assert!(selected_ids(&[]).is_empty());
+    // the requestor is never shared back
+    let node_2_id = peers.iter().find(|peer| peer.self_id == node_2.id()).unwrap().local_id;
+    assert!(select_peer_list(&peers, node_2_id, &networks(&[NID]), 10).is_empty());
+
+    stop_node_delete_dirs(dp_1, node_1);
+    stop_node_delete_dirs(dp_2, node_2);
+    stop_node_delete_dirs(dp_3, node_3);
+}
+
+#[test]
+fn disconnect() {
+    let (node_1, dp_1) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    let (node_2, dp_2) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    connect(&node_1, &node_2);
+    await_handshakes(&node_1);
+    await_handshakes(&node_2);
+
+    assert!(!node_1.drop_addr(node_1.internal_addr()));
+    assert!(node_1.drop_addr(node_2.internal_addr()));
+    assert!(!node_1.config.given_addresses.read().unwrap().contains(&node_2.internal_addr()));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !node_1.get_peer_stats(None).is_empty() {
+        assert!(Instant::now() < deadline, "The connection wasn't closed");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(!node_1.drop_addr(node_2.internal_addr()));
+
+    stop_node_delete_dirs(dp_1, node_1);
+    stop_node_delete_dirs(dp_2, node_2);
+}
+
+#[test]
+fn handshake_timeout() {
+    let port = next_available_port();
+    let (node, dp) = make_node_and_sync_with(port, vec![NID], PeerType::Node, vec![], |conf| {
+        conf.connection.handshake_timeout = 200;
+    })
+    .unwrap();
+
+    // a peer that connects but never sends the first handshake message
+    let _stream = TcpStream::connect(node.internal_addr()).unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while node.conn_candidates().lock().unwrap().is_empty() {
+        assert!(Instant::now() < deadline, "The incoming connection wasn't accepted");
+        thread::sleep(Duration::from_millis(10));
+    }
+    // well before the pre-handshake keep-alive expires
+    while !node.conn_candidates().lock().unwrap().is_empty() {
+        assert!(Instant::now() < deadline, "The stalled handshake wasn't dropped");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    stop_node_delete_dirs(dp, node);
+}
+
+#[test]
+fn read_stall_timeout() {
+    let port = next_available_port();
+    let (node, dp) = make_node_and_sync_with(port, vec![NID], PeerType::Node, vec![], |conf| {
+        conf.connection.handshake_timeout = 60_000;
+        conf.connection.read_stall_timeout = 200;
+    })
+    .unwrap();
+
+    // a peer that sends a single byte of a length prefix and then nothing
+    let mut stream = TcpStream::connect(node.internal_addr()).unwrap();
+    stream.write_all(&[0]).unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while node.conn_candidates().lock().unwrap().is_empty() {
+        assert!(Instant::now() < deadline, "The incoming connection wasn't accepted");
+        thread::sleep(Duration::from_millis(10));
+    }
+    // well before the handshake timeout expires
+    while !node.conn_candidates().lock().unwrap().is_empty() {
+        assert!(Instant::now() < deadline, "The stalled read wasn't dropped");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    stop_node_delete_dirs(dp, node);
+}
+
+#[test]
+fn allowlist() {
+    let (node_1, dp_1) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    let allowed_id = AllowlistEntry::Id(node_1.id());
+    let (node_2, dp_2) =
+        make_node_and_sync_with(next_available_port(), vec![NID], PeerType::Node, vec![], |conf| {
+            conf.connection.allowlist = vec![allowed_id];
+        })
+        .unwrap();
+    let other_ip = AllowlistEntry::Ip(IpAddr::from([10, 0, 0, 1]));
+    let (node_3, dp_3) =
+        make_node_and_sync_with(next_available_port(), vec![NID], PeerType::Node, vec![], |conf| {
+            conf.connection.allowlist = vec![other_ip];
+        })
+        .unwrap();
+
+    // connecting to a peer that is not on the allowlist is refused
+    assert!(matches!(
+        connectivity::connect(
+            &node_3,
+            PeerType::Node,
+            node_1.internal_addr(),
+            Some(node_1.id()),
+            false,
+            DiscoverySource::Given,
+        ),
+        Err(ConnectError::NotAllowed { .. })
+    ));
+
+    // and so is a connection from an address that is not on it
+    let _stream = TcpStream::connect(node_3.internal_addr()).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    assert!(node_3.conn_candidates().lock().unwrap().is_empty());
+    assert!(node_3.connections().read().unwrap().is_empty());
+
+    // a peer on the allowlist is connected to
+    connect(&node_2, &node_1);
+    await_handshakes(&node_2);
+    assert_eq!(node_2.get_peer_stats(None)[0].self_id, node_1.id());
+
+    stop_node_delete_dirs(dp_1, node_1);
+    stop_node_delete_dirs(dp_2, node_2);
+    stop_node_delete_dirs(dp_3, node_3);
+}
+
+#[test]
+fn ids_from_noise_keys() {
+    let (node_1, dp_1) =
+        make_node_and_sync_with(next_available_port(), vec![NID], PeerType::Node, vec![], |conf| {
+            conf.common.id_from_noise_key = true;
+        })
+        .unwrap();
+    let (node_2, dp_2) =
+        make_node_and_sync_with(next_available_port(), vec![NID], PeerType::Node, vec![], |conf| {
+            conf.common.id_from_noise_key = true;
+        })
+        .unwrap();
+    // a peer with a random id, which doesn't announce a key-derived one
+    let (node_3, dp_3) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    // a peer announcing a key-derived id while using another one
+    let (node_4, dp_4) =
+        make_node_and_sync_with(next_available_port(), vec![NID], PeerType::Node, vec![], |conf| {
+            conf.common.id_from_noise_key = true;
+            conf.common.id = Some(P2PNodeId(42));
+        })
+        .unwrap();
+    let peer_ids = |node: &P2PNode| {
+        let mut ids =
+            node.get_peer_stats(None).into_iter().map(|peer| peer.self_id).collect::<Vec<_>>();
+        ids.sort();
+        ids
+    };
+
+    // a peer whose id is derived from its key completes the handshake
+    connect(&node_2, &node_1);
+    await_handshakes(&node_1);
+    assert_eq!(peer_ids(&node_1), vec![node_2.id()]);
+
+    // and so does one that doesn't claim a key-derived id
+    connect(&node_3, &node_1);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while peer_ids(&node_1).len() < 2 {
+        assert!(Instant::now() < deadline, "The peer with a random id wasn't accepted");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // while one that claims a key-derived id that isn't its own is rejected
+    connect(&node_4, &node_1);
+    thread::sleep(Duration::from_millis(500));
+    let mut expected = vec![node_2.id(), node_3.id()];
+    expected.sort();
+    assert_eq!(peer_ids(&node_1), expected);
+
+    stop_node_delete_dirs(dp_1, node_1);
+    stop_node_delete_dirs(dp_2, node_2);
+    stop_node_delete_dirs(dp_3, node_3);
+    stop_node_delete_dirs(dp_4, node_4);
+}
+
+#[test]
+fn handshake_capabilities() {
+    let (node_1, dp_1) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    let (node_2, dp_2) =
+        make_node_and_sync_with(next_available_port(), vec![NID], PeerType::Node, vec![], |conf| {
+            conf.connection.no_handshake_capabilities = true;
+        })
+        .unwrap();
+    connect(&node_1, &node_2);
+    await_handshakes(&node_1);
+    await_handshakes(&node_2);
+
+    // the capabilities are stored if announced, and the handshake succeeds either
+    // way
+    let remote_capabilities = |node: &P2PNode| {
+        node.connections().read().unwrap().values().next().unwrap().remote_capabilities.clone()
+    };
+    assert_eq!(remote_capabilities(&node_1), None);
+    assert_eq!(remote_capabilities(&node_2), Some(node_1.capabilities()));
+
+    stop_node_delete_dirs(dp_1, node_1);
+    stop_node_delete_dirs(dp_2, node_2);
+}
+
+#[test]
+fn handshake_framing_version() {
+    let (node_1, dp_1) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    let (node_2, dp_2) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    connect(&node_1, &node_2);
+    await_handshakes(&node_1);
+
+    // a handshake that is acceptable apart from its framing version is rejected
+    let handshake = Handshake {
+        remote_id:       node_2.id(),
+        remote_port:     node_2.self_peer.port(),
+        networks:        [NID].iter().copied().map(NetworkId::from).collect(),
+        node_version:    Version::parse(env!("CARGO_PKG_VERSION")).unwrap(),
+        wire_versions:   vec![WIRE_PROTOCOL_VERSION],
+        genesis_blocks:  dummy_regenesis_blocks(),
+        proof:           vec![],
+        capabilities:    None,
+        framing_version: WIRE_FRAMING_VERSION + 1,
+    };
+    let result = {
+        let mut connections = node_1.connections().write().unwrap();
+        let conn = connections.values_mut().next().unwrap();
+        conn.handle_handshake_req(handshake, &[])
+    };
+    let error = result.unwrap_err().to_string();
+    assert!(error.contains("incompatible wire framing version"), "{}", error);
+
+    stop_node_delete_dirs(dp_1, node_1);
+    stop_node_delete_dirs(dp_2, node_2);
+}
+
+#[test]
+fn packet_compression_capabilities() {
+    const PAYLOAD_SIZE: usize = 64 * 1024;
+
+    let (node_1, dp_1) =
+        make_node_and_sync_with(next_available_port(), vec![NID], PeerType::Node, vec![], |conf| {
+            conf.connection.packet_compression_threshold = Some(1024);
+        })
+        .unwrap();
+    let (node_2, dp_2) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    let (node_3, dp_3) =
+        make_node_and_sync_with(next_available_port(), vec![NID], PeerType::Node, vec![], |conf| {
+            conf.connection.no_handshake_capabilities = true;
+        })
+        .unwrap();
+    connect(&node_1, &node_2);
+    connect(&node_1, &node_3);
+    await_handshakes(&node_1);
+    await_handshakes(&node_2);
+    await_handshakes(&node_3);
+
+    let received = |node: &P2PNode| {
+        let connections = node.connections().read().unwrap();
+        let stats = &connections.values().next().unwrap().stats;
+        (
+            stats.messages_received.load(Ordering::Relaxed),
+            stats.bytes_received.load(Ordering::Relaxed),
+        )
+    };
+    let (messages_before, bytes_before_2) = received(&node_2);
+    let (_, bytes_before_3) = received(&node_3);
+
+    // a highly compressible block
+    let mut block = vec![PacketType::Block as u8];
+    block.extend(vec![0u8; PAYLOAD_SIZE]);
+    assert_eq!(send_broadcast_message(&node_1, vec![], NetworkId::from(NID), Arc::from(block)), 2);
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while received(&node_2).0 == messages_before
+        || received(&node_3).1 < bytes_before_3 + PAYLOAD_SIZE as u64
+    {
+        assert!(Instant::now() < deadline, "The block wasn't received by both peers");
+        thread::sleep(Duration::from_millis(10));
+    }
+    // only the peer that announced it can decompress payloads gets a compressed one
+    assert!(received(&node_2).1 < bytes_before_2 + PAYLOAD_SIZE as u64 / 4);
+
+    stop_node_delete_dirs(dp_1, node_1);
+    stop_node_delete_dirs(dp_2, node_2);
+    stop_node_delete_dirs(dp_3, node_3);
+}
+
+#[test]
+fn latency_window() {
+    let stats = ConnectionStats::new(0, 5);
+    assert_eq!(stats.get_latency_summary(), LatencySummary::default());
+
+    // only the 5 most recent latencies are kept
+    for latency in &[100, 200, 3, 4, 5, 6, 7] {
+        stats.record_latency(*latency);
+    }
+    assert_eq!(stats.get_latency(), 7);
+    assert_eq!(stats.get_latency_summary(), LatencySummary {
+        samples: 5,
+        min:     3,
+        avg:     5,
+        p95:     7,
+    });
+
+    // a single slow pong doesn't determine the 95th percentile of a large window
+    let stats = ConnectionStats::new(0, MIN_P95_LATENCY_SAMPLES);
+    stats.record_latency(1000);
+    assert_eq!(stats.get_judged_latency(false), Some(1000));
+    // the connection isn't judged by the percentile before there are enough samples
+    assert_eq!(stats.get_judged_latency(true), None);
+    for _ in 1..MIN_P95_LATENCY_SAMPLES {
+        stats.record_latency(10);
+    }
+    assert_eq!(stats.get_latency_summary().p95, 10);
+    assert_eq!(stats.get_judged_latency(true), Some(10));
+}
+
+#[test]
+fn peer_score() {
+    let stats = ConnectionStats::new(0, 5);
+    assert_eq!(stats.get_score(), MAX_PEER_SCORE);
+
+    stats.penalize(30);
+    assert_eq!(stats.get_score(), MAX_PEER_SCORE - 30);
+    stats.recover_score(10);
+    assert_eq!(stats.get_score(), MAX_PEER_SCORE - 20);
+
+    // the score stays within its bounds
+    stats.recover_score(1000);
+    assert_eq!(stats.get_score(), MAX_PEER_SCORE);
+    stats.penalize(1000);
+    assert_eq!(stats.get_score(), 0);
+}
+
+#[test]
+fn connection_rates() {
+    // a new connection measures its first window from its creation
+    let stats = ConnectionStats::new(1000, 5);
+    assert_eq!(stats.get_rates(), (0, 0));
+    stats.bytes_received.store(4000, Ordering::Relaxed);
+    stats.bytes_sent.store(1000, Ordering::Relaxed);
+    stats.update_rates(3000);
+    assert_eq!(stats.get_rates(), (2000, 500));
+
+    // a window that hasn't elapsed keeps the previous rates
+    stats.update_rates(3000);
+    assert_eq!(stats.get_rates(), (2000, 500));
+
+    // only the traffic of the latest window counts
+    stats.bytes_received.store(5000, Ordering::Relaxed);
+    stats.update_rates(4000);
+    assert_eq!(stats.get_rates(), (1000, 0));
+
+    // counters that went down yield zero rates and a new baseline
+    stats.bytes_received.store(100, Ordering::Relaxed);
+    stats.update_rates(5000);
+    assert_eq!(stats.get_rates(), (0, 0));
+    stats.bytes_received.store(600, Ordering::Relaxed);
+    stats.bytes_sent.store(1200, Ordering::Relaxed);
+    stats.update_rates(6000);
+    assert_eq!(stats.get_rates(), (500, 200));
+}
+
+#[test]
+fn peer_quarantine() {
+    let (node_1, dp_1) =
+        make_node_and_sync_with(next_available_port(), vec![NID], PeerType::Node, vec![], |conf| {
+            conf.connection.peer_score_threshold = Some(50);
+        })
+        .unwrap();
+    let (node_2, dp_2) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    connect(&node_1, &node_2);
+    await_handshakes(&node_1);
+
+    // a few invalid messages aren't enough to get a peer quarantined
+    let peer_id = node_1.get_peer_stats(None)[0].local_id;
+    for _ in 0..3 {
+        node_1.bad_events.inc_invalid_messages(peer_id);
+    }
+    connection_housekeeping(&node_1);
+    let peer_stats = node_1.get_peer_stats(None);
+    assert_eq!(peer_stats.len(), 1);
+    assert!(peer_stats[0].score < MAX_PEER_SCORE);
+
+    // but more of them drive its score below the threshold
+    for _ in 0..3 {
+        node_1.bad_events.inc_invalid_messages(peer_id);
+    }
+    connection_housekeeping(&node_1);
+    assert!(node_1.get_peer_stats(None).is_empty());
+
+    // and the peer isn't accepted again until the quarantine expires
+    assert!(node_1.is_banned(PersistedBanId::Ip(node_2.internal_addr().ip())).unwrap());
+    assert!(matches!(
+        connectivity::connect(
+            &node_1,
+            PeerType::Node,
+            node_2.internal_addr(),
+            Some(node_2.id()),
+            false,
+            DiscoverySource::Given,
+        ),
+        Err(ConnectError::Banned { .. })
+    ));
+
+    stop_node_delete_dirs(dp_1, node_1);
+    stop_node_delete_dirs(dp_2, node_2);
+}
+
+#[test]
+fn message_timestamp_tolerance() {
+    let now = 1_000_000;
+    assert!(is_within_tolerance(now, now, 0));
+    assert!(is_within_tolerance(now - 500, now, 500));
+    assert!(is_within_tolerance(now + 500, now, 500));
+    assert!(!is_within_tolerance(now - 501, now, 500));
+    assert!(!is_within_tolerance(now + 501, now, 500));
+    // the window saturates instead of wrapping around
+    assert!(is_within_tolerance(0, 10, u64::MAX));
+    assert!(is_within_tolerance(u64::MAX, now, u64::MAX));
+}
+
+#[test]
+fn bind_address() {
+    let (node_1, dp_1) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    let (node_2, dp_2) =
+        make_node_and_sync_with(next_available_port(), vec![NID], PeerType::Node, vec![], |conf| {
+            conf.common.bind_address = Some(IpAddr::from([127, 0, 0, 1]));
+        })
+        .unwrap();
+    let (node_3, dp_3) =
+        make_node_and_sync_with(next_available_port(), vec![NID], PeerType::Node, vec![], |conf| {
+            conf.common.bind_address = Some(IpAddr::from([0u16, 0, 0, 0, 0, 0, 0, 1]));
+        })
+        .unwrap();
+
+    // the bind address must be of the same family as the peer's one
+    assert!(matches!(
+        connectivity::connect(
+            &node_3,
+            PeerType::Node,
+            node_1.internal_addr(),
+            Some(node_1.id()),
+            false,
+            DiscoverySource::Given,
+        ),
+        Err(ConnectError::AddressFamilyMismatch { .. })
+    ));
+    assert!(node_3.conn_candidates().lock().unwrap().is_empty());
+
+    connect(&node_2, &node_1);
+    await_handshakes(&node_2);
+    assert_eq!(node_2.get_peer_stats(None)[0].self_id, node_1.id());
+
+    stop_node_delete_dirs(dp_1, node_1);
+    stop_node_delete_dirs(dp_2, node_2);
+    stop_node_delete_dirs(dp_3, node_3);
+}
+
+#[test]
+fn self_connection() {
+    let (node, dp) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    let other_addr = SocketAddr::new(IpAddr::from([127, 0, 0, 1]), next_available_port());
+    let connect_to_other = |id| {
+        connectivity::connect(&node, PeerType::Node, other_addr, id, false, DiscoverySource::Given)
+    };
+
+    // an address advertised with the node's own id
+    let err = connect_to_other(Some(node.id())).unwrap_err();
+    assert!(matches!(err, ConnectError::SelfConnection));
+    assert!(err.to_string().contains("myself"));
+
+    // an address that turned out to lead back to the node
+    node.connection_handler.self_addresses.write().unwrap().insert(other_addr);
+    let err = connect_to_other(None).unwrap_err();
+    assert!(matches!(err, ConnectError::SelfConnection));
+
+    stop_node_delete_dirs(dp, node);
+}
+
+#[test]
+fn self_connection_handshake() {
+    // listening on all interfaces, the node is also reachable via the loopback
+    // address
+    let port = next_available_port();
+    let (node, dp_1) = make_node_and_sync_with(port, vec![NID], PeerType::Node, vec![], |conf| {
+        conf.common.listen_address = Some("0.0.0.0".to_owned());
+    })
+    .unwrap();
+    let loopback = SocketAddr::new(IpAddr::from([127, 0, 0, 1]), port);
+    let connect_to = |addr| {
+        connectivity::connect(&node, PeerType::Node, addr, None, false, DiscoverySource::Given)
+    };
+    let await_no_connections = || {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !node.conn_candidates().lock().unwrap().is_empty()
+            || !node.connections().read().unwrap().is_empty()
+        {
+            assert!(Instant::now() < deadline, "The handshake wasn't rejected");
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+
+    // the handshake reveals that the address leads back to the node
+    connect_to(loopback).unwrap();
+    await_no_connections();
+    assert!(node.connection_handler.self_addresses.read().unwrap().contains(&loopback));
+
+    // a peer with a different noise key claiming the node's id is rejected, but
+    // its address isn't taken for one of the node's own
+    let node_id = node.id();
+    let (impostor, dp_2) =
+        make_node_and_sync_with(next_available_port(), vec![NID], PeerType::Node, vec![], |conf| {
+            conf.common.id = Some(node_id);
+        })
+        .unwrap();
+    connect_to(impostor.internal_addr()).unwrap();
+    await_no_connections();
+    let self_addresses = node.connection_handler.self_addresses.read().unwrap().clone();
+    assert_eq!(self_addresses.into_iter().collect::<Vec<_>>(), vec![loopback]);
+
+    stop_node_delete_dirs(dp_1, node);
+    stop_node_delete_dirs(dp_2, impostor);
+}
+
+#[test]
+fn seed_peers_with_unknown_ids() {
+    let (seed, dp_1) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    let seed_addr = seed.self_peer.addr;
+    let (bootstrapper, dp_2) = make_node_and_sync_with(
+        next_available_port(),
+        vec![NID],
+        PeerType::Bootstrapper,
+        vec![],
+        |conf| {
+            conf.bootstrapper.seed_peers = vec![seed_addr.to_string()];
+        },
+    )
+    .unwrap();
+    let (node, dp_3) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+
+    // the bootstrapper doesn't know the id of its seed peer
+    let advertised = bootstrapper.config.bootstrapper_seed_peers.clone();
+    assert_eq!(advertised, vec![P2PPeer {
+        id:        P2PNodeId::UNKNOWN,
+        addr:      seed_addr,
+        peer_type: PeerType::Node,
+    }]);
+    assert_eq!(advertised[0].known_id(), None);
+
+    // so a node receiving it connects without expecting any id, and learns the real
+    // one in the handshake
+    node.register_conn_change(ConnChange::NewPeers(advertised, DiscoverySource::Bootstrap));
+    await_handshakes(&node);
+    let peer_ids = node.get_peer_stats(None).into_iter().map(|peer| peer.self_id);
+    assert_eq!(peer_ids.collect::<Vec<_>>(), vec![seed.id()]);
+
+    stop_node_delete_dirs(dp_1, seed);
+    stop_node_delete_dirs(dp_2, bootstrapper);
+    stop_node_delete_dirs(dp_3, node);
+}
+
+#[test]
+fn bootstrapper_message_size_limit() {
+    let (bootstrapper, dp_1) = make_node_and_sync_with(
+        next_available_port(),
+        vec![NID],
+        PeerType::Bootstrapper,
+        vec![],
+        |conf| {
+            conf.connection.bootstrapper_max_message_size = 4096;
+        },
+    )
+    .unwrap();
+    let (node, dp_2) =
+        make_node_and_sync(next_available_port(), vec![NID], PeerType::Node, vec![]).unwrap();
+    connect(&node, &bootstrapper);
+    await_handshakes(&node);
+    await_handshakes(&bootstrapper);
+
+    // a message that a node would accept, but a bootstrapper doesn't
+    let mut message = Vec::new();
+    create_random_packet(8192).serialize(&mut message).unwrap();
+    for conn in node.connections().write().unwrap().values_mut() {
+        conn.async_send(
+            Arc::from(message.clone()),
+            MessageSendingPriority::Normal,
+            MessageKind::Other,
+        );
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !bootstrapper.connections().read().unwrap().is_empty() {
+        assert!(Instant::now() < deadline, "The oversized message was accepted");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    stop_node_delete_dirs(dp_1, bootstrapper);
+    stop_node_delete_dirs(dp_2, node);
+}
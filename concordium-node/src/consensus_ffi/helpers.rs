@@ -165,6 +165,21 @@ impl fmt::Display for PacketType {
     }
 }
 
+impl FromStr for PacketType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(PacketType::Block),
+            "transaction" => Ok(PacketType::Transaction),
+            "finalization-record" => Ok(PacketType::FinalizationRecord),
+            "finalization-message" => Ok(PacketType::FinalizationMessage),
+            "catch-up-status" => Ok(PacketType::CatchUpStatus),
+            _ => Err(anyhow!("Unknown packet type ({})", s)),
+        }
+    }
+}
+
 impl PacketType {
     /// Determine whether a packet type can be relayed.
     /// Those that are must be subject to appropriate de-duplication
@@ -14,6 +14,8 @@ pub struct ConsensusMessage {
     pub payload:       Arc<[u8]>,
     pub dont_relay_to: Vec<RemotePeerId>,
     pub omit_status:   Option<PeerStatus>,
+    /// The number of times an inbound broadcast may still be relayed.
+    pub hop_limit:     Option<u8>,
 }
 
 impl ConsensusMessage {
@@ -30,6 +32,7 @@ impl ConsensusMessage {
             payload,
             dont_relay_to,
             omit_status,
+            hop_limit: None,
         }
     }
 
@@ -2,36 +2,280 @@
 
 cfg_if! {
     if #[cfg(feature = "network_dump")] {
-        use crate::common::P2PNodeId;
         use crossbeam_channel::{self, Receiver};
-        use std::io::Write;
+        use anyhow::{ensure, Context};
+        use std::{
+            convert::TryInto,
+            fs::{File, OpenOptions},
+            io::{BufWriter, Read, Seek, SeekFrom, Write},
+            path::Path,
+        };
     }
 }
-use crate::{network::NetworkMessage, spawn_or_die};
+use crate::{
+    common::P2PNodeId, consensus_ffi::helpers::PacketType, network::NetworkMessage, spawn_or_die,
+};
 use chrono::prelude::{DateTime, Utc};
 
-use std::{fmt, net::IpAddr, sync::Arc};
+use std::{collections::HashSet, convert::TryFrom, fmt, net::IpAddr, sync::Arc};
 
 /// A structure containing network data to be dumped to the disk.
 pub struct DumpItem {
     timestamp:   DateTime<Utc>,
     inbound:     bool,
     remote_addr: IpAddr,
+    /// The id of the remote peer; only known after the handshake.
+    remote_id:   Option<P2PNodeId>,
     msg:         Arc<[u8]>,
 }
 
 impl DumpItem {
     /// Creates a new dump item object.
-    pub fn new(inbound: bool, remote_addr: IpAddr, msg: Arc<[u8]>) -> Self {
+    pub fn new(
+        inbound: bool,
+        remote_addr: IpAddr,
+        remote_id: Option<P2PNodeId>,
+        msg: Arc<[u8]>,
+    ) -> Self {
         DumpItem {
             timestamp: Utc::now(),
             inbound,
             remote_addr,
+            remote_id,
             msg,
         }
     }
 }
 
+/// Restricts the messages written by the network dump. An unset criterion
+/// matches every message.
+#[derive(Debug, Clone, Default)]
+pub struct DumpFilter {
+    /// Only dump consensus packets of these types.
+    pub packet_types: Option<HashSet<PacketType>>,
+    /// Only dump messages exchanged with these peers.
+    pub peers:        Option<HashSet<P2PNodeId>>,
+}
+
+impl DumpFilter {
+    /// Checks whether the given dump item should be written.
+    pub fn matches(&self, item: &DumpItem) -> bool {
+        if let Some(ref peers) = self.peers {
+            if !item.remote_id.map_or(false, |id| peers.contains(&id)) {
+                return false;
+            }
+        }
+        if let Some(ref packet_types) = self.packet_types {
+            // the consensus packet type is the first byte of a packet's payload
+            let packet_type = NetworkMessage::peek_packet_payload(&item.msg)
+                .and_then(|b| PacketType::try_from(b).ok());
+            if !packet_type.map_or(false, |pt| packet_types.contains(&pt)) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// The format of the raw network dump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// Every message is written to a separate file, as is.
+    Raw,
+    /// All the messages are written to a single pcap file.
+    Pcap,
+}
+
+impl Default for DumpFormat {
+    fn default() -> Self { DumpFormat::Raw }
+}
+
+impl std::str::FromStr for DumpFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "raw" => Ok(DumpFormat::Raw),
+            "pcap" => Ok(DumpFormat::Pcap),
+            _ => anyhow::bail!("Could not parse the dump format"),
+        }
+    }
+}
+
+/// The pcap link-layer type reserved for private use (DLT_USER0), which the
+/// synthetic header of the dumped records is declared as.
+#[cfg(feature = "network_dump")]
+const PCAP_LINKTYPE_USER0: u32 = 147;
+/// The maximum number of bytes of a single message written to a pcap record.
+#[cfg(feature = "network_dump")]
+const PCAP_SNAPLEN: u32 = 262_144;
+/// The size of the synthetic link-layer header preceding every pcap record.
+#[cfg(feature = "network_dump")]
+const PCAP_LINK_HEADER_LEN: usize = 25;
+/// The size of the global header of a pcap file.
+#[cfg(feature = "network_dump")]
+const PCAP_HEADER_LEN: u64 = 24;
+/// The size of the header preceding the data of every pcap record.
+#[cfg(feature = "network_dump")]
+const PCAP_RECORD_HEADER_LEN: u64 = 16;
+/// The direction byte of the record marking that a capture was continued.
+#[cfg(feature = "network_dump")]
+const PCAP_CONTINUATION_MARKER: u8 = 2;
+
+/// Writes the global header of a pcap file (in little endian, with microsecond
+/// timestamps).
+#[cfg(feature = "network_dump")]
+fn write_pcap_header<W: Write>(target: &mut W) -> std::io::Result<()> {
+    target.write_all(&0xa1b2_c3d4u32.to_le_bytes())?; // magic number
+    target.write_all(&2u16.to_le_bytes())?; // major version
+    target.write_all(&4u16.to_le_bytes())?; // minor version
+    target.write_all(&0i32.to_le_bytes())?; // GMT offset
+    target.write_all(&0u32.to_le_bytes())?; // timestamp accuracy
+    target.write_all(&PCAP_SNAPLEN.to_le_bytes())?;
+    target.write_all(&PCAP_LINKTYPE_USER0.to_le_bytes())
+}
+
+/// Writes a dump item as a pcap record. The record's data starts with a
+/// synthetic link-layer header of `PCAP_LINK_HEADER_LEN` bytes:
+///
+/// - byte 0: the direction of the message; 0 for inbound, 1 for outbound, or
+///   `PCAP_CONTINUATION_MARKER` for the empty record that marks where a capture
+///   was continued after being appended to,
+/// - bytes 1-8: the id of the remote peer (big endian), 0 if not known yet,
+/// - bytes 9-24: the IP address of the remote peer, with IPv4 addresses mapped
+///   to IPv6 (`::ffff:a.b.c.d`),
+///
+/// which is followed by the decrypted message, truncated to the snapshot
+/// length.
+#[cfg(feature = "network_dump")]
+fn write_pcap_record<W: Write>(target: &mut W, item: &DumpItem) -> std::io::Result<()> {
+    let direction = if item.inbound {
+        0u8
+    } else {
+        1u8
+    };
+    let remote_id = item.remote_id.map(|id| id.0).unwrap_or(0);
+    write_pcap_frame(target, item.timestamp, direction, remote_id, item.remote_addr, &item.msg)
+}
+
+/// Writes the empty pcap record that marks where a capture was continued.
+#[cfg(feature = "network_dump")]
+fn write_pcap_continuation_marker<W: Write>(
+    target: &mut W,
+    timestamp: DateTime<Utc>,
+) -> std::io::Result<()> {
+    let unspecified = IpAddr::from(std::net::Ipv6Addr::UNSPECIFIED);
+    write_pcap_frame(target, timestamp, PCAP_CONTINUATION_MARKER, 0, unspecified, &[])
+}
+
+/// Writes a pcap record with the layout described at `write_pcap_record`.
+#[cfg(feature = "network_dump")]
+fn write_pcap_frame<W: Write>(
+    target: &mut W,
+    timestamp: DateTime<Utc>,
+    direction: u8,
+    remote_id: u64,
+    remote_addr: IpAddr,
+    msg: &[u8],
+) -> std::io::Result<()> {
+    let data_len = PCAP_LINK_HEADER_LEN + msg.len();
+    let captured_len = std::cmp::min(data_len, PCAP_SNAPLEN as usize);
+
+    target.write_all(&(timestamp.timestamp() as u32).to_le_bytes())?;
+    target.write_all(&timestamp.timestamp_subsec_micros().to_le_bytes())?;
+    target.write_all(&(captured_len as u32).to_le_bytes())?;
+    target.write_all(&(data_len as u32).to_le_bytes())?;
+
+    let remote_ip = match remote_addr {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    target.write_all(&[direction])?;
+    target.write_all(&remote_id.to_be_bytes())?;
+    target.write_all(&remote_ip.octets())?;
+    target.write_all(&msg[..captured_len - PCAP_LINK_HEADER_LEN])
+}
+
+/// Finds the end of the last complete record of a pcap file of the given
+/// length, so that a record that was only partially written, e.g., because
+/// the node was stopped mid-write, is not continued with unrelated data.
+#[cfg(feature = "network_dump")]
+fn find_pcap_append_point<R: Read + Seek>(source: &mut R, len: u64) -> std::io::Result<u64> {
+    let mut offset = PCAP_HEADER_LEN;
+    let mut header = [0u8; PCAP_RECORD_HEADER_LEN as usize];
+    while offset + PCAP_RECORD_HEADER_LEN <= len {
+        source.seek(SeekFrom::Start(offset))?;
+        source.read_exact(&mut header)?;
+        // safe - the slice is 4 bytes long
+        let captured_len = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let end = offset + PCAP_RECORD_HEADER_LEN + u64::from(captured_len);
+        if end > len {
+            break;
+        }
+        offset = end;
+    }
+    Ok(offset)
+}
+
+/// Opens a pcap dump file. Unless `append` is set, or the file doesn't exist
+/// yet, a fresh capture is started. Otherwise the existing capture is checked
+/// to have been written in the same format, any partially written record at
+/// its end is discarded, and a continuation marker is written.
+#[cfg(feature = "network_dump")]
+fn open_pcap_dump(path: &Path, append: bool) -> anyhow::Result<BufWriter<File>> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(!append)
+        .open(path)
+        .with_context(|| format!("Couldn't open the pcap dump {}", path.display()))?;
+    let len = file.metadata()?.len();
+    let mut expected_header = Vec::with_capacity(PCAP_HEADER_LEN as usize);
+    write_pcap_header(&mut expected_header)?;
+
+    if len == 0 {
+        file.write_all(&expected_header)?;
+        return Ok(BufWriter::new(file));
+    }
+
+    let mut header = vec![0u8; PCAP_HEADER_LEN as usize];
+    ensure!(
+        len >= PCAP_HEADER_LEN && file.read_exact(&mut header).is_ok() && header == expected_header,
+        "Refusing to append to {}, as it is not a pcap dump written by the node",
+        path.display()
+    );
+    let end = find_pcap_append_point(&mut file, len)?;
+    if end < len {
+        warn!(
+            "Discarding a partially written record ({} bytes) at the end of {}",
+            len - end,
+            path.display()
+        );
+        file.set_len(end)?;
+    }
+    file.seek(SeekFrom::Start(end))?;
+    let mut writer = BufWriter::new(file);
+    write_pcap_continuation_marker(&mut writer, Utc::now())?;
+    Ok(writer)
+}
+
+/// The index of the next file of a raw dump in the given directory, i.e., one
+/// past the highest index of the node's files already in it.
+#[cfg(feature = "network_dump")]
+fn next_raw_dump_index(dir: &Path, id: P2PNodeId) -> usize {
+    let prefix = format!("{}-", id);
+    std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            name.strip_prefix(&prefix)?.parse::<usize>().ok()
+        })
+        .max()
+        .map_or(1, |index| index + 1)
+}
+
 impl fmt::Display for DumpItem {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -53,21 +297,30 @@ impl fmt::Display for DumpItem {
 }
 
 /// Creates the thread responsible for intercepting and dumping network data.
+/// A dump is activated with its directory, whether to also dump the raw
+/// messages, their format, and whether to append to an existing dump in the
+/// directory instead of replacing it.
 #[cfg(feature = "network_dump")]
 pub fn create_dump_thread(
     ip: IpAddr,
     id: P2PNodeId,
     rx: Receiver<DumpItem>,
-    act_rx: Receiver<(std::path::PathBuf, bool)>,
+    act_rx: Receiver<(std::path::PathBuf, bool, DumpFormat, bool)>,
+    filter: DumpFilter,
     base_dir: std::path::PathBuf,
 ) {
     spawn_or_die!("network dump", move || -> anyhow::Result<()> {
         let mut dir: Option<std::path::PathBuf> = None;
         let mut pretty_dump: Option<std::fs::File> = None;
         let mut raw_dump: Option<std::fs::File> = None;
+        let mut pcap_dump: Option<BufWriter<File>> = None;
         let mut count = 0;
         loop {
-            if let Ok((new_path, raw)) = act_rx.try_recv() {
+            if let Ok((new_path, raw, format, append)) = act_rx.try_recv() {
+                // finish the capture of a previous dump
+                if let Some(mut pcap_file) = pcap_dump.take() {
+                    let _ = pcap_file.flush();
+                }
                 if new_path.components().next().is_none() {
                     info!("Dump process stopped");
                     break;
@@ -77,17 +330,25 @@ pub fn create_dump_thread(
                 let _ = std::fs::create_dir(&new_path.clone());
 
                 // Create and start pretty dump file
-                let mut pretty_dump_file = std::fs::File::create(
-                    base_dir
-                        .join(new_path.join(std::path::Path::new(&format!("{}-pretty.log", id)))),
-                )
-                .map_err(|e| {
-                    error!("Aborting dump due to error: {}", e);
-                    e
-                })?;
+                let mut pretty_dump_file = OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .append(append)
+                    .truncate(!append)
+                    .open(base_dir.join(new_path.join(Path::new(&format!("{}-pretty.log", id)))))
+                    .map_err(|e| {
+                        error!("Aborting dump due to error: {}", e);
+                        e
+                    })?;
+                let continued = pretty_dump_file.metadata()?.len() > 0;
                 pretty_dump_file
                     .write_fmt(format_args!(
-                        "Dumping started at: {}\nLocal IP is: {}\nLocal ID is: {}\n\n",
+                        "Dumping {} at: {}\nLocal IP is: {}\nLocal ID is: {}\n\n",
+                        if continued {
+                            "continued"
+                        } else {
+                            "started"
+                        },
                         Utc::now(),
                         ip,
                         id
@@ -99,24 +360,38 @@ pub fn create_dump_thread(
                 pretty_dump.replace(pretty_dump_file);
 
                 // Activate raw dump
-                if raw {
-                    count = 1;
+                if raw && format == DumpFormat::Pcap {
+                    let pcap_file = open_pcap_dump(&new_path.join(format!("{}.pcap", id)), append)
+                        .map_err(|e| {
+                            error!("Aborting dump due to error: {}", e);
+                            e
+                        })?;
+                    pcap_dump.replace(pcap_file);
+                    count = 0;
+                } else if raw {
+                    // the raw files of an appended dump continue the existing numbering
+                    count = if append {
+                        next_raw_dump_index(&new_path, id)
+                    } else {
+                        1
+                    };
                 };
                 info!("Starting dump in: {:?}", &new_path);
                 dir = Some(new_path);
             };
             if let Some(ref dir) = dir {
                 let msg = rx.recv()?;
+                if !filter.matches(&msg) {
+                    continue;
+                }
                 // Raw dump
                 if count > 0 {
                     // Create file
-                    let file = std::fs::File::create(
-                        dir.join(std::path::Path::new(&format!("{}-{}", id, count))),
-                    )
-                    .map_err(|e| {
-                        error!("Aborting dump due to error: {}", e);
-                        e
-                    })?;
+                    let file = File::create(dir.join(Path::new(&format!("{}-{}", id, count))))
+                        .map_err(|e| {
+                            error!("Aborting dump due to error: {}", e);
+                            e
+                        })?;
                     raw_dump.replace(file);
                     // Write message
                     if let Some(ref mut rd) = raw_dump {
@@ -128,6 +403,14 @@ pub fn create_dump_thread(
                     }
                 };
 
+                // Pcap dump
+                if let Some(ref mut pcap_file) = pcap_dump {
+                    write_pcap_record(pcap_file, &msg).map_err(|e| {
+                        error!("Aborting dump due to error: {}", e);
+                        e
+                    })?;
+                }
+
                 // Pretty dump
                 if let Some(ref mut pd) = pretty_dump {
                     pd.write_fmt(format_args!("{}\n\n", msg)).map_err(|e| {
@@ -141,3 +424,131 @@ pub fn create_dump_thread(
         Ok(())
     });
 }
+
+#[cfg(all(test, feature = "network_dump"))]
+mod tests {
+    use super::*;
+    use crate::network::{
+        NetworkId, NetworkPacket, NetworkPayload, PacketDestination, DEFAULT_BROADCAST_HOP_LIMIT,
+    };
+
+    fn packet_item(peer: u64, packet_type: PacketType, compressed: bool) -> DumpItem {
+        // a long, repetitive payload is only compressed if asked to
+        let mut message = vec![packet_type as u8];
+        message.resize(1024, 0);
+        let msg = NetworkMessage {
+            created:  0,
+            received: None,
+            payload:  NetworkPayload::NetworkPacket(NetworkPacket {
+                destination: PacketDestination::Broadcast(Vec::new()),
+                network_id: NetworkId::from(100),
+                message,
+                hop_limit: DEFAULT_BROADCAST_HOP_LIMIT,
+            }),
+        };
+        let mut buffer = Vec::new();
+        let threshold = if compressed {
+            Some(0)
+        } else {
+            None
+        };
+        msg.serialize_with_compression(&mut buffer, threshold).unwrap();
+        DumpItem::new(true, IpAddr::from([10, 0, 0, 1]), Some(P2PNodeId(peer)), buffer.into())
+    }
+
+    #[test]
+    fn dump_filter() {
+        let block = packet_item(1, PacketType::Block, false);
+        let transaction = packet_item(2, PacketType::Transaction, false);
+        let compressed_block = packet_item(1, PacketType::Block, true);
+        assert!(compressed_block.msg.len() < block.msg.len());
+        assert!(DumpFilter::default().matches(&block));
+
+        let by_type = DumpFilter {
+            packet_types: Some(std::iter::once(PacketType::Block).collect()),
+            peers:        None,
+        };
+        assert!(by_type.matches(&block));
+        assert!(by_type.matches(&compressed_block));
+        assert!(!by_type.matches(&transaction));
+
+        let by_peer = DumpFilter {
+            packet_types: None,
+            peers:        Some(std::iter::once(P2PNodeId(2)).collect()),
+        };
+        assert!(!by_peer.matches(&block));
+        assert!(by_peer.matches(&transaction));
+    }
+
+    #[test]
+    fn pcap_record_layout() {
+        let msg: Arc<[u8]> = Arc::from(&[1u8, 2, 3][..]);
+        let item = DumpItem::new(false, IpAddr::from([10, 0, 0, 1]), Some(P2PNodeId(7)), msg);
+        let mut record = Vec::new();
+        write_pcap_record(&mut record, &item).unwrap();
+
+        let data = &record[16..];
+        assert_eq!(&record[8..12], &(data.len() as u32).to_le_bytes());
+        assert_eq!(data.len(), PCAP_LINK_HEADER_LEN + 3);
+        assert_eq!(data[0], 1); // outbound
+        assert_eq!(&data[1..9], &7u64.to_be_bytes());
+        assert_eq!(&data[9..25], &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 10, 0, 0, 1]);
+        assert_eq!(&data[25..], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn pcap_append() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("dump.pcap");
+        let msg: Arc<[u8]> = Arc::from(&[1u8, 2, 3][..]);
+        let item = DumpItem::new(true, IpAddr::from([10, 0, 0, 1]), None, msg);
+
+        let mut dump = open_pcap_dump(&path, false)?;
+        write_pcap_record(&mut dump, &item)?;
+        drop(dump);
+        let record_len = PCAP_RECORD_HEADER_LEN as usize + PCAP_LINK_HEADER_LEN + 3;
+        let complete_len = PCAP_HEADER_LEN as usize + record_len;
+        assert_eq!(std::fs::metadata(&path)?.len() as usize, complete_len);
+
+        // simulate a record that was cut short
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        file.write_all(&[0u8; 20])?;
+        drop(file);
+
+        // the partial record is discarded and the continuation is marked
+        let mut dump = open_pcap_dump(&path, true)?;
+        write_pcap_record(&mut dump, &item)?;
+        drop(dump);
+        let contents = std::fs::read(&path)?;
+        let marker_len = PCAP_RECORD_HEADER_LEN as usize + PCAP_LINK_HEADER_LEN;
+        assert_eq!(contents.len(), complete_len + marker_len + record_len);
+        assert_eq!(
+            contents[complete_len + PCAP_RECORD_HEADER_LEN as usize],
+            PCAP_CONTINUATION_MARKER
+        );
+        let len = contents.len() as u64;
+        assert_eq!(find_pcap_append_point(&mut std::io::Cursor::new(&contents), len)?, len);
+
+        // without appending, a fresh capture is started
+        drop(open_pcap_dump(&path, false)?);
+        assert_eq!(std::fs::metadata(&path)?.len(), PCAP_HEADER_LEN);
+
+        // files that aren't pcap dumps aren't appended to
+        let other = dir.path().join("other.log");
+        std::fs::write(&other, b"not a pcap file, but long enough to have a header")?;
+        assert!(open_pcap_dump(&other, true).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn raw_dump_numbering() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let id = P2PNodeId(7);
+        assert_eq!(next_raw_dump_index(dir.path(), id), 1);
+        for name in &[format!("{}-1", id), format!("{}-12", id), format!("{}-pretty.log", id)] {
+            std::fs::write(dir.path().join(name), b"")?;
+        }
+        assert_eq!(next_raw_dump_index(dir.path(), id), 13);
+        Ok(())
+    }
+}
@@ -7,8 +7,6 @@ extern crate lazy_static;
 
 cfg_if! {
     if #[cfg(feature = "instrumentation")] {
-        #[macro_use]
-        extern crate prometheus;
         #[macro_use]
         extern crate gotham_derive;
     }
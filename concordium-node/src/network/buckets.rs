@@ -1,9 +1,12 @@
 //! Network bucket handling.
 
 use rand::seq::IteratorRandom;
+use serde::Serialize;
 use std::{
+    cmp::Ordering,
     collections::HashSet,
     hash::{Hash, Hasher},
+    net::SocketAddr,
 };
 
 use crate::{
@@ -16,10 +19,14 @@ const BUCKET_COUNT: usize = 1;
 /// A representation of a node in a bucket.
 #[derive(Eq, Clone)]
 pub struct Node {
-    pub peer:      RemotePeer,
-    pub networks:  Networks,
+    pub peer:       RemotePeer,
+    pub networks:   Networks,
     /// The timestamp pointing to when the node was seen last.
-    pub last_seen: u64,
+    pub last_seen:  u64,
+    /// The timestamp pointing to when the node was first added.
+    pub first_seen: u64,
+    /// The last measured latency of the connection to the node (in ms).
+    pub latency:    u64,
 }
 
 impl PartialEq for Node {
@@ -33,6 +40,39 @@ impl Hash for Node {
 /// A bucket of nodes.
 pub type Bucket = HashSet<Node>;
 
+/// The description of a node in a bucket that is exposed for diagnostics.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BucketEntry {
+    /// The id the node identified itself with, if known.
+    pub id:               Option<String>,
+    /// Either "Node" or "Bootstrapper".
+    pub peer_type:        String,
+    pub address:          SocketAddr,
+    pub external_address: SocketAddr,
+    pub networks:         Vec<u16>,
+    pub first_seen:       u64,
+    pub last_seen:        u64,
+    pub latency:          u64,
+}
+
+impl From<&Node> for BucketEntry {
+    fn from(node: &Node) -> Self {
+        let mut networks = node.networks.iter().map(|network| network.id).collect::<Vec<_>>();
+        networks.sort_unstable();
+        BucketEntry {
+            id: node.peer.self_id.map(|id| id.to_string()),
+            peer_type: node.peer.peer_type.to_string(),
+            address: node.peer.addr,
+            external_address: node.peer.external_addr(),
+            networks,
+            first_seen: node.first_seen,
+            last_seen: node.last_seen,
+            latency: node.latency,
+        }
+    }
+}
+
 /// The set of buckets.
 pub struct Buckets {
     pub buckets: Vec<Bucket>,
@@ -50,22 +90,89 @@ impl Buckets {
     /// Adds a peer to a bucket.
     pub fn insert_into_bucket(&mut self, peer: RemotePeer, networks: Networks) {
         let bucket = &mut self.buckets[0];
+        let now = get_current_stamp();
 
         bucket.insert(Node {
             peer,
             networks,
-            last_seen: get_current_stamp(),
+            last_seen: now,
+            first_seen: now,
+            latency: 0,
         });
     }
 
+    /// Adds a peer to a bucket holding at most `capacity` nodes. If the bucket
+    /// is full, the node with the worst `bucket_entry_score` is evicted to
+    /// make room for it, but only if the new node scores better; otherwise the
+    /// new node isn't added.
+    pub fn insert_with_scoring(
+        &mut self,
+        peer: RemotePeer,
+        networks: Networks,
+        latency: u64,
+        capacity: usize,
+    ) {
+        let bucket = &mut self.buckets[0];
+        let now = get_current_stamp();
+        let new_node = Node {
+            peer,
+            networks,
+            last_seen: now,
+            first_seen: now,
+            latency,
+        };
+
+        if !bucket.contains(&new_node) && bucket.len() >= capacity {
+            let typical_latency = typical_latency(bucket);
+            let score = |node: &Node| bucket_entry_score(node, now, typical_latency);
+            let worst = bucket
+                .iter()
+                .map(|node| (score(node), node))
+                .min_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(Ordering::Equal))
+                .map(|(worst_score, worst)| (worst_score, worst.clone()));
+            match worst {
+                Some((worst_score, worst)) if score(&new_node) > worst_score => {
+                    bucket.remove(&worst);
+                }
+                _ => return,
+            }
+        }
+
+        bucket.insert(new_node);
+    }
+
     /// Update the networks of a node in the bucket.
     pub fn update_network_ids(&mut self, peer: RemotePeer, networks: Networks) {
         let bucket = &mut self.buckets[0];
-        bucket.replace(Node {
+        let now = get_current_stamp();
+        let mut node = Node {
             peer,
             networks,
-            last_seen: get_current_stamp(),
-        });
+            last_seen: now,
+            first_seen: now,
+            latency: 0,
+        };
+        if let Some(existing) = bucket.get(&node) {
+            node.first_seen = existing.first_seen;
+            node.latency = existing.latency;
+        }
+        bucket.replace(node);
+    }
+
+    /// Record the latest latency measurement of a node in the bucket.
+    pub fn update_latency(&mut self, peer: RemotePeer, latency: u64) {
+        let bucket = &mut self.buckets[0];
+        let probe = Node {
+            peer,
+            networks: Default::default(),
+            last_seen: 0,
+            first_seen: 0,
+            latency,
+        };
+        if let Some(mut node) = bucket.take(&probe) {
+            node.latency = latency;
+            bucket.insert(node);
+        }
     }
 
     /// Returns all the nodes in buckets with the possible exception of the
@@ -75,7 +182,7 @@ impl Buckets {
         let filter_criteria = |node: &&Node| {
             node.peer.peer_type == PeerType::Node
                 && Some(node.peer.local_id) != sender
-                && (networks.is_empty() || !node.networks.is_disjoint(networks))
+                && !node.networks.is_disjoint(networks)
         };
 
         for bucket in &self.buckets {
@@ -90,6 +197,21 @@ impl Buckets {
         self.buckets.iter().flat_map(HashSet::iter).map(|node| node.networks.len()).sum()
     }
 
+    /// Describes the nodes of every bucket, bootstrappers first and otherwise
+    /// ordered by their address.
+    pub fn dump(&self) -> Vec<Vec<BucketEntry>> {
+        self.buckets
+            .iter()
+            .map(|bucket| {
+                let mut nodes = bucket.iter().collect::<Vec<_>>();
+                nodes.sort_by_key(|node| {
+                    (node.peer.peer_type != PeerType::Bootstrapper, node.peer.addr)
+                });
+                nodes.into_iter().map(BucketEntry::from).collect()
+            })
+            .collect()
+    }
+
     /// Checks whether the buckets are empty.
     pub fn is_empty(&self) -> bool { self.len() == 0 }
 
@@ -111,10 +233,34 @@ impl Buckets {
     }
 }
 
+/// The score deciding which nodes are kept in a full bucket; the higher, the
+/// better. Long-lived nodes with a low latency score best, with both factors
+/// taken logarithmically so that neither dominates the other. The latency of
+/// a node that wasn't measured yet is 0, which would be the best one, so it
+/// counts as the `typical_latency` of the bucket instead.
+pub fn bucket_entry_score(node: &Node, now: u64, typical_latency: u64) -> f64 {
+    let uptime_secs = now.saturating_sub(node.first_seen) / 1000;
+    let latency = if node.latency == 0 {
+        typical_latency
+    } else {
+        node.latency
+    };
+    (1.0 + uptime_secs as f64).ln() - (1.0 + latency as f64).ln()
+}
+
+/// The median of the measured latencies of the nodes in a bucket, or 0 if
+/// none was measured yet.
+fn typical_latency(bucket: &Bucket) -> u64 {
+    let mut latencies =
+        bucket.iter().map(|node| node.latency).filter(|&latency| latency > 0).collect::<Vec<_>>();
+    latencies.sort_unstable();
+    latencies.get(latencies.len() / 2).copied().unwrap_or(0)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::common::P2PNodeId;
+    use crate::{common::P2PNodeId, network::NetworkId};
     use rand::Rng;
     use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
@@ -146,4 +292,95 @@ mod tests {
         buckets.insert_into_bucket(p2p_duplicate_peer, Default::default());
         assert_eq!(buckets.buckets.len(), 1);
     }
+
+    fn node_peer(port: u16) -> RemotePeer {
+        RemotePeer {
+            self_id:       Some(rand::thread_rng().gen::<P2PNodeId>()),
+            addr:          SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port),
+            local_id:      RemotePeerId::from(port as usize),
+            external_port: port,
+            peer_type:     PeerType::Node,
+        }
+    }
+
+    #[test]
+    pub fn test_buckets_insert_with_scoring() {
+        let mut buckets = Buckets::default();
+        let (fast, slow, newcomer) = (node_peer(8000), node_peer(8001), node_peer(8002));
+
+        buckets.insert_with_scoring(fast, Default::default(), 10, 2);
+        buckets.insert_with_scoring(slow, Default::default(), 500, 2);
+        assert_eq!(buckets.buckets[0].len(), 2);
+
+        // the bucket is full, so the node with the highest latency is evicted
+        buckets.insert_with_scoring(newcomer, Default::default(), 10, 2);
+        let ports = |buckets: &Buckets| {
+            let mut ports =
+                buckets.buckets[0].iter().map(|node| node.peer.addr.port()).collect::<Vec<_>>();
+            ports.sort_unstable();
+            ports
+        };
+        assert_eq!(ports(&buckets), vec![8000, 8002]);
+
+        // but only for a newcomer that scores better than the worst node
+        buckets.insert_with_scoring(node_peer(8003), Default::default(), 1000, 2);
+        assert_eq!(ports(&buckets), vec![8000, 8002]);
+    }
+
+    #[test]
+    pub fn test_buckets_insert_unmeasured() {
+        let mut buckets = Buckets::default();
+        buckets.insert_with_scoring(node_peer(8000), Default::default(), 10, 2);
+        buckets.insert_with_scoring(node_peer(8001), Default::default(), 20, 2);
+
+        // a newcomer whose latency wasn't measured yet doesn't count as the fastest
+        // node
+        buckets.insert_with_scoring(node_peer(8002), Default::default(), 0, 2);
+        let ports = buckets.buckets[0].iter().map(|node| node.peer.addr.port()).collect::<Vec<_>>();
+        assert!(!ports.contains(&8002));
+    }
+
+    #[test]
+    pub fn test_buckets_dump() {
+        let mut buckets = Buckets::default();
+        let bootstrapper = RemotePeer {
+            peer_type: PeerType::Bootstrapper,
+            ..node_peer(8002)
+        };
+        let networks = [NetworkId::from(100)].iter().copied().collect();
+        buckets.insert_into_bucket(node_peer(8001), networks);
+        buckets.insert_into_bucket(bootstrapper, Default::default());
+        buckets.insert_into_bucket(node_peer(8000), Default::default());
+
+        let dump = buckets.dump();
+        assert_eq!(dump.len(), 1);
+        // bootstrappers are listed first and marked as such
+        let ports = dump[0].iter().map(|entry| entry.address.port()).collect::<Vec<_>>();
+        assert_eq!(ports, vec![8002, 8000, 8001]);
+        assert_eq!(dump[0][0].peer_type, "Bootstrapper");
+        assert_eq!(dump[0][1].peer_type, "Node");
+        assert_eq!(dump[0][2].networks, vec![100]);
+        assert!(dump[0].iter().all(|entry| entry.id.is_some()));
+    }
+
+    #[test]
+    pub fn test_bucket_entry_score() {
+        let now = get_current_stamp();
+        let node = |first_seen: u64, latency: u64| Node {
+            peer: node_peer(8000),
+            networks: Default::default(),
+            last_seen: now,
+            first_seen,
+            latency,
+        };
+
+        let score = |node: &Node| bucket_entry_score(node, now, 100);
+
+        // longer uptime and lower latency are both better
+        assert!(score(&node(now - 3_600_000, 50)) > score(&node(now, 50)));
+        assert!(score(&node(now, 10)) > score(&node(now, 500)));
+        // and an unmeasured latency counts as the typical one
+        assert_eq!(score(&node(now, 0)), score(&node(now, 100)));
+        assert!(score(&node(now, 0)) < score(&node(now, 10)));
+    }
 }
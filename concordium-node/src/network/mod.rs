@@ -26,6 +26,22 @@ pub type WireProtocolVersion = u8;
 /// flow. This value is sent in the Handshake request.
 pub const WIRE_PROTOCOL_VERSION: WireProtocolVersion = 0;
 
+/// The version of the framing of the messages on the wire: every noise message
+/// is prefixed with its length as a big-endian `u32`, and payloads larger than
+/// a single noise message are split into consecutive chunks. It is sent in the
+/// Handshake request, so that peers using a different framing are rejected
+/// before any post-handshake frame is read; peers that don't announce it use
+/// version 0.
+pub const WIRE_FRAMING_VERSION: u8 = 0;
+
+/// The hop limit of the broadcasts received from peers that don't send one.
+/// It is the default value of the field in the schema, so the two must match.
+pub const DEFAULT_BROADCAST_HOP_LIMIT: u8 = 32;
+
+/// The identifier of the flatbuffers serialization format in the handshake
+/// capabilities.
+pub const FLATBUFFERS_SERIALIZATION_FORMAT: u8 = 0;
+
 /// Identifies a network.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct NetworkId {
@@ -74,16 +90,34 @@ pub enum NetworkPayload {
     NetworkPacket(NetworkPacket),
 }
 
+/// The features supported by a node, announced in its handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The identifiers of the serialization formats the node understands.
+    pub serialization_formats: Vec<u8>,
+    /// Whether the node can decompress packet payloads.
+    pub compression:           bool,
+    /// The size of the largest message the node accepts.
+    pub max_message_size:      u32,
+    /// Whether the node id is derived from the node's static noise key.
+    pub key_derived_id:        bool,
+}
+
 /// The "high-level" network handshake.
 #[derive(Debug, PartialEq)]
 pub struct Handshake {
-    pub remote_id:      P2PNodeId,
-    pub remote_port:    u16,
-    pub networks:       Networks,
-    pub node_version:   Version,
-    pub wire_versions:  Vec<WireProtocolVersion>,
-    pub genesis_blocks: Vec<BlockHash>,
-    pub proof:          Vec<u8>,
+    pub remote_id:       P2PNodeId,
+    pub remote_port:     u16,
+    pub networks:        Networks,
+    pub node_version:    Version,
+    pub wire_versions:   Vec<WireProtocolVersion>,
+    pub genesis_blocks:  Vec<BlockHash>,
+    pub proof:           Vec<u8>,
+    /// Absent if the peer doesn't announce its capabilities, e.g. because
+    /// it runs an older version.
+    pub capabilities:    Option<Capabilities>,
+    /// The version of the peer's wire framing; see `WIRE_FRAMING_VERSION`.
+    pub framing_version: u8,
 }
 
 /// A network message serving a specified purpose.
@@ -116,6 +150,9 @@ pub struct NetworkPacket {
     pub destination: PacketDestination,
     pub network_id:  NetworkId,
     pub message:     Vec<u8>,
+    /// The number of times a broadcast may still be relayed; it is not used
+    /// by direct packets.
+    pub hop_limit:   u8,
 }
 
 /// The desired target of a network packet.
@@ -6,38 +6,86 @@ use crate::{
         p2p_peer::{P2PPeer, PeerType},
         P2PNodeId,
     },
+    configuration::PROTOCOL_MAX_MESSAGE_SIZE,
     consensus_ffi::blockchain_types::BlockHash,
     flatbuffers_shim::network,
     network::{
-        Handshake, NetworkId, NetworkMessage, NetworkPacket, NetworkPayload, NetworkRequest,
-        NetworkResponse, PacketDestination,
+        Capabilities, Handshake, NetworkId, NetworkMessage, NetworkPacket, NetworkPayload,
+        NetworkRequest, NetworkResponse, PacketDestination,
     },
 };
 use anyhow::{bail, Error};
-use flatbuffers::FlatBufferBuilder;
+use flatbuffers::{FlatBufferBuilder, InvalidFlatbuffer, VerifierOptions};
 use semver::Version;
 use std::{
+    convert::TryInto,
     io::{self, Read, Write},
     net::{IpAddr, SocketAddr},
     panic,
 };
+use thiserror::Error;
 
 /// The HANDSHAKE message version. In order to make the handshake robust, we
 /// need to version the message itself. Higher versions are assumed to append
 /// new fields at the end of the message so it should be still deserializable
 /// even if the new fields are not understood, but a warning will be emitted.
-pub const HANDSHAKE_MESSAGE_VERSION: u8 = 0;
+pub const HANDSHAKE_MESSAGE_VERSION: u8 = 2;
+
+/// The maximum number of peers serialized in a single PeerList response; any
+/// further peers are left out, which bounds the size of the message.
+pub const PEER_LIST_SIZE_LIMIT: usize = 1024;
+
+/// The zstd compression level used for packet payloads.
+const PACKET_COMPRESSION_LEVEL: i32 = 3;
+
+/// The reasons a buffer is rejected before its contents are read.
+#[derive(Debug, Error)]
+pub enum DeserializationError {
+    #[error("the buffer is too small")]
+    TooSmall,
+    #[error("the size prefix ({declared}) doesn't match the length of the buffer ({actual})")]
+    LengthMismatch {
+        declared: usize,
+        actual:   usize,
+    },
+    #[error("unrecognized protocol name")]
+    UnrecognizedIdentifier,
+    #[error("the buffer is not a valid network message: {0}")]
+    Invalid(#[from] InvalidFlatbuffer),
+    #[error("caught a panic: received a mangled buffer")]
+    Panicked,
+}
 
 impl NetworkMessage {
-    // FIXME: remove the unwind once the verifier is available
+    /// Deserialize a network message. The buffer is checked by the flatbuffers
+    /// verifier before any of its fields are accessed; the unwind guard only
+    /// remains as a last line of defence.
     pub fn deserialize(buffer: &[u8]) -> anyhow::Result<Self> {
         match panic::catch_unwind(|| _deserialize(buffer)) {
             Ok(msg) => msg,
-            Err(_) => bail!("caught a panic: received a mangled buffer"),
+            Err(_) => Err(DeserializationError::Panicked.into()),
         }
     }
 
+    /// Read the first byte of the payload of a serialized network packet
+    /// without deserializing the rest of the message. Returns `None` if the
+    /// buffer doesn't hold a well-formed packet with a non-empty payload.
+    pub fn peek_packet_payload(buffer: &[u8]) -> Option<u8> {
+        panic::catch_unwind(|| _peek_packet_payload(buffer)).ok().flatten()
+    }
+
     pub fn serialize<T: Write>(&self, target: &mut T) -> anyhow::Result<()> {
+        self.serialize_with_compression(target, None)
+    }
+
+    /// Serialize the message, compressing the payload of a network packet if
+    /// it is larger than `compression_threshold` bytes and compressing makes
+    /// it smaller. Requests and responses are never compressed.
+    pub fn serialize_with_compression<T: Write>(
+        &self,
+        target: &mut T,
+        compression_threshold: Option<usize>,
+    ) -> anyhow::Result<()> {
         let capacity = if let NetworkPayload::NetworkPacket(ref packet) = self.payload {
             packet.message.len() + 64 // FIXME: fine-tune the overhead
         } else {
@@ -46,9 +94,10 @@ impl NetworkMessage {
         let mut builder = FlatBufferBuilder::with_capacity(capacity);
 
         let (payload_type, payload_offset) = match self.payload {
-            NetworkPayload::NetworkPacket(ref packet) => {
-                (network::NetworkPayload::NetworkPacket, serialize_packet(&mut builder, packet)?)
-            }
+            NetworkPayload::NetworkPacket(ref packet) => (
+                network::NetworkPayload::NetworkPacket,
+                serialize_packet(&mut builder, packet, compression_threshold)?,
+            ),
             NetworkPayload::NetworkRequest(ref request) => {
                 (network::NetworkPayload::NetworkRequest, serialize_request(&mut builder, request)?)
             }
@@ -75,16 +124,36 @@ impl NetworkMessage {
 
 // deserialization
 
-fn _deserialize(buffer: &[u8]) -> anyhow::Result<NetworkMessage> {
+/// Check that the buffer is a well-formed, size-prefixed network message and
+/// return its root.
+fn verify(buffer: &[u8]) -> Result<network::NetworkMessage<'_>, DeserializationError> {
     if buffer.len() < 12 {
-        bail!("the buffer is too small")
+        return Err(DeserializationError::TooSmall);
+    }
+
+    // the size prefix must account for exactly the rest of the buffer; the
+    // conversion can't fail, as the buffer is long enough
+    let declared = u32::from_le_bytes(buffer[..4].try_into().unwrap()) as usize;
+    if declared != buffer.len() - 4 {
+        return Err(DeserializationError::LengthMismatch {
+            declared,
+            actual: buffer.len() - 4,
+        });
     }
 
     if !network::network_message_size_prefixed_buffer_has_identifier(buffer) {
-        bail!("unrecognized protocol name")
+        return Err(DeserializationError::UnrecognizedIdentifier);
     }
 
-    let root = network::size_prefixed_root_as_network_message(buffer)?;
+    let opts = VerifierOptions {
+        max_apparent_size: PROTOCOL_MAX_MESSAGE_SIZE as usize,
+        ..Default::default()
+    };
+    Ok(network::size_prefixed_root_as_network_message_with_opts(&opts, buffer)?)
+}
+
+fn _deserialize(buffer: &[u8]) -> anyhow::Result<NetworkMessage> {
+    let root = verify(buffer)?;
 
     let created = root.timestamp();
 
@@ -102,6 +171,23 @@ fn _deserialize(buffer: &[u8]) -> anyhow::Result<NetworkMessage> {
     })
 }
 
+fn _peek_packet_payload(buffer: &[u8]) -> Option<u8> {
+    let root = verify(buffer).ok()?;
+    if root.payload_type() != network::NetworkPayload::NetworkPacket {
+        return None;
+    }
+    let packet = network::NetworkPacket::init_from_table(root.payload()?);
+    let payload = packet.payload()?;
+    if packet.compressed() {
+        // only the first byte is decompressed
+        let mut head = [0u8; 1];
+        zstd::stream::read::Decoder::new(payload).ok()?.read_exact(&mut head).ok()?;
+        Some(head[0])
+    } else {
+        payload.first().copied()
+    }
+}
+
 fn deserialize_packet(root: &network::NetworkMessage) -> anyhow::Result<NetworkPayload> {
     let packet = if let Some(payload) = root.payload() {
         network::NetworkPacket::init_from_table(payload)
@@ -109,14 +195,15 @@ fn deserialize_packet(root: &network::NetworkMessage) -> anyhow::Result<NetworkP
         bail!("missing network message payload (expected a packet)")
     };
 
-    let destination = if let Some(destination) = packet.destination() {
-        match destination.variant() {
+    let (destination, hop_limit) = if let Some(destination) = packet.destination() {
+        let variant = match destination.variant() {
             network::Direction::Direct => {
                 PacketDestination::Direct((destination.target() as usize).into())
             }
             network::Direction::Broadcast => PacketDestination::Broadcast(Vec::new()),
             dst => bail!("Unsupported destination {:?}", dst),
-        }
+        };
+        (variant, destination.hop_limit())
     } else {
         bail!("missing direction on network packet");
     };
@@ -124,7 +211,12 @@ fn deserialize_packet(root: &network::NetworkMessage) -> anyhow::Result<NetworkP
     let network_id = NetworkId::from(packet.network_id());
 
     let payload = if let Some(payload) = packet.payload() {
-        payload.to_vec()
+        if packet.compressed() {
+            // the limit protects against payloads that decompress to huge sizes
+            zstd::bulk::decompress(payload, PROTOCOL_MAX_MESSAGE_SIZE as usize)?
+        } else {
+            payload.to_vec()
+        }
     } else {
         bail!("missing packet payload")
     };
@@ -133,6 +225,7 @@ fn deserialize_packet(root: &network::NetworkMessage) -> anyhow::Result<NetworkP
         destination,
         network_id,
         message: payload,
+        hop_limit,
     }))
 }
 
@@ -160,7 +253,7 @@ fn deserialize_request(root: &network::NetworkMessage) -> anyhow::Result<Network
         }
         network::RequestVariant::Handshake => {
             if let Some(handshake) = request.payload().map(network::Handshake::init_from_table) {
-                if handshake.version() != HANDSHAKE_MESSAGE_VERSION {
+                if handshake.version() > HANDSHAKE_MESSAGE_VERSION {
                     warn!(
                         "Received handshake version ({}) is higher than our version ({}). \
                          Attempting to parse.",
@@ -209,7 +302,20 @@ fn deserialize_request(root: &network::NetworkMessage) -> anyhow::Result<Network
                     node_version,
                     wire_versions,
                     genesis_blocks,
-                    proof: Vec::new(),
+                    // the proof is optional, as older peers don't send it
+                    proof: handshake.zk().map(|zk| zk.to_vec()).unwrap_or_default(),
+                    // and so are the capabilities
+                    capabilities: handshake.capabilities().map(|caps| Capabilities {
+                        serialization_formats: caps
+                            .serialization_formats()
+                            .map(|formats| formats.to_vec())
+                            .unwrap_or_default(),
+                        compression:           caps.compression(),
+                        max_message_size:      caps.max_message_size(),
+                        key_derived_id:        caps.key_derived_id(),
+                    }),
+                    // absent in the handshakes of older peers, which use version 0
+                    framing_version: handshake.framing_version(),
                 })))
             } else {
                 bail!("missing handshake payload")
@@ -302,28 +408,46 @@ fn deserialize_response(root: &network::NetworkMessage) -> anyhow::Result<Networ
 fn serialize_packet(
     builder: &mut FlatBufferBuilder,
     packet: &NetworkPacket,
+    compression_threshold: Option<usize>,
 ) -> io::Result<flatbuffers::WIPOffset<flatbuffers::UnionWIPOffset>> {
     let destination_offset = match packet.destination {
         PacketDestination::Direct(target_id) => {
             network::Destination::create(builder, &network::DestinationArgs {
-                variant: network::Direction::Direct,
-                target:  target_id.remote_peer_id as u64,
+                variant:   network::Direction::Direct,
+                target:    target_id.remote_peer_id as u64,
+                hop_limit: packet.hop_limit,
             })
         }
         PacketDestination::Broadcast(..) => {
             network::Destination::create(builder, &network::DestinationArgs {
-                variant: network::Direction::Broadcast,
-                target:  Default::default(),
+                variant:   network::Direction::Broadcast,
+                target:    Default::default(),
+                hop_limit: packet.hop_limit,
             })
         }
     };
 
-    let payload_offset = builder.create_vector_direct::<u8>(&packet.message);
+    let compressed_payload = match compression_threshold {
+        Some(threshold) if packet.message.len() > threshold => {
+            let compressed = zstd::bulk::compress(&packet.message, PACKET_COMPRESSION_LEVEL)?;
+            // incompressible payloads are sent as they are
+            if compressed.len() < packet.message.len() {
+                Some(compressed)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    };
+
+    let payload_offset = builder
+        .create_vector_direct::<u8>(compressed_payload.as_deref().unwrap_or(&packet.message));
 
     let packet_offset = network::NetworkPacket::create(builder, &network::NetworkPacketArgs {
         destination: Some(destination_offset),
         network_id:  packet.network_id.id,
         payload:     Some(payload_offset),
+        compressed:  compressed_payload.is_some(),
     })
     .as_union_value();
 
@@ -397,15 +521,41 @@ fn serialize_request(
             }
             let genesis_blocks_offset = Some(builder.end_vector(genesis_blocks.len()));
 
+            let zk_offset = if handshake.proof.is_empty() {
+                None
+            } else {
+                builder.start_vector::<u8>(handshake.proof.len());
+                for byte in handshake.proof.iter().rev() {
+                    builder.push(*byte);
+                }
+                Some(builder.end_vector(handshake.proof.len()))
+            };
+
+            let capabilities_offset = handshake.capabilities.as_ref().map(|caps| {
+                builder.start_vector::<u8>(caps.serialization_formats.len());
+                for format in caps.serialization_formats.iter().rev() {
+                    builder.push(*format);
+                }
+                let formats_offset = Some(builder.end_vector(caps.serialization_formats.len()));
+                network::Capabilities::create(builder, &network::CapabilitiesArgs {
+                    serialization_formats: formats_offset,
+                    compression:           caps.compression,
+                    max_message_size:      caps.max_message_size,
+                    key_derived_id:        caps.key_derived_id,
+                })
+            });
+
             let offset = network::Handshake::create(builder, &network::HandshakeArgs {
-                version:        0,
-                node_id:        handshake.remote_id.as_raw(),
-                port:           handshake.remote_port,
-                network_ids:    nets_offset,
-                node_version:   Some(node_version_offset),
-                wire_versions:  wire_version_offset,
-                genesis_blocks: genesis_blocks_offset,
-                zk:             None,
+                version:         HANDSHAKE_MESSAGE_VERSION,
+                node_id:         handshake.remote_id.as_raw(),
+                port:            handshake.remote_port,
+                network_ids:     nets_offset,
+                node_version:    Some(node_version_offset),
+                wire_versions:   wire_version_offset,
+                genesis_blocks:  genesis_blocks_offset,
+                zk:              zk_offset,
+                capabilities:    capabilities_offset,
+                framing_version: handshake.framing_version,
             });
             (
                 network::RequestVariant::Handshake,
@@ -454,8 +604,8 @@ fn serialize_response(
             (network::ResponseVariant::Pong, network::ResponsePayload::NONE, None)
         }
         NetworkResponse::PeerList(peerlist) => {
-            let mut peers = Vec::with_capacity(peerlist.len());
-            for peer in peerlist.iter() {
+            let mut peers = Vec::with_capacity(peerlist.len().min(PEER_LIST_SIZE_LIMIT));
+            for peer in peerlist.iter().take(PEER_LIST_SIZE_LIMIT) {
                 let (variant, octets) = match peer.addr.ip() {
                     IpAddr::V4(ip) => (network::IpVariant::V4, ip.octets().to_vec()),
                     IpAddr::V6(ip) => (network::IpVariant::V6, ip.octets().to_vec()),
@@ -1,9 +1,13 @@
+use rand::{thread_rng, Rng};
 use semver::Version;
 
 use crate::{
     common::{get_current_stamp, p2p_peer::P2PPeer, P2PNodeId, PeerType},
+    configuration::PROTOCOL_MAX_MESSAGE_SIZE,
     network::{
-        Handshake, NetworkId, NetworkMessage, NetworkPayload, NetworkRequest, NetworkResponse,
+        serialization::fbs::{DeserializationError, PEER_LIST_SIZE_LIMIT},
+        Capabilities, Handshake, NetworkId, NetworkMessage, NetworkPayload, NetworkRequest,
+        NetworkResponse, PacketDestination, FLATBUFFERS_SERIALIZATION_FORMAT, WIRE_FRAMING_VERSION,
     },
     test_utils::{create_random_packet, dummy_regenesis_blocks},
 };
@@ -41,13 +45,76 @@ test_s11n!(
 test_s11n!(
     s11n_req_handshake,
     NetworkPayload::NetworkRequest(NetworkRequest::Handshake(Handshake {
-        remote_id:      P2PNodeId(77),
-        remote_port:    1234,
-        networks:       [100u16, 1000, 1234, 9999].iter().copied().map(NetworkId::from).collect(),
-        node_version:   Version::parse(env!("CARGO_PKG_VERSION")).unwrap(),
-        wire_versions:  vec![0, 1, 2],
-        genesis_blocks: dummy_regenesis_blocks(),
-        proof:          Vec::new(),
+        remote_id:       P2PNodeId(77),
+        remote_port:     1234,
+        networks:        [100u16, 1000, 1234, 9999].iter().copied().map(NetworkId::from).collect(),
+        node_version:    Version::parse(env!("CARGO_PKG_VERSION")).unwrap(),
+        wire_versions:   vec![0, 1, 2],
+        genesis_blocks:  dummy_regenesis_blocks(),
+        proof:           Vec::new(),
+        capabilities:    None,
+        framing_version: WIRE_FRAMING_VERSION,
+    }))
+);
+test_s11n!(
+    s11n_req_handshake_prerelease_version,
+    NetworkPayload::NetworkRequest(NetworkRequest::Handshake(Handshake {
+        remote_id:       P2PNodeId(78),
+        remote_port:     4321,
+        networks:        [100u16].iter().copied().map(NetworkId::from).collect(),
+        node_version:    Version::parse("1.2.3-rc.1+build.5").unwrap(),
+        wire_versions:   vec![2],
+        genesis_blocks:  dummy_regenesis_blocks(),
+        proof:           Vec::new(),
+        capabilities:    None,
+        framing_version: WIRE_FRAMING_VERSION,
+    }))
+);
+test_s11n!(
+    s11n_req_handshake_with_proof,
+    NetworkPayload::NetworkRequest(NetworkRequest::Handshake(Handshake {
+        remote_id:       P2PNodeId(79),
+        remote_port:     8888,
+        networks:        [100u16].iter().copied().map(NetworkId::from).collect(),
+        node_version:    Version::parse(env!("CARGO_PKG_VERSION")).unwrap(),
+        wire_versions:   vec![0],
+        genesis_blocks:  dummy_regenesis_blocks(),
+        proof:           (0..=255u8).collect(),
+        capabilities:    None,
+        framing_version: WIRE_FRAMING_VERSION,
+    }))
+);
+test_s11n!(
+    s11n_req_handshake_with_capabilities,
+    NetworkPayload::NetworkRequest(NetworkRequest::Handshake(Handshake {
+        remote_id:       P2PNodeId(80),
+        remote_port:     8889,
+        networks:        [100u16].iter().copied().map(NetworkId::from).collect(),
+        node_version:    Version::parse(env!("CARGO_PKG_VERSION")).unwrap(),
+        wire_versions:   vec![0],
+        genesis_blocks:  dummy_regenesis_blocks(),
+        proof:           Vec::new(),
+        capabilities:    Some(Capabilities {
+            serialization_formats: vec![FLATBUFFERS_SERIALIZATION_FORMAT],
+            compression:           true,
+            max_message_size:      PROTOCOL_MAX_MESSAGE_SIZE,
+            key_derived_id:        true,
+        }),
+        framing_version: WIRE_FRAMING_VERSION,
+    }))
+);
+test_s11n!(
+    s11n_req_handshake_with_framing_version,
+    NetworkPayload::NetworkRequest(NetworkRequest::Handshake(Handshake {
+        remote_id:       P2PNodeId(81),
+        remote_port:     8890,
+        networks:        [100u16].iter().copied().map(NetworkId::from).collect(),
+        node_version:    Version::parse(env!("CARGO_PKG_VERSION")).unwrap(),
+        wire_versions:   vec![0],
+        genesis_blocks:  dummy_regenesis_blocks(),
+        proof:           Vec::new(),
+        capabilities:    None,
+        framing_version: WIRE_FRAMING_VERSION + 1,
     }))
 );
 test_s11n!(
@@ -82,6 +149,33 @@ test_s11n!(
     ))
 );
 
+#[test]
+fn s11n_oversized_peer_list() {
+    let peers = (0..PEER_LIST_SIZE_LIMIT as u64 + 10)
+        .map(|i| P2PPeer {
+            id:        P2PNodeId(i),
+            addr:      SocketAddr::new(IpAddr::from([10, 0, (i >> 8) as u8, i as u8]), 8888),
+            peer_type: PeerType::Node,
+        })
+        .collect::<Vec<_>>();
+    let msg = NetworkMessage {
+        created:  get_current_stamp(),
+        received: None,
+        payload:  NetworkPayload::NetworkResponse(NetworkResponse::PeerList(peers.clone())),
+    };
+    let mut buffer = Cursor::new(Vec::new());
+
+    msg.serialize(&mut buffer).unwrap();
+    let deserialized = NetworkMessage::deserialize(&buffer.get_ref()).unwrap();
+    // only the peers up to the limit are sent
+    assert_eq!(
+        deserialized.payload,
+        NetworkPayload::NetworkResponse(NetworkResponse::PeerList(
+            peers[..PEER_LIST_SIZE_LIMIT].to_vec()
+        ))
+    );
+}
+
 #[test]
 fn s11n_packet() {
     let msg = create_random_packet(8);
@@ -92,9 +186,105 @@ fn s11n_packet() {
     assert_eq!(deserialized.payload, msg.payload);
 }
 
+#[test]
+fn s11n_packet_hop_limit() {
+    let mut msg = create_random_packet(8);
+    if let NetworkPayload::NetworkPacket(ref mut packet) = msg.payload {
+        packet.destination = PacketDestination::Broadcast(Vec::new());
+        packet.hop_limit = 3;
+    }
+    let mut buffer = Cursor::new(Vec::new());
+
+    msg.serialize(&mut buffer).unwrap();
+    let deserialized = NetworkMessage::deserialize(&buffer.get_ref()).unwrap();
+    assert_eq!(deserialized.payload, msg.payload);
+}
+
+/// Serializes the message both without and with the given compression
+/// threshold, checks that the latter round-trips and returns the two sizes.
+fn s11n_packet_sizes(msg: &NetworkMessage, threshold: usize) -> (usize, usize) {
+    let mut plain = Vec::new();
+    msg.serialize(&mut plain).unwrap();
+
+    let mut compressed = Vec::new();
+    msg.serialize_with_compression(&mut compressed, Some(threshold)).unwrap();
+    let deserialized = NetworkMessage::deserialize(&compressed).unwrap();
+    assert_eq!(deserialized.payload, msg.payload);
+
+    (plain.len(), compressed.len())
+}
+
+#[test]
+fn s11n_compressed_packet() {
+    let msg = create_random_packet(64 * 1024);
+    let (plain, compressed) = s11n_packet_sizes(&msg, 1024);
+    assert!(compressed < plain);
+}
+
+#[test]
+fn s11n_incompressible_packet() {
+    let mut msg = create_random_packet(8);
+    if let NetworkPayload::NetworkPacket(ref mut packet) = msg.payload {
+        packet.message = (0..64 * 1024).map(|_| thread_rng().gen::<u8>()).collect();
+    }
+    let (plain, compressed) = s11n_packet_sizes(&msg, 1024);
+    assert_eq!(compressed, plain);
+}
+
+#[test]
+fn s11n_small_packet_not_compressed() {
+    let msg = create_random_packet(512);
+    let (plain, compressed) = s11n_packet_sizes(&msg, 1024);
+    assert_eq!(compressed, plain);
+}
+
+/// Checks that deserializing the buffer didn't need the unwind guard.
+fn deserialized_without_panic(buffer: &[u8]) -> bool {
+    match NetworkMessage::deserialize(buffer) {
+        Err(e) => !matches!(e.downcast_ref(), Some(DeserializationError::Panicked)),
+        Ok(_) => true,
+    }
+}
+
+#[test]
+fn s11n_truncated() {
+    let mut buffer = Vec::new();
+    create_random_packet(256).serialize(&mut buffer).unwrap();
+
+    for len in 0..buffer.len() {
+        assert!(NetworkMessage::deserialize(&buffer[..len]).is_err());
+    }
+}
+
+#[test]
+fn s11n_length_mismatch() {
+    let mut buffer = Vec::new();
+    create_random_packet(256).serialize(&mut buffer).unwrap();
+    buffer.push(0);
+
+    let err = NetworkMessage::deserialize(&buffer).unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(DeserializationError::LengthMismatch { .. })));
+}
+
+#[test]
+fn s11n_corrupted() {
+    let mut buffer = Vec::new();
+    create_random_packet(256).serialize(&mut buffer).unwrap();
+
+    let mut rng = thread_rng();
+    for _ in 0..1000 {
+        let mut corrupted = buffer.clone();
+        // keep the size prefix intact, so that the corruption reaches the verifier
+        for _ in 0..rng.gen_range(1, 8) {
+            let idx = rng.gen_range(4, corrupted.len());
+            corrupted[idx] = rng.gen();
+        }
+        assert!(deserialized_without_panic(&corrupted));
+    }
+}
+
 quickcheck! {
     fn s11n_fuzzed(bytes: Vec<u8>) -> bool {
-        let _ = NetworkMessage::deserialize(&bytes);
-        true
+        deserialized_without_panic(&bytes)
     }
 }
@@ -1,21 +1,263 @@
 //! Peer ban handling.
 
-use crate::{common::p2p_peer::RemotePeerId, connection::ConnChange, p2p::P2PNode, write_or_die};
-use anyhow::bail;
+use crate::{
+    common::{get_current_stamp, p2p_peer::RemotePeerId, PeerType},
+    connection::ConnChange,
+    p2p::{connectivity::DisconnectReason, P2PNode},
+    read_or_die, write_or_die,
+};
+use anyhow::{anyhow, bail, ensure, Context};
 use byteorder::{ReadBytesExt, WriteBytesExt};
 use crypto_common::{Buffer, Deserial, Serial};
-use rkv::{StoreOptions, Value};
-use std::net::{IpAddr, SocketAddr};
+use rkv::{
+    backend::{Lmdb, LmdbDatabase, LmdbEnvironment},
+    Manager, Rkv, SingleStore, StoreError, StoreOptions, Value,
+};
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    str::FromStr,
+    sync::{Arc, RwLock, RwLockReadGuard},
+    time::Duration,
+};
 
 const BAN_STORE_NAME: &str = "bans";
 
+/// The stored expiry of a ban that never expires.
+const PERMANENT_BAN_EXPIRY: u64 = 0;
+
+/// Check whether a ban with the given stored expiry timestamp (in ms) is no
+/// longer in force.
+fn is_ban_expired(expiry: u64, now: u64) -> bool { expiry != PERMANENT_BAN_EXPIRY && expiry <= now }
+
+/// Decode the expiry timestamp and the reason of a ban from its stored value.
+/// Bans without a reason are stored as the plain expiry; bans with one as the
+/// big-endian expiry followed by the UTF-8 encoded reason. Values of an
+/// unknown form are treated as permanent bans.
+fn decode_ban(value: &Value) -> (u64, Option<String>) {
+    match *value {
+        Value::U64(expiry) => (expiry, None),
+        Value::Blob(bytes) if bytes.len() >= 8 => {
+            let (expiry, reason) = bytes.split_at(8);
+            let expiry = u64::from_be_bytes(expiry.try_into().unwrap()); // safe - checked length
+            (expiry, Some(String::from_utf8_lossy(reason).into_owned()))
+        }
+        _ => (PERMANENT_BAN_EXPIRY, None),
+    }
+}
+
+/// A ban in force, as listed for operators.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BanInfo {
+    pub id:     PersistedBanId,
+    /// The timestamp (in ms) at which the ban expires, if it is temporary.
+    pub expiry: Option<u64>,
+    /// The reason given when the peer was banned, if any.
+    pub reason: Option<String>,
+}
+
+/// A ban as held by a `BanStore`: its expiry timestamp (in ms), which is
+/// `PERMANENT_BAN_EXPIRY` for permanent bans, and its reason, if any.
+pub type StoredBan = (u64, Option<String>);
+
+/// The storage of the bans of persisted ids. Expiry is left to the node, so
+/// the stores also hold bans that are no longer in force.
+pub trait BanStore: Send + Sync {
+    /// Store the ban of the given id, replacing any existing one.
+    fn put(&self, id: PersistedBanId, ban: StoredBan) -> anyhow::Result<()>;
+
+    /// Look up the ban of exactly the given id.
+    fn get(&self, id: PersistedBanId) -> anyhow::Result<Option<StoredBan>>;
+
+    /// Remove the bans of the given ids; ids that aren't banned are ignored.
+    fn remove(&self, ids: &[PersistedBanId]) -> anyhow::Result<()>;
+
+    /// List all the stored bans.
+    fn entries(&self) -> anyhow::Result<Vec<(PersistedBanId, StoredBan)>>;
+
+    /// Remove all the bans.
+    fn clear(&self) -> anyhow::Result<()>;
+}
+
+/// A ban store persisted to the key-value store in the node's data directory.
+pub struct KvsBanStore {
+    kvs:   Arc<RwLock<Rkv<LmdbEnvironment>>>,
+    /// The store is opened once, as creating it needs a write transaction.
+    store: SingleStore<LmdbDatabase>,
+}
+
+impl KvsBanStore {
+    /// Open the ban store in the given data directory, creating it if needed.
+    pub fn new(data_dir: &Path) -> anyhow::Result<Self> {
+        let kvs = Manager::<LmdbEnvironment>::singleton()
+            .write()
+            .unwrap()
+            .get_or_create(data_dir, Rkv::new::<Lmdb>)
+            .context("Could not create or obtain the ban database.")?;
+        let store = kvs
+            .read()
+            .map_err(|_| anyhow!("Couldn't obtain a lock over the ban database"))?
+            .open_single(BAN_STORE_NAME, StoreOptions::create())?;
+        Ok(Self {
+            kvs,
+            store,
+        })
+    }
+
+    fn env(&self) -> anyhow::Result<RwLockReadGuard<Rkv<LmdbEnvironment>>> {
+        self.kvs.read().map_err(|_| anyhow!("Couldn't obtain a lock over the ban database"))
+    }
+}
+
+fn store_key(id: PersistedBanId) -> Vec<u8> {
+    let mut store_key = Vec::new();
+    id.serial(&mut store_key);
+    store_key
+}
+
+impl BanStore for KvsBanStore {
+    fn put(&self, id: PersistedBanId, (expiry, reason): StoredBan) -> anyhow::Result<()> {
+        let env = self.env()?;
+        let mut writer = env.write()?;
+        if let Some(reason) = reason {
+            let mut value = expiry.to_be_bytes().to_vec();
+            value.extend_from_slice(reason.as_bytes());
+            self.store.put(&mut writer, store_key(id), &Value::Blob(&value))?;
+        } else {
+            self.store.put(&mut writer, store_key(id), &Value::U64(expiry))?;
+        }
+        writer.commit()?;
+        Ok(())
+    }
+
+    fn get(&self, id: PersistedBanId) -> anyhow::Result<Option<StoredBan>> {
+        let env = self.env()?;
+        let reader = env.read()?;
+        let ban = self.store.get(&reader, store_key(id))?.map(|value| decode_ban(&value));
+        Ok(ban)
+    }
+
+    fn remove(&self, ids: &[PersistedBanId]) -> anyhow::Result<()> {
+        let env = self.env()?;
+        let mut writer = env.write()?;
+        for &id in ids {
+            match self.store.delete(&mut writer, store_key(id)) {
+                // the ban might have been lifted concurrently
+                Ok(()) | Err(StoreError::KeyValuePairNotFound) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        writer.commit()?;
+        Ok(())
+    }
+
+    fn entries(&self) -> anyhow::Result<Vec<(PersistedBanId, StoredBan)>> {
+        let env = self.env()?;
+        let reader = env.read()?;
+        let mut entries = Vec::new();
+        for entry in self.store.iter_start(&reader)? {
+            let (id_bytes, value) = entry?;
+            entries.push((PersistedBanId::deserial(&mut &id_bytes[..])?, decode_ban(&value)));
+        }
+        Ok(entries)
+    }
+
+    fn clear(&self) -> anyhow::Result<()> {
+        let env = self.env()?;
+        let mut writer = env.write()?;
+        self.store.clear(&mut writer)?;
+        writer.commit()?;
+        Ok(())
+    }
+}
+
+/// A ban store held in memory only, so the bans don't outlive the node. It
+/// needs no data directory.
+#[derive(Default)]
+pub struct MemoryBanStore {
+    bans: RwLock<HashMap<PersistedBanId, StoredBan>>,
+}
+
+impl BanStore for MemoryBanStore {
+    fn put(&self, id: PersistedBanId, ban: StoredBan) -> anyhow::Result<()> {
+        write_or_die!(self.bans).insert(id, ban);
+        Ok(())
+    }
+
+    fn get(&self, id: PersistedBanId) -> anyhow::Result<Option<StoredBan>> {
+        Ok(read_or_die!(self.bans).get(&id).cloned())
+    }
+
+    fn remove(&self, ids: &[PersistedBanId]) -> anyhow::Result<()> {
+        let mut bans = write_or_die!(self.bans);
+        for id in ids {
+            bans.remove(id);
+        }
+        Ok(())
+    }
+
+    fn entries(&self) -> anyhow::Result<Vec<(PersistedBanId, StoredBan)>> {
+        Ok(read_or_die!(self.bans).iter().map(|(&id, ban)| (id, ban.clone())).collect())
+    }
+
+    fn clear(&self) -> anyhow::Result<()> {
+        write_or_die!(self.bans).clear();
+        Ok(())
+    }
+}
+
+/// Collect the subnet bans of the store along with their expiry, as cached by
+/// the node.
+pub(crate) fn cached_subnet_bans(
+    store: &dyn BanStore,
+) -> anyhow::Result<Vec<(PersistedBanId, u64)>> {
+    let entries = store.entries().context("Couldn't get the banlist")?;
+    Ok(entries
+        .into_iter()
+        .filter(|(id, _)| matches!(id, PersistedBanId::Cidr { .. }))
+        .map(|(id, (expiry, _))| (id, expiry))
+        .collect())
+}
+
+/// The maximum length of a subnet prefix for the given address family.
+fn max_prefix_len(base: IpAddr) -> u8 {
+    if base.is_ipv4() {
+        32
+    } else {
+        128
+    }
+}
+
+/// Check whether the IP address lies in the subnet with the given base address
+/// and prefix length.
+pub(crate) fn subnet_contains(base: IpAddr, prefix: u8, ip: IpAddr) -> bool {
+    let (base, ip, bits) = match (base, ip) {
+        (IpAddr::V4(base), IpAddr::V4(ip)) => {
+            (u128::from(u32::from(base)), u128::from(u32::from(ip)), 32)
+        }
+        (IpAddr::V6(base), IpAddr::V6(ip)) => (u128::from(base), u128::from(ip), 128),
+        _ => return false,
+    };
+    let prefix = u32::from(prefix).min(bits);
+    // a zero-length prefix covers every address (and shifting by 128 bits would
+    // overflow)
+    prefix == 0 || base >> (bits - prefix) == ip >> (bits - prefix)
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
-/// A node can be banned either by its IP or
-/// IP+port. This is used for soft bans only, i.e., bans with limited expiry
-/// that are not persisted to an external database.
+/// A node can be banned either by its IP, IP+port, or the subnet its IP lies
+/// in. This is used for soft bans only, i.e., bans with limited expiry that
+/// are not persisted to an external database.
 pub enum BanId {
     Ip(IpAddr),
     Socket(SocketAddr),
+    Cidr {
+        base:   IpAddr,
+        prefix: u8,
+    },
 }
 
 /// Some bans are persisted to the database so we block reconnects from those
@@ -23,12 +265,79 @@ pub enum BanId {
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum PersistedBanId {
     Ip(IpAddr),
+    /// All the addresses of a subnet, given by its base address and the length
+    /// of its prefix in bits.
+    Cidr {
+        base:   IpAddr,
+        prefix: u8,
+    },
+}
+
+impl PersistedBanId {
+    /// Create a ban of the subnet with the given base address and prefix
+    /// length.
+    pub fn cidr(base: IpAddr, prefix: u8) -> anyhow::Result<Self> {
+        ensure!(
+            prefix <= max_prefix_len(base),
+            "Invalid prefix length {} for the subnet of {}",
+            prefix,
+            base
+        );
+        Ok(PersistedBanId::Cidr {
+            base,
+            prefix,
+        })
+    }
+
+    /// Check whether the ban applies to the given IP address.
+    pub fn covers(&self, ip: IpAddr) -> bool {
+        match *self {
+            PersistedBanId::Ip(banned) => banned == ip,
+            PersistedBanId::Cidr {
+                base,
+                prefix,
+            } => subnet_contains(base, prefix, ip),
+        }
+    }
+}
+
+impl fmt::Display for PersistedBanId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PersistedBanId::Ip(ip) => write!(f, "{}", ip),
+            PersistedBanId::Cidr {
+                base,
+                prefix,
+            } => write!(f, "{}/{}", base, prefix),
+        }
+    }
+}
+
+/// Parses either a plain IP address or a subnet in CIDR notation, e.g.,
+/// `10.0.0.0/24`.
+impl FromStr for PersistedBanId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((base, prefix)) = s.split_once('/') {
+            PersistedBanId::cidr(IpAddr::from_str(base)?, u8::from_str(prefix)?)
+        } else {
+            Ok(PersistedBanId::Ip(IpAddr::from_str(s)?))
+        }
+    }
 }
 
 impl From<PersistedBanId> for BanId {
     fn from(pbid: PersistedBanId) -> Self {
         match pbid {
             PersistedBanId::Ip(ip) => Self::Ip(ip),
+            PersistedBanId::Cidr {
+                base,
+                prefix,
+            } => Self::Cidr {
+                base,
+                prefix,
+            },
         }
     }
 }
@@ -40,6 +349,14 @@ impl Serial for PersistedBanId {
                 target.write_u8(0).expect("Writing to memory is infallible.");
                 addr.serial(target);
             }
+            PersistedBanId::Cidr {
+                base,
+                prefix,
+            } => {
+                target.write_u8(1).expect("Writing to memory is infallible.");
+                base.serial(target);
+                target.write_u8(*prefix).expect("Writing to memory is infallible.");
+            }
         }
     }
 }
@@ -48,6 +365,10 @@ impl Deserial for PersistedBanId {
     fn deserial<R: ReadBytesExt>(source: &mut R) -> anyhow::Result<Self> {
         let bn = match source.read_u8()? {
             0 => Self::Ip(IpAddr::deserial(source)?),
+            1 => {
+                let base = IpAddr::deserial(source)?;
+                Self::cidr(base, source.read_u8()?)?
+            }
             _ => bail!("Unsupported type of `BanNode`"),
         };
 
@@ -67,32 +388,80 @@ impl P2PNode {
         }
     }
 
-    /// Register the node's connection to be closed and ban the IP.
-    pub fn drop_by_ip_and_ban(&self, ip_addr: IpAddr) -> anyhow::Result<bool> {
-        info!("Banning IP {}", ip_addr);
+    /// Register the node's connection to be closed and ban the IP, either
+    /// permanently or for the given duration.
+    pub fn drop_by_ip_and_ban(
+        &self,
+        ip_addr: IpAddr,
+        duration: Option<Duration>,
+    ) -> anyhow::Result<bool> {
+        self.drop_and_ban(PersistedBanId::Ip(ip_addr), duration, None)
+    }
 
-        let bid = PersistedBanId::Ip(ip_addr);
-        if let Ok(ban_kvs_env) = self.kvs.read() {
-            let mut store_key = Vec::new();
-            bid.serial(&mut store_key);
-            let ban_store = ban_kvs_env.open_single(BAN_STORE_NAME, StoreOptions::create())?;
-            let mut writer = ban_kvs_env.write()?;
-            // TODO: insert ban expiry timestamp as the Value
-            ban_store.put(&mut writer, store_key, &Value::U64(0))?;
-            writer.commit()?;
+    /// Ban a peer on an operator's request, optionally for a limited duration
+    /// and with a reason that is kept alongside the ban. Bans that would cover
+    /// the node itself, i.e. its advertised address, the addresses of its
+    /// network interfaces or the addresses it turned out to be reachable
+    /// under, are rejected. Returns whether any connections were dropped.
+    pub fn ban_peer(
+        &self,
+        bid: PersistedBanId,
+        duration: Option<Duration>,
+        reason: Option<String>,
+    ) -> anyhow::Result<bool> {
+        let mut own_ips = vec![self.self_peer.addr.ip()];
+        own_ips.extend(P2PNode::interface_addresses());
+        own_ips.extend(
+            read_or_die!(self.connection_handler.self_addresses).iter().map(|addr| addr.ip()),
+        );
+        for ip in own_ips {
+            ensure!(!bid.covers(ip), "Refusing to ban {}, as it covers this node ({})", bid, ip);
+        }
+        self.drop_and_ban(bid, duration, reason)
+    }
+
+    /// Register the connections to all the peers covered by the ban to be
+    /// closed and persist the ban, either permanently or for the given
+    /// duration, along with the reason for it, if given.
+    pub fn drop_and_ban(
+        &self,
+        bid: PersistedBanId,
+        duration: Option<Duration>,
+        reason: Option<String>,
+    ) -> anyhow::Result<bool> {
+        let expiry = if let Some(duration) = duration {
+            info!("Banning {} for {:?}", bid, duration);
+            let duration_ms = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+            // the expiry of a temporary ban must not collide with the permanent one
+            get_current_stamp().saturating_add(duration_ms).max(PERMANENT_BAN_EXPIRY + 1)
         } else {
-            bail!("Couldn't ban a peer: couldn't obtain a lock over the kvs");
+            info!("Banning {}", bid);
+            PERMANENT_BAN_EXPIRY
         };
 
-        // Remove all given addresses to that IP address.
+        self.ban_store.put(bid, (expiry, reason)).context("Couldn't ban a peer")?;
+        if let PersistedBanId::Cidr {
+            ..
+        } = bid
+        {
+            let mut subnet_bans = write_or_die!(self.subnet_bans);
+            subnet_bans.retain(|&(id, _)| id != bid);
+            subnet_bans.push((bid, expiry));
+        }
+
+        // Remove all given addresses covered by the ban.
         // This implies that after unbanning we will need to issue `ConnectTo` calls to
         // re-establish them. Removing all the given addresses is the most
         // consistent behaviour. It means that we won't repeately
         // try to reconnect to them and then failing because they are banned.
-        write_or_die!(self.config.given_addresses).retain(|addr| addr.ip() != ip_addr);
+        write_or_die!(self.config.given_addresses).retain(|addr| !bid.covers(addr.ip()));
 
-        let tokens = self.find_conn_tokens_by_ip(ip_addr);
-        let res = !tokens.is_empty();
+        let conns = self.find_conns_by_ban(bid);
+        let res = !conns.is_empty();
+        for &(_, addr) in &conns {
+            self.report_disconnect(addr, DisconnectReason::Banned);
+        }
+        let tokens = conns.into_iter().map(|(token, _)| token).collect();
         self.register_conn_change(ConnChange::RemoveAllByTokens(tokens));
         Ok(res)
     }
@@ -101,6 +470,7 @@ impl P2PNode {
         write_or_die!(self.config.given_addresses).remove(&addr);
         let maybe_token = self.find_conn_to(addr);
         if let Some(token) = maybe_token {
+            self.report_disconnect(addr, DisconnectReason::OperatorRequest);
             self.register_conn_change(ConnChange::RemovalByToken(token));
             true
         } else {
@@ -112,64 +482,158 @@ impl P2PNode {
     /// If the peer is not banned then this does nothing.
     pub fn unban_node(&self, peer: PersistedBanId) -> anyhow::Result<()> {
         info!("Unbanning node {:?}", peer);
-
-        if let Ok(ban_kvs_env) = self.kvs.read() {
-            let mut store_key = Vec::new();
-            peer.serial(&mut store_key);
-            let ban_store = ban_kvs_env.open_single(BAN_STORE_NAME, StoreOptions::create())?;
-            let mut writer = ban_kvs_env.write()?;
-            ban_store.delete(&mut writer, store_key)?;
-            writer.commit()?;
-        } else {
-            bail!("Couldn't unban a peer: couldn't obtain a lock over the kvs");
-        }
+        self.ban_store.remove(&[peer]).context("Couldn't unban a peer")?;
+        write_or_die!(self.subnet_bans).retain(|&(id, _)| id != peer);
         Ok(())
     }
 
-    /// Check whether a specified id has been banned.
+    /// Check whether a specified id has been banned, which for an IP address
+    /// includes a ban of a subnet it lies in. Expired bans are not in force;
+    /// they are removed from the store by `prune_expired_bans`.
     pub fn is_banned(&self, peer: PersistedBanId) -> anyhow::Result<bool> {
-        if let Ok(ban_kvs_env) = self.kvs.read() {
-            let ban_store = ban_kvs_env.open_single(BAN_STORE_NAME, StoreOptions::create())?;
-            let ban_reader = ban_kvs_env.read()?;
-            let mut store_key = Vec::new();
-            peer.serial(&mut store_key);
+        let now = get_current_stamp();
+        let ban = self.ban_store.get(peer).context("Couldn't check if a peer is banned")?;
+        if ban.map_or(false, |(expiry, _)| !is_ban_expired(expiry, now)) {
+            return Ok(true);
+        }
 
-            Ok(ban_store.get(&ban_reader, store_key)?.is_some())
-        } else {
-            bail!("Couldn't check if a peer is banned: read from the ban database.");
+        match peer {
+            PersistedBanId::Ip(ip) => Ok(read_or_die!(self.subnet_bans)
+                .iter()
+                .any(|&(ban, expiry)| !is_ban_expired(expiry, now) && ban.covers(ip))),
+            PersistedBanId::Cidr {
+                ..
+            } => Ok(false),
         }
     }
 
-    /// Obtain the list of banned nodes.
+    /// Remove the expired bans from the store.
+    pub fn prune_expired_bans(&self) -> anyhow::Result<()> { self.get_bans().map(|_| ()) }
+
+    /// Obtain the list of banned nodes. Expired bans are omitted and removed
+    /// from the store.
     pub fn get_banlist(&self) -> anyhow::Result<Vec<PersistedBanId>> {
-        if let Ok(ban_kvs_env) = self.kvs.read() {
-            let ban_store = ban_kvs_env.open_single(BAN_STORE_NAME, StoreOptions::create())?;
+        Ok(self.get_bans()?.into_iter().map(|ban| ban.id).collect())
+    }
 
-            let ban_reader = ban_kvs_env.read()?;
-            let ban_iter = ban_store.iter_start(&ban_reader)?;
+    /// Obtain the bans in force along with their expiry and reason. Expired
+    /// bans are omitted and removed from the store. The cached subnet bans are
+    /// refreshed along the way, so that they also pick up any changes made to
+    /// the store externally.
+    pub fn get_bans(&self) -> anyhow::Result<Vec<BanInfo>> {
+        let now = get_current_stamp();
+        let mut banlist = Vec::new();
+        let mut expired = Vec::new();
+        let mut subnet_bans = Vec::new();
+        let entries = self.ban_store.entries().context("Couldn't get the banlist")?;
+        for (id, (expiry, reason)) in entries {
+            if is_ban_expired(expiry, now) {
+                expired.push(id);
+            } else {
+                if let PersistedBanId::Cidr {
+                    ..
+                } = id
+                {
+                    subnet_bans.push((id, expiry));
+                }
+                banlist.push(BanInfo {
+                    id,
+                    expiry: Some(expiry).filter(|&e| e != PERMANENT_BAN_EXPIRY),
+                    reason,
+                });
+            }
+        }
+
+        *write_or_die!(self.subnet_bans) = subnet_bans;
 
-            let mut banlist = Vec::new();
-            for entry in ban_iter {
-                let (mut id_bytes, _expiry) = entry?;
-                let node_to_ban = PersistedBanId::deserial(&mut id_bytes)?;
-                banlist.push(node_to_ban);
+        if !expired.is_empty() {
+            debug!("Removing {} expired bans", expired.len());
+            self.ban_store.remove(&expired)?;
+        }
+
+        Ok(banlist)
+    }
+
+    /// Obtain the list of addresses that are considered unreachable, i.e.,
+    /// addresses we recently failed to connect to and will not retry until
+    /// their soft ban expires.
+    pub fn get_unreachable(&self) -> Vec<SocketAddr> {
+        read_or_die!(self.connection_handler.soft_bans)
+            .keys()
+            .filter_map(|id| match id {
+                BanId::Socket(addr) => Some(*addr),
+                BanId::Ip(_)
+                | BanId::Cidr {
+                    ..
+                } => None,
+            })
+            .collect()
+    }
+
+    /// Forget all unreachable addresses so that they can be connected to
+    /// again. Soft bans due to breaches of protocol are not affected.
+    /// Returns the addresses that were removed from the list.
+    pub fn clear_unreachable(&self) -> Vec<SocketAddr> {
+        let mut cleared = Vec::new();
+        write_or_die!(self.connection_handler.soft_bans).retain(|id, _| match id {
+            BanId::Socket(addr) => {
+                cleared.push(*addr);
+                false
             }
+            BanId::Ip(_)
+            | BanId::Cidr {
+                ..
+            } => true,
+        });
+        info!("Cleared {} unreachable addresses", cleared.len());
+        cleared
+    }
 
-            Ok(banlist)
-        } else {
-            bail!("Couldn't get the banlist: couldn't obtain a lock over the kvs");
+    /// Clear the list of unreachable addresses and schedule an immediate
+    /// connection attempt to each of them. Returns the number of scheduled
+    /// attempts.
+    pub fn retry_unreachable_now(&self) -> usize {
+        let cleared = self.clear_unreachable();
+        let given_addresses = read_or_die!(self.config.given_addresses).clone();
+        for &addr in &cleared {
+            self.register_conn_change(ConnChange::NewConn {
+                addr,
+                peer_type: PeerType::Node,
+                given: given_addresses.contains(&addr),
+            });
         }
+        cleared.len()
     }
 
     /// Lift all existing bans.
     pub fn clear_bans(&self) -> anyhow::Result<()> {
-        if let Ok(kvs_env) = self.kvs.read() {
-            let ban_store = kvs_env.open_single(BAN_STORE_NAME, StoreOptions::create())?;
-            let mut writer = kvs_env.write()?;
-            ban_store.clear(&mut writer)?;
-            writer.commit().map_err(|e| e.into())
-        } else {
-            bail!("Couldn't clear the bans: couldn't obtain a lock over the kvs");
+        self.ban_store.clear().context("Couldn't clear the bans")?;
+        write_or_die!(self.subnet_bans).clear();
+        Ok(())
+    }
+
+    /// Re-read the bans from the ban store, e.g., after it was edited
+    /// externally or restored from a backup, and enforce them straight away by
+    /// closing the connections to all the peers they cover. Returns the number
+    /// of connections that were closed as a result.
+    pub fn reload_bans(&self) -> anyhow::Result<usize> {
+        let banlist = self.get_banlist().context("Couldn't reload the bans")?;
+        info!("Reloaded {} bans from the ban store", banlist.len());
+
+        write_or_die!(self.config.given_addresses)
+            .retain(|addr| !banlist.iter().any(|bid| bid.covers(addr.ip())));
+
+        let mut conns =
+            banlist.into_iter().flat_map(|bid| self.find_conns_by_ban(bid)).collect::<Vec<_>>();
+        // a connection can be covered by several bans
+        conns.sort_unstable_by_key(|&(token, _)| token);
+        conns.dedup_by_key(|&mut (token, _)| token);
+        for &(_, addr) in &conns {
+            self.report_disconnect(addr, DisconnectReason::Banned);
         }
+        let closed = conns.len();
+        let tokens = conns.into_iter().map(|(token, _)| token).collect();
+        self.register_conn_change(ConnChange::RemoveAllByTokens(tokens));
+        Ok(closed)
     }
 }
@@ -1,30 +1,51 @@
 //! Node connection handling.
 
 use crate::{
-    common::{get_current_stamp, p2p_peer::RemotePeerId, P2PNodeId, PeerType, RemotePeer},
+    common::{
+        get_current_stamp, p2p_peer::RemotePeerId, DiscoverySource, P2PNodeId, P2PPeer, PeerStats,
+        PeerType, RemotePeer,
+    },
     configuration as config,
-    connection::{ConnChange, Connection, MessageSendingPriority},
+    connection::{
+        ConnChange, Connection, FrameAuthenticationFailed, HandshakeKey, HandshakeNonce,
+        MessageKind, MessageSendingPriority, OutputQueueFull, MAX_HANDSHAKE_PAYLOAD_LEN,
+    },
     lock_or_die, netmsg,
     network::{
-        Handshake, NetworkId, NetworkPacket, NetworkRequest, PacketDestination,
+        Capabilities, Handshake, NetworkId, NetworkPacket, NetworkRequest, PacketDestination,
+        DEFAULT_BROADCAST_HOP_LIMIT, FLATBUFFERS_SERIALIZATION_FORMAT, WIRE_FRAMING_VERSION,
         WIRE_PROTOCOL_VERSION,
     },
     p2p::{
         bans::{BanId, PersistedBanId},
-        maintenance::attempt_bootstrap,
+        geo::LocationLookup,
+        maintenance::{attempt_bootstrap, check_bootstrap_deadline},
         P2PNode,
     },
-    read_or_die, write_or_die,
+    read_or_die, utils, write_or_die,
+};
+use anyhow::{bail, ensure};
+use bytesize::ByteSize;
+use mio::{
+    event::Event,
+    net::{TcpSocket, TcpStream},
+    Events, Token,
+};
+use rand::{
+    seq::{IteratorRandom, SliceRandom},
+    Rng,
 };
-use anyhow::bail;
-use mio::{event::Event, net::TcpStream, Events, Token};
-use rand::seq::IteratorRandom;
 use rayon::iter::{IntoParallelRefMutIterator, ParallelIterator};
 use semver::Version;
 use std::{
-    io,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt, io,
     net::{IpAddr, SocketAddr},
-    sync::{atomic::Ordering, Arc},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
     time::{Duration, Instant},
 };
 use thiserror::Error;
@@ -32,6 +53,428 @@ use thiserror::Error;
 /// The poll token of the node's socket server.
 pub const SELF_TOKEN: Token = Token(0);
 
+/// The way the peers a broadcast is relayed to are chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayStrategy {
+    /// A random `relay_broadcast_percentage` of the eligible peers.
+    Percentage,
+    /// A random selection of the square root of the number of eligible peers.
+    SquareRoot,
+    /// The `relay_broadcast_percentage` of the eligible peers with the lowest
+    /// latency. Peers whose latency wasn't measured yet come last.
+    LowLatency,
+}
+
+impl FromStr for RelayStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(strategy: &str) -> Result<Self, Self::Err> {
+        match strategy {
+            "percentage" => Ok(RelayStrategy::Percentage),
+            "sqrt" => Ok(RelayStrategy::SquareRoot),
+            "low-latency" => Ok(RelayStrategy::LowLatency),
+            _ => bail!("Could not parse the relay strategy"),
+        }
+    }
+}
+
+/// A peer the node is allowed to connect to when running with an allowlist,
+/// given either by its id or by its IP address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AllowlistEntry {
+    Id(P2PNodeId),
+    Ip(IpAddr),
+}
+
+impl FromStr for AllowlistEntry {
+    type Err = anyhow::Error;
+
+    fn from_str(entry: &str) -> Result<Self, Self::Err> {
+        if let Ok(ip) = IpAddr::from_str(entry) {
+            Ok(AllowlistEntry::Ip(ip))
+        } else if let Ok(id) = P2PNodeId::from_str(entry) {
+            Ok(AllowlistEntry::Id(id))
+        } else {
+            bail!("Could not parse the allowlist entry {} as an IP address or a node id", entry)
+        }
+    }
+}
+
+/// The reason the node dropped a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The connection's latency exceeded the maximum allowed one.
+    Faulty,
+    /// Nothing was received from the peer for too long.
+    Inactive,
+    /// The peer didn't complete the handshake in time.
+    HandshakeTimeout,
+    /// The node had more peers than it allows.
+    OverCapacity,
+    /// The peer's address is banned.
+    Banned,
+    /// The peer breached the protocol.
+    ProtocolBreach,
+    /// The connection outlived the maximum connection lifetime.
+    LifetimeExpired,
+    /// The peer stopped sending in the middle of a message.
+    ReadStalled,
+    /// An operator asked for the peer to be disconnected.
+    OperatorRequest,
+    /// The peer's score dropped below the threshold.
+    Quarantined,
+}
+
+impl DisconnectReason {
+    /// The label of the reason in the metrics.
+    pub fn label(self) -> &'static str {
+        match self {
+            DisconnectReason::Faulty => "faulty",
+            DisconnectReason::Inactive => "inactive",
+            DisconnectReason::HandshakeTimeout => "handshake_timeout",
+            DisconnectReason::OverCapacity => "over_capacity",
+            DisconnectReason::Banned => "banned",
+            DisconnectReason::ProtocolBreach => "protocol_breach",
+            DisconnectReason::LifetimeExpired => "lifetime_expired",
+            DisconnectReason::ReadStalled => "read_stalled",
+            DisconnectReason::OperatorRequest => "operator_request",
+            DisconnectReason::Quarantined => "quarantined",
+        }
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            DisconnectReason::Faulty => "faulty",
+            DisconnectReason::Inactive => "inactive",
+            DisconnectReason::HandshakeTimeout => "handshake timeout",
+            DisconnectReason::OverCapacity => "over capacity",
+            DisconnectReason::Banned => "banned",
+            DisconnectReason::ProtocolBreach => "breach of protocol",
+            DisconnectReason::LifetimeExpired => "lifetime expired",
+            DisconnectReason::ReadStalled => "stalled mid-message",
+            DisconnectReason::OperatorRequest => "operator request",
+            DisconnectReason::Quarantined => "quarantined",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// Choose the peers to relay a broadcast to out of the eligible `candidates`,
+/// given as their tokens along with their latencies.
+pub(crate) fn select_relay_targets<R: Rng + ?Sized>(
+    strategy: RelayStrategy,
+    percentage: f64,
+    candidates: &[(RemotePeerId, u64)],
+    rng: &mut R,
+) -> Vec<RemotePeerId> {
+    let by_percentage = f64::floor(f64::from(candidates.len() as u32) * percentage) as usize;
+    match strategy {
+        RelayStrategy::Percentage => {
+            candidates.choose_multiple(rng, by_percentage).map(|&(token, _)| token).collect()
+        }
+        RelayStrategy::SquareRoot => {
+            let count = f64::ceil(f64::sqrt(f64::from(candidates.len() as u32))) as usize;
+            candidates.choose_multiple(rng, count).map(|&(token, _)| token).collect()
+        }
+        RelayStrategy::LowLatency => {
+            let mut candidates = candidates.to_vec();
+            // a latency of 0 means it wasn't measured yet
+            candidates.sort_by_key(|&(_, latency)| {
+                if latency == 0 {
+                    u64::MAX
+                } else {
+                    latency
+                }
+            });
+            candidates.into_iter().take(by_percentage).map(|(token, _)| token).collect()
+        }
+    }
+}
+
+/// The broadcasts relayed recently, identified by the hashes of their
+/// payloads, so that copies arriving shortly after are neither relayed nor
+/// passed to consensus again.
+pub struct RecentlyRelayed {
+    /// A random seed of the hashes, so that collisions can't be provoked.
+    seed:    u64,
+    /// The hashes along with the time until which they are kept.
+    entries: Mutex<HashMap<u64, Instant>>,
+}
+
+impl Default for RecentlyRelayed {
+    fn default() -> Self {
+        Self {
+            seed:    rand::thread_rng().gen(),
+            entries: Default::default(),
+        }
+    }
+}
+
+impl RecentlyRelayed {
+    fn hash(&self, payload: &[u8]) -> u64 {
+        use std::hash::Hasher;
+        let mut hasher = twox_hash::XxHash64::with_seed(self.seed);
+        hasher.write(payload);
+        hasher.finish()
+    }
+
+    /// Record the broadcast of the given payload as relayed for the `window`,
+    /// unless it already is. Returns whether it was recorded, i.e., whether
+    /// the broadcast should be relayed.
+    pub fn insert(&self, payload: &[u8], window: Duration, now: Instant) -> bool {
+        let hash = self.hash(payload);
+        let mut entries = lock_or_die!(self.entries);
+        if entries.get(&hash).map_or(false, |&until| until > now) {
+            false
+        } else {
+            entries.insert(hash, now + window);
+            true
+        }
+    }
+
+    /// Check whether the broadcast of the given payload was relayed recently.
+    pub fn contains(&self, payload: &[u8], now: Instant) -> bool {
+        let hash = self.hash(payload);
+        lock_or_die!(self.entries).get(&hash).map_or(false, |&until| until > now)
+    }
+
+    /// Forget the broadcasts whose window has passed.
+    pub fn prune(&self, now: Instant) {
+        lock_or_die!(self.entries).retain(|_, until| *until > now);
+    }
+}
+
+/// The nonces of the recent noise handshakes, along with the static keys of
+/// the peers that used them, so that replayed handshake messages can be
+/// rejected. All the nonces are kept for the same window, so they expire in
+/// the order they were seen; at most `capacity` of them are kept, so that a
+/// flood of handshakes can't make the set grow unboundedly.
+pub struct RecentHandshakes {
+    capacity: usize,
+    /// The nonces, along with the time until which they are kept in the order
+    /// they were seen.
+    entries: Mutex<(
+        HashSet<(HandshakeKey, HandshakeNonce)>,
+        VecDeque<((HandshakeKey, HandshakeNonce), Instant)>,
+    )>,
+}
+
+/// The reason a handshake nonce wasn't recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonceRejection {
+    /// The peer already used the nonce within the window.
+    Replayed,
+    /// The capacity is reached and none of the nonces is outside its window
+    /// yet; forgetting one would let it be replayed.
+    Full,
+}
+
+impl Default for RecentHandshakes {
+    fn default() -> Self { Self::new(config::MAX_RECENT_HANDSHAKES) }
+}
+
+impl RecentHandshakes {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Default::default(),
+        }
+    }
+
+    /// Record the nonce a peer with the given static key used in a handshake
+    /// for the `window`, unless it was already seen within it or the capacity
+    /// is reached.
+    pub fn insert(
+        &self,
+        key: HandshakeKey,
+        nonce: HandshakeNonce,
+        window: Duration,
+        now: Instant,
+    ) -> Result<(), NonceRejection> {
+        let mut entries = lock_or_die!(self.entries);
+        let (ref mut nonces, ref mut order) = *entries;
+        prune_handshake_nonces(nonces, order, now);
+        if nonces.contains(&(key, nonce)) {
+            return Err(NonceRejection::Replayed);
+        }
+        if order.len() >= self.capacity {
+            return Err(NonceRejection::Full);
+        }
+        nonces.insert((key, nonce));
+        order.push_back(((key, nonce), now + window));
+        Ok(())
+    }
+
+    /// Forget the nonces whose window has passed.
+    pub fn prune(&self, now: Instant) {
+        let mut entries = lock_or_die!(self.entries);
+        let (ref mut nonces, ref mut order) = *entries;
+        prune_handshake_nonces(nonces, order, now);
+    }
+
+    /// The number of nonces currently kept.
+    pub fn len(&self) -> usize { lock_or_die!(self.entries).1.len() }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+fn prune_handshake_nonces(
+    nonces: &mut HashSet<(HandshakeKey, HandshakeNonce)>,
+    order: &mut VecDeque<((HandshakeKey, HandshakeNonce), Instant)>,
+    now: Instant,
+) {
+    while let Some(&(entry, until)) = order.front() {
+        if until > now {
+            break;
+        }
+        order.pop_front();
+        nonces.remove(&entry);
+    }
+}
+
+/// A circuit breaker of an inbound consensus queue. Once the queue has been
+/// full for a sustained period, the peers whose messages it drops are not read
+/// from for a while, so that their messages aren't decrypted only to be
+/// discarded.
+#[derive(Default)]
+pub struct QueueBreaker {
+    /// The time since which the queue has been dropping messages, if it is
+    /// full.
+    full_since: Mutex<Option<Instant>>,
+    /// Whether any peer is paused; it keeps the checks cheap otherwise.
+    open:       AtomicBool,
+    /// The paused peers along with the time until which they are paused.
+    paused:     RwLock<HashMap<RemotePeerId, Instant>>,
+}
+
+impl QueueBreaker {
+    /// Register that the queue accepted a message, i.e., that it isn't full.
+    pub fn record_accept(&self) { *lock_or_die!(self.full_since) = None; }
+
+    /// Register that a message from `peer` was dropped because the queue was
+    /// full. If it has been full for at least the `threshold`, the peer is
+    /// paused for the `cooldown`. Returns whether this paused a peer that
+    /// wasn't paused already, i.e., whether the breaker tripped.
+    pub fn record_drop(
+        &self,
+        peer: RemotePeerId,
+        threshold: Duration,
+        cooldown: Duration,
+        now: Instant,
+    ) -> bool {
+        let full_since = *lock_or_die!(self.full_since).get_or_insert(now);
+        if now.saturating_duration_since(full_since) < threshold {
+            return false;
+        }
+        let mut paused = write_or_die!(self.paused);
+        self.open.store(true, Ordering::Relaxed);
+        paused.insert(peer, now + cooldown).map_or(true, |until| until <= now)
+    }
+
+    /// Check whether reading from the given peer is paused.
+    pub fn is_paused(&self, peer: RemotePeerId, now: Instant) -> bool {
+        self.open.load(Ordering::Relaxed)
+            && read_or_die!(self.paused).get(&peer).map_or(false, |&until| until > now)
+    }
+
+    /// Forget the pauses that ended. Returns whether any peer is still
+    /// paused, i.e., whether the breaker is open.
+    pub fn prune(&self, now: Instant) -> bool {
+        let mut paused = write_or_die!(self.paused);
+        paused.retain(|_, until| *until > now);
+        let open = !paused.is_empty();
+        self.open.store(open, Ordering::Relaxed);
+        open
+    }
+}
+
+/// The number of high priority messages of each peer that wait in the inbound
+/// consensus queue, which is used to pause reading only from the peers that
+/// fill the queue.
+#[derive(Default)]
+pub struct IngestionBacklog {
+    queued: Mutex<HashMap<RemotePeerId, usize>>,
+}
+
+impl IngestionBacklog {
+    /// Register that a message from `peer` was added to the queue.
+    pub fn record_queued(&self, peer: RemotePeerId) {
+        *lock_or_die!(self.queued).entry(peer).or_default() += 1;
+    }
+
+    /// Register that a message from `peer` was taken off the queue.
+    pub fn record_processed(&self, peer: RemotePeerId) {
+        let mut queued = lock_or_die!(self.queued);
+        if let Some(count) = queued.get_mut(&peer) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                queued.remove(&peer);
+            }
+        }
+    }
+
+    /// Check whether reading from `peer` should be paused, given the length of
+    /// the queue and the length at which reading is paused. Only the peers
+    /// that have at least an even share of the limit queued are paused, so
+    /// that a peer flooding the queue doesn't hold up the others.
+    pub fn is_paused(&self, peer: RemotePeerId, queue_len: usize, limit: usize) -> bool {
+        if queue_len < limit {
+            return false;
+        }
+        let queued = lock_or_die!(self.queued);
+        let share = (limit / queued.len().max(1)).max(1);
+        queued.get(&peer).map_or(false, |&count| count >= share)
+    }
+}
+
+/// The number of events per second, given their `count` within a window of
+/// `window` ms.
+pub(crate) fn per_second_rate(count: u64, window: u64) -> u64 {
+    if window == 0 {
+        0
+    } else {
+        count.saturating_mul(1000) / window
+    }
+}
+
+/// The number of peers established and dropped per minute, given the number of
+/// such `changes` within a window of `window` ms.
+pub(crate) fn peer_churn_rate(changes: u64, window: u64) -> u64 {
+    if window == 0 {
+        0
+    } else {
+        changes.saturating_mul(60_000) / window
+    }
+}
+
+/// Choose the connection to rotate out out of the `candidates`, given as their
+/// tokens along with their uptimes (in ms): the one that outlived the
+/// `lifetime` (in ms) by the most, if any. Only a single connection is
+/// chosen so that connections established at around the same time are
+/// closed over several housekeeping passes instead of all at once.
+pub(crate) fn select_expired_connection(
+    lifetime: u64,
+    candidates: &[(RemotePeerId, u64)],
+) -> Option<RemotePeerId> {
+    candidates
+        .iter()
+        .filter(|&&(_, uptime)| uptime >= lifetime)
+        .max_by_key(|&&(_, uptime)| uptime)
+        .map(|&(token, _)| token)
+}
+
+/// Choose the peer to connect to in place of a rotated outbound connection out
+/// of the `known` ones, which are expected in a random order: the first one
+/// whose address isn't among the `connected` ones.
+pub(crate) fn select_replacement_peer(
+    known: &[RemotePeer],
+    connected: &HashSet<SocketAddr>,
+) -> Option<P2PPeer> {
+    known.iter().filter_map(RemotePeer::peer).find(|peer| !connected.contains(&peer.addr))
+}
+
 impl P2PNode {
     /// Broadcast a request to join a network.
     /// Note that this needs a write lock on the node's connections object.
@@ -54,15 +497,16 @@ impl P2PNode {
             error!("Could not serialize a network request message: {}", e)
         } else {
             let filter = |_: &Connection| true;
-            self.send_over_all_connections(&serialized, &filter);
+            self.send_over_all_connections(&serialized, MessageKind::Network, &filter);
         }
     }
 
-    /// Send a `data` message to all connections adhering to the specified
-    /// filter. Returns the number of sent messages.
+    /// Send a `data` message of the given kind to all connections adhering to
+    /// the specified filter. Returns the number of sent messages.
     pub fn send_over_all_connections(
         &self,
         data: &[u8],
+        kind: MessageKind,
         conn_filter: &dyn Fn(&Connection) -> bool,
     ) -> usize {
         let mut sent_messages = 0usize;
@@ -70,7 +514,7 @@ impl P2PNode {
 
         for conn in write_or_die!(self.connections()).values_mut().filter(|conn| conn_filter(conn))
         {
-            conn.async_send(Arc::clone(&data), MessageSendingPriority::Normal);
+            conn.async_send(Arc::clone(&data), MessageSendingPriority::Normal, kind);
             sent_messages += 1;
         }
 
@@ -82,15 +526,42 @@ impl P2PNode {
         debug!("Measuring connection latencies");
 
         for conn in write_or_die!(self.connections()).values_mut() {
+            // keep the latencies used to score bucket entries up to date
+            if conn.remote_peer_type() == PeerType::Node {
+                write_or_die!(self.buckets()).update_latency(conn.remote_peer, conn.get_latency());
+            }
             if let Err(e) = conn.send_ping() {
                 error!("Can't send a ping to {}: {}", conn, e);
             }
         }
     }
 
-    /// Add a network to the list of node's networks.
-    pub fn add_network(&self, network_id: NetworkId) {
-        write_or_die!(self.connection_handler.networks).insert(network_id);
+    /// Add a network to the list of node's networks. Returns whether the
+    /// network wasn't on the list yet.
+    pub fn add_network(&self, network_id: NetworkId) -> bool {
+        write_or_die!(self.connection_handler.networks).insert(network_id)
+    }
+
+    /// Remove a network from the list of node's networks. Returns whether the
+    /// network was on the list.
+    pub fn remove_network(&self, network_id: NetworkId) -> bool {
+        write_or_die!(self.connection_handler.networks).remove(&network_id)
+    }
+
+    /// Join a network and announce it to all the peers. Returns whether the
+    /// node wasn't in the network yet.
+    pub fn join_network(&self, network_id: NetworkId) -> bool {
+        let joined = self.add_network(network_id);
+        self.send_join_network(network_id);
+        joined
+    }
+
+    /// Leave a network and announce it to all the peers. Returns whether the
+    /// node was in the network.
+    pub fn leave_network(&self, network_id: NetworkId) -> bool {
+        let left = self.remove_network(network_id);
+        self.send_leave_network(network_id);
+        left
     }
 
     /// Find a connection token of the connection to the given post-handshake
@@ -139,6 +610,50 @@ impl P2PNode {
             .collect()
     }
 
+    /// Find connection tokens for all connections to peers covered by the
+    /// given ban. This acquires a read lock on the node's connections and
+    /// connection_candidates objects.
+    pub fn find_conns_by_ban(&self, ban: PersistedBanId) -> Vec<(Token, SocketAddr)> {
+        lock_or_die!(self.conn_candidates())
+            .values()
+            .chain(read_or_die!(self.connections()).values())
+            .filter_map(|conn| {
+                if ban.covers(conn.remote_peer.addr.ip()) {
+                    Some((conn.token(), conn.remote_addr()))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Check whether the node may be connected to the peer with the given IP
+    /// address and, if already known, id. If the node doesn't run with an
+    /// allowlist, every peer is allowed. If the id is not known yet but the
+    /// allowlist contains ids, the peer is allowed provisionally, since it is
+    /// checked again once its handshake reveals the id.
+    pub fn is_allowed(&self, ip: IpAddr, id: Option<P2PNodeId>) -> bool {
+        let allowlist = match self.config.allowlist {
+            Some(ref allowlist) => allowlist,
+            None => return true,
+        };
+        if allowlist.contains(&AllowlistEntry::Ip(ip)) {
+            return true;
+        }
+        match id {
+            Some(id) => allowlist.contains(&AllowlistEntry::Id(id)),
+            None => allowlist.iter().any(|entry| matches!(entry, AllowlistEntry::Id(_))),
+        }
+    }
+
+    /// Report that the node dropped the connection to the given address. The
+    /// event is logged under the `peer_drop` target, so that it can be
+    /// filtered and correlated with the churn of peers.
+    pub fn report_disconnect(&self, addr: SocketAddr, reason: DisconnectReason) {
+        info!(target: "peer_drop", "Dropping the connection to {}: {}", addr, reason);
+        self.stats.peer_dropped_by_reason_inc(reason);
+    }
+
     /// Shut down connection with the given poll token.
     /// Returns the remote peer, i.e., the other end, of the just closed
     /// connection, if it exists. None is only returned if no connection
@@ -150,7 +665,7 @@ impl P2PNode {
         } else {
             // otherwise try to remove a full peer
             let removed_conn = write_or_die!(self.connections()).remove(&token)?;
-            self.bump_last_peer_update();
+            self.register_peers_dropped(1);
             Some(removed_conn.remote_peer)
         }
     }
@@ -163,19 +678,19 @@ impl P2PNode {
         let conn_candidates = &mut lock_or_die!(self.conn_candidates());
         let connections = &mut write_or_die!(self.connections());
 
-        let mut removed_peers = false;
+        let mut removed_peers = 0;
         let mut removed_candidates = false;
         for token in tokens {
             if conn_candidates.remove(&token).is_some() {
                 removed_candidates = true;
             } else if connections.remove(&token).is_some() {
-                removed_peers = true;
+                removed_peers += 1;
             }
         }
-        if removed_peers {
-            self.bump_last_peer_update();
+        if removed_peers > 0 {
+            self.register_peers_dropped(removed_peers);
         }
-        removed_candidates || removed_peers
+        removed_candidates || removed_peers > 0
     }
 
     /// Close connection to the given address, if any.
@@ -185,23 +700,46 @@ impl P2PNode {
     }
 
     fn process_network_packet(&self, inner_pkt: NetworkPacket) -> anyhow::Result<usize> {
+        if let (PacketDestination::Broadcast(_), Some(window)) =
+            (&inner_pkt.destination, self.config.relay_dampening_window)
+        {
+            let window = Duration::from_millis(window);
+            let recently_relayed = &self.connection_handler.recently_relayed;
+            if !recently_relayed.insert(&inner_pkt.message, window, Instant::now()) {
+                self.stats.relays_suppressed_inc();
+                return Ok(0);
+            }
+        }
+
         let peers_to_skip = match inner_pkt.destination {
             PacketDestination::Direct(..) => vec![],
             PacketDestination::Broadcast(ref dont_relay_to) => {
-                if self.config.relay_broadcast_percentage < 1.0 {
-                    use rand::seq::SliceRandom;
-                    let mut rng = rand::thread_rng();
-                    let mut peers = self.get_node_peer_tokens();
-                    peers.retain(|token| !dont_relay_to.contains(&token));
-                    let peers_to_take = f64::floor(
-                        f64::from(peers.len() as u32) * self.config.relay_broadcast_percentage,
-                    );
-                    peers
-                        .choose_multiple(&mut rng, peers_to_take as usize)
-                        .copied()
-                        .collect::<Vec<_>>()
-                } else {
+                if self.config.relay_strategy == RelayStrategy::Percentage
+                    && self.config.relay_broadcast_percentage >= 1.0
+                {
                     dont_relay_to.to_owned()
+                } else {
+                    let candidates = self
+                        .get_peer_stats(Some(PeerType::Node))
+                        .into_iter()
+                        .filter(|stats| !dont_relay_to.contains(&stats.local_id))
+                        .map(|stats| (stats.local_id, stats.latency))
+                        .collect::<Vec<_>>();
+                    let targets = self.with_rng(|rng| {
+                        select_relay_targets(
+                            self.config.relay_strategy,
+                            self.config.relay_broadcast_percentage,
+                            &candidates,
+                            rng,
+                        )
+                    });
+                    // skip all the peers that weren't selected
+                    candidates
+                        .into_iter()
+                        .map(|(token, _)| token)
+                        .filter(|token| !targets.contains(token))
+                        .chain(dont_relay_to.iter().copied())
+                        .collect::<Vec<_>>()
                 }
             }
         };
@@ -212,21 +750,43 @@ impl P2PNode {
             None
         };
         let network_id = inner_pkt.network_id;
+        let kind = MessageKind::of_packet(&inner_pkt.message);
 
+        let compression_threshold = self
+            .config
+            .packet_compression_threshold
+            .filter(|&threshold| inner_pkt.message.len() > threshold);
         let message = netmsg!(NetworkPacket, inner_pkt);
         let mut serialized = Vec::with_capacity(256);
         message.serialize(&mut serialized)?;
+        // only the peers that announced they can decompress payloads get the
+        // compressed variant of the packet
+        let compressed = if compression_threshold.is_some() {
+            let mut compressed = Vec::with_capacity(256);
+            message.serialize_with_compression(&mut compressed, compression_threshold)?;
+            Some(compressed)
+        } else {
+            None
+        };
+
+        let is_target = |conn: &Connection| {
+            if let Some(target_token) = target {
+                // direct messages
+                conn.remote_peer.local_id == target_token
+            } else {
+                // broadcast messages
+                is_valid_broadcast_target(conn, &peers_to_skip, network_id)
+            }
+        };
 
         let mut sent = 0;
-        if let Some(target_token) = target {
-            // direct messages
-            let filter = |conn: &Connection| conn.remote_peer.local_id == target_token;
-            sent += self.send_over_all_connections(&serialized, &filter);
+        if let Some(ref compressed) = compressed {
+            let filter = |conn: &Connection| is_target(conn) && accepts_compression(conn);
+            sent += self.send_over_all_connections(compressed, kind, &filter);
+            let filter = |conn: &Connection| is_target(conn) && !accepts_compression(conn);
+            sent += self.send_over_all_connections(&serialized, kind, &filter);
         } else {
-            // broadcast messages
-            let filter =
-                |conn: &Connection| is_valid_broadcast_target(conn, &peers_to_skip, network_id);
-            sent += self.send_over_all_connections(&serialized, &filter);
+            sent += self.send_over_all_connections(&serialized, kind, &is_target);
         }
 
         Ok(sent)
@@ -237,6 +797,7 @@ impl P2PNode {
     #[inline]
     pub fn process_network_events(&self, events: &Events) {
         let conn_stats = self.get_peer_stats(Some(PeerType::Node));
+        let max_pending_messages = AtomicUsize::new(0);
 
         lock_or_die!(self.conn_candidates())
             .par_iter_mut()
@@ -247,10 +808,61 @@ impl P2PNode {
                     conn.low_level.notify_writable();
                 }
 
-                if let Err(e) =
-                    conn.send_pending_messages().and_then(|_| conn.low_level.flush_socket())
-                {
-                    error!("[sending to {}] {}", conn, e);
+                max_pending_messages.fetch_max(conn.pending_messages.len(), Ordering::Relaxed);
+
+                let bytes_before = conn.stats.bytes_handled();
+                self.process_connection_events(conn, events, &conn_stats);
+
+                // account for the work done by the current thread of the socket pool
+                let thread_stats = rayon::current_thread_index()
+                    .and_then(|idx| self.connection_handler.socket_thread_stats.get(idx));
+                if let Some(thread_stats) = thread_stats {
+                    thread_stats.connections_processed.fetch_add(1, Ordering::Relaxed);
+                    thread_stats.bytes_handled.fetch_add(
+                        conn.stats.bytes_handled().saturating_sub(bytes_before),
+                        Ordering::Relaxed,
+                    );
+                }
+            });
+
+        self.stats.set_max_pending_messages_depth(max_pending_messages.into_inner() as i64);
+    }
+
+    /// Send queued messages to and then receive any pending messages from a
+    /// single connection, registering its removal if it failed or was closed.
+    fn process_connection_events(
+        &self,
+        conn: &mut Connection,
+        events: &Events,
+        conn_stats: &[PeerStats],
+    ) {
+        if let Err(e) = conn.send_pending_messages().and_then(|_| conn.low_level.flush_socket()) {
+            error!("[sending to {}] {}", conn, e);
+            // peers that don't read fast enough are dropped, but not soft-banned
+            if e.is::<io::Error>() || e.is::<OutputQueueFull>() {
+                self.register_conn_change(ConnChange::RemovalByToken(conn.token()));
+            } else {
+                self.register_conn_change(ConnChange::ExpulsionByToken(conn.token()));
+            }
+            return;
+        }
+
+        // sockets are polled in edge-triggered mode, so a connection whose reading
+        // was paused is read from even if there is no new readable event for it
+        if conn.read_paused
+            || events.iter().any(|event| event.token() == conn.token() && event.is_readable())
+        {
+            match conn.read_stream(conn_stats) {
+                Err(e) => {
+                    error!("[receiving from {}] {}", conn, e);
+                    if e.is::<FrameAuthenticationFailed>() {
+                        self.stats.frame_auth_failures_inc();
+                        // a spike of these points to tampering rather than benign disconnects
+                        debug!(
+                            "Frame authentication failed for {} (local id {})",
+                            conn, conn.remote_peer.local_id
+                        );
+                    }
                     if let Ok(_io_err) = e.downcast::<io::Error>() {
                         self.register_conn_change(ConnChange::RemovalByToken(conn.token()));
                     } else {
@@ -258,44 +870,74 @@ impl P2PNode {
                     }
                     return;
                 }
-
-                if events.iter().any(|event| event.token() == conn.token() && event.is_readable()) {
-                    match conn.read_stream(&conn_stats) {
-                        Err(e) => {
-                            error!("[receiving from {}] {}", conn, e);
-                            if let Ok(_io_err) = e.downcast::<io::Error>() {
-                                self.register_conn_change(ConnChange::RemovalByToken(conn.token()));
-                            } else {
-                                self.register_conn_change(ConnChange::ExpulsionByToken(
-                                    conn.token(),
-                                ));
-                            }
-                            return;
-                        }
-                        Ok(false) => {
-                            // The connection was closed by the peer.
-                            debug!("Connection to {} closed by peer", conn);
-                            self.register_conn_change(ConnChange::RemovalByToken(conn.token()));
-                            return;
-                        }
-                        Ok(true) => {}
-                    }
+                Ok(false) => {
+                    // The connection was closed by the peer.
+                    debug!("Connection to {} closed by peer", conn);
+                    self.register_conn_change(ConnChange::RemovalByToken(conn.token()));
+                    return;
                 }
+                Ok(true) => {}
+            }
+        }
 
-                let closed_or_error = |event: &Event| {
-                    event.token() == conn.token()
-                        && (event.is_read_closed() || event.is_write_closed() || event.is_error())
-                };
+        let closed_or_error = |event: &Event| {
+            event.token() == conn.token()
+                && (event.is_read_closed() || event.is_write_closed() || event.is_error())
+        };
 
-                if events.iter().any(closed_or_error) {
-                    // Generally, connections will be closed as a result of a read or write failing
-                    // or returning 0 bytes, rather than reaching here. This is more of a back stop,
-                    // and might catch a failure sooner in the case where we do not currently have
-                    // anything to write.
-                    debug!("Closing connection to {}", conn);
-                    self.register_conn_change(ConnChange::RemovalByToken(conn.token()));
-                }
+        if events.iter().any(closed_or_error) {
+            // Generally, connections will be closed as a result of a read or write failing
+            // or returning 0 bytes, rather than reaching here. This is more of a back stop,
+            // and might catch a failure sooner in the case where we do not currently have
+            // anything to write.
+            debug!("Closing connection to {}", conn);
+            self.register_conn_change(ConnChange::RemovalByToken(conn.token()));
+        }
+    }
+
+    /// Log the work done by each thread of the socket thread pool since the
+    /// last report, export the share of the bytes handled by the busiest one
+    /// and reset the counters.
+    pub fn report_socket_thread_stats(&self) {
+        let work = self
+            .connection_handler
+            .socket_thread_stats
+            .iter()
+            .map(|stats| {
+                (
+                    stats.connections_processed.swap(0, Ordering::Relaxed),
+                    stats.bytes_handled.swap(0, Ordering::Relaxed),
+                )
             })
+            .collect::<Vec<_>>();
+
+        for (idx, (conns, bytes)) in work.iter().enumerate() {
+            debug!("Socket thread {} processed {} connections and {} bytes", idx, conns, bytes);
+        }
+
+        let total_bytes = work.iter().map(|&(_, bytes)| u128::from(bytes)).sum::<u128>();
+        let max_bytes = work.iter().map(|&(_, bytes)| u128::from(bytes)).max().unwrap_or(0);
+        let share = if total_bytes > 0 {
+            max_bytes * 100 / total_bytes
+        } else {
+            0
+        };
+        self.stats.set_socket_thread_max_bytes_share(share as i64);
+    }
+
+    /// The features this node announces in its handshakes.
+    pub fn capabilities(&self) -> Capabilities {
+        let max_message_size = if self.self_peer.peer_type == PeerType::Bootstrapper {
+            self.config.bootstrapper_max_message_size
+        } else {
+            self.config.node_max_message_size
+        };
+        Capabilities {
+            serialization_formats: vec![FLATBUFFERS_SERIALIZATION_FORMAT],
+            compression: true,
+            max_message_size,
+            key_derived_id: self.config.id_from_noise_key,
+        }
     }
 
     /// Creates a "high-level" handshake request to be sent to new peers.
@@ -303,17 +945,30 @@ impl P2PNode {
         let handshake_request = netmsg!(
             NetworkRequest,
             NetworkRequest::Handshake(Handshake {
-                remote_id:      self.self_peer.id,
-                remote_port:    self.self_peer.port(),
-                networks:       read_or_die!(self.networks()).iter().copied().collect(),
-                node_version:   Version::parse(env!("CARGO_PKG_VERSION"))?,
-                wire_versions:  vec![WIRE_PROTOCOL_VERSION],
-                genesis_blocks: self.config.regenesis_arc.read().expect("").clone(),
-                proof:          vec![],
+                remote_id:       self.self_peer.id,
+                remote_port:     self.self_peer.port(),
+                networks:        read_or_die!(self.networks()).iter().copied().collect(),
+                node_version:    Version::parse(env!("CARGO_PKG_VERSION"))?,
+                wire_versions:   vec![WIRE_PROTOCOL_VERSION],
+                genesis_blocks:  self.config.regenesis_arc.read().expect("").clone(),
+                proof:           vec![],
+                capabilities:    if self.config.announce_capabilities {
+                    Some(self.capabilities())
+                } else {
+                    None
+                },
+                framing_version: WIRE_FRAMING_VERSION,
             })
         );
         let mut serialized = Vec::with_capacity(128);
         handshake_request.serialize(&mut serialized)?;
+        // peers reject handshake messages exceeding the limit before reading them
+        ensure!(
+            serialized.len() <= MAX_HANDSHAKE_PAYLOAD_LEN,
+            "The handshake request ({}B) exceeds the maximum handshake payload size ({}B)",
+            serialized.len(),
+            MAX_HANDSHAKE_PAYLOAD_LEN
+        );
 
         Ok(serialized)
     }
@@ -329,6 +984,10 @@ pub enum AcceptFailureReason {
     AlreadyConnectedToIP {
         ip: IpAddr,
     },
+    #[error("Too many connections to IP {ip}.")]
+    TooManyConnectionsFromIP {
+        ip: IpAddr,
+    },
     #[error("Duplicate connection attempt from {addr}.")]
     DuplicateConnection {
         addr: SocketAddr,
@@ -337,6 +996,14 @@ pub enum AcceptFailureReason {
     Banned,
     #[error("Connection attempt from a soft-banned address.")]
     SoftBanned,
+    #[error("Connection attempt from {ip}, which is not on the allowlist.")]
+    NotAllowed {
+        ip: IpAddr,
+    },
+    #[error("Not accepting a connection from {addr} while the memory usage is too high.")]
+    MemoryPressure {
+        addr: SocketAddr,
+    },
     #[error("{err}")]
     Other {
         #[from]
@@ -344,6 +1011,197 @@ pub enum AcceptFailureReason {
     },
 }
 
+/// The reasons an outgoing connection attempt can fail.
+#[derive(Debug, Error)]
+pub enum ConnectError {
+    #[error("Maximum number of peers reached {current}/{max}")]
+    MaxPeersReached {
+        current: u16,
+        max:     u16,
+    },
+    #[error("Refusing to connect to {addr} while shutting down")]
+    ShuttingDown {
+        addr: SocketAddr,
+    },
+    #[error("Attempted to connect to myself")]
+    SelfConnection,
+    #[error("Refusing to connect to a banned IP ({ip})")]
+    Banned {
+        ip: IpAddr,
+    },
+    #[error("Refusing to connect to a soft-banned IP ({ip})")]
+    SoftBanned {
+        ip: IpAddr,
+    },
+    #[error("Refusing to connect to {addr}, which is not on the allowlist")]
+    NotAllowed {
+        addr: SocketAddr,
+    },
+    #[error("Already connected to IP {ip}")]
+    AlreadyConnectedToIP {
+        ip: IpAddr,
+    },
+    #[error("Already connected to {addr}")]
+    AlreadyConnected {
+        addr: SocketAddr,
+    },
+    #[error("Mismatched address families of {addr} and the bind address")]
+    AddressFamilyMismatch {
+        addr: SocketAddr,
+    },
+    #[error("{err}")]
+    Unreachable {
+        addr: SocketAddr,
+        err:  io::Error,
+    },
+    #[error("{err}")]
+    Other {
+        #[from]
+        err: anyhow::Error,
+    },
+}
+
+impl ConnectError {
+    /// Whether the attempt failed because the peer is already connected.
+    pub fn is_already_connected(&self) -> bool {
+        matches!(
+            self,
+            ConnectError::AlreadyConnected { .. } | ConnectError::AlreadyConnectedToIP { .. }
+        )
+    }
+}
+
+/// A token bucket limiting the rate at which incoming connections are
+/// accepted. It is only used by the poll loop, so it needs no synchronization.
+pub struct AcceptThrottle {
+    /// The number of connections accepted per second on average.
+    rate:        f64,
+    /// The number of connections that can be accepted at once.
+    burst:       f64,
+    tokens:      f64,
+    last_refill: Instant,
+}
+
+impl AcceptThrottle {
+    pub fn new(rate: u32, burst: u32, now: Instant) -> Self {
+        AcceptThrottle {
+            rate:        f64::from(rate),
+            burst:       f64::from(burst),
+            tokens:      f64::from(burst),
+            last_refill: now,
+        }
+    }
+
+    /// Check whether a connection may be accepted at the given time, using up
+    /// a token if so.
+    pub fn try_acquire(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Accounts for the bytes written to the sockets of all the connections and,
+/// if a cap is set, keeps their rate below it. It is shared by the threads of
+/// the socket thread pool.
+///
+/// The capped rate is split evenly among the connections that have bytes
+/// waiting to be written, each of which takes from its own `SendShare`, so
+/// that the connections that happen to be flushed first can't use up the
+/// allowance of the others.
+pub struct SendThrottle {
+    /// The number of bytes that may be written per second, if capped.
+    rate:          Option<f64>,
+    /// The number of connections the rate is split among.
+    senders:       AtomicUsize,
+    bytes_written: AtomicU64,
+    /// The time and number of bytes written at the last rate sample.
+    last_sample:   Mutex<(Instant, u64)>,
+}
+
+/// The allowance of a single connection: the bytes that it may write at once,
+/// along with the time they were last replenished. Up to a second's worth of
+/// its share of the rate can be accumulated.
+pub struct SendShare {
+    available:   f64,
+    last_refill: Instant,
+}
+
+impl SendShare {
+    pub fn new(now: Instant) -> Self {
+        SendShare {
+            available:   0.0,
+            last_refill: now,
+        }
+    }
+}
+
+impl SendThrottle {
+    pub fn new(rate: Option<u64>, now: Instant) -> Self {
+        SendThrottle {
+            rate:          rate.map(|rate| rate as f64),
+            senders:       Default::default(),
+            bytes_written: Default::default(),
+            last_sample:   Mutex::new((now, 0)),
+        }
+    }
+
+    /// Register that a connection started or stopped having bytes waiting to
+    /// be written.
+    pub fn set_sending(&self, is_sending: bool) {
+        if is_sending {
+            self.senders.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.senders.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Take up to `wanted` bytes out of the allowance of a connection at the
+    /// given time, returning the number of bytes that may be written now.
+    pub fn take(&self, share: &mut SendShare, wanted: usize, now: Instant) -> usize {
+        let rate = match self.rate {
+            Some(rate) => rate / self.senders.load(Ordering::Relaxed).max(1) as f64,
+            None => return wanted,
+        };
+        let elapsed = now.saturating_duration_since(share.last_refill).as_secs_f64();
+        share.available = (share.available + elapsed * rate).min(rate);
+        share.last_refill = now;
+        let taken = share.available.floor().min(wanted as f64);
+        share.available -= taken;
+        taken as usize
+    }
+
+    /// Record the number of bytes that were written out of the ones `taken`
+    /// from the allowance of a connection, returning the rest to it.
+    pub fn record(&self, share: &mut SendShare, written: usize, taken: usize) {
+        self.bytes_written.fetch_add(written as u64, Ordering::Relaxed);
+        if self.rate.is_some() {
+            share.available += taken.saturating_sub(written) as f64;
+        }
+    }
+
+    /// The average number of bytes written per second since the previous
+    /// sample.
+    pub fn sample_rate(&self, now: Instant) -> u64 {
+        let bytes_written = self.bytes_written.load(Ordering::Relaxed);
+        let mut last_sample = lock_or_die!(self.last_sample);
+        let (last_time, last_bytes) = *last_sample;
+        *last_sample = (now, bytes_written);
+        let elapsed = now.saturating_duration_since(last_time).as_secs_f64();
+        if elapsed > 0.0 {
+            (bytes_written.saturating_sub(last_bytes) as f64 / elapsed) as u64
+        } else {
+            0
+        }
+    }
+}
+
 /// Attempt to accept an incoming network connection.
 /// - If an error occurs, e.g., fail to accept the socket connection, or fail to
 ///   register with the poll registry return Err
@@ -363,6 +1221,18 @@ pub fn accept(
         return Err(AcceptFailureReason::Banned);
     }
 
+    if !node.is_allowed(addr.ip(), None) {
+        return Err(AcceptFailureReason::NotAllowed {
+            ip: addr.ip(),
+        });
+    }
+
+    if node.connection_handler.memory_pressure.load(Ordering::Relaxed) {
+        return Err(AcceptFailureReason::MemoryPressure {
+            addr,
+        });
+    }
+
     // Lock the candidate list for added safety against duplicate connections
     let mut candidates_lock = lock_or_die!(node.conn_candidates());
 
@@ -385,8 +1255,26 @@ pub fn accept(
             });
         }
 
+        // If established connections are not dropped when over the limit we stop
+        // accepting new ones as soon as the maximum number of peers is reached.
+        if node.self_peer.peer_type == PeerType::Node
+            && node.config.no_drop_excess_peers
+            && candidates_lock.len()
+                + conn_read_lock
+                    .values()
+                    .filter(|conn| conn.remote_peer_type() == PeerType::Node)
+                    .count()
+                >= node.config.max_allowed_nodes as usize
+        {
+            return Err(AcceptFailureReason::TooManyConnections {
+                addr,
+            });
+        }
+
+        let mut conns_to_ip = 0;
         for conn in candidates_lock.values().chain(conn_read_lock.values()) {
             if conn.remote_addr().ip() == addr.ip() {
+                conns_to_ip += 1;
                 if node.config.disallow_multiple_peers_on_ip {
                     return Err(AcceptFailureReason::AlreadyConnectedToIP {
                         ip: addr.ip(),
@@ -401,6 +1289,17 @@ pub fn accept(
             }
         }
 
+        // Bootstrappers serve many short-lived connections, so the cap only applies
+        // to nodes.
+        if let Some(max_conns) = node.config.max_connections_per_ip {
+            if node.self_peer.peer_type == PeerType::Node && conns_to_ip >= max_conns as usize {
+                node.stats.conn_per_ip_rejections_inc();
+                return Err(AcceptFailureReason::TooManyConnectionsFromIP {
+                    ip: addr.ip(),
+                });
+            }
+        }
+
         if node.connection_handler.is_soft_banned(addr) {
             warn!("Connection attempt from a soft-banned IP ({}); rejecting", addr.ip());
             return Err(AcceptFailureReason::SoftBanned);
@@ -419,7 +1318,7 @@ pub fn accept(
         peer_type: PeerType::Node,
     };
 
-    let conn = Connection::new(node, socket, token, remote_peer, false)?;
+    let conn = Connection::new(node, socket, token, remote_peer, false, DiscoverySource::Incoming)?;
     candidates_lock.insert(conn.token(), conn);
 
     Ok(token)
@@ -434,7 +1333,8 @@ pub fn connect(
     peer_addr: SocketAddr,      // address to connect to
     peer_id: Option<P2PNodeId>, // id of the peer we are connecting to, if known
     respect_max_peers: bool,    // whether this should respect the maximum peeers setting or not.
-) -> anyhow::Result<()> {
+    source: DiscoverySource,    // how we learned about the peer
+) -> Result<(), ConnectError> {
     debug!(
         "Attempting to connect to {}{}",
         peer_addr,
@@ -448,27 +1348,49 @@ pub fn connect(
     if respect_max_peers && peer_type == PeerType::Node {
         let current_peer_count = node.get_peer_stats(Some(PeerType::Node)).len() as u16;
         if current_peer_count >= node.config.max_allowed_nodes {
-            bail!(
-                "Maximum number of peers reached {}/{}",
-                current_peer_count,
-                node.config.max_allowed_nodes
-            );
+            return Err(ConnectError::MaxPeersReached {
+                current: current_peer_count,
+                max:     node.config.max_allowed_nodes,
+            });
         }
     }
 
-    // Don't connect to ourselves
-    if node.self_peer.addr == peer_addr {
-        bail!("Attempted to connect to myself");
+    // Don't make new connections while shutting down
+    if node.is_draining.load(Ordering::Relaxed) {
+        return Err(ConnectError::ShuttingDown {
+            addr: peer_addr,
+        });
+    }
+
+    // Don't connect to ourselves, including via the addresses that earlier
+    // turned out to lead back to this node
+    if node.self_peer.addr == peer_addr
+        || peer_id == Some(node.self_peer.id)
+        || read_or_die!(node.connection_handler.self_addresses).contains(&peer_addr)
+    {
+        return Err(ConnectError::SelfConnection);
     }
 
     // Don't connect to banned IPs.
     if node.is_banned(PersistedBanId::Ip(peer_addr.ip())).unwrap_or(false) {
-        bail!("Refusing to connect to a banned IP ({})", peer_addr.ip());
+        return Err(ConnectError::Banned {
+            ip: peer_addr.ip(),
+        });
+    }
+
+    // Only connect to allowed peers if the node runs with an allowlist; this
+    // applies to bootstrappers as well.
+    if !node.is_allowed(peer_addr.ip(), peer_id) {
+        return Err(ConnectError::NotAllowed {
+            addr: peer_addr,
+        });
     }
 
     // Or to soft-banned nodes.
     if node.connection_handler.is_soft_banned(peer_addr) {
-        bail!("Refusing to connect to a soft-banned IP ({})", peer_addr.ip());
+        return Err(ConnectError::SoftBanned {
+            ip: peer_addr.ip(),
+        });
     }
 
     // Lock the candidate list for added safety against duplicate connections
@@ -478,14 +1400,32 @@ pub fn connect(
     for conn in read_or_die!(node.connections()).values().chain(candidates_lock.values()) {
         if node.config.disallow_multiple_peers_on_ip {
             if conn.remote_addr().ip() == peer_addr.ip() {
-                bail!("Already connected to IP {}", peer_addr.ip());
+                return Err(ConnectError::AlreadyConnectedToIP {
+                    ip: peer_addr.ip(),
+                });
             }
         } else if conn.remote_addr() == peer_addr || conn.remote_peer.external_addr() == peer_addr {
-            bail!("Already connected to {}", peer_addr);
+            return Err(ConnectError::AlreadyConnected {
+                addr: peer_addr,
+            });
+        }
+    }
+
+    // An outgoing socket bound to an address of one family can't reach a peer
+    // of the other one.
+    if let Some(bind_address) = node.config.bind_address {
+        if bind_address.is_ipv4() != peer_addr.is_ipv4() {
+            error!(
+                "Can't connect to {} from the bind address {}, as their address families differ",
+                peer_addr, bind_address
+            );
+            return Err(ConnectError::AddressFamilyMismatch {
+                addr: peer_addr,
+            });
         }
     }
 
-    match TcpStream::connect(peer_addr) {
+    match connect_socket(peer_addr, node.config.bind_address) {
         Ok(socket) => {
             trace!("Connected to {}", peer_addr);
             node.stats.conn_received_inc();
@@ -500,7 +1440,7 @@ pub fn connect(
                 peer_type,
             };
 
-            let mut conn = Connection::new(node, socket, token, remote_peer, true)?;
+            let mut conn = Connection::new(node, socket, token, remote_peer, true, source)?;
             // send the initial handshake
             conn.low_level.send_handshake_message_a()?;
             // and record the connection candidate. Note that we maintain the
@@ -512,13 +1452,112 @@ pub fn connect(
         }
         Err(e) => {
             if peer_type == PeerType::Node {
-                write_or_die!(node.connection_handler.soft_bans).insert(
-                    BanId::Socket(peer_addr),
-                    Instant::now() + Duration::from_secs(config::UNREACHABLE_EXPIRATION_SECS),
+                // the address is marked unreachable until it is due to be retried
+                let now = Instant::now();
+                let failures = {
+                    let mut reconnect_failures =
+                        lock_or_die!(node.connection_handler.reconnect_failures);
+                    let entry = reconnect_failures.entry(peer_addr).or_insert((0, now));
+                    *entry = (entry.0.saturating_add(1), now);
+                    entry.0
+                };
+                let jitter = node.with_rng(|rng| rng.gen::<f64>());
+                let backoff = reconnect_backoff(
+                    failures,
+                    node.config.reconnect_backoff_base,
+                    node.config.reconnect_backoff_max,
+                    jitter,
+                );
+                debug!(
+                    "Retrying {} in {}s ({} consecutive failures)",
+                    peer_addr,
+                    backoff.as_secs(),
+                    failures
                 );
+                write_or_die!(node.connection_handler.soft_bans)
+                    .insert(BanId::Socket(peer_addr), now + backoff);
             }
-            bail!(e)
+            Err(ConnectError::Unreachable {
+                addr: peer_addr,
+                err:  e,
+            })
+        }
+    }
+}
+
+/// The delay before an address is retried after the given number of
+/// consecutive failures to connect to it. It starts at `base` seconds and is
+/// doubled with every further failure up to `max` seconds, and the `jitter`
+/// (between 0 and 1) randomizes it by up to a half, so that addresses that
+/// failed at the same time aren't all retried at once.
+pub(crate) fn reconnect_backoff(failures: u32, base: u64, max: u64, jitter: f64) -> Duration {
+    let exponent = failures.saturating_sub(1).min(32);
+    let delay_ms = base.saturating_mul(1u64 << exponent).min(max).saturating_mul(1000);
+    let randomized_ms = (delay_ms / 2) as f64 * jitter.clamp(0.0, 1.0);
+    Duration::from_millis(delay_ms - delay_ms / 2 + randomized_ms as u64)
+}
+
+/// Open a connection to the given address, binding the socket to the given
+/// local address first, if any.
+fn connect_socket(peer_addr: SocketAddr, bind_address: Option<IpAddr>) -> io::Result<TcpStream> {
+    match bind_address {
+        Some(bind_address) => {
+            let socket = if bind_address.is_ipv4() {
+                TcpSocket::new_v4()?
+            } else {
+                TcpSocket::new_v6()?
+            };
+            socket.bind(SocketAddr::new(bind_address, 0))?;
+            socket.connect(peer_addr)
+        }
+        None => TcpStream::connect(peer_addr),
+    }
+}
+
+/// Drop the connection candidates that didn't complete the handshake within
+/// the handshake timeout. This is cheap enough to run much more often than the
+/// connection housekeeping.
+pub fn remove_stalled_handshakes(node: &P2PNode) {
+    let deadline = get_current_stamp().saturating_sub(node.config.handshake_timeout);
+    lock_or_die!(node.conn_candidates()).retain(|_, conn| {
+        if conn.stats.created < deadline {
+            node.report_disconnect(conn.remote_addr(), DisconnectReason::HandshakeTimeout);
+            node.stats.handshake_timeouts_inc();
+            false
+        } else {
+            true
+        }
+    });
+}
+
+/// Drop the connections whose peers started sending a message but sent no more
+/// of it within the read stall timeout, which guards against peers tying up
+/// connections by trickling out partial messages. Connections whose reading is
+/// paused by the node itself are exempt.
+pub fn remove_stalled_reads(node: &P2PNode) {
+    let deadline = get_current_stamp().saturating_sub(node.config.read_stall_timeout);
+    let is_stalled =
+        |conn: &Connection| !conn.read_paused && conn.low_level.is_read_stalled(deadline);
+
+    lock_or_die!(node.conn_candidates()).retain(|_, conn| {
+        if is_stalled(conn) {
+            node.report_disconnect(conn.remote_addr(), DisconnectReason::ReadStalled);
+            false
+        } else {
+            true
         }
+    });
+
+    let stalled = read_or_die!(node.connections())
+        .values()
+        .filter(|conn| is_stalled(conn))
+        .map(|conn| {
+            node.report_disconnect(conn.remote_addr(), DisconnectReason::ReadStalled);
+            conn.token()
+        })
+        .collect::<Vec<_>>();
+    if !stalled.is_empty() {
+        node.remove_connections(&stalled);
     }
 }
 
@@ -532,12 +1571,38 @@ pub fn connection_housekeeping(node: &Arc<P2PNode>) -> bool {
 
     let is_conn_faulty = |conn: &Connection| -> bool {
         if let Some(max_latency) = node.config.max_latency {
-            conn.get_latency() >= max_latency
+            conn.stats
+                .get_judged_latency(node.config.max_latency_use_p95)
+                .map_or(false, |latency| latency >= max_latency)
         } else {
             false
         }
     };
 
+    // the invalid messages since the last round count against the peers' scores,
+    // but the dropped packets don't, as they are down to this node's own queues
+    // being full; all of the bad events are logged further down
+    let invalid_messages = std::mem::take(&mut *lock_or_die!(node.bad_events.invalid_messages));
+    let dropped_high_queue = std::mem::take(&mut *lock_or_die!(node.bad_events.dropped_high_queue));
+    let dropped_low_queue = std::mem::take(&mut *lock_or_die!(node.bad_events.dropped_low_queue));
+
+    // update the score of a connection and check whether it should be quarantined
+    let is_conn_quarantined = |conn: &Connection| -> bool {
+        let id = conn.remote_peer.local_id;
+        let count = |events: &HashMap<RemotePeerId, u64>| events.get(&id).copied().unwrap_or(0);
+        let mut penalty = count(&invalid_messages).saturating_mul(config::INVALID_MESSAGE_PENALTY);
+        if conn.get_latency() > node.config.peer_score_latency {
+            penalty += config::HIGH_LATENCY_PENALTY;
+        }
+        if penalty > 0 {
+            conn.stats.penalize(penalty);
+        } else {
+            conn.stats.recover_score(node.config.peer_score_recovery);
+        }
+        let score = conn.stats.get_score();
+        node.config.peer_score_threshold.map_or(false, |threshold| score < threshold)
+    };
+
     let is_conn_inactive = |conn: &Connection| -> bool {
         (peer_type == PeerType::Node
             && conn.last_seen() + config::MAX_NORMAL_KEEP_ALIVE < curr_stamp)
@@ -550,45 +1615,150 @@ pub fn connection_housekeeping(node: &Arc<P2PNode>) -> bool {
     };
 
     // remove connections without handshakes
-    lock_or_die!(node.conn_candidates()).retain(|_, conn| !is_conn_without_handshake(&conn));
+    lock_or_die!(node.conn_candidates()).retain(|_, conn| {
+        if is_conn_without_handshake(&conn) {
+            node.report_disconnect(conn.remote_addr(), DisconnectReason::HandshakeTimeout);
+            false
+        } else {
+            true
+        }
+    });
 
-    // remove faulty and inactive connections
+    // remove faulty, quarantined and inactive connections
+    let mut quarantined = Vec::new();
     {
-        let mut faulty_removed = false;
+        let mut faulty_removed = 0;
         write_or_die!(node.connections()).retain(|_, conn| {
-            if is_conn_faulty(&conn) || is_conn_inactive(&conn) {
-                faulty_removed = true;
-                false
+            let reason = if is_conn_faulty(&conn) {
+                DisconnectReason::Faulty
+            } else if is_conn_quarantined(&conn) {
+                quarantined.push(conn.remote_addr().ip());
+                DisconnectReason::Quarantined
+            } else if is_conn_inactive(&conn) {
+                DisconnectReason::Inactive
             } else {
-                true
-            }
+                conn.stats.update_rates(curr_stamp);
+                return true;
+            };
+            node.report_disconnect(conn.remote_addr(), reason);
+            faulty_removed += 1;
+            false
         });
-        if faulty_removed {
-            node.bump_last_peer_update();
+        if faulty_removed > 0 {
+            node.register_peers_dropped(faulty_removed);
+        }
+    }
+
+    // the locations of new peers are looked up lazily, so the lookup never delays
+    // handling messages, and without holding the connections lock
+    if let Some(ref locator) = node.peer_locator {
+        let unlocated = read_or_die!(node.connections())
+            .iter()
+            .filter(|(_, conn)| conn.location == LocationLookup::NotLooked)
+            .map(|(&token, conn)| (token, conn.remote_addr().ip()))
+            .collect::<Vec<_>>();
+        let located = unlocated
+            .into_iter()
+            .map(|(token, ip)| (token, LocationLookup::from(locator.locate(ip))))
+            .collect::<Vec<_>>();
+        if !located.is_empty() {
+            let mut connections = write_or_die!(node.connections());
+            for (token, location) in located {
+                if let Some(conn) = connections.get_mut(&token) {
+                    conn.location = location;
+                }
+            }
+        }
+    }
+
+    // quarantined peers are banned for a while, so they aren't accepted again
+    // straight away
+    let quarantine_duration = Duration::from_secs(node.config.peer_quarantine_duration);
+    for ip in quarantined {
+        warn!("Quarantining {} due to a low peer score", ip);
+        let reason = Some("quarantined due to a low peer score".to_owned());
+        let ban = PersistedBanId::Ip(ip);
+        if let Err(e) = node.drop_and_ban(ban, Some(quarantine_duration), reason) {
+            error!("Couldn't quarantine {}: {}", ip, e);
         }
     }
 
     // if the number of peers exceeds the desired value, close a random selection of
-    // post-handshake non-given connections to lower it
-    if peer_type == PeerType::Node {
+    // post-handshake non-given connections to lower it, unless configured to keep
+    // them
+    if peer_type == PeerType::Node && !node.config.no_drop_excess_peers {
         let max_allowed_nodes = node.config.max_allowed_nodes;
         let peer_count = node.get_peer_stats(Some(PeerType::Node)).len() as u16;
         if peer_count > max_allowed_nodes {
             // drop connections to any non-given peers.
-            let mut rng = rand::thread_rng();
-            let to_drop = read_or_die!(node.connections())
+            let to_drop = node.with_rng(|rng| {
+                read_or_die!(node.connections())
+                    .iter()
+                    .filter_map(|(&token, conn)| {
+                        // only consider non-given connections for removal
+                        if node.is_given_connection(conn) {
+                            None
+                        } else {
+                            Some((token, conn.remote_addr()))
+                        }
+                    })
+                    .choose_multiple(rng, (peer_count - max_allowed_nodes) as usize)
+            });
+
+            for &(_, addr) in &to_drop {
+                node.report_disconnect(addr, DisconnectReason::OverCapacity);
+            }
+            let tokens = to_drop.into_iter().map(|(token, _)| token).collect::<Vec<_>>();
+            node.remove_connections(&tokens);
+        }
+    }
+
+    // rotate out the connection that outlived the maximum connection lifetime by
+    // the most; an outbound one is replaced by a connection to a fresh peer
+    // straight away, while the slot of an inbound one is left to be filled by
+    // the usual peer discovery. The only peer is never rotated.
+    if let (PeerType::Node, Some(lifetime)) = (peer_type, node.config.max_connection_lifetime) {
+        let peer_stats = node.get_peer_stats(Some(PeerType::Node));
+        let candidates = {
+            let given_addresses = read_or_die!(node.config.given_addresses);
+            peer_stats
                 .iter()
-                .filter_map(|(&token, conn)| {
-                    // only consider non-given connections for removal
-                    if node.is_given_connection(conn) {
-                        None
-                    } else {
-                        Some(token)
-                    }
+                .filter(|peer| {
+                    !given_addresses.contains(&peer.addr)
+                        && !given_addresses.contains(&peer.external_address())
                 })
-                .choose_multiple(&mut rng, (peer_count - max_allowed_nodes) as usize);
-
-            node.remove_connections(&to_drop);
+                .map(|peer| (peer.local_id, peer.uptime))
+                .collect::<Vec<_>>()
+        };
+        let peer_count = peer_stats.len();
+        let expired = select_expired_connection(lifetime.saturating_mul(1000), &candidates)
+            .and_then(|id| peer_stats.iter().find(|peer| peer.local_id == id))
+            .map(|peer| (peer.local_id, peer.addr, peer.external_address(), peer.discovery_source));
+        if let Some((id, addr, external_addr, source)) = expired.filter(|_| peer_count > 1) {
+            node.report_disconnect(addr, DisconnectReason::LifetimeExpired);
+            // don't reconnect to the same peer straight away
+            write_or_die!(node.connection_handler.soft_bans).insert(
+                BanId::Socket(external_addr),
+                Instant::now() + Duration::from_secs(config::ROTATED_PEER_EXPIRATION_SECS),
+            );
+            node.remove_connection(id.to_token());
+
+            if source != DiscoverySource::Incoming {
+                let connected = peer_stats.iter().map(PeerStats::external_address).collect();
+                let replacement = {
+                    let networks = read_or_die!(node.networks()).clone();
+                    let buckets = read_or_die!(node.buckets());
+                    let known = buckets.get_random_nodes(id, buckets.len(), &networks);
+                    select_replacement_peer(&known, &connected)
+                };
+                if let Some(peer) = replacement {
+                    debug!("Replacing the rotated connection to {} with one to {}", addr, peer);
+                    node.register_conn_change(ConnChange::NewPeers(
+                        vec![peer],
+                        DiscoverySource::PeerList,
+                    ));
+                }
+            }
         }
     }
 
@@ -601,24 +1771,107 @@ pub fn connection_housekeeping(node: &Arc<P2PNode>) -> bool {
         }
     }
 
+    // forget the failures of addresses that haven't failed for twice the maximum
+    // backoff, so that they are retried quickly again
+    {
+        let forget_after = Duration::from_secs(node.config.reconnect_backoff_max.saturating_mul(2));
+        lock_or_die!(node.connection_handler.reconnect_failures)
+            .retain(|_, (_, last_failure)| last_failure.elapsed() < forget_after);
+    }
+
+    // remove the expired bans from the ban store
+    if let Err(e) = node.prune_expired_bans() {
+        warn!("Couldn't remove the expired bans: {}", e);
+    }
+
+    // forget the nonces of handshakes that are outside the replay window
+    {
+        let now = Instant::now();
+        node.connection_handler.recent_handshakes.prune(now);
+        node.connection_handler.recently_relayed.prune(now);
+    }
+
+    // forget the queue breaker pauses that ended and export whether any is left
+    if node.config.queue_breaker_threshold.is_some() {
+        let now = Instant::now();
+        let high_open = node.connection_handler.high_queue_breaker.prune(now);
+        let low_open = node.connection_handler.low_queue_breaker.prune(now);
+        node.stats.set_queue_breaker_open((high_open || low_open) as i64);
+    }
+
+    let send_rate = node.connection_handler.send_throttle.sample_rate(Instant::now());
+    node.stats.set_send_rate(send_rate as i64);
+
+    if let Some(limit) = node.config.memory_soft_limit {
+        check_memory_pressure(node, limit);
+    }
+
+    // close the churn window, exporting how many peers were established and dropped
+    // per minute since the previous round
+    {
+        let handler = &node.connection_handler;
+        let window_start = handler.churn_window_start.swap(curr_stamp, Ordering::Relaxed);
+        let established = handler.peers_established.swap(0, Ordering::Relaxed);
+        let dropped = handler.peers_dropped.swap(0, Ordering::Relaxed);
+        let churn_rate =
+            peer_churn_rate(established + dropped, curr_stamp.saturating_sub(window_start));
+        if churn_rate > 0 {
+            debug!(
+                "Peer churn: {} established and {} dropped ({}/min)",
+                established, dropped, churn_rate
+            );
+        }
+        node.stats.set_peer_churn_rate(churn_rate as i64);
+    }
+
+    // close the ingestion window, exporting how many blocks and finalization
+    // messages consensus processed per second since the previous round
+    {
+        let handler = &node.connection_handler;
+        let window_start = handler.ingestion_window.swap(curr_stamp, Ordering::Relaxed);
+        let window = curr_stamp.saturating_sub(window_start);
+        let blocks = handler.blocks_processed.swap(0, Ordering::Relaxed);
+        let fin_msgs = handler.fin_msgs_processed.swap(0, Ordering::Relaxed);
+        node.stats.set_consensus_blocks_rate(per_second_rate(blocks, window) as i64);
+        node.stats.set_consensus_finalizations_rate(per_second_rate(fin_msgs, window) as i64);
+    }
+
+    // Re-resolve the hostnames of the given peers we are not connected to, in case
+    // their IP changed.
+    let resolve_interval = node.config.connect_to_resolve_interval;
+    if resolve_interval > 0
+        && curr_stamp
+            >= node.connection_handler.last_given_resolve.load(Ordering::Relaxed)
+                + resolve_interval * 1000
+    {
+        node.connection_handler.last_given_resolve.store(curr_stamp, Ordering::Relaxed);
+        resolve_given_hostnames(node);
+    }
+
     // Try to connect to any given addresses we are not connected to.
     for given in node.unconnected_given_addresses() {
-        if let Err(e) = connect(node, PeerType::Node, given, None, false) {
+        if let Err(e) = connect(node, PeerType::Node, given, None, false, DiscoverySource::Given) {
             warn!("Cannot establish connection to a given address {}: {}", given, e)
         }
     }
 
-    // Log all the bad events that happened and reset all their counters.
-    for (peer_id, invalid_msgs) in lock_or_die!(node.bad_events.invalid_messages).drain() {
+    // Log all the bad events that happened since the last round.
+    for (peer_id, invalid_msgs) in invalid_messages {
         warn!("Received {} invalid messages from peer {}", invalid_msgs, peer_id);
     }
-    for (peer_id, dropped) in lock_or_die!(node.bad_events.dropped_high_queue).drain() {
+    for (peer_id, dropped) in dropped_high_queue {
         warn!("Dropped {} high priority messages from peer {}.", dropped, peer_id);
     }
-    for (peer_id, dropped) in lock_or_die!(node.bad_events.dropped_low_queue).drain() {
+    for (peer_id, dropped) in dropped_low_queue {
         warn!("Dropped {} low priority messages from peer {}.", dropped, peer_id);
     }
 
+    if let Some(deadline) = node.config.bootstrap_deadline {
+        if !node.config.no_bootstrap_dns && peer_type == PeerType::Node {
+            check_bootstrap_deadline(node, deadline);
+        }
+    }
+
     // Reconnect to bootstrappers after a specified amount of time.
     // It's unclear whether we should always be doing this, even if we have enough
     // peers. But the current logic is to try to bootstrap again, and if we have
@@ -626,6 +1879,7 @@ pub fn connection_housekeeping(node: &Arc<P2PNode>) -> bool {
     if !node.config.no_bootstrap_dns
         && peer_type == PeerType::Node
         && curr_stamp >= node.get_last_bootstrap() + node.config.bootstrapping_interval * 1000
+        && curr_stamp >= node.connection_handler.next_bootstrap.load(Ordering::Relaxed)
     {
         attempt_bootstrap(node);
         true
@@ -634,6 +1888,89 @@ pub fn connection_housekeeping(node: &Arc<P2PNode>) -> bool {
     }
 }
 
+/// Compare the resident memory of the node with the soft limit (in MiB) and
+/// shed load while it is exceeded: no new connections are accepted and the
+/// transactions received from peers are dropped until the usage recovers, and
+/// the queued low priority messages are dropped on every round.
+fn check_memory_pressure(node: &P2PNode, limit: u64) {
+    let usage = match utils::resident_memory() {
+        Some(usage) => usage,
+        None => return,
+    };
+    let limit = limit.saturating_mul(1024 * 1024);
+    let is_exceeded = usage > limit;
+    let was_exceeded = node.connection_handler.memory_pressure.swap(is_exceeded, Ordering::Relaxed);
+
+    if !is_exceeded {
+        if was_exceeded {
+            info!(
+                "The memory usage ({}) is back under the soft limit ({}); no longer shedding load",
+                ByteSize(usage).to_string_as(true),
+                ByteSize(limit).to_string_as(true)
+            );
+        }
+        return;
+    }
+
+    if !was_exceeded {
+        warn!(
+            "The memory usage ({}) exceeds the soft limit ({}); not accepting new connections and \
+             dropping the transactions received from peers",
+            ByteSize(usage).to_string_as(true),
+            ByteSize(limit).to_string_as(true)
+        );
+        node.stats.memory_shedding_inc();
+    }
+
+    let dropped = write_or_die!(node.connections())
+        .values_mut()
+        .map(|conn| conn.pending_messages.drop_low_priority())
+        .sum::<usize>();
+    if dropped > 0 {
+        warn!("Dropped {} queued low priority messages due to the memory usage", dropped);
+        node.stats.memory_shedding_inc();
+    }
+}
+
+/// Resolve the hostnames of the given peers again. If none of the addresses a
+/// hostname resolved to before is connected and it now resolves to different
+/// ones, they replace the stale ones among the given addresses, so that the
+/// node reconnects to the peer at its new address.
+fn resolve_given_hostnames(node: &P2PNode) {
+    let unconnected = node.unconnected_given_addresses();
+    let mut given_hostnames = write_or_die!(node.config.given_hostnames);
+    for (hostname, addrs) in given_hostnames.iter_mut() {
+        // addresses that are no longer given, e.g. after an operator disconnected
+        // the peer, are left alone
+        if !addrs.iter().all(|addr| unconnected.contains(addr)) {
+            continue;
+        }
+        let resolved = match utils::parse_host_port(
+            hostname,
+            &node.config.dns_resolvers,
+            node.config.dns_options,
+        ) {
+            Ok(resolved) => resolved.into_iter().collect::<HashSet<_>>(),
+            Err(e) => {
+                warn!("Can't resolve the given peer {} again: {}", hostname, e);
+                continue;
+            }
+        };
+        if resolved.is_empty() || resolved == *addrs {
+            continue;
+        }
+        info!("The given peer {} moved from {:?} to {:?}", hostname, addrs, resolved);
+        {
+            let mut given_addresses = write_or_die!(node.config.given_addresses);
+            for addr in addrs.iter() {
+                given_addresses.remove(addr);
+            }
+            given_addresses.extend(resolved.iter().copied());
+        }
+        *addrs = resolved;
+    }
+}
+
 /// A connection is applicable for a broadcast if it is not in the exclusion
 /// list, belongs to the same network, and doesn't belong to a bootstrapper.
 fn is_valid_broadcast_target(
@@ -646,6 +1983,12 @@ fn is_valid_broadcast_target(
         && conn.remote_end_networks.contains(&network_id)
 }
 
+/// Whether the peer on the other end of the connection announced in its
+/// handshake that it can decompress packet payloads.
+fn accepts_compression(conn: &Connection) -> bool {
+    conn.remote_capabilities.as_ref().map_or(false, |capabilities| capabilities.compression)
+}
+
 /// Send a direct packet with `msg` contents to the specified peer.
 #[inline]
 pub fn send_direct_message(
@@ -654,7 +1997,14 @@ pub fn send_direct_message(
     network_id: NetworkId,
     msg: Arc<[u8]>,
 ) -> usize {
-    send_message_over_network(node, Some(target_id), vec![], network_id, msg)
+    send_message_over_network(
+        node,
+        Some(target_id),
+        vec![],
+        network_id,
+        msg,
+        DEFAULT_BROADCAST_HOP_LIMIT,
+    )
 }
 
 /// Send a broadcast packet with `msg` contents to the specified peer.
@@ -665,7 +2015,37 @@ pub fn send_broadcast_message(
     network_id: NetworkId,
     msg: Arc<[u8]>,
 ) -> usize {
-    send_message_over_network(node, None, dont_relay_to, network_id, msg)
+    let hop_limit = node.config.broadcast_hop_limit;
+    send_message_over_network(node, None, dont_relay_to, network_id, msg, hop_limit)
+}
+
+/// Relay a broadcast packet received from a peer, which may still be relayed
+/// `hop_limit` times.
+#[inline]
+pub fn relay_broadcast_message(
+    node: &P2PNode,
+    dont_relay_to: Vec<RemotePeerId>,
+    network_id: NetworkId,
+    msg: Arc<[u8]>,
+    hop_limit: u8,
+) -> usize {
+    match relayed_hop_limit(hop_limit, node.config.broadcast_hop_limit) {
+        Some(hop_limit) => {
+            send_message_over_network(node, None, dont_relay_to, network_id, msg, hop_limit)
+        }
+        None => {
+            trace!("Not relaying a broadcast that reached its hop limit");
+            node.stats.relays_suppressed_inc();
+            0
+        }
+    }
+}
+
+/// The hop limit a received broadcast is relayed with, if it is relayed at
+/// all. Every relay uses up one of its hops, and a peer can't make a broadcast
+/// travel further than the node's own broadcasts by sending a larger limit.
+pub fn relayed_hop_limit(received: u8, max_hop_limit: u8) -> Option<u8> {
+    received.min(max_hop_limit).checked_sub(1)
 }
 
 #[inline]
@@ -675,6 +2055,7 @@ fn send_message_over_network(
     dont_relay_to: Vec<RemotePeerId>,
     network_id: NetworkId,
     message: Arc<[u8]>,
+    hop_limit: u8,
 ) -> usize {
     let destination = if let Some(target_id) = target_id {
         PacketDestination::Direct(target_id)
@@ -689,6 +2070,7 @@ fn send_message_over_network(
         destination,
         network_id,
         message,
+        hop_limit,
     };
 
     if let Ok(sent_packets) = node.process_network_packet(packet) {
@@ -0,0 +1,130 @@
+//! Annotation of peers with their geographic location and autonomous system.
+
+use crate::p2p::bans::subnet_contains;
+use anyhow::{bail, ensure, Context};
+use std::{net::IpAddr, path::Path, str::FromStr};
+
+/// The location of a peer as far as it can be derived from its IP address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerLocation {
+    /// The region, e.g., the country code, the address is assigned to.
+    pub region: String,
+    /// The number of the autonomous system announcing the address, if known.
+    pub asn:    Option<u32>,
+}
+
+/// The state of the lookup of a peer's location.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LocationLookup {
+    /// The location hasn't been looked up yet.
+    NotLooked,
+    /// The lookup didn't find the peer's address.
+    Unknown,
+    /// The lookup found the peer's location.
+    Known(PeerLocation),
+}
+
+impl LocationLookup {
+    /// The location of the peer, if it was found.
+    pub fn location(&self) -> Option<&PeerLocation> {
+        match self {
+            LocationLookup::Known(location) => Some(location),
+            _ => None,
+        }
+    }
+}
+
+impl From<Option<PeerLocation>> for LocationLookup {
+    fn from(location: Option<PeerLocation>) -> Self {
+        location.map_or(LocationLookup::Unknown, LocationLookup::Known)
+    }
+}
+
+/// A lookup of the location of peers by their IP address. Lookups are done by
+/// the connection housekeeping, so they must not block for long.
+pub trait PeerLocator: Send + Sync {
+    /// Find the location of the given address, if it is known.
+    fn locate(&self, ip: IpAddr) -> Option<PeerLocation>;
+}
+
+/// A peer locator backed by a list of subnets, which is loaded from a file
+/// with one subnet per line in the form `<subnet>,<region>[,<asn>]`, where the
+/// subnet is given in CIDR notation, e.g., `10.0.0.0/8,DK,64512`. Empty lines
+/// and lines starting with `#` are ignored. If several subnets contain an
+/// address, the most specific one determines its location.
+#[derive(Debug, Default)]
+pub struct SubnetPeerLocator {
+    /// The subnets, ordered from the most to the least specific one.
+    subnets: Vec<(IpAddr, u8, PeerLocation)>,
+}
+
+impl SubnetPeerLocator {
+    /// Load the subnets from the given file.
+    pub fn new(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read the peer locations {}", path.display()))?;
+        contents
+            .parse()
+            .with_context(|| format!("Could not parse the peer locations {}", path.display()))
+    }
+}
+
+impl FromStr for SubnetPeerLocator {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut subnets = Vec::new();
+        for (idx, line) in s.lines().enumerate().map(|(idx, line)| (idx + 1, line.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+            let (subnet, region, asn) = match fields[..] {
+                [subnet, region] => (subnet, region, None),
+                [subnet, region, asn] => (subnet, region, Some(asn)),
+                _ => bail!("Line {}: expected `<subnet>,<region>[,<asn>]`", idx),
+            };
+            let (base, prefix) = match subnet.split_once('/') {
+                Some((base, prefix)) => (base, Some(prefix)),
+                None => (subnet, None),
+            };
+            let base = IpAddr::from_str(base).with_context(|| format!("Line {}", idx))?;
+            let max_prefix = if base.is_ipv4() {
+                32
+            } else {
+                128
+            };
+            let prefix = match prefix {
+                Some(prefix) => u8::from_str(prefix).with_context(|| format!("Line {}", idx))?,
+                None => max_prefix,
+            };
+            ensure!(prefix <= max_prefix, "Line {}: invalid prefix length {}", idx, prefix);
+            let asn = match asn {
+                Some(asn) => {
+                    // accept the common `AS64512` form as well
+                    let number = asn.strip_prefix("AS").unwrap_or(asn);
+                    Some(u32::from_str(number).with_context(|| format!("Line {}", idx))?)
+                }
+                None => None,
+            };
+            subnets.push((base, prefix, PeerLocation {
+                region: region.to_owned(),
+                asn,
+            }));
+        }
+        // the first subnet that contains an address is then the most specific one
+        subnets.sort_by(|(_, prefix_1, _), (_, prefix_2, _)| prefix_2.cmp(prefix_1));
+        Ok(SubnetPeerLocator {
+            subnets,
+        })
+    }
+}
+
+impl PeerLocator for SubnetPeerLocator {
+    fn locate(&self, ip: IpAddr) -> Option<PeerLocation> {
+        self.subnets
+            .iter()
+            .find(|(base, prefix, _)| subnet_contains(*base, *prefix, ip))
+            .map(|(_, _, location)| location.clone())
+    }
+}
@@ -1,22 +1,28 @@
 //! Node maintenance methods.
 
-use anyhow::Context;
+use anyhow::{bail, ensure, Context};
 use chrono::prelude::*;
 use crossbeam_channel::{self, Receiver, Sender};
-use mio::{net::TcpListener, Events, Interest, Poll, Registry, Token};
-use nohash_hasher::BuildNoHashHasher;
-use rand::{prelude::SliceRandom, thread_rng, Rng};
-use rkv::{
-    backend::{Lmdb, LmdbEnvironment},
-    Manager, Rkv,
+use mio::{
+    net::{TcpListener, TcpStream},
+    Events, Interest, Poll, Registry, Token,
 };
+use nohash_hasher::BuildNoHashHasher;
+use noiseexplorer_xx::types::Keypair;
+use rand::{prelude::SliceRandom, rngs::StdRng, thread_rng, Rng, RngCore, SeedableRng};
 
 #[cfg(feature = "network_dump")]
-use crate::dumper::{create_dump_thread, DumpItem};
+use crate::dumper::{create_dump_thread, DumpFilter, DumpFormat, DumpItem};
 use crate::{
-    common::{get_current_stamp, p2p_peer::RemotePeerId, P2PNodeId, P2PPeer, PeerType},
+    common::{
+        get_current_stamp, p2p_peer::RemotePeerId, DiscoverySource, P2PNodeId, P2PPeer, PeerType,
+    },
+    concordium_dns::dns,
     configuration::{self as config, Config},
-    connection::{ConnChange, Connection, DeduplicationHashAlgorithm, DeduplicationQueues},
+    connection::{
+        ConnChange, Connection, DeduplicationHashAlgorithm, DeduplicationQueues, HandshakeKey,
+        HandshakeNonce, TcpKeepalive,
+    },
     consensus_ffi::{
         blockchain_types::BlockHash,
         catch_up::PeerList,
@@ -25,11 +31,20 @@ use crate::{
     lock_or_die,
     network::{Buckets, NetworkId, Networks},
     p2p::{
-        bans::BanId,
-        connectivity::{accept, connect, connection_housekeeping, AcceptFailureReason, SELF_TOKEN},
+        bans::{
+            cached_subnet_bans, subnet_contains, BanId, BanStore, KvsBanStore, MemoryBanStore,
+            PersistedBanId,
+        },
+        connectivity::{
+            accept, connect, connection_housekeeping, remove_stalled_handshakes,
+            remove_stalled_reads, AcceptFailureReason, AcceptThrottle, AllowlistEntry,
+            DisconnectReason, IngestionBacklog, NonceRejection, RecentHandshakes, RecentlyRelayed,
+            RelayStrategy, SendThrottle, SELF_TOKEN,
+        },
+        geo::{PeerLocator, SubnetPeerLocator},
         peers::check_peers,
     },
-    plugins::consensus::{check_peer_states, update_peer_list},
+    plugins::consensus::{check_peer_states, update_consensus_stats, update_peer_list},
     read_or_die, spawn_or_die,
     stats_export_service::StatsExportService,
     utils, write_or_die,
@@ -46,13 +61,62 @@ use std::{
     path::PathBuf,
     str::FromStr,
     sync::{
-        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
         Arc, Mutex, RwLock,
     },
-    thread::JoinHandle,
+    thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
 
+/// Time (in ms) to wait between attempts to flush the connections when
+/// draining them on shutdown.
+const DRAIN_RETRY_INTERVAL: u64 = 10;
+
+/// Time (in ms) between checks for connections that didn't complete the
+/// handshake within the handshake timeout or stalled in the middle of a
+/// message.
+const STALLED_CONNECTION_CHECK_INTERVAL: u64 = 500;
+
+/// The number of cores left to the poll loop and the consensus threads when
+/// the socket thread pool is sized automatically.
+const RESERVED_CORES: usize = 2;
+
+/// The requested size of the thread pool processing connection events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadPoolSize {
+    /// As many threads as there are available cores, minus the reserved ones.
+    Auto,
+    /// A fixed number of threads.
+    Fixed(usize),
+}
+
+impl FromStr for ThreadPoolSize {
+    type Err = anyhow::Error;
+
+    fn from_str(size: &str) -> Result<Self, Self::Err> {
+        if size == "auto" {
+            return Ok(ThreadPoolSize::Auto);
+        }
+        match size.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(ThreadPoolSize::Fixed(n)),
+            _ => bail!("The thread pool size must be a positive number or \"auto\""),
+        }
+    }
+}
+
+/// Determines the number of socket threads for the requested pool size.
+/// `available_cores` overrides the number of cores the process may run on,
+/// e.g., if it is pinned to a subset of them.
+pub fn effective_thread_pool_size(size: ThreadPoolSize, available_cores: Option<usize>) -> usize {
+    match size {
+        ThreadPoolSize::Fixed(n) => n,
+        ThreadPoolSize::Auto => {
+            let cores = available_cores.unwrap_or_else(num_cpus::get);
+            cores.saturating_sub(RESERVED_CORES).max(1)
+        }
+    }
+}
+
 /// Configuration bits applicable to a node.
 pub struct NodeConfig {
     pub no_net: bool,
@@ -61,8 +125,12 @@ pub struct NodeConfig {
     /// Do not clear persistent bans on startup.
     pub no_clear_bans: bool,
     pub dns_resolvers: Vec<String>,
-    pub require_dnssec: bool,
+    /// How DNS queries are performed, including whether DNSSEC is required.
+    pub dns_options: dns::QueryOptions,
     pub disallow_multiple_peers_on_ip: bool,
+    /// If set, incoming connections from an IP address are refused once
+    /// there are this many connections to it.
+    pub max_connections_per_ip: Option<u16>,
     pub bootstrap_nodes: Vec<String>,
     /// Nodes to try and keep the connections to. A node will maintain two
     /// classes of connections, one which is explicitly given, and one which is
@@ -70,20 +138,97 @@ pub struct NodeConfig {
     /// are resolved on startup or when they are added and during execution
     /// we only keep them instead of the domain name.
     pub given_addresses: RwLock<HashSet<SocketAddr>>,
+    /// The given nodes named by a hostname, along with the addresses it last
+    /// resolved to, so that it can be resolved again if their IP changes.
+    pub given_hostnames: RwLock<HashMap<String, HashSet<SocketAddr>>>,
+    /// Interval (in s) at which the hostnames of the given nodes that aren't
+    /// connected are resolved again; 0 if they are not.
+    pub connect_to_resolve_interval: u64,
     pub max_allowed_nodes: u16,
+    /// If set, established connections are never dropped because the number
+    /// of peers exceeds `max_allowed_nodes`. Instead new connections are
+    /// refused while the node is full.
+    pub no_drop_excess_peers: bool,
+    /// Whether the node's capabilities are announced in its handshakes.
+    pub announce_capabilities: bool,
+    /// Time (in s) after which a connection is closed so that its slot can be
+    /// taken by a fresh peer. Disabled if not set.
+    pub max_connection_lifetime: Option<u64>,
+    /// Time (in s) the node waits on shutdown for its connections to be
+    /// drained.
+    pub shutdown_drain_timeout: u64,
     pub relay_broadcast_percentage: f64,
+    pub relay_strategy: RelayStrategy,
+    /// Time (in ms) during which copies of a relayed broadcast are neither
+    /// relayed nor processed again. Disabled if not set.
+    pub relay_dampening_window: Option<u64>,
+    /// The number of times the broadcasts originating from the node may be
+    /// relayed.
+    pub broadcast_hop_limit: u8,
+    /// Whether the node id is derived from the static noise key, which is
+    /// announced in the handshakes.
+    pub id_from_noise_key: bool,
+    /// If set, the only peers the node accepts, connects to and keeps
+    /// connections to.
+    pub allowlist: Option<HashSet<AllowlistEntry>>,
     pub poll_interval: u64,
     pub housekeeping_interval: u64,
     pub bootstrapping_interval: u64,
+    /// Time (in s) within which bootstrapping must produce a peer.
+    pub bootstrap_deadline: Option<u64>,
+    /// Maximum time (in s) by which a failed bootstrap delays the next one.
+    pub bootstrap_backoff_max: u64,
+    /// Time (in ms) within which a bootstrapper must accept a TCP connection
+    /// for it to be used, if probing is enabled.
+    pub bootstrap_probe_timeout: Option<u64>,
+    /// Time (in s) after which an unreachable address is first retried.
+    pub reconnect_backoff_base: u64,
+    /// Maximum time (in s) before an unreachable address is retried.
+    pub reconnect_backoff_max: u64,
+    /// Time (in s) for which bootstrapper probe results are reused.
+    pub bootstrap_probe_cache_ttl: u64,
+    /// Time (in s) for which the cached bootstrapper addresses are used when
+    /// the bootstrap nodes can't be resolved; 0 disables the cache.
+    pub bootstrap_cache_ttl: u64,
+    /// Whether to shut down if bootstrapping fails within the deadline.
+    pub bootstrap_strict: bool,
     pub print_peers: bool,
     pub bootstrapper_wait_minimum_peers: u16,
     pub data_dir_path: PathBuf,
     pub max_latency: Option<u64>,
+    /// If set, the local address outgoing connections are bound to.
+    pub bind_address: Option<IpAddr>,
+    /// Whether `max_latency` is compared against the 95th percentile of the
+    /// recent latencies of a connection instead of the last one.
+    pub max_latency_use_p95: bool,
+    /// The number of recent latencies kept for every connection.
+    pub latency_window: usize,
+    /// If set, peers whose score drops below it are quarantined.
+    pub peer_score_threshold: Option<u64>,
+    /// The score a peer recovers at every housekeeping round without a
+    /// penalty.
+    pub peer_score_recovery: u64,
+    /// The latency (in ms) above which a peer's score is decreased.
+    pub peer_score_latency: u64,
+    /// The time (in s) for which a quarantined peer is banned.
+    pub peer_quarantine_duration: u64,
+    /// If set, the maximum difference (in s) between the timestamp of an
+    /// incoming message and the local time; messages outside it are dropped.
+    pub message_timestamp_tolerance: Option<u64>,
     pub hard_connection_limit: u16,
     pub conn_requests_batch_limit: u16,
+    /// If set, the maximum average number of incoming connections accepted per
+    /// second; connections above it are closed right away.
+    pub accept_rate_limit: Option<u32>,
+    /// The number of incoming connections that can be accepted at once when
+    /// the accept rate is limited.
+    pub accept_burst: u32,
     pub catch_up_batch_limit: i64,
     pub timeout_bucket_entry_period: u64,
     pub bucket_cleanup_interval: u64,
+    /// If set, the maximum number of peers kept in each bucket.
+    pub max_bucket_size: Option<usize>,
+    /// The effective number of threads processing connection events.
     pub thread_pool_size: usize,
     pub dedup_size_long: usize,
     pub dedup_size_short: usize,
@@ -91,10 +236,51 @@ pub struct NodeConfig {
     pub socket_write_size: usize,
     pub no_rebroadcast_consensus_validation: bool,
     pub drop_rebroadcast_probability: Option<f64>,
+    /// If set, transactions are shed instead of rebroadcast with probability
+    /// `transaction_shedding_probability` while the low priority inbound
+    /// consensus queue holds at least this many messages.
+    pub transaction_shedding_queue_size: Option<usize>,
+    pub transaction_shedding_probability: f64,
     pub bootstrapper_peer_list_size: usize,
+    /// The maximum number of peers a node shares in a PeerList response.
+    pub max_peer_list_size: usize,
+    /// Peers that a bootstrapper advertises in its PeerList responses
+    /// regardless of whether it is connected to them.
+    pub bootstrapper_seed_peers: Vec<P2PPeer>,
     pub default_network: NetworkId,
     pub socket_so_linger: Option<u16>,
+    /// If set, TCP keepalive is enabled on all sockets with these parameters.
+    pub socket_keepalive: Option<TcpKeepalive>,
     pub events_queue_size: usize,
+    pub max_pending_messages: usize,
+    pub max_output_queue_bytes: usize,
+    /// Time (in ms) within which a new connection must complete the handshake.
+    pub handshake_timeout: u64,
+    /// Time (in ms) after which a connection is dropped if its peer sent part
+    /// of a message but none of the rest.
+    pub read_stall_timeout: u64,
+    /// The minimum time (in ms) between two answered GetPeers requests of a
+    /// peer.
+    pub get_peers_min_interval: u64,
+    /// The maximum size of a message accepted from a node.
+    pub node_max_message_size: u32,
+    /// The maximum size of a message accepted over a connection involving a
+    /// bootstrapper.
+    pub bootstrapper_max_message_size: u32,
+    /// If set, the payloads of network packets larger than this many bytes
+    /// are compressed.
+    pub packet_compression_threshold: Option<usize>,
+    /// If set, reading from a peer is paused while the high priority inbound
+    /// consensus queue holds at least this many messages and the peer's
+    /// messages take up at least an even share of them.
+    pub ingestion_pause_queue_size: Option<usize>,
+    /// If set, the time (in ms) an inbound consensus queue has to be full
+    /// before the peers whose messages it drops stop being read from.
+    pub queue_breaker_threshold: Option<u64>,
+    /// The time (in ms) for which reading from such a peer stops.
+    pub queue_breaker_cooldown: u64,
+    /// If set, the resident memory (in MiB) above which the node sheds load.
+    pub memory_soft_limit: Option<u64>,
     pub deduplication_hashing_algorithm: DeduplicationHashAlgorithm,
     pub regenesis_arc: Arc<RwLock<Vec<BlockHash>>>,
 }
@@ -108,6 +294,16 @@ pub struct ConnChanges {
     notifier:    Sender<ConnChange>,
 }
 
+/// The work done by a single thread of the socket thread pool since the last
+/// report.
+#[derive(Default)]
+pub struct SocketThreadStats {
+    /// The number of connections the thread processed.
+    pub connections_processed: AtomicU64,
+    /// The number of bytes the thread sent and received.
+    pub bytes_handled:         AtomicU64,
+}
+
 /// The set of objects related to node's connections.
 pub struct ConnectionHandler {
     pub socket_server:        TcpListener,
@@ -119,16 +315,63 @@ pub struct ConnectionHandler {
     pub connections:          RwLock<Connections>,
     pub conn_changes:         ConnChanges,
     pub soft_bans:            RwLock<HashMap<BanId, Instant>>, // (id, expiry)
+    /// The consecutive failures to connect to unreachable addresses, which
+    /// determine when they are retried, and the time of the latest one.
+    pub reconnect_failures:   Mutex<HashMap<SocketAddr, (u32, Instant)>>,
+    pub recent_handshakes:    RecentHandshakes,
     pub networks:             RwLock<Networks>,
     pub deduplication_queues: DeduplicationQueues,
+    /// The broadcasts relayed within the relay dampening window.
+    pub recently_relayed:     RecentlyRelayed,
+    /// The circuit breakers of the high and low priority inbound consensus
+    /// queues.
+    pub high_queue_breaker:   QueueBreaker,
+    pub low_queue_breaker:    QueueBreaker,
+    /// Whether the resident memory exceeded the soft limit as of the last
+    /// connection housekeeping, in which case the node sheds load.
+    pub memory_pressure:      AtomicBool,
     pub last_bootstrap:       AtomicU64,
+    /// The timestamp of the first bootstrap attempt that has not yet produced
+    /// any peers, or 0 if there is no such attempt.
+    pub bootstrap_started:    AtomicU64,
+    /// The number of consecutive bootstrap rounds that failed to produce
+    /// peers within the deadline.
+    pub bootstrap_failures:   AtomicU32,
+    /// The timestamp before which no new bootstrap attempt is made.
+    pub next_bootstrap:       AtomicU64,
+    /// The timestamp of the last resolution of the given nodes' hostnames.
+    pub last_given_resolve:   AtomicU64,
+    /// The results of recent reachability probes of bootstrappers.
+    pub bootstrap_probes:     Mutex<HashMap<SocketAddr, (Instant, bool)>>, // (probe time, result)
+    /// Addresses other than the node's own one under which it turned out to
+    /// be reachable, e.g., via NAT; connections to them would loop back.
+    pub self_addresses:       RwLock<HashSet<SocketAddr>>,
     pub last_peer_update:     AtomicU64,
+    /// The numbers of peers established and dropped since the start of the
+    /// current churn window, which the connection housekeeping closes.
+    pub peers_established:    AtomicU64,
+    pub peers_dropped:        AtomicU64,
+    pub churn_window_start:   AtomicU64,
+    /// The numbers of blocks and finalization messages processed by consensus
+    /// since the start of the current ingestion window, which the connection
+    /// housekeeping closes.
+    pub blocks_processed:     AtomicU64,
+    pub fin_msgs_processed:   AtomicU64,
+    pub ingestion_window:     AtomicU64,
+    /// The high priority messages of each peer waiting to be processed by
+    /// consensus, if the ingestion of peers' messages is paced.
+    pub ingestion_backlog:    IngestionBacklog,
     pub total_received:       AtomicU64,
     pub total_sent:           AtomicU64,
+    /// The accounting and the cap of the bytes written to the sockets.
+    pub send_throttle:        Arc<SendThrottle>,
+    /// Work accounting for each thread of the socket thread pool, indexed by
+    /// the thread's index in the pool.
+    pub socket_thread_stats:  Vec<SocketThreadStats>,
 }
 
 impl ConnectionHandler {
-    fn new(conf: &Config, socket_server: TcpListener) -> Self {
+    fn new(conf: &Config, socket_server: TcpListener, thread_pool_size: usize) -> Self {
         let networks = conf.common.network_ids.iter().cloned().map(NetworkId::from).collect();
         let (sndr, rcvr) =
             crossbeam_channel::bounded(conf.connection.hard_connection_limit as usize);
@@ -143,6 +386,11 @@ impl ConnectionHandler {
             conf.connection.dedup_size_short,
         );
 
+        let socket_thread_stats = (0..thread_pool_size).map(|_| Default::default()).collect();
+
+        let send_throttle =
+            Arc::new(SendThrottle::new(conf.connection.max_send_rate, Instant::now()));
+
         ConnectionHandler {
             socket_server,
             next_token: AtomicUsize::new(1),
@@ -153,12 +401,33 @@ impl ConnectionHandler {
             connections: Default::default(),
             conn_changes,
             soft_bans: Default::default(),
+            reconnect_failures: Default::default(),
+            recent_handshakes: Default::default(),
             networks: RwLock::new(networks),
             deduplication_queues,
+            recently_relayed: Default::default(),
+            high_queue_breaker: Default::default(),
+            low_queue_breaker: Default::default(),
+            memory_pressure: Default::default(),
             last_bootstrap: Default::default(),
+            bootstrap_started: Default::default(),
+            bootstrap_failures: Default::default(),
+            next_bootstrap: Default::default(),
+            last_given_resolve: AtomicU64::new(get_current_stamp()),
+            bootstrap_probes: Default::default(),
+            self_addresses: Default::default(),
             last_peer_update: Default::default(),
+            peers_established: Default::default(),
+            peers_dropped: Default::default(),
+            churn_window_start: AtomicU64::new(get_current_stamp()),
+            blocks_processed: Default::default(),
+            fin_msgs_processed: Default::default(),
+            ingestion_window: AtomicU64::new(get_current_stamp()),
+            ingestion_backlog: Default::default(),
             total_received: Default::default(),
             total_sent: Default::default(),
+            send_throttle,
+            socket_thread_stats,
         }
     }
 
@@ -168,13 +437,32 @@ impl ConnectionHandler {
         let soft_bans = read_or_die!(self.soft_bans);
         soft_bans.get(&BanId::Ip(addr.ip())).is_some()
             || soft_bans.get(&BanId::Socket(addr)).is_some()
+            || soft_bans.keys().any(|id| match *id {
+                BanId::Cidr {
+                    base,
+                    prefix,
+                } => subnet_contains(base, prefix, addr.ip()),
+                _ => false,
+            })
+    }
+
+    /// Record the nonce a peer with the given static key used in a noise
+    /// handshake. An error means that the handshake should be rejected, e.g.
+    /// because the peer already used the nonce within the replay window.
+    pub(crate) fn register_handshake_nonce(
+        &self,
+        key: HandshakeKey,
+        nonce: HandshakeNonce,
+    ) -> Result<(), NonceRejection> {
+        let window = Duration::from_secs(config::HANDSHAKE_REPLAY_WINDOW_SECS);
+        self.recent_handshakes.insert(key, nonce, window, Instant::now())
     }
 }
 
 /// Facilitates the `network_dump` feature.
 #[cfg(feature = "network_dump")]
 pub struct NetworkDumper {
-    switch: Sender<(std::path::PathBuf, bool)>,
+    switch: Sender<(std::path::PathBuf, bool, DumpFormat, bool)>,
     sender: Sender<crate::dumper::DumpItem>,
 }
 
@@ -183,7 +471,22 @@ impl NetworkDumper {
     fn new(ip: IpAddr, id: P2PNodeId, config: &Config) -> Self {
         let (dump_tx, dump_rx) = crossbeam_channel::bounded(config::DUMP_QUEUE_DEPTH);
         let (act_tx, act_rx) = crossbeam_channel::bounded(config::DUMP_SWITCH_QUEUE_DEPTH);
-        create_dump_thread(ip, id, dump_rx, act_rx, config.common.data_dir.clone());
+        // an empty list of packet types or peers doesn't restrict the dump
+        let packet_types = &config.common.network_dump_packet_types;
+        let peers = &config.common.network_dump_peers;
+        let filter = DumpFilter {
+            packet_types: if packet_types.is_empty() {
+                None
+            } else {
+                Some(packet_types.iter().copied().collect())
+            },
+            peers:        if peers.is_empty() {
+                None
+            } else {
+                Some(peers.iter().copied().collect())
+            },
+        };
+        create_dump_thread(ip, id, dump_rx, act_rx, filter, config.common.data_dir.clone());
 
         Self {
             switch: act_tx,
@@ -253,13 +556,25 @@ pub struct P2PNode {
     pub start_time:         DateTime<Utc>,
     /// The flag indicating whether a node should shut down.
     pub is_terminated:      AtomicBool,
-    /// The key-value store holding the node's persistent data.
-    pub kvs:                Arc<RwLock<Rkv<LmdbEnvironment>>>,
+    /// The flag indicating whether a node is draining its connections before
+    /// shutting down. No new connections are made while it is set.
+    pub is_draining:        AtomicBool,
+    /// The store of the node's bans.
+    pub ban_store:          Box<dyn BanStore>,
+    /// The subnet bans of the ban store along with their expiry, cached so
+    /// that checking whether an address is banned doesn't scan the store.
+    pub(crate) subnet_bans: RwLock<Vec<(PersistedBanId, u64)>>,
+    /// The lookup of the peers' locations, if configured.
+    pub peer_locator:       Option<Box<dyn PeerLocator>>,
     /// The catch-up list of peers.
     pub peers:              RwLock<PeerList>,
     /// Cache of bad events that we report on each connection housekeeping
     /// interval to avoid spamming the logs in case of failure.
     pub bad_events:         BadEvents,
+    /// The static keypair used in the noise handshakes of all connections.
+    pub noise_keypair:      Keypair,
+    /// The seeded source of randomness for choosing peers, if configured.
+    rng:                    Option<Mutex<StdRng>>,
 }
 
 impl P2PNode {
@@ -288,11 +603,24 @@ impl P2PNode {
         let ip = if let Some(ref addy) = conf.common.listen_address {
             IpAddr::from_str(addy).context("Could not parse the provided listen address.")?
         } else {
-            P2PNode::get_ip()
+            P2PNode::get_ip(conf.common.prefer_ipv6)
                 .context("Could not compute my own ip. Use `--listen-address` to specify it.")?
         };
 
-        let id = supplied_id.unwrap_or_else(|| rand::thread_rng().gen::<P2PNodeId>());
+        let noise_keypair = utils::load_or_create_noise_keypair(
+            &conf.common.data_dir.join(utils::NOISE_KEY_FILE),
+            conf.common.regenerate_noise_key,
+        )?;
+
+        let id = if let Some(id) = supplied_id {
+            id
+        } else if conf.common.id_from_noise_key {
+            P2PNodeId::from_public_key(&noise_keypair.get_public_key().as_bytes())
+        } else if let Some(seed) = conf.common.id_seed {
+            P2PNodeId::from_seed_and_address(seed, SocketAddr::new(ip, conf.common.listen_port))
+        } else {
+            rand::thread_rng().gen::<P2PNodeId>()
+        };
 
         info!("My Node ID is {}", id);
         info!("Listening on {}:{}", ip, conf.common.listen_port);
@@ -323,7 +651,27 @@ impl P2PNode {
 
         let dns_resolvers =
             utils::get_resolvers(&conf.connection.resolv_conf, &conf.connection.dns_resolver);
-        let given_addresses = RwLock::new(parse_config_nodes(&conf.connection, &dns_resolvers)?);
+        let (given_addresses, given_hostnames) =
+            parse_config_nodes(&conf.connection, &dns_resolvers)?;
+        let bootstrapper_seed_peers = match peer_type {
+            PeerType::Bootstrapper => parse_seed_peers(
+                &conf.bootstrapper,
+                &dns_resolvers,
+                conf.connection.dns_query_options(),
+            )?,
+            PeerType::Node => Vec::new(),
+        };
+
+        // bootstrappers don't do much work per connection; a single thread suffices
+        let thread_pool_size = match peer_type {
+            PeerType::Bootstrapper => 1,
+            PeerType::Node => effective_thread_pool_size(
+                conf.connection.thread_pool_size,
+                conf.connection.available_cores,
+            ),
+        };
+        info!("Processing connection events with {} thread(s)", thread_pool_size);
+        stats.set_socket_thread_pool_size(thread_pool_size as i64);
 
         let config = NodeConfig {
             no_net: conf.cli.no_network,
@@ -331,10 +679,13 @@ impl P2PNode {
             no_bootstrap_dns: conf.connection.no_bootstrap_dns,
             no_clear_bans: conf.connection.no_clear_bans,
             dns_resolvers,
-            require_dnssec: conf.connection.require_dnssec,
+            dns_options: conf.connection.dns_query_options(),
             disallow_multiple_peers_on_ip: conf.connection.disallow_multiple_peers_on_ip,
+            max_connections_per_ip: conf.connection.max_connections_per_ip,
             bootstrap_nodes: conf.connection.bootstrap_nodes.clone(),
-            given_addresses,
+            given_addresses: RwLock::new(given_addresses),
+            given_hostnames: RwLock::new(given_hostnames),
+            connect_to_resolve_interval: conf.connection.connect_to_resolve_interval,
             max_allowed_nodes: if let Some(max) = conf.connection.max_allowed_nodes {
                 max
             } else {
@@ -343,10 +694,31 @@ impl P2PNode {
                         * (f64::from(conf.connection.max_allowed_nodes_percentage) / 100f64),
                 ) as u16
             },
+            no_drop_excess_peers: conf.connection.no_drop_excess_peers,
+            announce_capabilities: !conf.connection.no_handshake_capabilities,
+            max_connection_lifetime: conf.connection.max_connection_lifetime,
+            shutdown_drain_timeout: conf.connection.shutdown_drain_timeout,
             relay_broadcast_percentage: conf.connection.relay_broadcast_percentage,
+            relay_strategy: conf.connection.relay_strategy,
+            relay_dampening_window: conf.connection.relay_dampening_window,
+            broadcast_hop_limit: conf.connection.broadcast_hop_limit,
+            id_from_noise_key: conf.common.id_from_noise_key,
+            allowlist: if conf.connection.allowlist.is_empty() {
+                None
+            } else {
+                Some(conf.connection.allowlist.iter().copied().collect())
+            },
             poll_interval: conf.cli.poll_interval,
             housekeeping_interval: conf.connection.housekeeping_interval,
             bootstrapping_interval: conf.connection.bootstrapping_interval,
+            bootstrap_deadline: conf.connection.bootstrap_deadline,
+            bootstrap_backoff_max: conf.connection.bootstrap_backoff_max,
+            reconnect_backoff_base: conf.connection.reconnect_backoff_base,
+            reconnect_backoff_max: conf.connection.reconnect_backoff_max,
+            bootstrap_probe_timeout: conf.connection.bootstrap_probe_timeout,
+            bootstrap_probe_cache_ttl: conf.connection.bootstrap_probe_cache_ttl,
+            bootstrap_cache_ttl: conf.connection.bootstrap_cache_ttl,
+            bootstrap_strict: conf.connection.bootstrap_strict,
             print_peers: true,
             bootstrapper_wait_minimum_peers: match peer_type {
                 PeerType::Bootstrapper => conf.bootstrapper.wait_until_minimum_nodes,
@@ -354,7 +726,17 @@ impl P2PNode {
             },
             data_dir_path: conf.common.data_dir.clone(),
             max_latency: conf.connection.max_latency,
+            bind_address: conf.common.bind_address,
+            max_latency_use_p95: conf.connection.max_latency_use_p95,
+            latency_window: conf.connection.latency_window,
+            peer_score_threshold: conf.connection.peer_score_threshold,
+            peer_score_recovery: conf.connection.peer_score_recovery,
+            peer_score_latency: conf.connection.peer_score_latency,
+            peer_quarantine_duration: conf.connection.peer_quarantine_duration,
+            message_timestamp_tolerance: conf.connection.message_timestamp_tolerance,
             conn_requests_batch_limit: conf.connection.conn_requests_batch_limit,
+            accept_rate_limit: conf.connection.accept_rate_limit,
+            accept_burst: conf.connection.accept_burst,
             hard_connection_limit: conf.connection.hard_connection_limit,
             catch_up_batch_limit: conf.connection.catch_up_batch_limit,
             timeout_bucket_entry_period: if peer_type == PeerType::Bootstrapper {
@@ -363,7 +745,8 @@ impl P2PNode {
                 conf.cli.timeout_bucket_entry_period
             },
             bucket_cleanup_interval: conf.common.bucket_cleanup_interval,
-            thread_pool_size: conf.connection.thread_pool_size,
+            max_bucket_size: conf.common.max_bucket_size,
+            thread_pool_size,
             dedup_size_long: conf.connection.dedup_size_long,
             dedup_size_short: conf.connection.dedup_size_short,
             socket_read_size: conf.connection.socket_read_size,
@@ -373,22 +756,52 @@ impl P2PNode {
                 PeerType::Node => conf.cli.drop_rebroadcast_probability,
                 _ => None,
             },
+            transaction_shedding_queue_size: match peer_type {
+                PeerType::Node => conf.cli.transaction_shedding_queue_size,
+                _ => None,
+            },
+            transaction_shedding_probability: conf.cli.transaction_shedding_probability,
             bootstrapper_peer_list_size: conf.bootstrapper.peer_list_size,
+            max_peer_list_size: conf.connection.max_peer_list_size,
+            bootstrapper_seed_peers,
             default_network: NetworkId::from(conf.common.network_ids[0]), // always present
             socket_so_linger: conf.connection.socket_so_linger,
+            socket_keepalive: conf.connection.tcp_keepalive_idle.map(|idle| TcpKeepalive {
+                idle,
+                interval: conf.connection.tcp_keepalive_interval,
+                probes: conf.connection.tcp_keepalive_probes,
+            }),
             events_queue_size: conf.connection.events_queue_size,
+            max_pending_messages: conf.connection.max_pending_messages,
+            max_output_queue_bytes: conf.connection.max_output_queue_bytes,
+            handshake_timeout: conf.connection.handshake_timeout,
+            read_stall_timeout: conf.connection.read_stall_timeout,
+            get_peers_min_interval: conf.connection.get_peers_min_interval,
+            node_max_message_size: conf.connection.node_max_message_size,
+            bootstrapper_max_message_size: conf.connection.bootstrapper_max_message_size,
+            packet_compression_threshold: conf.connection.packet_compression_threshold,
+            ingestion_pause_queue_size: conf.connection.ingestion_pause_queue_size,
+            queue_breaker_threshold: conf.connection.queue_breaker_threshold,
+            queue_breaker_cooldown: conf.connection.queue_breaker_cooldown,
+            memory_soft_limit: conf.connection.memory_soft_limit,
             deduplication_hashing_algorithm: conf.connection.deduplication_hashing_algorithm,
             regenesis_arc,
         };
 
-        let connection_handler = ConnectionHandler::new(conf, server);
+        let connection_handler = ConnectionHandler::new(conf, server, thread_pool_size);
+
+        let ban_store: Box<dyn BanStore> = if conf.connection.in_memory_bans {
+            Box::new(MemoryBanStore::default())
+        } else {
+            Box::new(KvsBanStore::new(&config.data_dir_path)?)
+        };
+
+        let subnet_bans = RwLock::new(cached_subnet_bans(&*ban_store)?);
 
-        // Create the node key-value store environment
-        let kvs = Manager::<LmdbEnvironment>::singleton()
-            .write()
-            .unwrap()
-            .get_or_create(config.data_dir_path.as_path(), Rkv::new::<Lmdb>)
-            .context("Could not create or obtain the ban database.")?;
+        let peer_locator = match conf.connection.peer_location_subnets {
+            Some(ref path) => Some(Box::new(SubnetPeerLocator::new(path)?) as Box<dyn PeerLocator>),
+            None => None,
+        };
 
         let node = Arc::new(P2PNode {
             poll_registry,
@@ -401,9 +814,14 @@ impl P2PNode {
             self_peer,
             stats,
             is_terminated: Default::default(),
-            kvs,
+            is_draining: Default::default(),
+            ban_store,
+            subnet_bans,
+            peer_locator,
             peers: Default::default(),
             bad_events: BadEvents::default(),
+            noise_keypair,
+            rng: conf.common.rng_seed.map(|seed| Mutex::new(StdRng::seed_from_u64(seed))),
         });
 
         if !node.config.no_clear_bans {
@@ -413,6 +831,15 @@ impl P2PNode {
         Ok((node, poll))
     }
 
+    /// Run `f` with the source of randomness used to choose peers: the seeded
+    /// one if the node was configured with a seed, `thread_rng` otherwise.
+    pub fn with_rng<T>(&self, f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+        match self.rng {
+            Some(ref rng) => f(&mut *lock_or_die!(rng)),
+            None => f(&mut thread_rng()),
+        }
+    }
+
     /// Get the timestamp of the node's last bootstrap attempt.
     pub fn get_last_bootstrap(&self) -> u64 {
         self.connection_handler.last_bootstrap.load(Ordering::Relaxed)
@@ -478,6 +905,13 @@ impl P2PNode {
     #[inline]
     pub fn buckets(&self) -> &RwLock<Buckets> { &self.connection_handler.buckets }
 
+    /// Describe the routing table, i.e., the entries of every bucket, as JSON.
+    /// Bootstrappers are marked as such, as they are added to the buckets on
+    /// handshake just like nodes.
+    pub fn routing_table_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string(&read_or_die!(self.buckets()).dump())?)
+    }
+
     /// Notify the node handler that a connection needs to undergo a major
     /// change.
     #[inline]
@@ -487,11 +921,22 @@ impl P2PNode {
         }
     }
 
-    /// Activate the network dump feature.
+    /// Activate the network dump feature. If `raw` is set, the messages are
+    /// also dumped in the given format. Only the messages matching the filter
+    /// configured with `--network-dump-packet-types` and `--network-dump-peers`
+    /// are written. If `append` is set, an existing dump at the path is
+    /// continued instead of being replaced, e.g., to keep a capture across
+    /// restarts.
     #[cfg(feature = "network_dump")]
-    pub fn activate_dump(&self, path: &str, raw: bool) -> anyhow::Result<()> {
+    pub fn activate_dump(
+        &self,
+        path: &str,
+        raw: bool,
+        format: DumpFormat,
+        append: bool,
+    ) -> anyhow::Result<()> {
         let path = std::path::PathBuf::from(path);
-        self.network_dumper.switch.send((path, raw))?;
+        self.network_dumper.switch.send((path, raw, format, append))?;
         self.dump_start(self.network_dumper.sender.clone());
         Ok(())
     }
@@ -500,7 +945,7 @@ impl P2PNode {
     #[cfg(feature = "network_dump")]
     pub fn stop_dump(&self) -> anyhow::Result<()> {
         let path = std::path::PathBuf::new();
-        self.network_dumper.switch.send((path, false))?;
+        self.network_dumper.switch.send((path, false, DumpFormat::default(), false))?;
         self.dump_stop();
         Ok(())
     }
@@ -525,52 +970,34 @@ impl P2PNode {
     #[inline]
     pub fn peer_type(&self) -> PeerType { self.self_peer.peer_type }
 
-    /// Get the node's uptime in milliseconds.
+    /// Get the node's uptime in milliseconds. If the clock went backwards
+    /// since the node was started the uptime is reported as 0.
     pub fn get_uptime(&self) -> i64 {
-        Utc::now().timestamp_millis() - self.start_time.timestamp_millis()
+        Utc::now().timestamp_millis().saturating_sub(self.start_time.timestamp_millis()).max(0)
+    }
+
+    /// Procure an IP address for the node. If both IPv4 and IPv6 addresses
+    /// are available, `prefer_ipv6` decides which kind is used.
+    fn get_ip(prefer_ipv6: bool) -> Option<IpAddr> {
+        select_own_ip(&P2PNode::interface_addresses(), prefer_ipv6)
     }
 
-    /// Procure an IP address for the node.
+    /// List the addresses of all the network interfaces of the host.
     #[cfg(not(windows))]
-    fn get_ip() -> Option<IpAddr> {
-        let localhost = Ipv4Addr::LOCALHOST;
-        let mut ip: IpAddr = IpAddr::V4(localhost);
-
-        if let Ok(addresses) = get_if_addrs::get_if_addrs() {
-            for adapter in addresses {
-                if let Some(addr) = get_ip_if_suitable(&adapter.addr.ip()) {
-                    ip = addr
-                }
-            }
-        }
-        if ip == localhost {
-            None
-        } else {
-            Some(ip)
-        }
+    pub(crate) fn interface_addresses() -> Vec<IpAddr> {
+        get_if_addrs::get_if_addrs()
+            .map(|adapters| adapters.iter().map(|adapter| adapter.addr.ip()).collect())
+            .unwrap_or_default()
     }
 
-    /// Procure an IP address for the node.
+    /// List the addresses of all the network interfaces of the host.
     #[cfg(windows)]
-    pub fn get_ip() -> Option<IpAddr> {
-        let localhost = Ipv4Addr::LOCALHOST;
-        let mut ip: IpAddr = IpAddr::V4(localhost);
-
-        if let Ok(adapters) = ipconfig::get_adapters() {
-            for adapter in adapters {
-                for ip_new in adapter.ip_addresses() {
-                    if let Some(addr) = get_ip_if_suitable(ip_new) {
-                        ip = addr
-                    }
-                }
-            }
-        }
-
-        if ip == localhost {
-            None
-        } else {
-            Some(ip)
-        }
+    pub(crate) fn interface_addresses() -> Vec<IpAddr> {
+        ipconfig::get_adapters()
+            .map(|adapters| {
+                adapters.iter().flat_map(|adapter| adapter.ip_addresses().iter().copied()).collect()
+            })
+            .unwrap_or_default()
     }
 
     /// Get the IP of the node.
@@ -591,6 +1018,49 @@ impl P2PNode {
         queues_stopped
     }
 
+    /// Shut the node down without terminating its threads, but only after
+    /// giving the connections a chance to send everything queued for them.
+    /// No new connections are made while draining, and connections that still
+    /// have data to send when `timeout` elapses are closed forcibly. Returns
+    /// the number of connections that were flushed cleanly and the number of
+    /// those that were closed forcibly, or an error if the node couldn't be
+    /// closed properly.
+    pub fn close_gracefully(&self, timeout: Duration) -> anyhow::Result<(usize, usize)> {
+        self.is_draining.store(true, Ordering::Relaxed);
+        let deadline = Instant::now() + timeout;
+
+        // no new connections are made while draining, so the ones to flush are
+        // known upfront; the locks are only held while flushing a single one,
+        // so that the poll loop can keep handling the others in the meantime
+        let candidates: Vec<Token> = lock_or_die!(self.conn_candidates()).keys().copied().collect();
+        let peers: Vec<Token> = read_or_die!(self.connections()).keys().copied().collect();
+
+        let (flushed, forced) = loop {
+            let mut drained = Vec::with_capacity(candidates.len() + peers.len());
+            for token in &candidates {
+                if let Some(conn) = lock_or_die!(self.conn_candidates()).get_mut(token) {
+                    drained.push(flush_on_shutdown(conn));
+                }
+            }
+            for token in &peers {
+                if let Some(conn) = write_or_die!(self.connections()).get_mut(token) {
+                    drained.push(flush_on_shutdown(conn));
+                }
+            }
+            let flushed = drained.iter().filter(|&&drained| drained).count();
+            let forced = drained.len() - flushed;
+            if forced == 0 || Instant::now() >= deadline {
+                break (flushed, forced);
+            }
+            // the poll loop keeps running and marks sockets writable again
+            thread::sleep(Duration::from_millis(DRAIN_RETRY_INTERVAL));
+        };
+        info!("Drained {} connections; closing {} connections forcibly", flushed, forced);
+
+        ensure!(self.close(), "Can't stop the consensus queues");
+        Ok((flushed, forced))
+    }
+
     /// Waits for all the spawned threads to terminate.
     /// This may panic or deadlock (depending on platform) if used from two
     /// different node threads.
@@ -624,6 +1094,110 @@ impl P2PNode {
     }
 }
 
+/// Write what is queued for the connection to its socket as part of a graceful
+/// shutdown, returning whether nothing is left to send.
+fn flush_on_shutdown(conn: &mut Connection) -> bool {
+    if let Err(e) = conn.send_pending_messages().and_then(|_| conn.low_level.flush_socket()) {
+        debug!("Can't flush the connection to {} on shutdown: {}", conn, e);
+    }
+    !conn.has_pending_output()
+}
+
+/// Install handlers for SIGTERM and SIGINT (CTRL-C on Windows) that shut the
+/// node down with `close_gracefully`, so that stopping the process doesn't cut
+/// its connections off mid-write. Only the first signal starts the shutdown;
+/// any further ones are just logged. If the node can't be closed properly, the
+/// process exits with status 1. The handlers run on a dedicated thread instead
+/// of in the signal context.
+pub fn install_shutdown_handlers(node: &Arc<P2PNode>) -> anyhow::Result<()> {
+    let shutdown_started = AtomicBool::new(false);
+    let node = Arc::clone(node);
+    let on_signal = move || {
+        if shutdown_started.swap(true, Ordering::SeqCst) {
+            info!("Signal received to shut down the node, but a shutdown is already in progress");
+        } else {
+            info!("Signal received; shutting down the node cleanly");
+            let timeout = Duration::from_secs(node.config.shutdown_drain_timeout);
+            if let Err(e) = node.close_gracefully(timeout) {
+                error!("Can't shut down the node properly: {}", e);
+                std::process::exit(1);
+            }
+        }
+    };
+
+    #[cfg(not(windows))]
+    {
+        use signal_hook::{
+            consts::{SIGINT, SIGTERM},
+            iterator::Signals,
+        };
+        let mut signals = Signals::new(&[SIGTERM, SIGINT])?;
+        // the thread is not registered with the node, as it never terminates
+        spawn_or_die!("signal handler", move || {
+            for _ in signals.forever() {
+                on_signal();
+            }
+        });
+    }
+    #[cfg(windows)]
+    ctrlc::set_handler(on_signal)?;
+
+    Ok(())
+}
+
+/// Install a handler for SIGHUP that re-reads the ban list with `reload_bans`
+/// and closes the connections to the peers that are banned now, e.g., after
+/// the ban store was edited while the node was running. It also retries the
+/// addresses the node recently failed to connect to straight away, e.g., after
+/// a network outage was fixed. There is no equivalent on Windows, where this
+/// does nothing.
+pub fn install_ban_reload_handler(node: &Arc<P2PNode>) -> anyhow::Result<()> {
+    #[cfg(not(windows))]
+    {
+        use signal_hook::{consts::SIGHUP, iterator::Signals};
+        let node = Arc::clone(node);
+        let mut signals = Signals::new(&[SIGHUP])?;
+        // the thread is not registered with the node, as it never terminates
+        spawn_or_die!("ban reload handler", move || {
+            for _ in signals.forever() {
+                match node.reload_bans() {
+                    Ok(closed) => {
+                        info!("Reloaded the bans; closing {} banned connection(s)", closed)
+                    }
+                    Err(e) => error!("Couldn't reload the bans: {}", e),
+                }
+                node.retry_unreachable_now();
+            }
+        });
+    }
+    #[cfg(windows)]
+    let _ = node;
+
+    Ok(())
+}
+
+/// Install a handler for SIGUSR1 that logs the node's live state with
+/// `log_state`, for debugging a running node. There is no equivalent on
+/// Windows, where this does nothing.
+pub fn install_state_dump_handler(node: &Arc<P2PNode>) -> anyhow::Result<()> {
+    #[cfg(not(windows))]
+    {
+        use signal_hook::{consts::SIGUSR1, iterator::Signals};
+        let node = Arc::clone(node);
+        let mut signals = Signals::new(&[SIGUSR1])?;
+        // the thread is not registered with the node, as it never terminates
+        spawn_or_die!("state dump handler", move || {
+            for _ in signals.forever() {
+                node.log_state();
+            }
+        });
+    }
+    #[cfg(windows)]
+    let _ = node;
+
+    Ok(())
+}
+
 /// Spawn the node's poll thread.
 pub fn spawn(node_ref: &Arc<P2PNode>, mut poll: Poll, consensus: Option<ConsensusContainer>) {
     let node = Arc::clone(node_ref);
@@ -631,15 +1205,15 @@ pub fn spawn(node_ref: &Arc<P2PNode>, mut poll: Poll, consensus: Option<Consensu
         let mut events = Events::with_capacity(node.config.events_queue_size);
         let mut log_time = Instant::now();
         let mut last_buckets_cleaned = Instant::now();
+        let mut last_stall_check = Instant::now();
         let mut last_peer_list_update = 0;
         // The number of polling loop iterations since the last housekeeping.
         let mut iterations_since_housekeeping = 0;
 
-        let num_socket_threads = match node.self_peer.peer_type {
-            PeerType::Bootstrapper => 1,
-            PeerType::Node => node.config.thread_pool_size,
-        };
-        let pool = rayon::ThreadPoolBuilder::new().num_threads(num_socket_threads).build().unwrap();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(node.config.thread_pool_size)
+            .build()
+            .unwrap();
         let poll_interval = Duration::from_millis(node.config.poll_interval);
 
         // A flag indicating whether there are unprocessed incoming connection attempts.
@@ -651,6 +1225,11 @@ pub fn spawn(node_ref: &Arc<P2PNode>, mut poll: Poll, consensus: Option<Consensu
         // Maximum number of connection requests to process per iteration.
         let max_num_requests = node.config.conn_requests_batch_limit;
 
+        let mut accept_throttle = node
+            .config
+            .accept_rate_limit
+            .map(|rate| AcceptThrottle::new(rate, node.config.accept_burst, Instant::now()));
+
         // Process network events until signalled to terminate.
         // For each loop iteration do the following in sequence
         // - check whether ther are any incoming connection requests
@@ -667,14 +1246,23 @@ pub fn spawn(node_ref: &Arc<P2PNode>, mut poll: Poll, consensus: Option<Consensu
                 continue;
             }
 
-            // check for new connections
-            if unprocessed_attempts || events.iter().any(|event| event.token() == SELF_TOKEN) {
+            // check for new connections, unless the node is shutting down
+            if !node.is_draining.load(Ordering::Relaxed)
+                && (unprocessed_attempts || events.iter().any(|event| event.token() == SELF_TOKEN))
+            {
                 let mut attempt_number = 0;
                 unprocessed_attempts = true;
                 while attempt_number < max_num_requests {
                     match node.connection_handler.socket_server.accept() {
                         Ok((socket, addr)) => {
-                            if let Err(e) = accept(&node, socket, addr) {
+                            let throttled = accept_throttle
+                                .as_mut()
+                                .map_or(false, |throttle| !throttle.try_acquire(Instant::now()));
+                            if throttled {
+                                // the socket is closed right away
+                                debug!("Closing connection from {} due to the accept rate", addr);
+                                node.stats.conn_throttled_inc();
+                            } else if let Err(e) = accept(&node, socket, addr) {
                                 error!("{}", e);
                                 if let AcceptFailureReason::TooManyConnections {
                                     addr: _,
@@ -718,6 +1306,15 @@ pub fn spawn(node_ref: &Arc<P2PNode>, mut poll: Poll, consensus: Option<Consensu
             // perform socket reads and writes in parallel across connections
             pool.install(|| node.process_network_events(&events));
 
+            // drop stalled handshakes and reads without waiting for the next housekeeping
+            if last_stall_check.elapsed()
+                >= Duration::from_millis(STALLED_CONNECTION_CHECK_INTERVAL)
+            {
+                remove_stalled_handshakes(&node);
+                remove_stalled_reads(&node);
+                last_stall_check = Instant::now();
+            }
+
             // Run periodic tasks
             // We prevent housekeeping from occurring too often so that new connections have
             // a chance to complete the handshake in between invocations of
@@ -727,6 +1324,7 @@ pub fn spawn(node_ref: &Arc<P2PNode>, mut poll: Poll, consensus: Option<Consensu
                     >= Duration::from_secs(node.config.housekeeping_interval)
                 {
                     let attempted_bootstrap = connection_housekeeping(&node);
+                    node.report_socket_thread_stats();
                     if node.peer_type() != PeerType::Bootstrapper {
                         node.measure_connection_latencies()
                     }
@@ -736,6 +1334,10 @@ pub fn spawn(node_ref: &Arc<P2PNode>, mut poll: Poll, consensus: Option<Consensu
                     if let Err(e) = node.measure_throughput(&peer_stat_list) {
                         error!("Could not measure throughput: {}", e);
                     }
+                    node.measure_output_queue_backlog(&peer_stat_list);
+                    if let Some(ref consensus) = consensus {
+                        update_consensus_stats(&node, consensus);
+                    }
 
                     log_time = Instant::now();
                     iterations_since_housekeeping = 0;
@@ -770,8 +1372,17 @@ fn process_conn_change(node: &Arc<P2PNode>, conn_change: ConnChange) {
         } => {
             // for given addresses we do not respect the max peer bound, for discovered
             // peers that are automatically discovered we do
-            if let Err(e) = connect(node, peer_type, addr, None, !given) {
-                error!("Can't connect to the desired address: {}", e);
+            let source = match (peer_type, given) {
+                (PeerType::Bootstrapper, _) => DiscoverySource::Bootstrap,
+                (PeerType::Node, true) => DiscoverySource::Given,
+                (PeerType::Node, false) => DiscoverySource::PeerList,
+            };
+            if let Err(e) = connect(node, peer_type, addr, None, !given, source) {
+                if e.is_already_connected() {
+                    info!("Not connecting to the desired address: {}", e);
+                } else {
+                    error!("Can't connect to the desired address: {}", e);
+                }
             } else if given && !write_or_die!(node.config.given_addresses).insert(addr) {
                 info!("New given address recorded {}", given);
             }
@@ -788,24 +1399,40 @@ fn process_conn_change(node: &Arc<P2PNode>, conn_change: ConnChange) {
                 let is_connected = conns.values().any(|existing| {
                     existing.remote_addr() == addr || existing.remote_peer.external_addr() == addr
                 });
-                if !is_connected {
+                // if we are not allowed to drop excess peers we must make sure not to
+                // exceed the maximum in the first place.
+                let is_full = node.config.no_drop_excess_peers
+                    && node.self_peer.peer_type == PeerType::Node
+                    && conn.remote_peer_type() == PeerType::Node
+                    && !node.is_given_connection(&conn)
+                    && conns.values().filter(|c| c.remote_peer_type() == PeerType::Node).count()
+                        >= node.config.max_allowed_nodes as usize;
+                if is_full {
+                    debug!("Maximum number of peers reached; not promoting a new connection.")
+                } else if !is_connected {
+                    // the address is reachable again, so its next failure is retried quickly
+                    lock_or_die!(node.connection_handler.reconnect_failures)
+                        .remove(&conn.remote_addr());
                     conns.insert(conn.token(), conn);
-                    node.bump_last_peer_update();
+                    node.register_peer_established();
                 } else {
                     warn!("Already connected to a peer on the given address.")
                 }
             }
         }
-        ConnChange::NewPeers(mut peers) => {
+        ConnChange::NewPeers(mut peers, source) => {
             let mut new_peers = 0;
             let current_peers = node.get_peer_stats(Some(PeerType::Node));
 
             let curr_peer_count = current_peers.len();
 
+            // Skip the peers that aren't allowed when running with an allowlist
+            peers.retain(|peer| node.is_allowed(peer.addr.ip(), peer.known_id()));
+
             // Shuffle the peers we received try to discover more useful peers over time
             // and not get stuck continuously connecting to useless ones, and then dropping
             // connections.
-            peers.shuffle(&mut thread_rng());
+            node.with_rng(|rng| peers.shuffle(rng));
 
             // Try to connect to each peer in turn.
             // If we are already connected to a peer, this will fail.
@@ -815,7 +1442,9 @@ fn process_conn_change(node: &Arc<P2PNode>, conn_change: ConnChange) {
                 }
 
                 trace!("Got info for peer {} ({})", peer.id, peer.addr);
-                if let Err(e) = connect(node, PeerType::Node, peer.addr, Some(peer.id), true) {
+                if let Err(e) =
+                    connect(node, PeerType::Node, peer.addr, peer.known_id(), true, source)
+                {
                     debug!("Could not connect to discovered peer {}", e);
                 } else {
                     new_peers += 1;
@@ -824,6 +1453,7 @@ fn process_conn_change(node: &Arc<P2PNode>, conn_change: ConnChange) {
         }
         ConnChange::ExpulsionByToken(token) => {
             if let Some(remote_peer) = node.remove_connection(token) {
+                node.report_disconnect(remote_peer.addr, DisconnectReason::ProtocolBreach);
                 let ip = remote_peer.addr.ip();
                 warn!("Soft-banning {} due to a breach of protocol", ip);
                 write_or_die!(node.connection_handler.soft_bans).insert(
@@ -847,22 +1477,47 @@ fn process_conn_change(node: &Arc<P2PNode>, conn_change: ConnChange) {
 pub fn attempt_bootstrap(node: &Arc<P2PNode>) {
     if !node.config.no_net {
         info!("Attempting to bootstrap");
+        // start measuring the deadline, unless an earlier attempt is still pending
+        let _ = node.connection_handler.bootstrap_started.compare_exchange(
+            0,
+            get_current_stamp(),
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
 
         let bootstrap_nodes = utils::get_bootstrap_nodes(
             &node.config.dns_resolvers,
-            node.config.require_dnssec,
+            node.config.dns_options,
             &node.config.bootstrap_nodes,
-        );
+        )
+        .map(|nodes| update_or_use_bootstrap_cache(node, nodes));
 
         match bootstrap_nodes {
             Ok(nodes) => {
-                for addr in nodes {
-                    info!("Using bootstrapper {}", addr);
-                    node.register_conn_change(ConnChange::NewConn {
-                        addr,
-                        peer_type: PeerType::Bootstrapper,
-                        given: false,
-                    });
+                if let Some(timeout) = node.config.bootstrap_probe_timeout {
+                    // the bootstrappers are probed at once, so this blocks for at most the timeout
+                    let timeout = Duration::from_millis(timeout);
+                    match probe_bootstrappers(node, &nodes, timeout) {
+                        Ok(reachable) => {
+                            for (addr, reachable) in nodes.into_iter().zip(reachable) {
+                                if reachable {
+                                    use_bootstrapper(node, addr);
+                                } else {
+                                    warn!("Skipping bootstrapper {}, as it is unreachable", addr);
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            warn!("Can't probe the bootstrappers: {}", e);
+                            for addr in nodes {
+                                use_bootstrapper(node, addr);
+                            }
+                        }
+                    }
+                } else {
+                    for addr in nodes {
+                        use_bootstrapper(node, addr);
+                    }
                 }
             }
             Err(e) => error!("Can't bootstrap: {:?}", e),
@@ -870,7 +1525,176 @@ pub fn attempt_bootstrap(node: &Arc<P2PNode>) {
     }
 }
 
-fn get_ip_if_suitable(addr: &IpAddr) -> Option<IpAddr> {
+/// Refresh the on-disk cache of bootstrapper addresses with the freshly
+/// resolved ones. The bootstrap nodes that couldn't be resolved keep their
+/// cached addresses, which are used instead, so that a partial resolution
+/// doesn't replace a complete one.
+fn update_or_use_bootstrap_cache(
+    node: &P2PNode,
+    resolved: Vec<(String, Vec<SocketAddr>)>,
+) -> Vec<SocketAddr> {
+    if node.config.bootstrap_cache_ttl == 0 {
+        return resolved.into_iter().flat_map(|(_, addrs)| addrs).collect();
+    }
+    let path = node.config.data_dir_path.join(utils::BOOTSTRAP_CACHE_FILE);
+    let mut cache = utils::read_bootstrap_cache(&path, node.config.bootstrap_cache_ttl)
+        .unwrap_or_else(|e| {
+            warn!("Can't read the bootstrap cache: {}", e);
+            Default::default()
+        });
+
+    let now = get_current_stamp();
+    let mut nodes = Vec::new();
+    for (bootstrap_node, addrs) in resolved {
+        if !addrs.is_empty() {
+            nodes.extend_from_slice(&addrs);
+            cache.insert(bootstrap_node, (now, addrs));
+        }
+    }
+    if !nodes.is_empty() {
+        if let Err(e) = utils::write_bootstrap_cache(&path, &node.config.bootstrap_nodes, &cache) {
+            warn!("Can't update the bootstrap cache: {}", e);
+        }
+    }
+
+    for bootstrap_node in &node.config.bootstrap_nodes {
+        match cache.get(bootstrap_node) {
+            Some((resolved_at, addrs)) if *resolved_at != now => {
+                warn!(
+                    "Can't resolve bootstrap node {}; using {} cached addresses",
+                    bootstrap_node,
+                    addrs.len()
+                );
+                nodes.extend_from_slice(addrs);
+            }
+            _ => {}
+        }
+    }
+    nodes
+}
+
+fn use_bootstrapper(node: &P2PNode, addr: SocketAddr) {
+    info!("Using bootstrapper {}", addr);
+    node.register_conn_change(ConnChange::NewConn {
+        addr,
+        peer_type: PeerType::Bootstrapper,
+        given: false,
+    });
+}
+
+/// Check which of the bootstrappers at the given addresses accept TCP
+/// connections within the timeout. The connections are attempted all at once
+/// without blocking, so probing takes at most the timeout however many
+/// bootstrappers there are. Recent results for the same address are reused for
+/// the configured time.
+pub(crate) fn probe_bootstrappers(
+    node: &P2PNode,
+    addrs: &[SocketAddr],
+    timeout: Duration,
+) -> anyhow::Result<Vec<bool>> {
+    let cache_ttl = Duration::from_secs(node.config.bootstrap_probe_cache_ttl);
+    let mut reachable = vec![false; addrs.len()];
+    let mut probed = Vec::new();
+    let mut poll = Poll::new()?;
+    let mut pending = HashMap::new();
+    {
+        let probes = lock_or_die!(node.connection_handler.bootstrap_probes);
+        for (i, addr) in addrs.iter().enumerate() {
+            match probes.get(addr) {
+                Some(&(probed_at, result)) if probed_at.elapsed() < cache_ttl => {
+                    reachable[i] = result;
+                }
+                _ => {
+                    probed.push(i);
+                    // a connection that fails right away leaves the address unreachable
+                    if let Ok(mut stream) = TcpStream::connect(*addr) {
+                        poll.registry().register(&mut stream, Token(i), Interest::WRITABLE)?;
+                        pending.insert(Token(i), stream);
+                    }
+                }
+            }
+        }
+    }
+
+    // a connection is established or has failed once its socket is writable
+    let deadline = Instant::now() + timeout;
+    let mut events = Events::with_capacity(addrs.len().max(1));
+    while !pending.is_empty() {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        poll.poll(&mut events, Some(deadline - now))?;
+        for event in events.iter() {
+            let stream = match pending.remove(&event.token()) {
+                Some(stream) => stream,
+                None => continue,
+            };
+            match (stream.take_error(), stream.peer_addr()) {
+                (Ok(None), Ok(_)) => reachable[event.token().0] = true,
+                // a spurious event; the connection is still being established
+                (Ok(None), Err(e)) if e.kind() == ErrorKind::NotConnected => {
+                    pending.insert(event.token(), stream);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let now = Instant::now();
+    let mut probes = lock_or_die!(node.connection_handler.bootstrap_probes);
+    for i in probed {
+        probes.insert(addrs[i], (now, reachable[i]));
+    }
+    Ok(reachable)
+}
+
+/// Check whether the pending bootstrap attempts produced any peers within the
+/// given deadline (in seconds). If they did not, the failure is reported and
+/// either the node is shut down (in strict mode) or the next attempt is
+/// delayed with an exponential backoff, bounded by `bootstrap_backoff_max`.
+pub fn check_bootstrap_deadline(node: &Arc<P2PNode>, deadline: u64) {
+    let handler = &node.connection_handler;
+    let started = handler.bootstrap_started.load(Ordering::Relaxed);
+    if started == 0 {
+        return;
+    }
+
+    if !node.get_peer_stats(Some(PeerType::Node)).is_empty() {
+        handler.bootstrap_started.store(0, Ordering::Relaxed);
+        handler.bootstrap_failures.store(0, Ordering::Relaxed);
+        return;
+    }
+
+    let now = get_current_stamp();
+    if now < started.saturating_add(deadline.saturating_mul(1000)) {
+        return;
+    }
+
+    let failures = handler.bootstrap_failures.fetch_add(1, Ordering::Relaxed) + 1;
+    error!(
+        "Bootstrap failed within the deadline of {}s ({} consecutive failures).",
+        deadline, failures
+    );
+
+    if node.config.bootstrap_strict {
+        error!("Shutting down, since bootstrapping is required to succeed within the deadline.");
+        if !node.close() {
+            error!("Can't shutdown node properly!");
+        }
+        return;
+    }
+
+    let backoff = deadline
+        .saturating_mul(1 << failures.min(16))
+        .min(node.config.bootstrap_backoff_max)
+        .saturating_mul(1000);
+    info!("Delaying the next bootstrap attempt by {}s.", backoff / 1000);
+    handler.next_bootstrap.store(now.saturating_add(backoff), Ordering::Relaxed);
+    handler.bootstrap_started.store(0, Ordering::Relaxed);
+}
+
+pub(crate) fn get_ip_if_suitable(addr: &IpAddr) -> Option<IpAddr> {
     match addr {
         V4(x) => {
             if !x.is_loopback() && !x.is_link_local() && !x.is_multicast() && !x.is_broadcast() {
@@ -879,19 +1703,76 @@ fn get_ip_if_suitable(addr: &IpAddr) -> Option<IpAddr> {
                 None
             }
         }
-        V6(_) => None,
+        V6(x) => {
+            // only global-scope addresses; the link-local (fe80::/10) and
+            // unique-local (fc00::/7) checks aren't stable in std yet
+            let first_segment = x.segments()[0];
+            let is_link_local = first_segment & 0xffc0 == 0xfe80;
+            let is_unique_local = first_segment & 0xfe00 == 0xfc00;
+            if !x.is_loopback()
+                && !x.is_unspecified()
+                && !x.is_multicast()
+                && !is_link_local
+                && !is_unique_local
+            {
+                Some(IpAddr::V6(*x))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Choose the node's own address out of the addresses of its interfaces. As
+/// with a single address family, the last suitable address of a kind wins.
+pub(crate) fn select_own_ip(addresses: &[IpAddr], prefer_ipv6: bool) -> Option<IpAddr> {
+    let suitable = addresses.iter().filter_map(get_ip_if_suitable);
+    let ipv4 = suitable.clone().filter(IpAddr::is_ipv4).last();
+    let ipv6 = suitable.filter(IpAddr::is_ipv6).last();
+    if prefer_ipv6 {
+        ipv6.or(ipv4)
+    } else {
+        ipv4.or(ipv6)
     }
 }
 
 /// Parse and potentially resolve IPs (via DNS) of nodes supplied on startup.
+/// The nodes named by a hostname are also returned along with the addresses
+/// it resolved to.
 fn parse_config_nodes(
     conf: &config::ConnectionConfig,
     dns_resolvers: &[String],
-) -> anyhow::Result<HashSet<SocketAddr>> {
+) -> anyhow::Result<(HashSet<SocketAddr>, HashMap<String, HashSet<SocketAddr>>)> {
     let mut out = HashSet::new();
+    let mut hostnames = HashMap::new();
     for connect_to in &conf.connect_to {
-        let new_addresses = utils::parse_host_port(connect_to, dns_resolvers, conf.require_dnssec)?;
+        let new_addresses =
+            utils::parse_host_port(connect_to, dns_resolvers, conf.dns_query_options())?;
+        if utils::is_hostname(connect_to) {
+            hostnames.insert(connect_to.clone(), new_addresses.iter().copied().collect());
+        }
         out.extend(new_addresses)
     }
+    Ok((out, hostnames))
+}
+
+/// Resolve the addresses of the static seed peers of a bootstrapper. Their ids
+/// are not known in advance, so they are advertised as unknown and the nodes
+/// learn them in the handshake.
+fn parse_seed_peers(
+    conf: &config::BootstrapperConfig,
+    dns_resolvers: &[String],
+    dns_options: dns::QueryOptions,
+) -> anyhow::Result<Vec<P2PPeer>> {
+    let mut out = Vec::new();
+    for seed_peer in &conf.seed_peers {
+        for addr in utils::parse_host_port(seed_peer, dns_resolvers, dns_options)? {
+            out.push(P2PPeer {
+                id: P2PNodeId::UNKNOWN,
+                addr,
+                peer_type: PeerType::Node,
+            });
+        }
+    }
     Ok(out)
 }
@@ -2,6 +2,7 @@
 
 pub mod bans;
 pub mod connectivity;
+pub mod geo;
 pub mod maintenance;
 pub mod peers;
 
@@ -10,11 +11,30 @@ pub use self::maintenance::{Connections, P2PNode};
 #[cfg(test)]
 mod tests {
     use crate::{
-        common::{p2p_peer::RemotePeerId, PeerType},
-        p2p::bans::PersistedBanId,
+        common::{get_current_stamp, p2p_peer::RemotePeerId, P2PNodeId, PeerType, RemotePeer},
+        p2p::{
+            bans::{BanId, BanInfo, BanStore, KvsBanStore, MemoryBanStore, PersistedBanId},
+            connectivity::{
+                peer_churn_rate, per_second_rate, reconnect_backoff, select_expired_connection,
+                select_relay_targets, select_replacement_peer, AcceptThrottle, IngestionBacklog,
+                NonceRejection, QueueBreaker, RecentHandshakes, RecentlyRelayed, RelayStrategy,
+                SendShare, SendThrottle,
+            },
+            geo::{PeerLocation, PeerLocator, SubnetPeerLocator},
+            maintenance::{
+                effective_thread_pool_size, get_ip_if_suitable, probe_bootstrappers, select_own_ip,
+                P2PNode, ThreadPoolSize,
+            },
+        },
         test_utils::*,
+        write_or_die,
+    };
+    use rand::{rngs::StdRng, thread_rng, SeedableRng};
+    use std::{
+        collections::HashSet,
+        net::{IpAddr, SocketAddr},
+        time::{Duration, Instant},
     };
-    use std::net::IpAddr;
 
     #[test]
     fn test_ban_functionalities() -> anyhow::Result<()> {
@@ -40,7 +60,7 @@ mod tests {
 
         // Insertion by ip
         assert!(
-            !node.drop_by_ip_and_ban(to_ban2)?,
+            !node.drop_by_ip_and_ban(to_ban2, None)?,
             "Should have returned false since the peer does not exist."
         );
         let reply = node.get_banlist()?;
@@ -49,7 +69,7 @@ mod tests {
 
         // Duplicates check
         assert!(
-            !node.drop_by_ip_and_ban(to_ban2)?,
+            !node.drop_by_ip_and_ban(to_ban2, None)?,
             "Should have banned the same IP again, returning false since no peer exists."
         );
         let reply = node.get_banlist()?;
@@ -61,6 +81,614 @@ mod tests {
         let reply = node.get_banlist()?;
         assert!(reply.is_empty());
 
+        // Temporary bans expire
+        node.drop_by_ip_and_ban(to_ban2, Some(Duration::from_millis(50)))?;
+        assert!(node.is_banned(PersistedBanId::Ip(to_ban2))?);
+        assert_eq!(node.get_banlist()?.len(), 1);
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!node.is_banned(PersistedBanId::Ip(to_ban2))?);
+        assert!(node.get_banlist()?.is_empty());
+
+        stop_node_delete_dirs(dp, node);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_subnet_bans() -> anyhow::Result<()> {
+        let port = next_available_port();
+        let (node, dp) = make_node_and_sync(port, vec![100], PeerType::Node, vec![])?;
+
+        let subnet = "10.1.0.0/16".parse::<PersistedBanId>()?;
+        assert_eq!(subnet.to_string(), "10.1.0.0/16");
+        assert!("10.1.0.0/33".parse::<PersistedBanId>().is_err());
+
+        node.drop_and_ban(subnet, None, None)?;
+        assert_eq!(node.get_banlist()?, vec![subnet]);
+        assert!(node.is_banned(PersistedBanId::Ip("10.1.2.3".parse()?))?);
+        assert!(!node.is_banned(PersistedBanId::Ip("10.2.0.1".parse()?))?);
+        assert!(!node.is_banned(PersistedBanId::Ip("::1".parse()?))?);
+
+        node.unban_node(subnet)?;
+        assert!(!node.is_banned(PersistedBanId::Ip("10.1.2.3".parse()?))?);
+
+        // temporary subnet bans expire, and are then pruned from the store
+        node.drop_and_ban(subnet, Some(Duration::from_millis(50)), None)?;
+        assert!(node.is_banned(PersistedBanId::Ip("10.1.2.3".parse()?))?);
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!node.is_banned(PersistedBanId::Ip("10.1.2.3".parse()?))?);
+        assert_eq!(node.ban_store.entries()?.len(), 1);
+        node.prune_expired_bans()?;
+        assert!(node.ban_store.entries()?.is_empty());
+
+        // subnet bans added to the store externally are picked up by a reload
+        node.ban_store.put(subnet, (0, None))?;
+        node.reload_bans()?;
+        assert!(node.is_banned(PersistedBanId::Ip("10.1.2.3".parse()?))?);
+
+        stop_node_delete_dirs(dp, node);
+
+        Ok(())
+    }
+
+    fn check_ban_store(store: &dyn BanStore) -> anyhow::Result<()> {
+        let ip = PersistedBanId::Ip("10.0.0.1".parse()?);
+        let subnet = "10.1.0.0/16".parse::<PersistedBanId>()?;
+        assert!(store.entries()?.is_empty());
+
+        store.put(ip, (0, None))?;
+        store.put(subnet, (1234, Some("spam".to_owned())))?;
+        assert_eq!(store.get(ip)?, Some((0, None)));
+        assert_eq!(store.get(subnet)?, Some((1234, Some("spam".to_owned()))));
+        assert_eq!(store.entries()?.len(), 2);
+
+        // removing an id without a ban is not an error
+        store.remove(&[ip, PersistedBanId::Ip("10.0.0.2".parse()?)])?;
+        assert_eq!(store.get(ip)?, None);
+        assert_eq!(store.entries()?, vec![(subnet, (1234, Some("spam".to_owned())))]);
+
+        store.clear()?;
+        assert!(store.entries()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ban_stores() -> anyhow::Result<()> {
+        check_ban_store(&MemoryBanStore::default())?;
+        let dir = tempfile::tempdir()?;
+        check_ban_store(&KvsBanStore::new(dir.path())?)
+    }
+
+    #[test]
+    fn test_ban_reasons() -> anyhow::Result<()> {
+        let port = next_available_port();
+        let (node, dp) = make_node_and_sync(port, vec![100], PeerType::Node, vec![])?;
+
+        // the test node listens on 127.0.0.1
+        assert!(node.ban_peer(PersistedBanId::Ip("127.0.0.1".parse()?), None, None).is_err());
+        assert!(node.ban_peer("127.0.0.0/8".parse()?, None, None).is_err());
+        // neither can the addresses of the host's interfaces be banned
+        for ip in P2PNode::interface_addresses() {
+            assert!(node.ban_peer(PersistedBanId::Ip(ip), None, None).is_err());
+        }
+        assert!(node.get_bans()?.is_empty());
+
+        let spammer = PersistedBanId::Ip("10.0.0.1".parse()?);
+        node.ban_peer(spammer, None, Some("spam".to_owned()))?;
+        assert_eq!(node.get_bans()?, vec![BanInfo {
+            id:     spammer,
+            expiry: None,
+            reason: Some("spam".to_owned()),
+        }]);
+
+        let subnet = "10.1.0.0/16".parse::<PersistedBanId>()?;
+        let before = get_current_stamp();
+        node.ban_peer(subnet, Some(Duration::from_secs(60)), None)?;
+        let bans = node.get_bans()?;
+        assert_eq!(bans.len(), 2);
+        let ban = bans.iter().find(|ban| ban.id == subnet).unwrap();
+        assert!(ban.expiry.unwrap() >= before + 60_000);
+        assert_eq!(ban.reason, None);
+        assert!(node.is_banned(PersistedBanId::Ip("10.1.2.3".parse()?))?);
+
+        node.unban_node(spammer)?;
+        assert_eq!(node.get_bans()?, vec![ban.clone()]);
+        node.unban_node(subnet)?;
+        assert!(node.get_bans()?.is_empty());
+
+        stop_node_delete_dirs(dp, node);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reload_bans() -> anyhow::Result<()> {
+        let (node_1, dp_1) =
+            make_node_and_sync(next_available_port(), vec![100], PeerType::Node, vec![])?;
+        let (node_2, dp_2) =
+            make_node_and_sync(next_available_port(), vec![100], PeerType::Node, vec![])?;
+        connect(&node_1, &node_2);
+        await_handshakes(&node_1);
+
+        // nothing to enforce without bans
+        assert_eq!(node_1.reload_bans()?, 0);
+
+        // permanent bans written to the store behind the node's back are only enforced
+        // on reload, and a connection covered by both of them is closed once
+        node_1.ban_store.put(PersistedBanId::Ip("127.0.0.1".parse()?), (0, None))?;
+        node_1.ban_store.put("127.0.0.0/8".parse()?, (0, None))?;
+        assert_eq!(node_1.connections().read().unwrap().len(), 1);
+        assert_eq!(node_1.reload_bans()?, 1);
+
+        stop_node_delete_dirs(dp_1, node_1);
+        stop_node_delete_dirs(dp_2, node_2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_seeded_node_ids() -> anyhow::Result<()> {
+        let addr_1 = "127.0.0.1:8888".parse::<SocketAddr>()?;
+        let addr_2 = "127.0.0.1:8889".parse::<SocketAddr>()?;
+        let id = P2PNodeId::from_seed_and_address(0, addr_1);
+        assert_eq!(id, P2PNodeId::from_seed_and_address(0, addr_1));
+        assert_ne!(id, P2PNodeId::from_seed_and_address(0, addr_2));
+        assert_ne!(id, P2PNodeId::from_seed_and_address(1, addr_1));
+        // the derived id has the format accepted by `--id`
+        assert_eq!(id.to_string().len(), 16);
+        assert_eq!(id.to_string().parse::<P2PNodeId>()?, id);
+
+        // test nodes derive their ids from their addresses
+        let port = next_available_port();
+        let (node, dp) = make_node_and_sync(port, vec![100], PeerType::Node, vec![])?;
+        let addr = SocketAddr::new(node.self_peer.ip(), port);
+        assert_eq!(node.id(), P2PNodeId::from_seed_and_address(0, addr));
+
+        stop_node_delete_dirs(dp, node);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_subnet_peer_locator() -> anyhow::Result<()> {
+        let subnets = "# subnet,region,asn\n10.0.0.0/8,DK\n10.1.0.0/16,DE,AS64512\n\n2001:db8::/\
+                       32,CH,64513\n10.1.2.0/24,SE\n";
+        let locator = subnets.parse::<SubnetPeerLocator>()?;
+        let location = |ip: &str| locator.locate(ip.parse().unwrap());
+
+        assert_eq!(
+            location("10.2.0.1"),
+            Some(PeerLocation {
+                region: "DK".to_owned(),
+                asn:    None,
+            })
+        );
+        // the most specific subnet wins, wherever it is listed
+        assert_eq!(
+            location("10.1.3.4"),
+            Some(PeerLocation {
+                region: "DE".to_owned(),
+                asn:    Some(64512),
+            })
+        );
+        assert_eq!(location("10.1.2.3").map(|l| l.region), Some("SE".to_owned()));
+        assert_eq!(location("2001:db8::1").map(|l| l.asn), Some(Some(64513)));
+        assert_eq!(location("192.168.0.1"), None);
+
+        assert!("10.0.0.0/33,DK".parse::<SubnetPeerLocator>().is_err());
+        assert!("10.0.0.0/8".parse::<SubnetPeerLocator>().is_err());
+        assert!("10.0.0.0/8,DK,ASX".parse::<SubnetPeerLocator>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let secs = Duration::from_secs;
+        // the delay doubles with every failure, up to the maximum
+        assert_eq!(reconnect_backoff(1, 10, 3600, 1.0), secs(10));
+        assert_eq!(reconnect_backoff(2, 10, 3600, 1.0), secs(20));
+        assert_eq!(reconnect_backoff(4, 10, 3600, 1.0), secs(80));
+        assert_eq!(reconnect_backoff(20, 10, 3600, 1.0), secs(3600));
+        assert_eq!(reconnect_backoff(u32::MAX, 10, 3600, 1.0), secs(3600));
+        // the jitter shortens it by up to a half
+        assert_eq!(reconnect_backoff(2, 10, 3600, 0.0), secs(10));
+        assert_eq!(reconnect_backoff(2, 10, 3600, 0.5), secs(15));
+        assert_eq!(reconnect_backoff(20, 10, 3600, 0.0), secs(1800));
+    }
+
+    #[test]
+    fn test_clear_unreachable() -> anyhow::Result<()> {
+        let port = next_available_port();
+        let (node, dp) = make_node_and_sync(port, vec![100], PeerType::Node, vec![])?;
+
+        let unreachable = "127.0.0.1:8888".parse::<SocketAddr>()?;
+        let misbehaving = "10.0.0.1".parse::<IpAddr>()?;
+        {
+            let expiry = Instant::now() + Duration::from_secs(60);
+            let mut soft_bans = write_or_die!(node.connection_handler.soft_bans);
+            soft_bans.insert(BanId::Socket(unreachable), expiry);
+            soft_bans.insert(BanId::Ip(misbehaving), expiry);
+        }
+        assert_eq!(node.get_unreachable(), vec![unreachable]);
+
+        // only the unreachable address is cleared, not the protocol breach
+        assert_eq!(node.clear_unreachable(), vec![unreachable]);
+        assert!(node.get_unreachable().is_empty());
+        assert!(node.connection_handler.is_soft_banned(SocketAddr::new(misbehaving, 8888)));
+
+        // what SIGHUP does: an attempt is scheduled for every cleared address
+        write_or_die!(node.connection_handler.soft_bans)
+            .insert(BanId::Socket(unreachable), Instant::now() + Duration::from_secs(60));
+        assert_eq!(node.retry_unreachable_now(), 1);
+
+        stop_node_delete_dirs(dp, node);
+
+        Ok(())
+    }
+
+    fn relay_candidates(count: usize) -> Vec<(RemotePeerId, u64)> {
+        // the latency decreases with the token
+        (0..count).map(|i| (RemotePeerId::from(i), (count - i) as u64 * 10)).collect()
+    }
+
+    fn sorted_tokens(targets: &[RemotePeerId]) -> Vec<u64> {
+        let mut tokens = targets.iter().copied().map(u64::from).collect::<Vec<_>>();
+        tokens.sort_unstable();
+        tokens
+    }
+
+    fn assert_distinct_candidates(targets: &[RemotePeerId], candidates: &[(RemotePeerId, u64)]) {
+        let mut sorted = sorted_tokens(targets);
+        sorted.dedup();
+        assert_eq!(sorted.len(), targets.len());
+        assert!(targets.iter().all(|target| candidates.iter().any(|(token, _)| token == target)));
+    }
+
+    #[test]
+    fn test_relay_percentage() {
+        let candidates = relay_candidates(10);
+        let rng = &mut thread_rng();
+        let targets = select_relay_targets(RelayStrategy::Percentage, 0.5, &candidates, rng);
+        assert_eq!(targets.len(), 5);
+        assert_distinct_candidates(&targets, &candidates);
+
+        assert!(select_relay_targets(RelayStrategy::Percentage, 0.0, &candidates, rng).is_empty());
+    }
+
+    #[test]
+    fn test_relay_square_root() {
+        let candidates = relay_candidates(16);
+        let rng = &mut thread_rng();
+        let targets = select_relay_targets(RelayStrategy::SquareRoot, 1.0, &candidates, rng);
+        assert_eq!(targets.len(), 4);
+        assert_distinct_candidates(&targets, &candidates);
+
+        // the square root is rounded up
+        let targets = select_relay_targets(RelayStrategy::SquareRoot, 1.0, &candidates[..10], rng);
+        assert_eq!(targets.len(), 4);
+        assert!(select_relay_targets(RelayStrategy::SquareRoot, 1.0, &[], rng).is_empty());
+    }
+
+    #[test]
+    fn test_relay_low_latency() {
+        let candidates = relay_candidates(10);
+        let targets =
+            select_relay_targets(RelayStrategy::LowLatency, 0.3, &candidates, &mut thread_rng());
+        assert_eq!(sorted_tokens(&targets), vec![7, 8, 9]);
+
+        // the peers whose latency wasn't measured yet are picked last
+        let mut candidates = candidates;
+        candidates[9].1 = 0;
+        let targets =
+            select_relay_targets(RelayStrategy::LowLatency, 0.3, &candidates, &mut thread_rng());
+        assert_eq!(sorted_tokens(&targets), vec![6, 7, 8]);
+    }
+
+    #[test]
+    fn test_seeded_relay_selection() {
+        let candidates = relay_candidates(20);
+        let select = |seed| {
+            let rng = &mut StdRng::seed_from_u64(seed);
+            select_relay_targets(RelayStrategy::Percentage, 0.5, &candidates, rng)
+        };
+
+        // the same seed always leads to the same peers
+        assert_eq!(select(42), select(42));
+        assert_eq!(select(42).len(), 10);
+        assert_distinct_candidates(&select(42), &candidates);
+    }
+
+    fn expiry_candidates(uptimes: &[u64]) -> Vec<(RemotePeerId, u64)> {
+        uptimes.iter().enumerate().map(|(i, &uptime)| (RemotePeerId::from(i), uptime)).collect()
+    }
+
+    #[test]
+    fn test_expired_connection_selection() {
+        let candidates = expiry_candidates(&[20, 50, 10, 40, 30]);
+        assert_eq!(select_expired_connection(60, &candidates), None);
+        // the connection that outlived the lifetime by the most is chosen
+        assert_eq!(select_expired_connection(50, &candidates), Some(RemotePeerId::from(1)));
+        assert_eq!(select_expired_connection(10, &candidates), Some(RemotePeerId::from(1)));
+        assert_eq!(select_expired_connection(10, &candidates[2..]), Some(RemotePeerId::from(3)));
+        assert_eq!(select_expired_connection(10, &[]), None);
+    }
+
+    #[test]
+    fn test_replacement_peer_selection() {
+        let known_peer = |id: u8, handshake_complete: bool| RemotePeer {
+            self_id:       if handshake_complete {
+                Some(P2PNodeId(u64::from(id)))
+            } else {
+                None
+            },
+            addr:          SocketAddr::from(([10, 0, 0, id], 40000)),
+            local_id:      RemotePeerId::from(usize::from(id)),
+            external_port: 8888,
+            peer_type:     PeerType::Node,
+        };
+        let known = vec![known_peer(1, true), known_peer(2, false), known_peer(3, true)];
+
+        // the first peer the node isn't connected to is chosen, by its external address
+        let connected = HashSet::new();
+        let replacement = select_replacement_peer(&known, &connected).map(|peer| peer.addr);
+        assert_eq!(replacement, Some(SocketAddr::from(([10, 0, 0, 1], 8888))));
+
+        // peers without a known id are skipped
+        let connected = vec![SocketAddr::from(([10, 0, 0, 1], 8888))].into_iter().collect();
+        let replacement = select_replacement_peer(&known, &connected).map(|peer| peer.addr);
+        assert_eq!(replacement, Some(SocketAddr::from(([10, 0, 0, 3], 8888))));
+
+        let connected = known.iter().map(RemotePeer::external_addr).collect();
+        assert_eq!(select_replacement_peer(&known, &connected), None);
+        assert_eq!(select_replacement_peer(&[], &HashSet::new()), None);
+    }
+
+    #[test]
+    fn test_peer_churn_rate() {
+        assert_eq!(peer_churn_rate(0, 30_000), 0);
+        assert_eq!(peer_churn_rate(3, 30_000), 6);
+        assert_eq!(peer_churn_rate(5, 120_000), 2);
+        // an empty window doesn't divide by zero
+        assert_eq!(peer_churn_rate(5, 0), 0);
+    }
+
+    #[test]
+    fn test_per_second_rate() {
+        assert_eq!(per_second_rate(0, 30_000), 0);
+        assert_eq!(per_second_rate(90, 30_000), 3);
+        assert_eq!(per_second_rate(5, 500), 10);
+        // an empty window doesn't divide by zero
+        assert_eq!(per_second_rate(5, 0), 0);
+    }
+
+    #[test]
+    fn test_ingestion_backlog() {
+        let backlog = IngestionBacklog::default();
+        let (flooding, slow) = (RemotePeerId::from(1usize), RemotePeerId::from(2usize));
+        (0..9).for_each(|_| backlog.record_queued(flooding));
+        backlog.record_queued(slow);
+
+        // nobody is paused while the queue is below the limit
+        assert!(!backlog.is_paused(flooding, 9, 10));
+
+        // only the peer that holds more than an even share of the queue is paused
+        assert!(backlog.is_paused(flooding, 10, 10));
+        assert!(!backlog.is_paused(slow, 10, 10));
+        assert!(!backlog.is_paused(RemotePeerId::from(3usize), 10, 10));
+
+        // the pause ends once enough of the peer's messages are processed
+        (0..5).for_each(|_| backlog.record_processed(flooding));
+        assert!(!backlog.is_paused(flooding, 10, 10));
+
+        // processing more messages than were queued doesn't underflow
+        (0..3).for_each(|_| backlog.record_processed(slow));
+        backlog.record_queued(slow);
+        assert!(!backlog.is_paused(slow, 10, 10));
+    }
+
+    #[test]
+    fn test_accept_throttle() {
+        let start = Instant::now();
+        let mut throttle = AcceptThrottle::new(10, 3, start);
+
+        // a burst is accepted at once, but no more
+        assert!((0..3).all(|_| throttle.try_acquire(start)));
+        assert!(!throttle.try_acquire(start));
+
+        // tokens are replenished at the configured rate
+        assert!(!throttle.try_acquire(start + Duration::from_millis(50)));
+        assert!(throttle.try_acquire(start + Duration::from_millis(150)));
+        assert!(!throttle.try_acquire(start + Duration::from_millis(150)));
+
+        // but never beyond the burst
+        let later = start + Duration::from_secs(10);
+        assert_eq!((0..10).filter(|_| throttle.try_acquire(later)).count(), 3);
+    }
+
+    #[test]
+    fn test_send_throttle() {
+        let start = Instant::now();
+        let throttle = SendThrottle::new(Some(1000), start);
+        let mut share = SendShare::new(start);
+        throttle.set_sending(true);
+
+        // at most a second's worth of bytes can be sent at once
+        let later = start + Duration::from_secs(5);
+        assert_eq!(throttle.take(&mut share, 4096, later), 1000);
+        assert_eq!(throttle.take(&mut share, 4096, later), 0);
+        // and bytes that couldn't be written are returned to the allowance
+        throttle.record(&mut share, 600, 1000);
+        assert_eq!(throttle.take(&mut share, 4096, later), 400);
+
+        // the average rate stays under the cap over a longer window
+        let mut sent = 0;
+        for step in 1..=100 {
+            let now = later + Duration::from_millis(step * 100);
+            let taken = throttle.take(&mut share, 4096, now);
+            throttle.record(&mut share, taken, taken);
+            sent += taken;
+        }
+        assert!(sent <= 10 * 1000);
+        assert!(sent >= 9 * 1000);
+
+        // without a cap everything can be sent
+        let unlimited = SendThrottle::new(None, start);
+        assert_eq!(unlimited.take(&mut SendShare::new(start), 4096, start), 4096);
+    }
+
+    #[test]
+    fn test_send_throttle_fairness() {
+        let start = Instant::now();
+        let throttle = SendThrottle::new(Some(1000), start);
+        let mut shares: Vec<_> = (0..4).map(|_| SendShare::new(start)).collect();
+        shares.iter().for_each(|_| throttle.set_sending(true));
+
+        // the connections flushed first in each round can't starve the others
+        let mut sent = vec![0; shares.len()];
+        for step in 1..=100 {
+            let now = start + Duration::from_millis(step * 100);
+            for (share, sent) in shares.iter_mut().zip(sent.iter_mut()) {
+                let taken = throttle.take(share, 4096, now);
+                throttle.record(share, taken, taken);
+                *sent += taken;
+            }
+        }
+        assert!(sent.iter().all(|&sent| sent >= 9 * 250 && sent <= 10 * 250));
+
+        // the rate is split only among the connections that are sending
+        (0..3).for_each(|_| throttle.set_sending(false));
+        let later = start + Duration::from_secs(20);
+        assert_eq!(throttle.take(&mut shares[0], 4096, later), 1000);
+    }
+
+    #[test]
+    fn test_recently_relayed() {
+        let start = Instant::now();
+        let window = Duration::from_secs(1);
+        let relayed = RecentlyRelayed::default();
+
+        // identical copies are only relayed once within the window
+        assert!(relayed.insert(b"block", window, start));
+        assert!(!relayed.insert(b"block", window, start + Duration::from_millis(500)));
+        assert!(relayed.contains(b"block", start + Duration::from_millis(500)));
+        assert!(relayed.insert(b"transaction", window, start));
+
+        // but again once it has passed
+        let later = start + Duration::from_millis(1500);
+        assert!(!relayed.contains(b"block", later));
+        relayed.prune(later);
+        assert!(relayed.insert(b"block", window, later));
+    }
+
+    #[test]
+    fn test_recent_handshakes() {
+        let start = Instant::now();
+        let window = Duration::from_secs(1);
+        let handshakes = RecentHandshakes::new(2);
+        let (key_1, key_2) = ([1u8; 32], [2u8; 32]);
+        let (nonce_1, nonce_2) = ([1u8; 32], [2u8; 32]);
+
+        // a replayed nonce is rejected within the window
+        assert_eq!(handshakes.insert(key_1, nonce_1, window, start), Ok(()));
+        let replayed = handshakes.insert(key_1, nonce_1, window, start + window / 2);
+        assert_eq!(replayed, Err(NonceRejection::Replayed));
+        // but the same nonce used by another peer isn't a replay
+        assert_eq!(handshakes.insert(key_2, nonce_1, window, start), Ok(()));
+
+        // once the capacity is reached, no nonce is forgotten before its window
+        // has passed, so new ones are rejected instead
+        assert_eq!(handshakes.insert(key_1, nonce_2, window, start), Err(NonceRejection::Full));
+        let replayed = handshakes.insert(key_1, nonce_1, window, start);
+        assert_eq!(replayed, Err(NonceRejection::Replayed));
+        assert_eq!(handshakes.len(), 2);
+
+        // all of them are forgotten once the window has passed
+        let later = start + Duration::from_millis(1500);
+        handshakes.prune(later);
+        assert!(handshakes.is_empty());
+        assert_eq!(handshakes.insert(key_1, nonce_2, window, later), Ok(()));
+    }
+
+    #[test]
+    fn test_queue_breaker() {
+        let start = Instant::now();
+        let threshold = Duration::from_secs(1);
+        let cooldown = Duration::from_secs(5);
+        let breaker = QueueBreaker::default();
+        let (peer_1, peer_2) = (RemotePeerId::from(1usize), RemotePeerId::from(2usize));
+
+        // drops shortly after the queue filled up don't trip the breaker
+        assert!(!breaker.record_drop(peer_1, threshold, cooldown, start));
+        assert!(!breaker.record_drop(peer_1, threshold, cooldown, start + threshold / 2));
+        assert!(!breaker.is_paused(peer_1, start + threshold / 2));
+
+        // and neither do they once the queue has accepted a message again
+        breaker.record_accept();
+        assert!(!breaker.record_drop(peer_1, threshold, cooldown, start + threshold));
+
+        // but a queue that stays full trips it for the peers whose messages are dropped
+        let later = start + threshold * 2;
+        assert!(breaker.record_drop(peer_1, threshold, cooldown, later));
+        assert!(!breaker.record_drop(peer_1, threshold, cooldown, later));
+        assert!(breaker.is_paused(peer_1, later));
+        assert!(!breaker.is_paused(peer_2, later));
+        assert!(breaker.prune(later));
+
+        // until the cooldown passes
+        let resumed = later + cooldown;
+        assert!(!breaker.is_paused(peer_1, resumed));
+        assert!(!breaker.prune(resumed));
+    }
+
+    #[test]
+    fn test_thread_pool_size() -> anyhow::Result<()> {
+        assert_eq!("auto".parse::<ThreadPoolSize>()?, ThreadPoolSize::Auto);
+        assert_eq!("3".parse::<ThreadPoolSize>()?, ThreadPoolSize::Fixed(3));
+        assert!("0".parse::<ThreadPoolSize>().is_err());
+        assert!("many".parse::<ThreadPoolSize>().is_err());
+
+        assert_eq!(effective_thread_pool_size(ThreadPoolSize::Fixed(3), Some(64)), 3);
+        // some cores are left to the poll loop and consensus
+        assert_eq!(effective_thread_pool_size(ThreadPoolSize::Auto, Some(16)), 14);
+        // but there is always at least one socket thread
+        assert_eq!(effective_thread_pool_size(ThreadPoolSize::Auto, Some(2)), 1);
+        assert!(effective_thread_pool_size(ThreadPoolSize::Auto, None) >= 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_own_ip_selection() -> anyhow::Result<()> {
+        for unsuitable in &["::1", "::", "fe80::1", "fd00::1", "ff02::1", "127.0.0.1"] {
+            assert_eq!(get_ip_if_suitable(&unsuitable.parse()?), None);
+        }
+
+        let ipv4 = "10.1.2.3".parse::<IpAddr>()?;
+        let ipv6 = "2001:db8::1".parse::<IpAddr>()?;
+        let link_local = "fe80::1".parse::<IpAddr>()?;
+        let addresses = [ipv4, link_local, ipv6];
+        assert_eq!(select_own_ip(&addresses, false), Some(ipv4));
+        assert_eq!(select_own_ip(&addresses, true), Some(ipv6));
+        // an IPv6-only host can discover its address
+        assert_eq!(select_own_ip(&[link_local, ipv6], false), Some(ipv6));
+        assert_eq!(select_own_ip(&[link_local], false), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bootstrapper_probe() -> anyhow::Result<()> {
+        let port = next_available_port();
+        let (node, dp) = make_node_and_sync(port, vec![100], PeerType::Node, vec![])?;
+        let timeout = Duration::from_millis(500);
+
+        let closed = SocketAddr::new(IpAddr::from([127, 0, 0, 1]), next_available_port());
+        let addrs = [node.internal_addr(), closed];
+        assert_eq!(probe_bootstrappers(&node, &addrs, timeout)?, vec![true, false]);
+        // both results are cached for the following attempts
+        assert_eq!(node.connection_handler.bootstrap_probes.lock().unwrap().len(), 2);
+        assert_eq!(probe_bootstrappers(&node, &[closed], Duration::from_millis(0))?, vec![false]);
+
         stop_node_delete_dirs(dp, node);
 
         Ok(())
@@ -1,16 +1,19 @@
 //! Peer handling.
 
 use crate::{
-    common::{get_current_stamp, p2p_peer::RemotePeerId, PeerStats, PeerType},
-    connection::Connection,
-    netmsg,
+    common::{get_current_stamp, p2p_peer::RemotePeerId, ConnectionInfo, PeerStats, PeerType},
+    connection::{Connection, MessageKind},
+    lock_or_die, netmsg,
     network::NetworkRequest,
     p2p::{maintenance::attempt_bootstrap, P2PNode},
     read_or_die,
 };
-use anyhow::ensure;
+use anyhow::{bail, ensure};
 use chrono::Utc;
-use std::sync::{atomic::Ordering, Arc};
+use std::{
+    convert::TryFrom,
+    sync::{atomic::Ordering, Arc},
+};
 
 impl P2PNode {
     /// Obtain the list of statistics from all the peers, optionally of a
@@ -20,30 +23,61 @@ impl P2PNode {
             .values()
             .filter(|conn| peer_type.is_none() || peer_type == Some(conn.remote_peer_type()))
             .map(|conn| {
-                PeerStats::new(
-                    conn.remote_peer.local_id,
-                    conn.remote_peer.self_id.unwrap(), // safe - always available post-handshake
-                    conn.remote_addr(),
-                    conn.remote_peer_external_port(),
-                    conn.remote_peer_type(),
-                    &conn.stats,
-                )
+                // safe - always available post-handshake
+                PeerStats::new(conn.remote_peer.self_id.unwrap(), conn)
             })
             .collect()
     }
 
+    /// Obtain the live state of every connection, including the ones that
+    /// haven't completed the handshake yet.
+    pub fn get_connection_info(&self) -> Vec<ConnectionInfo> {
+        let mut info =
+            read_or_die!(self.connections()).values().map(connection_info).collect::<Vec<_>>();
+        info.extend(lock_or_die!(self.conn_candidates()).values().map(connection_info));
+        info
+    }
+
+    /// Log the live state of the node, i.e., of every connection, including
+    /// the ones that haven't completed the handshake yet, the addresses
+    /// considered unreachable and the routing table.
+    pub fn log_state(&self) {
+        let connections = self.get_connection_info();
+        info!("The node has {} connection(s)", connections.len());
+        for conn in connections {
+            info!("{:?}", conn);
+        }
+        info!("Unreachable addresses: {:?}", self.get_unreachable());
+        match self.routing_table_json() {
+            Ok(table) => info!("Routing table: {}", table),
+            Err(e) => warn!("Can't describe the routing table: {}", e),
+        }
+    }
+
     /// Prints information about all the peers.
     pub fn print_stats(&self, peer_stat_list: &[PeerStats]) {
         for (i, peer) in peer_stat_list.iter().enumerate() {
             trace!(
-                "Peer {}({}): {}/{}/{}",
+                "Peer {}({}): {}/{}/{} v{}, up {}s (discovered via {})",
                 i,
                 peer.self_id,
                 peer.local_id,
                 peer.addr,
-                peer.peer_type
+                peer.peer_type,
+                peer.node_version,
+                peer.uptime / 1000,
+                peer.discovery_source
             );
         }
+
+        let (long_hits, long_misses, short_hits, short_misses) = self.stats.get_dedup_stats();
+        trace!(
+            "Deduplication hits/misses: {}/{} in the long queues, {}/{} in the short queues",
+            long_hits,
+            long_misses,
+            short_hits,
+            short_misses
+        );
     }
 
     /// Obtain the node ids of all the node peers.
@@ -51,6 +85,17 @@ impl P2PNode {
         self.get_peer_stats(Some(PeerType::Node)).into_iter().map(|stats| stats.local_id).collect()
     }
 
+    /// Update the statistics of the bytes waiting in the output queues of the
+    /// given peers. A growing total means the node can't keep up with the
+    /// volume it sends, while a large maximum can point to a single stuck peer.
+    pub fn measure_output_queue_backlog(&self, peer_stats: &[PeerStats]) {
+        let backlogs = peer_stats.iter().map(|ps| ps.output_queue_bytes);
+        let total = backlogs.clone().fold(0u64, |acc, bytes| acc.saturating_add(bytes));
+        let min = backlogs.clone().min().unwrap_or(0);
+        let max = backlogs.max().unwrap_or(0);
+        self.stats.set_output_queue_backlog(total, min, max);
+    }
+
     /// Measures the node's average byte throughput as bps i.e., bytes per
     /// second.
     pub fn measure_throughput(&self, peer_stats: &[PeerStats]) -> anyhow::Result<()> {
@@ -61,23 +106,28 @@ impl P2PNode {
             .iter()
             .filter(|ps| ps.peer_type == PeerType::Node)
             .map(|ps| (ps.bytes_received, ps.bytes_sent))
-            .fold((0, 0), |(acc_i, acc_o), (i, o)| (acc_i + i, acc_o + o));
+            .fold((0u64, 0u64), |(acc_i, acc_o), (i, o)| {
+                (acc_i.saturating_add(i), acc_o.saturating_add(o))
+            });
 
         self.stats.set_bytes_received(bytes_received);
         self.stats.set_bytes_sent(bytes_sent);
 
         let now = Utc::now().timestamp_millis();
-        let (avg_bps_in, avg_bps_out) = calculate_average_throughput(
+        let throughput = calculate_average_throughput(
             self.stats.get_last_throughput_measurement_timestamp(),
             now,
             prev_bytes_received,
             bytes_received,
             prev_bytes_sent,
             bytes_sent,
-        )?;
+        );
+        // The timestamp is updated even if the calculation failed, so that after a
+        // counter reset the next measurement starts from a consistent baseline.
+        self.stats.set_last_throughput_measurement_timestamp(now);
+        let (avg_bps_in, avg_bps_out) = throughput?;
         self.stats.set_avg_bps_in(avg_bps_in);
         self.stats.set_avg_bps_out(avg_bps_out);
-        self.stats.set_last_throughput_measurement_timestamp(now);
         Ok(())
     }
 
@@ -92,7 +142,7 @@ impl P2PNode {
         if let Err(e) = message
             .serialize(&mut buf)
             .map(|_| buf)
-            .map(|buf| self.send_over_all_connections(&buf, &filter))
+            .map(|buf| self.send_over_all_connections(&buf, MessageKind::PeerList, &filter))
         {
             error!("Can't send a GetPeers request: {}", e);
         }
@@ -103,6 +153,22 @@ impl P2PNode {
         self.connection_handler.last_peer_update.store(get_current_stamp(), Ordering::SeqCst)
     }
 
+    /// Record that a connection was promoted to a peer and bump the timestamp
+    /// of the last peer update.
+    pub fn register_peer_established(&self) {
+        self.connection_handler.peers_established.fetch_add(1, Ordering::Relaxed);
+        self.stats.peers_established_inc();
+        self.bump_last_peer_update();
+    }
+
+    /// Record that `count` peers were dropped and bump the timestamp of the
+    /// last peer update.
+    pub fn register_peers_dropped(&self, count: u64) {
+        self.connection_handler.peers_dropped.fetch_add(count, Ordering::Relaxed);
+        self.stats.peers_dropped_inc(count);
+        self.bump_last_peer_update();
+    }
+
     /// Obtain the timestamp of the last peer update.
     pub fn last_peer_update(&self) -> u64 {
         self.connection_handler.last_peer_update.load(Ordering::SeqCst)
@@ -130,7 +196,11 @@ pub fn check_peers(node: &Arc<P2PNode>, peer_stats: &[PeerStats], attempted_boot
         if !node.config.no_net && node_count < node.config.desired_nodes_count as usize {
             if peer_stats.is_empty() {
                 if !attempted_bootstrap {
-                    if !node.config.no_bootstrap_dns {
+                    let next_bootstrap =
+                        node.connection_handler.next_bootstrap.load(Ordering::Relaxed);
+                    if get_current_stamp() < next_bootstrap {
+                        debug!("No peers at all - bootstrapping is backed off");
+                    } else if !node.config.no_bootstrap_dns {
                         info!("No peers at all - retrying bootstrapping");
                         attempt_bootstrap(node);
                     } else {
@@ -150,7 +220,7 @@ pub fn check_peers(node: &Arc<P2PNode>, peer_stats: &[PeerStats], attempted_boot
 
 /// Calculate the average bytes bps (Bytes per second) received and sent during
 /// the time `delta` (specified in milliseconds).
-fn calculate_average_throughput(
+pub(crate) fn calculate_average_throughput(
     before_millis: i64,   // timestamp of the last measurement
     now_millis: i64,      // timestamp of the current measurement
     prev_bytes_recv: u64, // number of bytes received at the time of previous measurement
@@ -158,28 +228,64 @@ fn calculate_average_throughput(
     prev_bytes_sent: u64, // number of bytes sent at the time of previous measurement
     bytes_sent: u64,      // number of bytes sent at the the time of current measurement
 ) -> anyhow::Result<(u64, u64)> {
-    let milliseconds_to_second = 1000u64;
+    let milliseconds_to_second = 1000u128;
     ensure!(
         now_millis > before_millis,
         "Time went backwards or did not change. Refusing to calculate average throughput."
     );
-    let delta: u64 = (now_millis - before_millis) as u64; // as is safe since we checked the difference is positive.
+    // The subtraction can only overflow if the timestamps are at the opposite ends
+    // of the range of i64, in which case the measurement is meaningless anyway.
+    let delta = match now_millis.checked_sub(before_millis) {
+        Some(delta) => u128::from(delta as u64), // as is safe since the difference is positive.
+        None => bail!("Time difference is too large. Refusing to calculate average throughput."),
+    };
 
-    ensure!(
-        bytes_recv >= prev_bytes_recv,
-        "Received bytes were lost. Refusing to calculate average throughput."
-    );
-    let avg_bps_in = (milliseconds_to_second * (bytes_recv - prev_bytes_recv)) / delta;
+    // A counter that went down was either reset or wrapped around, so the
+    // difference does not describe the traffic that happened in the meantime.
+    let recv_diff = match bytes_recv.checked_sub(prev_bytes_recv) {
+        Some(diff) => u128::from(diff),
+        None => bail!("Received bytes were lost. Refusing to calculate average throughput."),
+    };
+    // The multiplication is done in u128 so it cannot overflow, and the result
+    // is saturated in the unlikely case it does not fit in a u64.
+    let avg_bps_in = u64::try_from(milliseconds_to_second * recv_diff / delta).unwrap_or(u64::MAX);
 
-    ensure!(
-        bytes_sent >= prev_bytes_sent,
-        "Sent bytes were lost. Refusing to calculate average throughput."
-    );
-    let avg_bps_out = (milliseconds_to_second * (bytes_sent - prev_bytes_sent)) / delta;
+    let sent_diff = match bytes_sent.checked_sub(prev_bytes_sent) {
+        Some(diff) => u128::from(diff),
+        None => bail!("Sent bytes were lost. Refusing to calculate average throughput."),
+    };
+    let avg_bps_out = u64::try_from(milliseconds_to_second * sent_diff / delta).unwrap_or(u64::MAX);
 
     Ok((avg_bps_in, avg_bps_out))
 }
 
+fn connection_info(conn: &Connection) -> ConnectionInfo {
+    ConnectionInfo {
+        local_id:           conn.remote_peer.local_id,
+        remote_id:          conn.remote_id(),
+        addr:               conn.remote_addr(),
+        peer_type:          conn.remote_peer_type(),
+        discovery_source:   conn.discovery_source,
+        handshake_complete: conn.remote_id().is_some(),
+        created:            conn.stats.created,
+        last_seen:          conn.last_seen(),
+        latency:            conn.get_latency(),
+        msgs_sent:          conn.stats.messages_sent.load(Ordering::Relaxed),
+        msgs_received:      conn.stats.messages_received.load(Ordering::Relaxed),
+        bytes_sent:         conn.stats.bytes_sent.load(Ordering::Relaxed),
+        bytes_received:     conn.stats.bytes_received.load(Ordering::Relaxed),
+        output_queue_bytes: conn.output_queue_bytes() as u64,
+        node_version:       conn.remote_version.clone(),
+        uptime:             if conn.remote_id().is_some() {
+            get_current_stamp().saturating_sub(conn.handshake_completed)
+        } else {
+            0
+        },
+        networks:           conn.remote_end_networks.clone(),
+        location:           conn.location.location().cloned(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -214,4 +320,38 @@ mod tests {
             "Sent bytes were lost. Refusing to calculate average throughput."
         );
     }
+
+    #[test]
+    fn test_average_throughput_counter_reset() {
+        // A reset of the counters must not be reported as a (wrapped) huge throughput.
+        assert!(
+            calculate_average_throughput(1, 1001, u64::MAX, 0, 0, 0).is_err(),
+            "Received counter was reset. Refusing to calculate average throughput."
+        );
+        assert!(
+            calculate_average_throughput(1, 1001, 0, 0, u64::MAX, 0).is_err(),
+            "Sent counter was reset. Refusing to calculate average throughput."
+        );
+    }
+
+    #[test]
+    fn test_average_throughput_overflow() {
+        // The multiplication by 1000 would overflow a u64, but the result fits.
+        let (recv, send) = calculate_average_throughput(0, 1000, 0, u64::MAX, 0, u64::MAX / 2)
+            .expect("Calculation should not overflow.");
+        assert_eq!(u64::MAX, recv);
+        assert_eq!(u64::MAX / 2, send);
+
+        // The result does not fit in a u64 and is saturated.
+        let (recv, send) = calculate_average_throughput(0, 1, 0, u64::MAX, 0, u64::MAX)
+            .expect("Calculation should not overflow.");
+        assert_eq!(u64::MAX, recv);
+        assert_eq!(u64::MAX, send);
+
+        // The time difference does not fit in an i64.
+        assert!(
+            calculate_average_throughput(i64::MIN, i64::MAX, 0, 1, 0, 1).is_err(),
+            "Calculation should fail since the time difference overflows."
+        );
+    }
 }
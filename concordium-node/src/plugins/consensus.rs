@@ -19,7 +19,9 @@ use crate::{
         messaging::{ConsensusMessage, DistributionMode, MessageType},
     },
     p2p::{
-        connectivity::{send_broadcast_message, send_direct_message},
+        connectivity::{
+            relay_broadcast_message, send_broadcast_message, send_direct_message, QueueBreaker,
+        },
         P2PNode,
     },
     read_or_die, write_or_die,
@@ -32,7 +34,8 @@ use std::{
     fs::OpenOptions,
     io::{Cursor, Read},
     path::Path,
-    sync::{Arc, RwLock},
+    sync::{atomic::Ordering, Arc, RwLock},
+    time::{Duration, Instant},
 };
 
 const FILE_NAME_GENESIS_DATA: &str = "genesis.dat";
@@ -90,8 +93,17 @@ pub fn get_baker_data(
     app_prefs: &configuration::AppPreferences,
     conf: &configuration::BakerConfig,
 ) -> anyhow::Result<(Vec<u8>, Option<Vec<u8>>)> {
-    let mut genesis_loc = app_prefs.get_user_app_dir().to_path_buf();
-    genesis_loc.push(FILE_NAME_GENESIS_DATA);
+    let genesis_loc = if let Some(path) = &conf.genesis_data_file {
+        ensure!(
+            path.is_file(),
+            "The genesis data file {} set by --genesis-data-file does not exist",
+            path.display()
+        );
+        path.to_owned()
+    } else {
+        app_prefs.get_user_app_dir().join(FILE_NAME_GENESIS_DATA)
+    };
+    info!("Reading the genesis data from {}", genesis_loc.display());
 
     let genesis_data = match OpenOptions::new().read(true).open(&genesis_loc) {
         Ok(mut file) => {
@@ -105,6 +117,12 @@ pub fn get_baker_data(
     };
 
     let private_data = if let Some(path) = &conf.baker_credentials_file {
+        ensure!(
+            path.is_file(),
+            "The baker credentials file {} set by --baker-credentials-file does not exist",
+            path.display()
+        );
+        info!("Reading the baker credentials from {}", path.display());
         let read_data = match std::fs::read(&path) {
             Ok(read_data) => read_data,
             Err(e) => bail!("Can't open the baker credentials file ({})!", e),
@@ -138,6 +156,7 @@ pub fn handle_pkt_out(
     peer_id: RemotePeerId, // id of the peer that sent the message.
     msg: Vec<u8>,
     is_broadcast: bool,
+    hop_limit: u8,
 ) -> anyhow::Result<()> {
     ensure!(!msg.is_empty(), "Packet payload can't be empty");
     let consensus_type = u8::deserial(&mut Cursor::new(&msg[..1]))?;
@@ -151,13 +170,16 @@ pub fn handle_pkt_out(
     // length of the actual payload. The message has a 1-byte tag prepended to it.
     let payload_len = msg[1..].len();
 
-    let request = ConsensusMessage::new(
+    let mut request = ConsensusMessage::new(
         MessageType::Inbound(peer_id, distribution_mode),
         packet_type,
         Arc::from(msg),
         dont_relay_to,
         None,
     );
+    if is_broadcast {
+        request.hop_limit = Some(hop_limit);
+    }
 
     if packet_type == PacketType::Transaction {
         if payload_len > configuration::PROTOCOL_MAX_TRANSACTION_SIZE {
@@ -166,11 +188,19 @@ pub fn handle_pkt_out(
                 configuration::PROTOCOL_MAX_TRANSACTION_SIZE
             )
         }
+        // the transactions would pile up in consensus' transaction table
+        if node.connection_handler.memory_pressure.load(Ordering::Relaxed) {
+            trace!("Dropping a transaction due to the memory usage");
+            node.stats.transactions_shed_inc();
+            return Ok(());
+        }
+        let breaker = &node.connection_handler.low_queue_breaker;
         if let Err(e) = CALLBACK_QUEUE.send_in_low_priority_message(request) {
             match e.downcast::<TrySendError<QueueMsg<ConsensusMessage>>>()? {
                 TrySendError::Full(_) => {
                     node.stats.inbound_low_priority_consensus_drops_inc();
                     node.bad_events.inc_dropped_low_queue(peer_id);
+                    update_queue_breaker(node, breaker, peer_id, true);
                 }
                 TrySendError::Disconnected(_) => {
                     panic!("Low priority consensus queue has been shutdown!")
@@ -178,14 +208,17 @@ pub fn handle_pkt_out(
             }
         } else {
             node.stats.inbound_low_priority_consensus_inc();
+            update_queue_breaker(node, breaker, peer_id, false);
         }
     } else {
         // high priority message
+        let breaker = &node.connection_handler.high_queue_breaker;
         if let Err(e) = CALLBACK_QUEUE.send_in_high_priority_message(request) {
             match e.downcast::<TrySendError<QueueMsg<ConsensusMessage>>>()? {
                 TrySendError::Full(_) => {
                     node.stats.inbound_high_priority_consensus_drops_inc();
                     node.bad_events.inc_dropped_high_queue(peer_id);
+                    update_queue_breaker(node, breaker, peer_id, true);
                 }
                 TrySendError::Disconnected(_) => {
                     panic!("High priority consensus queue has been shutdown!")
@@ -193,12 +226,45 @@ pub fn handle_pkt_out(
             }
         } else {
             node.stats.inbound_high_priority_consensus_inc();
+            update_queue_breaker(node, breaker, peer_id, false);
+            if node.config.ingestion_pause_queue_size.is_some() {
+                node.connection_handler.ingestion_backlog.record_queued(peer_id);
+            }
         }
     }
 
     Ok(())
 }
 
+/// Let the circuit breaker of an inbound consensus queue, if enabled, know
+/// whether a message from the given peer was dropped because the queue was
+/// full.
+fn update_queue_breaker(
+    node: &P2PNode,
+    breaker: &QueueBreaker,
+    peer_id: RemotePeerId,
+    dropped: bool,
+) {
+    if let Some(threshold) = node.config.queue_breaker_threshold {
+        if !dropped {
+            breaker.record_accept();
+        } else if breaker.record_drop(
+            peer_id,
+            Duration::from_millis(threshold),
+            Duration::from_millis(node.config.queue_breaker_cooldown),
+            Instant::now(),
+        ) {
+            warn!(
+                "An inbound consensus queue has been full for over {} ms; pausing reads from peer \
+                 {}",
+                threshold, peer_id
+            );
+            node.stats.queue_breaker_trips_inc();
+            node.stats.set_queue_breaker_open(1);
+        }
+    }
+}
+
 /// Routes a self-made consensus message to the right peers.
 pub fn handle_consensus_outbound_msg(
     node: &P2PNode,
@@ -215,6 +281,7 @@ pub fn handle_consensus_outbound_msg(
                 node,
                 Vec::new(),
                 Some(peer),
+                None,
                 (message.payload.clone(), message.variant),
             );
         }
@@ -223,6 +290,7 @@ pub fn handle_consensus_outbound_msg(
             node,
             message.dont_relay_to(),
             message.target_peer(),
+            None,
             (message.payload, message.variant),
         );
     }
@@ -250,8 +318,26 @@ pub fn handle_consensus_inbound_msg(
         _ => false,
     };
 
+    // Under load, some transactions are not rebroadcast so that the node
+    // sheds the least critical traffic first.
+    let drop_message = if !drop_message
+        && request.variant == Transaction
+        && request.distribution_mode() == DistributionMode::Broadcast
+        && should_shed_transaction(node)?
+    {
+        trace!("Shedding the rebroadcast of a transaction");
+        node.stats.transactions_shed_inc();
+        true
+    } else {
+        drop_message
+    };
+
     let source = request.source_peer();
 
+    if node.config.ingestion_pause_queue_size.is_some() && request.variant != Transaction {
+        node.connection_handler.ingestion_backlog.record_processed(source);
+    }
+
     if node.config.no_rebroadcast_consensus_validation {
         if !drop_message
             && request.distribution_mode() == DistributionMode::Broadcast
@@ -261,6 +347,7 @@ pub fn handle_consensus_inbound_msg(
                 &node,
                 request.dont_relay_to(),
                 None,
+                request.hop_limit,
                 (request.payload.clone(), request.variant),
             );
         }
@@ -293,6 +380,7 @@ pub fn handle_consensus_inbound_msg(
                 &node,
                 request.dont_relay_to(),
                 None,
+                request.hop_limit,
                 (request.payload, request.variant),
             );
         }
@@ -301,6 +389,20 @@ pub fn handle_consensus_inbound_msg(
     Ok(())
 }
 
+/// Decide whether to shed the rebroadcast of a transaction, which happens with
+/// the configured probability while the low priority inbound consensus queue
+/// is above the configured size.
+fn should_shed_transaction(node: &P2PNode) -> anyhow::Result<bool> {
+    match node.config.transaction_shedding_queue_size {
+        Some(limit) if CALLBACK_QUEUE.inbound.sender_low_priority.len() >= limit => {
+            use rand::distributions::{Bernoulli, Distribution};
+            let shedding = Bernoulli::new(node.config.transaction_shedding_probability)?;
+            Ok(shedding.sample(&mut rand::thread_rng()))
+        }
+        _ => Ok(false),
+    }
+}
+
 fn send_msg_to_consensus(
     node: &P2PNode,
     source_id: RemotePeerId,
@@ -321,6 +423,18 @@ fn send_msg_to_consensus(
 
     if consensus_response.is_acceptable() {
         debug!("Processed a {} from {}", message.variant, source_id);
+        let handler = &node.connection_handler;
+        match message.variant {
+            Block => {
+                node.stats.consensus_blocks_processed_inc();
+                handler.blocks_processed.fetch_add(1, Ordering::Relaxed);
+            }
+            FinalizationMessage | FinalizationRecord => {
+                node.stats.consensus_finalizations_processed_inc();
+                handler.fin_msgs_processed.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
     } else {
         let num_bad_events = node.bad_events.inc_invalid_messages(source_id);
         // we do log some invalid messages to both ease debugging and see problems in
@@ -337,10 +451,20 @@ fn send_consensus_msg_to_net(
     node: &P2PNode,
     dont_relay_to: Vec<RemotePeerId>,
     target_id: Option<RemotePeerId>,
+    hop_limit: Option<u8>,
     (payload, msg_desc): (Arc<[u8]>, PacketType),
 ) {
     let sent = if let Some(target_id) = target_id {
         send_direct_message(node, target_id, node.config.default_network, payload)
+    } else if let Some(hop_limit) = hop_limit {
+        // a relayed broadcast keeps the hop limit it was received with
+        relay_broadcast_message(
+            node,
+            dont_relay_to.into_iter().collect(),
+            node.config.default_network,
+            payload,
+            hop_limit,
+        )
     } else {
         send_broadcast_message(
             node,
@@ -443,6 +567,36 @@ pub fn check_peer_states(node: &P2PNode, consensus: &ConsensusContainer) {
     } else {
         try_catch_up(node, consensus, &mut write_or_die!(node.peers));
     }
+    report_catch_up_progress(node, &read_or_die!(node.peers), now);
+}
+
+/// Export the consensus status next to the network statistics: whether the
+/// baker is running and the heights of the best and the last finalized block.
+pub fn update_consensus_stats(node: &P2PNode, consensus: &ConsensusContainer) {
+    if let Err(e) =
+        node.stats.set_consensus_status(consensus.is_baking(), &consensus.get_consensus_status())
+    {
+        warn!("Can't parse the consensus status: {}", e);
+    }
+}
+
+/// Export the number of peers in each catch-up state and the time since the
+/// last catch-up attempt, so that a node stuck catching up can be detected.
+fn report_catch_up_progress(node: &P2PNode, peers: &PeerList, now: u64) {
+    let (mut pending, mut catching_up, mut up_to_date) = (0, 0, 0);
+    for status in peers.peer_states.values() {
+        match status {
+            PeerStatus::Pending => pending += 1,
+            PeerStatus::CatchingUp => catching_up += 1,
+            PeerStatus::UpToDate => up_to_date += 1,
+        }
+    }
+    let stamp_age = if peers.catch_up_stamp > 0 {
+        now.saturating_sub(peers.catch_up_stamp)
+    } else {
+        0
+    };
+    node.stats.set_catch_up_progress(pending, catching_up, up_to_date, stamp_age);
 }
 
 fn update_peer_states(
@@ -524,6 +678,7 @@ fn update_peer_states(
                         node,
                         Vec::new(),
                         Some(non_pending_peer),
+                        None,
                         (request.payload.clone(), request.variant),
                     );
                 }
@@ -541,4 +696,5 @@ fn update_peer_states(
             _ => {}
         }
     }
+    report_catch_up_progress(node, &peers, get_current_stamp());
 }
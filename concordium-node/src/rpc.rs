@@ -26,12 +26,22 @@ use std::{
 };
 use tonic::{transport::Server, Code, Request, Response, Status};
 
+/// Where the gRPC server listens for connections.
+#[derive(Clone, Debug)]
+enum ListenAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+}
+
 /// The object used to initiate a gRPC server.
 #[derive(Clone)]
 pub struct RpcServerImpl {
     node:         Arc<P2PNode>,
-    listen_addr:  SocketAddr,
-    access_token: String,
+    listen_addr:  ListenAddr,
+    /// The token requests must be authenticated with; `None` if access is
+    /// controlled otherwise, i.e., by the permissions of a Unix socket.
+    access_token: Option<String>,
     // this field is optional only for test purposes
     consensus:    Option<ConsensusContainer>,
 }
@@ -43,13 +53,25 @@ impl RpcServerImpl {
         consensus: Option<ConsensusContainer>,
         conf: &configuration::RpcCliConfig,
     ) -> anyhow::Result<Self> {
-        let listen_addr =
-            SocketAddr::from((IpAddr::from_str(&conf.rpc_server_addr)?, conf.rpc_server_port));
+        let (listen_addr, access_token) = match conf.rpc_server_socket {
+            #[cfg(unix)]
+            Some(ref path) => (ListenAddr::Unix(path.clone()), None),
+            #[cfg(not(unix))]
+            Some(_) => anyhow::bail!("Unix sockets are not supported on this platform"),
+            None => {
+                let ip = match conf.rpc_server_addr {
+                    Some(ref addr) => IpAddr::from_str(addr)?,
+                    None => IpAddr::from_str(configuration::DEFAULT_RPC_SERVER_ADDR)?,
+                };
+                let port = conf.rpc_server_port.unwrap_or(configuration::DEFAULT_RPC_SERVER_PORT);
+                (ListenAddr::Tcp(SocketAddr::new(ip, port)), Some(conf.rpc_server_token.clone()))
+            }
+        };
 
         Ok(RpcServerImpl {
             node: Arc::clone(&node),
             listen_addr,
-            access_token: conf.rpc_server_token.clone(),
+            access_token,
             consensus,
         })
     }
@@ -59,23 +81,127 @@ impl RpcServerImpl {
         let self_clone = self.clone();
         let server = Server::builder().add_service(P2pServer::new(self_clone));
 
-        server.serve(self.listen_addr).await.map_err(|e| e.into())
+        match self.listen_addr {
+            ListenAddr::Tcp(addr) => server.serve(addr).await.map_err(|e| e.into()),
+            #[cfg(unix)]
+            ListenAddr::Unix(ref path) => {
+                let incoming = uds::bind(path)?;
+                info!("Serving RPC on Unix socket {}", path.display());
+                server.serve_with_incoming(incoming).await.map_err(|e| e.into())
+            }
+        }
+    }
+}
+
+/// Serving gRPC on a Unix domain socket.
+#[cfg(unix)]
+mod uds {
+    use std::{
+        fs, io,
+        os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        path::Path,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio_stream::{wrappers::UnixListenerStream, Stream, StreamExt};
+    use tonic::transport::server::Connected;
+
+    /// A connection to the server's Unix socket.
+    pub struct UnixStream(tokio::net::UnixStream);
+
+    impl Connected for UnixStream {
+        fn remote_addr(&self) -> Option<std::net::SocketAddr> { None }
+    }
+
+    impl AsyncRead for UnixStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for UnixStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_shutdown(cx)
+        }
+    }
+
+    /// Binds a Unix socket at the given path, replacing a socket left behind
+    /// by a previous run, and restricts its access to the node's user. Any
+    /// other file at the path is left alone and an error is returned.
+    pub fn bind(path: &Path) -> io::Result<impl Stream<Item = io::Result<UnixStream>>> {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        // The socket is bound in a directory only the node's user can access and
+        // only moved to its place once its permissions are restricted, as the
+        // access token isn't checked for connections to it.
+        let file_name = path.file_name().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "The socket path has no file name")
+        })?;
+        let mut staging_name = std::ffi::OsString::from(".");
+        staging_name.push(file_name);
+        staging_name.push(format!(".{}", std::process::id()));
+        let staging_dir = path.with_file_name(staging_name);
+        if staging_dir.exists() {
+            // left behind by a previous run that crashed while binding
+            fs::remove_dir_all(&staging_dir)?;
+        }
+        fs::DirBuilder::new().mode(0o700).create(&staging_dir)?;
+        let staging_path = staging_dir.join(file_name);
+        let bound = tokio::net::UnixListener::bind(&staging_path).and_then(|listener| {
+            fs::set_permissions(&staging_path, fs::Permissions::from_mode(0o600))?;
+            fs::rename(&staging_path, path)?;
+            Ok(listener)
+        });
+        let _ = fs::remove_dir_all(&staging_dir);
+        Ok(UnixListenerStream::new(bound?).map(|conn| conn.map(UnixStream)))
     }
 }
 
 macro_rules! authenticate {
     ($req:expr, $access_token:expr) => {
-        if let Some(val) = $req.metadata().get("authentication") {
-            match std::str::from_utf8(val.as_bytes()) {
-                Ok(at) if at == $access_token => {}
-                _ => {
-                    error!("failed to reply to {:?}: invalid authentication token", $req);
-                    return Err(Status::new(Code::Unauthenticated, "invalid authentication token"));
+        if let Some(ref access_token) = $access_token {
+            if let Some(val) = $req.metadata().get("authentication") {
+                match std::str::from_utf8(val.as_bytes()) {
+                    Ok(at) if at == access_token.as_str() => {}
+                    _ => {
+                        error!("failed to reply to {:?}: invalid authentication token", $req);
+                        return Err(Status::new(
+                            Code::Unauthenticated,
+                            "invalid authentication token",
+                        ));
+                    }
                 }
+            } else {
+                error!("failed to reply to {:?}: missing authentication token", $req);
+                return Err(Status::new(Code::Unauthenticated, "missing authentication token"));
             }
-        } else {
-            error!("failed to reply to {:?}: missing authentication token", $req);
-            return Err(Status::new(Code::Unauthenticated, "missing authentication token"));
         }
     };
 }
@@ -285,9 +411,8 @@ impl P2p for RpcServerImpl {
             if id > 0 && id < 100_000 {
                 info!("Attempting to join network {}", id);
                 let network_id = NetworkId::from(id as u16);
-                self.node.send_join_network(network_id);
                 Ok(Response::new(BoolResponse {
-                    value: true,
+                    value: self.node.join_network(network_id),
                 }))
             } else {
                 Err(Status::new(Code::InvalidArgument, "Invalid network id"))
@@ -307,9 +432,8 @@ impl P2p for RpcServerImpl {
             if id > 0 && id < 100_000 {
                 info!("Attempting to leave network {}", id);
                 let network_id = NetworkId::from(id as u16);
-                self.node.send_leave_network(network_id);
                 Ok(Response::new(BoolResponse {
-                    value: true,
+                    value: self.node.leave_network(network_id),
                 }))
             } else {
                 Err(Status::new(Code::InvalidArgument, "Invalid network id"))
@@ -449,8 +573,9 @@ impl P2p for RpcServerImpl {
                 }
             }
             (None, Some(ip)) => {
-                if let Ok(ip) = IpAddr::from_str(&ip.to_string()) {
-                    self.node.drop_by_ip_and_ban(ip)
+                // either a single IP address or a subnet in CIDR notation
+                if let Ok(bid) = PersistedBanId::from_str(ip) {
+                    self.node.ban_peer(bid, None, None)
                 } else {
                     return Err(Status::new(Code::InvalidArgument, "Malformed IP address."));
                 }
@@ -484,7 +609,7 @@ impl P2p for RpcServerImpl {
         authenticate!(req, self.access_token);
         let req = req.get_ref();
         let banned_node = match req.ip {
-            Some(ref ip) => IpAddr::from_str(&ip.to_string()).ok().map(PersistedBanId::Ip),
+            Some(ref ip) => PersistedBanId::from_str(ip).ok(),
             _ => None,
         };
 
@@ -754,9 +879,8 @@ impl P2p for RpcServerImpl {
             banlist
                 .into_iter()
                 .map(|banned_node| {
-                    let ip = match banned_node {
-                        PersistedBanId::Ip(addr) => addr.to_string(),
-                    };
+                    // a single IP address or a subnet in CIDR notation
+                    let ip = banned_node.to_string();
 
                     PeerElement {
                         node_id:        Some("*".to_owned()), /* we do not record the id of
@@ -813,6 +937,8 @@ impl P2p for RpcServerImpl {
                     &file_path
                 },
                 req.get_ref().raw,
+                crate::dumper::DumpFormat::default(),
+                false,
             )
             .is_ok();
         Ok(Response::new(BoolResponse {
@@ -840,7 +966,9 @@ impl P2p for RpcServerImpl {
 mod tests {
     use crate::{
         common::{grpc_api, P2PNodeId, PeerType},
+        network::NetworkId,
         p2p::P2PNode,
+        read_or_die,
         rpc::RpcServerImpl,
         test_utils::{
             await_handshakes, connect, dummy_regenesis_blocks, get_test_config, make_node_and_sync,
@@ -866,8 +994,8 @@ mod tests {
 
         let rpc_port = next_available_port();
         let mut config = get_test_config(8888, vec![100]);
-        config.cli.rpc.rpc_server_port = rpc_port;
-        config.cli.rpc.rpc_server_addr = "127.0.0.1".to_owned();
+        config.cli.rpc.rpc_server_port = Some(rpc_port);
+        config.cli.rpc.rpc_server_addr = Some("127.0.0.1".to_owned());
         config.cli.rpc.rpc_server_token = TOKEN.to_owned();
         let mut rpc_server = RpcServerImpl::new(node.clone(), None, &config.cli.rpc)?;
         tokio::spawn(async move { rpc_server.start_server().await });
@@ -1004,13 +1132,18 @@ mod tests {
         connect(&node2, &node);
         await_handshakes(&node);
         await_handshakes(&node2);
-        let ncr = req_with_auth!(
-            grpc_api::NetworkChangeRequest {
-                network_id: Some(10),
-            },
-            TOKEN
-        );
-        assert!(client.join_network(ncr).await.unwrap().get_ref().value);
+        let ncr = || {
+            req_with_auth!(
+                grpc_api::NetworkChangeRequest {
+                    network_id: Some(10),
+                },
+                TOKEN
+            )
+        };
+        assert!(client.join_network(ncr()).await.unwrap().get_ref().value);
+        assert!(read_or_die!(node.networks()).contains(&NetworkId::from(10)));
+        // the node is already in the network, so nothing changes
+        assert!(!client.join_network(ncr()).await.unwrap().get_ref().value);
         stop_node_delete_dirs(dp, node);
         stop_node_delete_dirs(dp2, node2);
         Ok(())
@@ -1025,13 +1158,18 @@ mod tests {
         connect(&node2, &node);
         await_handshakes(&node);
         await_handshakes(&node2);
-        let ncr = req_with_auth!(
-            grpc_api::NetworkChangeRequest {
-                network_id: Some(100),
-            },
-            TOKEN
-        );
-        assert!(client.leave_network(ncr).await.unwrap().get_ref().value);
+        let ncr = || {
+            req_with_auth!(
+                grpc_api::NetworkChangeRequest {
+                    network_id: Some(100),
+                },
+                TOKEN
+            )
+        };
+        assert!(client.leave_network(ncr()).await.unwrap().get_ref().value);
+        assert!(!read_or_die!(node.networks()).contains(&NetworkId::from(100)));
+        // the node already left the network, so nothing changes
+        assert!(!client.leave_network(ncr()).await.unwrap().get_ref().value);
         stop_node_delete_dirs(dp, node);
         stop_node_delete_dirs(dp2, node2);
         Ok(())
@@ -1149,4 +1287,34 @@ mod tests {
         wait_node_delete_dirs(dp, node);
         Ok(())
     }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket_bind() -> anyhow::Result<()> {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("rpc.sock");
+
+        // the socket is only accessible to the node's user
+        let listener = super::uds::bind(&path)?;
+        let metadata = std::fs::symlink_metadata(&path)?;
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        // and the directory it was bound in is gone
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        tokio::net::UnixStream::connect(&path).await?;
+
+        // a socket left behind is replaced
+        drop(listener);
+        let _listener = super::uds::bind(&path)?;
+        tokio::net::UnixStream::connect(&path).await?;
+
+        // while any other file is kept
+        let file_path = dir.path().join("rpc.txt");
+        std::fs::write(&file_path, "keep me")?;
+        assert!(super::uds::bind(&file_path).is_err());
+        assert_eq!(std::fs::read_to_string(&file_path)?, "keep me");
+        Ok(())
+    }
 }
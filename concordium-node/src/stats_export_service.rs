@@ -2,8 +2,9 @@
 
 cfg_if! {
     if #[cfg(feature = "instrumentation")] {
-        use prometheus::{self, Encoder, core::{AtomicI64, AtomicU64, GenericGauge}, IntCounter, IntGauge, Opts, Registry, TextEncoder};
+        use prometheus::{self, Encoder, core::{AtomicI64, AtomicU64, GenericGauge}, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
         use crate::{common::p2p_node_id::P2PNodeId, spawn_or_die, read_or_die};
+        use anyhow::Context;
         use std::{net::SocketAddr, thread, time, sync::RwLock};
         use gotham::{
             handler::IntoResponse,
@@ -19,9 +20,18 @@ cfg_if! {
         use std::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
     }
 }
-use crate::configuration;
+use crate::{configuration, connection::MessageKind, p2p::connectivity::DisconnectReason};
+use serde::Deserialize;
 use std::sync::Arc;
 
+/// The block heights exported from the consensus status.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConsensusHeights {
+    best_block_height:           u64,
+    last_finalized_block_height: u64,
+}
+
 cfg_if! {
     if #[cfg(feature = "instrumentation")] {
         struct HTMLStringResponse(pub String);
@@ -65,6 +75,48 @@ cfg_if! {
             bytes_sent: GenericGauge<AtomicU64>,
             avg_bps_in: GenericGauge<AtomicU64>,
             avg_bps_out: GenericGauge<AtomicU64>,
+            handshake_replays: IntCounter,
+            pending_messages_dropped: IntCounter,
+            max_pending_messages_depth: IntGauge,
+            messages_sent_by_kind: IntCounterVec,
+            bytes_sent_by_kind: IntCounterVec,
+            consensus_blocks_processed: IntCounter,
+            consensus_finalizations_processed: IntCounter,
+            consensus_blocks_rate: IntGauge,
+            consensus_finalizations_rate: IntGauge,
+            ingestion_pauses: IntCounter,
+            socket_thread_max_bytes_share: IntGauge,
+            transactions_shed: IntCounter,
+            conn_per_ip_rejections: IntCounter,
+            handshake_timeouts: IntCounter,
+            dedup_long_hits: IntCounter,
+            dedup_long_misses: IntCounter,
+            dedup_short_hits: IntCounter,
+            dedup_short_misses: IntCounter,
+            output_queue_bytes_total: GenericGauge<AtomicU64>,
+            output_queue_bytes_min: GenericGauge<AtomicU64>,
+            output_queue_bytes_max: GenericGauge<AtomicU64>,
+            get_peers_ignored: IntCounter,
+            catch_up_peers_pending: GenericGauge<AtomicU64>,
+            catch_up_peers_catching_up: GenericGauge<AtomicU64>,
+            catch_up_peers_up_to_date: GenericGauge<AtomicU64>,
+            catch_up_stamp_age: GenericGauge<AtomicU64>,
+            stale_messages_dropped: IntCounter,
+            socket_thread_pool_size: IntGauge,
+            connections_throttled: IntCounter,
+            relays_suppressed: IntCounter,
+            send_rate: IntGauge,
+            baking: IntGauge,
+            best_block_height: GenericGauge<AtomicU64>,
+            last_finalized_block_height: GenericGauge<AtomicU64>,
+            queue_breaker_trips: IntCounter,
+            queue_breaker_open: IntGauge,
+            peers_established: IntCounter,
+            peers_dropped: IntCounter,
+            peer_churn_rate: IntGauge,
+            peers_dropped_by_reason: IntCounterVec,
+            memory_shedding_actions: IntCounter,
+            frame_auth_failures: IntCounter,
         }
     }
 }
@@ -90,6 +142,45 @@ pub struct StatsExportService {
     bytes_sent: AtomicU64,
     avg_bps_in: AtomicU64,
     avg_bps_out: AtomicU64,
+    handshake_replays: AtomicUsize,
+    pending_messages_dropped: AtomicUsize,
+    max_pending_messages_depth: AtomicUsize,
+    consensus_blocks_processed: AtomicUsize,
+    consensus_finalizations_processed: AtomicUsize,
+    consensus_blocks_rate: AtomicUsize,
+    consensus_finalizations_rate: AtomicUsize,
+    ingestion_pauses: AtomicUsize,
+    socket_thread_max_bytes_share: AtomicUsize,
+    transactions_shed: AtomicUsize,
+    conn_per_ip_rejections: AtomicUsize,
+    handshake_timeouts: AtomicUsize,
+    dedup_long_hits: AtomicUsize,
+    dedup_long_misses: AtomicUsize,
+    dedup_short_hits: AtomicUsize,
+    dedup_short_misses: AtomicUsize,
+    output_queue_bytes_total: AtomicU64,
+    output_queue_bytes_min: AtomicU64,
+    output_queue_bytes_max: AtomicU64,
+    get_peers_ignored: AtomicUsize,
+    catch_up_peers_pending: AtomicU64,
+    catch_up_peers_catching_up: AtomicU64,
+    catch_up_peers_up_to_date: AtomicU64,
+    catch_up_stamp_age: AtomicU64,
+    stale_messages_dropped: AtomicUsize,
+    socket_thread_pool_size: AtomicUsize,
+    connections_throttled: AtomicUsize,
+    relays_suppressed: AtomicUsize,
+    send_rate: AtomicUsize,
+    baking: AtomicUsize,
+    best_block_height: AtomicU64,
+    last_finalized_block_height: AtomicU64,
+    queue_breaker_trips: AtomicUsize,
+    queue_breaker_open: AtomicUsize,
+    peers_established: AtomicUsize,
+    peers_dropped: AtomicUsize,
+    peer_churn_rate: AtomicUsize,
+    memory_shedding_actions: AtomicUsize,
+    frame_auth_failures: AtomicUsize,
 }
 
 impl StatsExportService {
@@ -229,6 +320,267 @@ impl StatsExportService {
         let avg_bps_out = GenericGauge::with_opts(avg_bps_out_opts)?;
         registry.register(Box::new(avg_bps_out.clone()))?;
 
+        let handshake_replays_opts = Opts::new("handshake_replays", "replayed handshakes rejected");
+        let handshake_replays = IntCounter::with_opts(handshake_replays_opts)?;
+        registry.register(Box::new(handshake_replays.clone()))?;
+
+        let pending_messages_dropped_opts = Opts::new(
+            "pending_messages_dropped",
+            "queued outbound messages dropped due to the per-connection limit",
+        );
+        let pending_messages_dropped = IntCounter::with_opts(pending_messages_dropped_opts)?;
+        registry.register(Box::new(pending_messages_dropped.clone()))?;
+
+        let max_pending_messages_depth_opts = Opts::new(
+            "max_pending_messages_depth",
+            "maximum number of queued outbound messages of a single connection",
+        );
+        let max_pending_messages_depth = IntGauge::with_opts(max_pending_messages_depth_opts)?;
+        registry.register(Box::new(max_pending_messages_depth.clone()))?;
+
+        let messages_sent_by_kind_opts =
+            Opts::new("messages_sent_by_kind", "messages sent to peers, by message kind");
+        let messages_sent_by_kind = IntCounterVec::new(messages_sent_by_kind_opts, &["kind"])?;
+        registry.register(Box::new(messages_sent_by_kind.clone()))?;
+
+        let bytes_sent_by_kind_opts =
+            Opts::new("bytes_sent_by_kind", "bytes sent to peers, by message kind");
+        let bytes_sent_by_kind = IntCounterVec::new(bytes_sent_by_kind_opts, &["kind"])?;
+        registry.register(Box::new(bytes_sent_by_kind.clone()))?;
+
+        let consensus_blocks_processed_opts = Opts::new(
+            "consensus_blocks_processed",
+            "blocks received from peers and processed by consensus",
+        );
+        let consensus_blocks_processed = IntCounter::with_opts(consensus_blocks_processed_opts)?;
+        registry.register(Box::new(consensus_blocks_processed.clone()))?;
+
+        let consensus_finalizations_processed_opts = Opts::new(
+            "consensus_finalizations_processed",
+            "finalization messages and records received from peers and processed by consensus",
+        );
+        let consensus_finalizations_processed =
+            IntCounter::with_opts(consensus_finalizations_processed_opts)?;
+        registry.register(Box::new(consensus_finalizations_processed.clone()))?;
+
+        let consensus_blocks_rate_opts = Opts::new(
+            "consensus_blocks_rate",
+            "blocks received from peers and processed by consensus per second",
+        );
+        let consensus_blocks_rate = IntGauge::with_opts(consensus_blocks_rate_opts)?;
+        registry.register(Box::new(consensus_blocks_rate.clone()))?;
+
+        let consensus_finalizations_rate_opts = Opts::new(
+            "consensus_finalizations_rate",
+            "finalization messages and records received from peers and processed by consensus per \
+             second",
+        );
+        let consensus_finalizations_rate = IntGauge::with_opts(consensus_finalizations_rate_opts)?;
+        registry.register(Box::new(consensus_finalizations_rate.clone()))?;
+
+        let ingestion_pauses_opts = Opts::new(
+            "ingestion_pauses",
+            "reads from peers paused due to a full inbound consensus queue",
+        );
+        let ingestion_pauses = IntCounter::with_opts(ingestion_pauses_opts)?;
+        registry.register(Box::new(ingestion_pauses.clone()))?;
+
+        let socket_thread_max_bytes_share_opts = Opts::new(
+            "socket_thread_max_bytes_share",
+            "percentage of recently handled bytes handled by the busiest socket thread",
+        );
+        let socket_thread_max_bytes_share =
+            IntGauge::with_opts(socket_thread_max_bytes_share_opts)?;
+        registry.register(Box::new(socket_thread_max_bytes_share.clone()))?;
+
+        let transactions_shed_opts =
+            Opts::new("transactions_shed", "transactions not rebroadcast due to load shedding");
+        let transactions_shed = IntCounter::with_opts(transactions_shed_opts)?;
+        registry.register(Box::new(transactions_shed.clone()))?;
+
+        let conn_per_ip_rejections_opts = Opts::new(
+            "conn_per_ip_rejections",
+            "incoming connections refused due to the per-IP connection limit",
+        );
+        let conn_per_ip_rejections = IntCounter::with_opts(conn_per_ip_rejections_opts)?;
+        registry.register(Box::new(conn_per_ip_rejections.clone()))?;
+
+        let handshake_timeouts_opts = Opts::new(
+            "handshake_timeouts",
+            "Connections dropped for not completing the handshake in time",
+        );
+        let handshake_timeouts = IntCounter::with_opts(handshake_timeouts_opts)?;
+        registry.register(Box::new(handshake_timeouts.clone()))?;
+
+        let dedup_long_hits_opts = Opts::new(
+            "dedup_long_hits",
+            "Duplicate packets dropped by the long deduplication queues",
+        );
+        let dedup_long_hits = IntCounter::with_opts(dedup_long_hits_opts)?;
+        registry.register(Box::new(dedup_long_hits.clone()))?;
+
+        let dedup_long_misses_opts =
+            Opts::new("dedup_long_misses", "New packets passed by the long deduplication queues");
+        let dedup_long_misses = IntCounter::with_opts(dedup_long_misses_opts)?;
+        registry.register(Box::new(dedup_long_misses.clone()))?;
+
+        let dedup_short_hits_opts = Opts::new(
+            "dedup_short_hits",
+            "Duplicate packets dropped by the short deduplication queues",
+        );
+        let dedup_short_hits = IntCounter::with_opts(dedup_short_hits_opts)?;
+        registry.register(Box::new(dedup_short_hits.clone()))?;
+
+        let dedup_short_misses_opts =
+            Opts::new("dedup_short_misses", "New packets passed by the short deduplication queues");
+        let dedup_short_misses = IntCounter::with_opts(dedup_short_misses_opts)?;
+        registry.register(Box::new(dedup_short_misses.clone()))?;
+
+        let output_queue_bytes_total_opts = Opts::new(
+            "output_queue_bytes_total",
+            "total number of bytes waiting in the output queues of all connections",
+        );
+        let output_queue_bytes_total = GenericGauge::with_opts(output_queue_bytes_total_opts)?;
+        registry.register(Box::new(output_queue_bytes_total.clone()))?;
+
+        let output_queue_bytes_min_opts = Opts::new(
+            "output_queue_bytes_min",
+            "smallest number of bytes waiting in the output queue of a connection",
+        );
+        let output_queue_bytes_min = GenericGauge::with_opts(output_queue_bytes_min_opts)?;
+        registry.register(Box::new(output_queue_bytes_min.clone()))?;
+
+        let output_queue_bytes_max_opts = Opts::new(
+            "output_queue_bytes_max",
+            "largest number of bytes waiting in the output queue of a connection",
+        );
+        let output_queue_bytes_max = GenericGauge::with_opts(output_queue_bytes_max_opts)?;
+        registry.register(Box::new(output_queue_bytes_max.clone()))?;
+
+        let get_peers_ignored_opts =
+            Opts::new("get_peers_ignored", "number of rate-limited GetPeers requests");
+        let get_peers_ignored = IntCounter::with_opts(get_peers_ignored_opts)?;
+        registry.register(Box::new(get_peers_ignored.clone()))?;
+
+        let catch_up_peers_pending_opts = Opts::new(
+            "catch_up_peers_pending",
+            "number of peers we are behind and wait to catch up with",
+        );
+        let catch_up_peers_pending = GenericGauge::with_opts(catch_up_peers_pending_opts)?;
+        registry.register(Box::new(catch_up_peers_pending.clone()))?;
+
+        let catch_up_peers_catching_up_opts = Opts::new(
+            "catch_up_peers_catching_up",
+            "number of peers we are currently catching up with",
+        );
+        let catch_up_peers_catching_up = GenericGauge::with_opts(catch_up_peers_catching_up_opts)?;
+        registry.register(Box::new(catch_up_peers_catching_up.clone()))?;
+
+        let catch_up_peers_up_to_date_opts =
+            Opts::new("catch_up_peers_up_to_date", "number of peers we are up to date with");
+        let catch_up_peers_up_to_date = GenericGauge::with_opts(catch_up_peers_up_to_date_opts)?;
+        registry.register(Box::new(catch_up_peers_up_to_date.clone()))?;
+
+        let catch_up_stamp_age_opts =
+            Opts::new("catch_up_stamp_age", "time since the last catch-up attempt in milliseconds");
+        let catch_up_stamp_age = GenericGauge::with_opts(catch_up_stamp_age_opts)?;
+        registry.register(Box::new(catch_up_stamp_age.clone()))?;
+
+        let stale_messages_dropped_opts = Opts::new(
+            "network_stale_messages_dropped",
+            "messages dropped due to a timestamp outside the tolerated window",
+        );
+        let stale_messages_dropped = IntCounter::with_opts(stale_messages_dropped_opts)?;
+        registry.register(Box::new(stale_messages_dropped.clone()))?;
+
+        let socket_thread_pool_size_opts = Opts::new(
+            "socket_thread_pool_size",
+            "the number of threads processing connection events",
+        );
+        let socket_thread_pool_size = IntGauge::with_opts(socket_thread_pool_size_opts)?;
+        registry.register(Box::new(socket_thread_pool_size.clone()))?;
+
+        let connections_throttled_opts =
+            Opts::new("conn_throttled", "incoming connections closed due to the accept rate limit");
+        let connections_throttled = IntCounter::with_opts(connections_throttled_opts)?;
+        registry.register(Box::new(connections_throttled.clone()))?;
+
+        let relays_suppressed_opts = Opts::new(
+            "relays_suppressed",
+            "copies of recently relayed broadcasts that were dropped",
+        );
+        let relays_suppressed = IntCounter::with_opts(relays_suppressed_opts)?;
+        registry.register(Box::new(relays_suppressed.clone()))?;
+
+        let send_rate_opts =
+            Opts::new("send_rate", "average number of bytes written to the sockets per second");
+        let send_rate = IntGauge::with_opts(send_rate_opts)?;
+        registry.register(Box::new(send_rate.clone()))?;
+
+        let baking_opts = Opts::new("baking", "whether the baker is running");
+        let baking = IntGauge::with_opts(baking_opts)?;
+        registry.register(Box::new(baking.clone()))?;
+
+        let best_block_height_opts = Opts::new("best_block_height", "the height of the best block");
+        let best_block_height = GenericGauge::with_opts(best_block_height_opts)?;
+        registry.register(Box::new(best_block_height.clone()))?;
+
+        let last_finalized_block_height_opts =
+            Opts::new("last_finalized_block_height", "the height of the last finalized block");
+        let last_finalized_block_height =
+            GenericGauge::with_opts(last_finalized_block_height_opts)?;
+        registry.register(Box::new(last_finalized_block_height.clone()))?;
+
+        let queue_breaker_trips_opts = Opts::new(
+            "queue_breaker_trips",
+            "times the circuit breaker of an inbound consensus queue paused reads from a peer",
+        );
+        let queue_breaker_trips = IntCounter::with_opts(queue_breaker_trips_opts)?;
+        registry.register(Box::new(queue_breaker_trips.clone()))?;
+
+        let queue_breaker_open_opts = Opts::new(
+            "queue_breaker_open",
+            "whether reads from any peer are paused by an inbound consensus queue breaker",
+        );
+        let queue_breaker_open = IntGauge::with_opts(queue_breaker_open_opts)?;
+        registry.register(Box::new(queue_breaker_open.clone()))?;
+
+        let peers_established_opts =
+            Opts::new("peers_established", "connections promoted to peers");
+        let peers_established = IntCounter::with_opts(peers_established_opts)?;
+        registry.register(Box::new(peers_established.clone()))?;
+
+        let peers_dropped_opts = Opts::new("peers_dropped", "peers whose connection was dropped");
+        let peers_dropped = IntCounter::with_opts(peers_dropped_opts)?;
+        registry.register(Box::new(peers_dropped.clone()))?;
+
+        let peer_churn_rate_opts = Opts::new(
+            "peer_churn_rate",
+            "peers established and dropped per minute over the last housekeeping window",
+        );
+        let peer_churn_rate = IntGauge::with_opts(peer_churn_rate_opts)?;
+        registry.register(Box::new(peer_churn_rate.clone()))?;
+
+        let peers_dropped_by_reason_opts =
+            Opts::new("peers_dropped_by_reason", "connections dropped by the node, by reason");
+        let peers_dropped_by_reason =
+            IntCounterVec::new(peers_dropped_by_reason_opts, &["reason"])?;
+        registry.register(Box::new(peers_dropped_by_reason.clone()))?;
+
+        let memory_shedding_actions_opts = Opts::new(
+            "memory_shedding_actions",
+            "load shedding actions taken because the resident memory exceeded the soft limit",
+        );
+        let memory_shedding_actions = IntCounter::with_opts(memory_shedding_actions_opts)?;
+        registry.register(Box::new(memory_shedding_actions.clone()))?;
+
+        let frame_auth_failures_opts = Opts::new(
+            "frame_auth_failures",
+            "received frames that failed authentication during decryption",
+        );
+        let frame_auth_failures = IntCounter::with_opts(frame_auth_failures_opts)?;
+        registry.register(Box::new(frame_auth_failures.clone()))?;
+
         Ok(StatsExportService {
             registry,
             pkts_received_counter: prc,
@@ -248,6 +600,48 @@ impl StatsExportService {
             bytes_sent: bsc,
             avg_bps_in,
             avg_bps_out,
+            handshake_replays,
+            pending_messages_dropped,
+            max_pending_messages_depth,
+            messages_sent_by_kind,
+            bytes_sent_by_kind,
+            consensus_blocks_processed,
+            consensus_finalizations_processed,
+            consensus_blocks_rate,
+            consensus_finalizations_rate,
+            ingestion_pauses,
+            socket_thread_max_bytes_share,
+            transactions_shed,
+            conn_per_ip_rejections,
+            handshake_timeouts,
+            dedup_long_hits,
+            dedup_long_misses,
+            dedup_short_hits,
+            dedup_short_misses,
+            output_queue_bytes_total,
+            output_queue_bytes_min,
+            output_queue_bytes_max,
+            get_peers_ignored,
+            catch_up_peers_pending,
+            catch_up_peers_catching_up,
+            catch_up_peers_up_to_date,
+            catch_up_stamp_age,
+            stale_messages_dropped,
+            socket_thread_pool_size,
+            connections_throttled,
+            relays_suppressed,
+            send_rate,
+            baking,
+            best_block_height,
+            last_finalized_block_height,
+            queue_breaker_trips,
+            queue_breaker_open,
+            peers_established,
+            peers_dropped,
+            peer_churn_rate,
+            peers_dropped_by_reason,
+            memory_shedding_actions,
+            frame_auth_failures,
         })
     }
 
@@ -457,6 +851,373 @@ impl StatsExportService {
         self.avg_bps_out.store(value, Ordering::Relaxed);
     }
 
+    /// Increases the number of rejected replayed handshakes.
+    pub fn handshake_replays_inc(&self) {
+        #[cfg(feature = "instrumentation")]
+        self.handshake_replays.inc();
+        #[cfg(not(feature = "instrumentation"))]
+        self.handshake_replays.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Increases the number of outbound messages dropped because a
+    /// connection's queue of pending messages was full.
+    pub fn pending_messages_dropped_inc(&self) {
+        #[cfg(feature = "instrumentation")]
+        self.pending_messages_dropped.inc();
+        #[cfg(not(feature = "instrumentation"))]
+        self.pending_messages_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Sets the maximum number of outbound messages queued for a single
+    /// connection.
+    pub fn set_max_pending_messages_depth(&self, value: i64) {
+        #[cfg(feature = "instrumentation")]
+        self.max_pending_messages_depth.set(value);
+        #[cfg(not(feature = "instrumentation"))]
+        self.max_pending_messages_depth.store(value as usize, Ordering::Relaxed);
+    }
+
+    /// Increases the number of messages of the given kind sent to peers and
+    /// their total size. The breakdown is only kept for the prometheus
+    /// exporter.
+    pub fn msg_sent_by_kind_inc(&self, kind: MessageKind, bytes: usize) {
+        #[cfg(feature = "instrumentation")]
+        {
+            let label = [kind.label()];
+            self.messages_sent_by_kind.with_label_values(&label).inc();
+            self.bytes_sent_by_kind.with_label_values(&label).inc_by(bytes as u64);
+        }
+        #[cfg(not(feature = "instrumentation"))]
+        let _ = (kind, bytes);
+    }
+
+    /// Increases the number of blocks received from peers and processed by
+    /// consensus.
+    pub fn consensus_blocks_processed_inc(&self) {
+        #[cfg(feature = "instrumentation")]
+        self.consensus_blocks_processed.inc();
+        #[cfg(not(feature = "instrumentation"))]
+        self.consensus_blocks_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Increases the number of finalization messages and records received
+    /// from peers and processed by consensus.
+    pub fn consensus_finalizations_processed_inc(&self) {
+        #[cfg(feature = "instrumentation")]
+        self.consensus_finalizations_processed.inc();
+        #[cfg(not(feature = "instrumentation"))]
+        self.consensus_finalizations_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Sets the number of blocks processed by consensus per second.
+    pub fn set_consensus_blocks_rate(&self, value: i64) {
+        #[cfg(feature = "instrumentation")]
+        self.consensus_blocks_rate.set(value);
+        #[cfg(not(feature = "instrumentation"))]
+        self.consensus_blocks_rate.store(value as usize, Ordering::Relaxed);
+    }
+
+    /// Sets the number of finalization messages and records processed by
+    /// consensus per second.
+    pub fn set_consensus_finalizations_rate(&self, value: i64) {
+        #[cfg(feature = "instrumentation")]
+        self.consensus_finalizations_rate.set(value);
+        #[cfg(not(feature = "instrumentation"))]
+        self.consensus_finalizations_rate.store(value as usize, Ordering::Relaxed);
+    }
+
+    /// Increases the number of times reading from a peer was paused because
+    /// the inbound consensus queue was too full and the peer filled its share
+    /// of it.
+    pub fn ingestion_pauses_inc(&self) {
+        #[cfg(feature = "instrumentation")]
+        self.ingestion_pauses.inc();
+        #[cfg(not(feature = "instrumentation"))]
+        self.ingestion_pauses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Sets the share (in percent) of the bytes handled by the busiest thread
+    /// of the socket thread pool since the last report.
+    pub fn set_socket_thread_max_bytes_share(&self, value: i64) {
+        #[cfg(feature = "instrumentation")]
+        self.socket_thread_max_bytes_share.set(value);
+        #[cfg(not(feature = "instrumentation"))]
+        self.socket_thread_max_bytes_share.store(value as usize, Ordering::Relaxed);
+    }
+
+    /// Increases the number of transactions that were not rebroadcast in order
+    /// to shed load.
+    pub fn transactions_shed_inc(&self) {
+        #[cfg(feature = "instrumentation")]
+        self.transactions_shed.inc();
+        #[cfg(not(feature = "instrumentation"))]
+        self.transactions_shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Increases the number of incoming connections refused because the
+    /// per-IP connection limit was reached.
+    pub fn conn_per_ip_rejections_inc(&self) {
+        #[cfg(feature = "instrumentation")]
+        self.conn_per_ip_rejections.inc();
+        #[cfg(not(feature = "instrumentation"))]
+        self.conn_per_ip_rejections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Increases the number of connections dropped because they didn't
+    /// complete the handshake in time.
+    pub fn handshake_timeouts_inc(&self) {
+        #[cfg(feature = "instrumentation")]
+        self.handshake_timeouts.inc();
+        #[cfg(not(feature = "instrumentation"))]
+        self.handshake_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Registers the outcome of a check in the long (finalization messages and
+    /// transactions) or short (blocks and finalization records) deduplication
+    /// queues.
+    pub fn dedup_check_inc(&self, is_long_queue: bool, is_duplicate: bool) {
+        let counter = match (is_long_queue, is_duplicate) {
+            (true, true) => &self.dedup_long_hits,
+            (true, false) => &self.dedup_long_misses,
+            (false, true) => &self.dedup_short_hits,
+            (false, false) => &self.dedup_short_misses,
+        };
+        #[cfg(feature = "instrumentation")]
+        counter.inc();
+        #[cfg(not(feature = "instrumentation"))]
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Gets the deduplication hits and misses of the long and the short
+    /// queues, in this order.
+    pub fn get_dedup_stats(&self) -> (u64, u64, u64, u64) {
+        #[cfg(feature = "instrumentation")]
+        {
+            (
+                self.dedup_long_hits.get(),
+                self.dedup_long_misses.get(),
+                self.dedup_short_hits.get(),
+                self.dedup_short_misses.get(),
+            )
+        }
+        #[cfg(not(feature = "instrumentation"))]
+        {
+            (
+                self.dedup_long_hits.load(Ordering::Relaxed) as u64,
+                self.dedup_long_misses.load(Ordering::Relaxed) as u64,
+                self.dedup_short_hits.load(Ordering::Relaxed) as u64,
+                self.dedup_short_misses.load(Ordering::Relaxed) as u64,
+            )
+        }
+    }
+
+    /// Sets the total, the smallest and the largest number of bytes waiting
+    /// in the output queues of the connections.
+    pub fn set_output_queue_backlog(&self, total: u64, min: u64, max: u64) {
+        #[cfg(feature = "instrumentation")]
+        {
+            self.output_queue_bytes_total.set(total);
+            self.output_queue_bytes_min.set(min);
+            self.output_queue_bytes_max.set(max);
+        }
+        #[cfg(not(feature = "instrumentation"))]
+        {
+            self.output_queue_bytes_total.store(total, Ordering::Relaxed);
+            self.output_queue_bytes_min.store(min, Ordering::Relaxed);
+            self.output_queue_bytes_max.store(max, Ordering::Relaxed);
+        }
+    }
+
+    /// Gets the total, the smallest and the largest number of bytes waiting
+    /// in the output queues of the connections, in this order.
+    pub fn get_output_queue_backlog(&self) -> (u64, u64, u64) {
+        #[cfg(feature = "instrumentation")]
+        {
+            (
+                self.output_queue_bytes_total.get(),
+                self.output_queue_bytes_min.get(),
+                self.output_queue_bytes_max.get(),
+            )
+        }
+        #[cfg(not(feature = "instrumentation"))]
+        {
+            (
+                self.output_queue_bytes_total.load(Ordering::Relaxed),
+                self.output_queue_bytes_min.load(Ordering::Relaxed),
+                self.output_queue_bytes_max.load(Ordering::Relaxed),
+            )
+        }
+    }
+
+    /// Increases the number of GetPeers requests ignored because they arrived
+    /// too soon after the previous one from the same peer.
+    pub fn get_peers_ignored_inc(&self) {
+        #[cfg(feature = "instrumentation")]
+        self.get_peers_ignored.inc();
+        #[cfg(not(feature = "instrumentation"))]
+        self.get_peers_ignored.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Sets the number of peers in each catch-up state and the time since the
+    /// last catch-up attempt in milliseconds.
+    pub fn set_catch_up_progress(
+        &self,
+        pending: u64,
+        catching_up: u64,
+        up_to_date: u64,
+        stamp_age: u64,
+    ) {
+        #[cfg(feature = "instrumentation")]
+        {
+            self.catch_up_peers_pending.set(pending);
+            self.catch_up_peers_catching_up.set(catching_up);
+            self.catch_up_peers_up_to_date.set(up_to_date);
+            self.catch_up_stamp_age.set(stamp_age);
+        }
+        #[cfg(not(feature = "instrumentation"))]
+        {
+            self.catch_up_peers_pending.store(pending, Ordering::Relaxed);
+            self.catch_up_peers_catching_up.store(catching_up, Ordering::Relaxed);
+            self.catch_up_peers_up_to_date.store(up_to_date, Ordering::Relaxed);
+            self.catch_up_stamp_age.store(stamp_age, Ordering::Relaxed);
+        }
+    }
+
+    /// Increases the number of messages dropped because their timestamp was
+    /// outside the tolerated window.
+    pub fn stale_messages_dropped_inc(&self) {
+        #[cfg(feature = "instrumentation")]
+        self.stale_messages_dropped.inc();
+        #[cfg(not(feature = "instrumentation"))]
+        self.stale_messages_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Sets the size of the socket thread pool.
+    pub fn set_socket_thread_pool_size(&self, value: i64) {
+        #[cfg(feature = "instrumentation")]
+        self.socket_thread_pool_size.set(value);
+        #[cfg(not(feature = "instrumentation"))]
+        self.socket_thread_pool_size.store(value as usize, Ordering::Relaxed);
+    }
+
+    /// Increases the number of incoming connections closed due to the accept
+    /// rate limit.
+    pub fn conn_throttled_inc(&self) {
+        #[cfg(feature = "instrumentation")]
+        self.connections_throttled.inc();
+        #[cfg(not(feature = "instrumentation"))]
+        self.connections_throttled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Increases the number of dropped copies of recently relayed broadcasts.
+    pub fn relays_suppressed_inc(&self) {
+        #[cfg(feature = "instrumentation")]
+        self.relays_suppressed.inc();
+        #[cfg(not(feature = "instrumentation"))]
+        self.relays_suppressed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Sets the average number of bytes written to the sockets per second.
+    pub fn set_send_rate(&self, value: i64) {
+        #[cfg(feature = "instrumentation")]
+        self.send_rate.set(value);
+        #[cfg(not(feature = "instrumentation"))]
+        self.send_rate.store(value as usize, Ordering::Relaxed);
+    }
+
+    /// Sets whether the baker is running and the heights of the best and the
+    /// last finalized block, which are read from the consensus status.
+    pub fn set_consensus_status(&self, baking: bool, status: &str) -> anyhow::Result<()> {
+        #[cfg(feature = "instrumentation")]
+        self.baking.set(baking as i64);
+        #[cfg(not(feature = "instrumentation"))]
+        self.baking.store(baking as usize, Ordering::Relaxed);
+
+        let heights = serde_json::from_str::<ConsensusHeights>(status)?;
+        #[cfg(feature = "instrumentation")]
+        {
+            self.best_block_height.set(heights.best_block_height);
+            self.last_finalized_block_height.set(heights.last_finalized_block_height);
+        }
+        #[cfg(not(feature = "instrumentation"))]
+        {
+            self.best_block_height.store(heights.best_block_height, Ordering::Relaxed);
+            self.last_finalized_block_height
+                .store(heights.last_finalized_block_height, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Increases the number of times the circuit breaker of an inbound
+    /// consensus queue paused reads from a peer.
+    pub fn queue_breaker_trips_inc(&self) {
+        #[cfg(feature = "instrumentation")]
+        self.queue_breaker_trips.inc();
+        #[cfg(not(feature = "instrumentation"))]
+        self.queue_breaker_trips.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Sets whether reads from any peer are paused by the circuit breaker of
+    /// an inbound consensus queue (1) or not (0).
+    pub fn set_queue_breaker_open(&self, value: i64) {
+        #[cfg(feature = "instrumentation")]
+        self.queue_breaker_open.set(value);
+        #[cfg(not(feature = "instrumentation"))]
+        self.queue_breaker_open.store(value as usize, Ordering::Relaxed);
+    }
+
+    /// Increases the number of connections promoted to peers.
+    pub fn peers_established_inc(&self) {
+        #[cfg(feature = "instrumentation")]
+        self.peers_established.inc();
+        #[cfg(not(feature = "instrumentation"))]
+        self.peers_established.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Increases the number of peers whose connection was dropped.
+    pub fn peers_dropped_inc(&self, count: u64) {
+        #[cfg(feature = "instrumentation")]
+        self.peers_dropped.inc_by(count);
+        #[cfg(not(feature = "instrumentation"))]
+        self.peers_dropped.fetch_add(count as usize, Ordering::Relaxed);
+    }
+
+    /// Sets the number of peers established and dropped per minute over the
+    /// last housekeeping window.
+    pub fn set_peer_churn_rate(&self, value: i64) {
+        #[cfg(feature = "instrumentation")]
+        self.peer_churn_rate.set(value);
+        #[cfg(not(feature = "instrumentation"))]
+        self.peer_churn_rate.store(value as usize, Ordering::Relaxed);
+    }
+
+    /// Increases the number of connections the node dropped for the given
+    /// reason. The breakdown is only kept for the prometheus exporter.
+    pub fn peer_dropped_by_reason_inc(&self, reason: DisconnectReason) {
+        #[cfg(feature = "instrumentation")]
+        self.peers_dropped_by_reason.with_label_values(&[reason.label()]).inc();
+        #[cfg(not(feature = "instrumentation"))]
+        let _ = reason;
+    }
+
+    /// Increases the number of load shedding actions taken because the
+    /// resident memory exceeded the soft limit.
+    pub fn memory_shedding_inc(&self) {
+        #[cfg(feature = "instrumentation")]
+        self.memory_shedding_actions.inc();
+        #[cfg(not(feature = "instrumentation"))]
+        self.memory_shedding_actions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Increases the number of received frames that failed authentication
+    /// during decryption.
+    pub fn frame_auth_failures_inc(&self) {
+        #[cfg(feature = "instrumentation")]
+        self.frame_auth_failures.inc();
+        #[cfg(not(feature = "instrumentation"))]
+        self.frame_auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
     #[cfg(feature = "instrumentation")]
     fn metrics(state: State) -> (State, String) {
         let state_data = PrometheusStateData::borrow_from(&state);
@@ -501,38 +1262,155 @@ impl StatsExportService {
     #[cfg(feature = "instrumentation")]
     fn start_push_to_gateway(
         &self,
-        prometheus_push_gateway: String,
-        prometheus_push_interval: u64,
-        prometheus_job_name: String,
-        prometheus_instance_name: String,
-        prometheus_push_username: Option<String>,
-        prometheus_push_password: Option<String>,
-    ) {
-        let metrics_families = self.registry.gather();
-        let _th = spawn_or_die!("Prometheus", move || loop {
-            debug!("Pushing data to push gateway");
-            let username_pass = prometheus_push_username.clone().and_then(|username| {
-                prometheus_push_password.clone().map(|password| prometheus::BasicAuthentication {
-                    username,
-                    password,
-                })
-            });
-            thread::sleep(time::Duration::from_secs(prometheus_push_interval));
-            prometheus::push_metrics(
-                &prometheus_job_name,
-                labels! {
-                    "instance".to_owned() => prometheus_instance_name.clone(),
-                },
-                &prometheus_push_gateway,
-                metrics_families.clone(),
-                username_pass,
-            )
-            .map_err(|e| error!("Can't push to prometheus push gateway {}", e))
-            .ok();
+        conf: &configuration::PrometheusConfig,
+        push_gateway: &str,
+        instance_name: &str,
+    ) -> anyhow::Result<()> {
+        let url = push_gateway_url(
+            push_gateway,
+            conf.prometheus_push_tls,
+            &conf.prometheus_job_name,
+            instance_name,
+        );
+        let proxy = conf
+            .prometheus_push_proxy
+            .as_ref()
+            .map(|proxy| {
+                reqwest::Proxy::all(proxy)
+                    .with_context(|| format!("Invalid push gateway proxy {}", proxy))
+            })
+            .transpose()?;
+        let credentials =
+            conf.prometheus_push_username.clone().zip(conf.prometheus_push_password.clone());
+        let interval = time::Duration::from_secs(conf.prometheus_push_interval);
+        let registry = self.registry.clone();
+
+        let _th = spawn_or_die!("Prometheus", move || {
+            // the blocking client runs a runtime of its own, so it must not be created
+            // (or dropped) within the node's async runtime
+            let mut client = reqwest::blocking::Client::builder().timeout(PUSH_GATEWAY_TIMEOUT);
+            if let Some(proxy) = proxy {
+                client = client.proxy(proxy);
+            }
+            let client = match client.build() {
+                Ok(client) => client,
+                Err(e) => {
+                    error!("Can't create the push gateway client: {}", e);
+                    return;
+                }
+            };
+            let mut failures = 0u32;
+            loop {
+                thread::sleep(push_gateway_backoff(interval, failures));
+                debug!("Pushing data to push gateway");
+                match push_metrics(&client, &url, &registry, credentials.as_ref()) {
+                    Ok(()) => {
+                        if failures > 0 {
+                            info!("Pushing to the prometheus push gateway works again");
+                        }
+                        failures = 0;
+                    }
+                    Err(e) => {
+                        failures = failures.saturating_add(1);
+                        warn!(
+                            "Can't push to the prometheus push gateway ({} consecutive failures, \
+                             retrying in {}s): {:#}",
+                            failures,
+                            push_gateway_backoff(interval, failures).as_secs(),
+                            e
+                        );
+                    }
+                }
+            }
         });
+
+        Ok(())
     }
 }
 
+/// The timeout of a single push to the push gateway.
+#[cfg(feature = "instrumentation")]
+const PUSH_GATEWAY_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// The longest time to wait between pushes to a failing push gateway.
+#[cfg(feature = "instrumentation")]
+const MAX_PUSH_GATEWAY_BACKOFF: time::Duration = time::Duration::from_secs(300);
+
+/// Build the url the metrics of the given job and instance are pushed to.
+/// The scheme is only added if the gateway address doesn't already have one.
+#[cfg(feature = "instrumentation")]
+pub fn push_gateway_url(gateway: &str, tls: bool, job: &str, instance: &str) -> String {
+    let gateway = gateway.trim_end_matches('/');
+    let scheme = if gateway.contains("://") {
+        ""
+    } else if tls {
+        "https://"
+    } else {
+        "http://"
+    };
+    format!(
+        "{}{}/metrics/{}/{}",
+        scheme,
+        gateway,
+        push_gateway_label("job", job),
+        push_gateway_label("instance", instance)
+    )
+}
+
+/// Encode a grouping label as a part of the push gateway url. The value is
+/// percent-encoded, unless it contains a `/` or is empty; the gateway can't
+/// tell a `/` apart from the path separator even if it is percent-encoded, so
+/// such values are base64-encoded instead, as the gateway expects.
+#[cfg(feature = "instrumentation")]
+fn push_gateway_label(name: &str, value: &str) -> String {
+    if value.is_empty() {
+        return format!("{}@base64/=", name);
+    }
+    if value.contains('/') {
+        return format!("{}@base64/{}", name, base64::encode_config(value, base64::URL_SAFE));
+    }
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    format!("{}/{}", name, encoded)
+}
+
+/// The time to wait before the next push, doubling with every consecutive
+/// failure up to `MAX_PUSH_GATEWAY_BACKOFF`.
+#[cfg(feature = "instrumentation")]
+pub fn push_gateway_backoff(interval: time::Duration, failures: u32) -> time::Duration {
+    let factor = 1u32 << failures.min(16);
+    let backoff = interval.checked_mul(factor).unwrap_or(MAX_PUSH_GATEWAY_BACKOFF);
+    std::cmp::max(interval, std::cmp::min(backoff, MAX_PUSH_GATEWAY_BACKOFF))
+}
+
+/// Push the current metrics to the push gateway at the given url.
+#[cfg(feature = "instrumentation")]
+fn push_metrics(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    registry: &Registry,
+    credentials: Option<&(String, String)>,
+) -> anyhow::Result<()> {
+    let encoder = prometheus::ProtobufEncoder::new();
+    let mut body = Vec::new();
+    encoder.encode(&registry.gather(), &mut body)?;
+
+    let mut request =
+        client.put(url).header(reqwest::header::CONTENT_TYPE, encoder.format_type()).body(body);
+    if let Some((username, password)) = credentials {
+        request = request.basic_auth(username, Some(password));
+    }
+    request.send()?.error_for_status()?;
+
+    Ok(())
+}
+
 /// Starts the stats export engine.
 #[cfg(feature = "instrumentation")]
 pub fn instantiate_stats_export_engine(
@@ -564,20 +1442,109 @@ pub fn start_push_gateway(
     conf: &configuration::PrometheusConfig,
     service: &StatsExportService,
     id: P2PNodeId,
-) {
+) -> anyhow::Result<()> {
     if let Some(prom_push_addy) = conf.prometheus_push_gateway.as_ref() {
         let instance_name = if let Some(ref instance_id) = conf.prometheus_instance_name {
             instance_id.clone()
         } else {
             id.to_string()
         };
-        service.start_push_to_gateway(
-            prom_push_addy.clone(),
-            conf.prometheus_push_interval,
-            conf.prometheus_job_name.clone(),
-            instance_name,
-            conf.prometheus_push_username.clone(),
-            conf.prometheus_push_password.clone(),
+        service.start_push_to_gateway(conf, prom_push_addy, &instance_name)?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "instrumentation"))]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_push_gateway_url() {
+        assert_eq!(
+            push_gateway_url("gateway:9091", false, "job", "node"),
+            "http://gateway:9091/metrics/job/job/instance/node"
+        );
+        assert_eq!(
+            push_gateway_url("gateway:9091/", true, "job", "node"),
+            "https://gateway:9091/metrics/job/job/instance/node"
+        );
+        // an explicit scheme takes precedence
+        assert_eq!(
+            push_gateway_url("http://gateway:9091", true, "job", "node"),
+            "http://gateway:9091/metrics/job/job/instance/node"
+        );
+        // the labels are encoded
+        assert_eq!(
+            push_gateway_url("gateway:9091", false, "my job", "node?1#x"),
+            "http://gateway:9091/metrics/job/my%20job/instance/node%3F1%23x"
+        );
+        assert_eq!(
+            push_gateway_url("gateway:9091", false, "job", "dc/node"),
+            "http://gateway:9091/metrics/job/job/instance@base64/ZGMvbm9kZQ=="
+        );
+        assert_eq!(
+            push_gateway_url("gateway:9091", false, "job", ""),
+            "http://gateway:9091/metrics/job/job/instance@base64/="
+        );
+    }
+
+    #[test]
+    fn test_push_gateway_backoff() {
+        let interval = Duration::from_secs(2);
+        assert_eq!(push_gateway_backoff(interval, 0), interval);
+        assert_eq!(push_gateway_backoff(interval, 3), Duration::from_secs(16));
+        assert_eq!(push_gateway_backoff(interval, 100), MAX_PUSH_GATEWAY_BACKOFF);
+        // the backoff never shortens a long interval
+        let interval = Duration::from_secs(600);
+        assert_eq!(push_gateway_backoff(interval, 5), interval);
+    }
+
+    #[test]
+    fn test_msg_sent_by_kind() {
+        let stats = StatsExportService::new().unwrap();
+        stats.msg_sent_by_kind_inc(MessageKind::Ping, 40);
+        stats.msg_sent_by_kind_inc(MessageKind::Ping, 40);
+        stats.msg_sent_by_kind_inc(MessageKind::of_packet(&[0, 1, 2]), 100);
+
+        assert_eq!(stats.messages_sent_by_kind.with_label_values(&["ping"]).get(), 2);
+        assert_eq!(stats.bytes_sent_by_kind.with_label_values(&["ping"]).get(), 80);
+        assert_eq!(stats.bytes_sent_by_kind.with_label_values(&["packet_block"]).get(), 100);
+        assert_eq!(stats.messages_sent_by_kind.with_label_values(&["handshake"]).get(), 0);
+    }
+
+    #[test]
+    fn test_peer_dropped_by_reason() {
+        let stats = StatsExportService::new().unwrap();
+        stats.peer_dropped_by_reason_inc(DisconnectReason::Inactive);
+        stats.peer_dropped_by_reason_inc(DisconnectReason::Inactive);
+        stats.peer_dropped_by_reason_inc(DisconnectReason::HandshakeTimeout);
+
+        assert_eq!(stats.peers_dropped_by_reason.with_label_values(&["inactive"]).get(), 2);
+        assert_eq!(
+            stats.peers_dropped_by_reason.with_label_values(&["handshake_timeout"]).get(),
+            1
         );
+        assert_eq!(stats.peers_dropped_by_reason.with_label_values(&["banned"]).get(), 0);
+    }
+
+    #[test]
+    fn test_consensus_status() {
+        let stats = StatsExportService::new().unwrap();
+        let status = r#"{
+            "bestBlock": "abcd",
+            "bestBlockHeight": 120,
+            "lastFinalizedBlockHeight": 117,
+            "genesisTime": "2021-06-09T06:00:00Z"
+        }"#;
+        stats.set_consensus_status(true, status).unwrap();
+        assert_eq!(stats.baking.get(), 1);
+        assert_eq!(stats.best_block_height.get(), 120);
+        assert_eq!(stats.last_finalized_block_height.get(), 117);
+
+        // a malformed status leaves the heights untouched
+        assert!(stats.set_consensus_status(false, r#"{"bestBlockHeight":121}"#).is_err());
+        assert_eq!(stats.baking.get(), 0);
+        assert_eq!(stats.best_block_height.get(), 120);
     }
 }
@@ -4,7 +4,7 @@ use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use structopt::StructOpt;
 
 use crate::{
-    common::{get_current_stamp, p2p_peer::RemotePeerId, PeerType},
+    common::{get_current_stamp, p2p_peer::RemotePeerId, DiscoverySource, PeerType},
     configuration::Config,
     connection::ConnChange,
     consensus_ffi::{
@@ -12,7 +12,9 @@ use crate::{
         helpers::{PacketType, SHA256},
     },
     netmsg,
-    network::{NetworkId, NetworkMessage, NetworkPacket, PacketDestination},
+    network::{
+        NetworkId, NetworkMessage, NetworkPacket, PacketDestination, DEFAULT_BROADCAST_HOP_LIMIT,
+    },
     p2p::{maintenance::spawn, P2PNode},
     read_or_die,
     stats_export_service::StatsExportService,
@@ -62,6 +64,10 @@ pub fn get_test_config(port: u16, networks: Vec<u16>) -> Config {
     config.connection.no_bootstrap_dns = true;
     config.connection.require_dnssec = false;
     config.cli.no_network = true;
+    // keep the choices of peers reproducible
+    config.common.rng_seed = Some(u64::from(port));
+    // give the nodes stable ids, distinct since they listen on different ports
+    config.common.id_seed = Some(0);
     let dir = td.into_path();
     config.common.data_dir = dir.clone();
     config.common.config_dir = dir;
@@ -110,16 +116,30 @@ pub fn make_node_and_sync(
     networks: Vec<u16>,
     node_type: PeerType,
     regenesis_blocks: Vec<BlockHash>,
+) -> anyhow::Result<(Arc<P2PNode>, DeletePermission)> {
+    make_node_and_sync_with(port, networks, node_type, regenesis_blocks, |_| {})
+}
+
+/// Creates a `P2PNode` for test purposes like `make_node_and_sync`, but allows
+/// the test to adjust the node's configuration first.
+pub fn make_node_and_sync_with(
+    port: u16,
+    networks: Vec<u16>,
+    node_type: PeerType,
+    regenesis_blocks: Vec<BlockHash>,
+    configure: impl FnOnce(&mut Config),
 ) -> anyhow::Result<(Arc<P2PNode>, DeletePermission)> {
     // locally-run tests and benches can be polled with a much greater frequency
     let mut config = get_test_config(port, networks);
     config.cli.no_network = true;
     config.cli.poll_interval = 1;
     config.connection.housekeeping_interval = 10;
+    config.connection.in_memory_bans = true;
+    configure(&mut config);
     let regenesis_arc = Arc::new(RwLock::new(regenesis_blocks));
 
     let stats = Arc::new(StatsExportService::new().unwrap());
-    let (node, poll) = P2PNode::new(None, &config, node_type, stats, regenesis_arc)?;
+    let (node, poll) = P2PNode::new(config.common.id, &config, node_type, stats, regenesis_arc)?;
 
     spawn(&node, poll, None);
     Ok((node, DeletePermission {
@@ -129,7 +149,8 @@ pub fn make_node_and_sync(
 
 /// Connects `source` and `target` nodes
 pub fn connect(source: &Arc<P2PNode>, target: &P2PNode) {
-    source.register_conn_change(ConnChange::NewPeers(vec![target.self_peer]));
+    source
+        .register_conn_change(ConnChange::NewPeers(vec![target.self_peer], DiscoverySource::Given));
 }
 
 /// Waits until all handshakes with other nodes have concluded.
@@ -164,5 +185,6 @@ pub fn create_random_packet(size: usize) -> NetworkMessage {
         destination: PacketDestination::Direct(rand::thread_rng().gen::<RemotePeerId>()),
         network_id:  NetworkId::from(thread_rng().gen::<u16>()),
         message:     generate_fake_block(size).unwrap(),
+        hop_limit:   DEFAULT_BROADCAST_HOP_LIMIT,
     })
 }
@@ -1,16 +1,18 @@
 //! Miscellaneous utilities.
 
-use crate::{concordium_dns::dns, configuration as config};
-use anyhow::{bail, ensure, Context};
+use crate::{common::get_current_stamp, concordium_dns::dns, configuration as config};
+use anyhow::{anyhow, bail, ensure, Context};
 use byteorder::{NetworkEndian, WriteBytesExt};
 use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer};
 use env_logger::{Builder, Env};
 use log::LevelFilter;
-use rand::rngs::OsRng;
+use noiseexplorer_xx::types::{Keypair as NoiseKeypair, PrivateKey as NoisePrivateKey};
+use rand::{rngs::OsRng, RngCore};
 #[cfg(not(target_os = "windows"))]
 use std::fs::File;
 use std::{
-    io::Write,
+    collections::HashMap,
+    io::{ErrorKind, Write},
     net::{IpAddr, SocketAddr},
     path::Path,
     str::{self, FromStr},
@@ -70,14 +72,70 @@ fn parse_ip_port(input: &str) -> Option<SocketAddr> {
     None
 }
 
-pub fn setup_logger_env(env: Env, no_log_timestamp: bool) {
+/// The format of the log output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    Text,
+    /// A JSON object per line, for log aggregators.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!("Could not parse the log format"),
+        }
+    }
+}
+
+/// Describe a log record as a JSON object with its level, target, timestamp
+/// (unless omitted), the id of the thread that logged it and its message.
+fn json_log_record(record: &log::Record, timestamp: Option<String>) -> serde_json::Value {
+    let thread_id = format!("{:?}", std::thread::current().id());
+    // the id is only exposed through its debug representation, "ThreadId(n)"
+    let thread_id = thread_id
+        .trim_start_matches("ThreadId(")
+        .trim_end_matches(')')
+        .parse::<u64>()
+        .map_or_else(|_| serde_json::Value::from(thread_id.as_str()), serde_json::Value::from);
+    let mut fields = serde_json::json!({
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "thread_id": thread_id,
+        "message": record.args().to_string(),
+    });
+    if let Some(timestamp) = timestamp {
+        fields["timestamp"] = serde_json::Value::from(timestamp);
+    }
+    fields
+}
+
+pub fn setup_logger_env(env: Env, no_log_timestamp: bool, format: LogFormat) {
     let mut log_builder = Builder::from_env(env);
-    if no_log_timestamp {
-        log_builder.format_timestamp(None);
-    } else {
-        log_builder.format(|buf, record| {
-            writeln!(buf, "{}: {}: {}", buf.timestamp_nanos(), record.level(), record.args())
-        });
+    match format {
+        LogFormat::Text if no_log_timestamp => {
+            log_builder.format_timestamp(None);
+        }
+        LogFormat::Text => {
+            log_builder.format(|buf, record| {
+                writeln!(buf, "{}: {}: {}", buf.timestamp_nanos(), record.level(), record.args())
+            });
+        }
+        LogFormat::Json => {
+            log_builder.format(move |buf, record| {
+                let timestamp = if no_log_timestamp {
+                    None
+                } else {
+                    Some(buf.timestamp_nanos().to_string())
+                };
+                writeln!(buf, "{}", json_log_record(record, timestamp))
+            });
+        }
     }
     log_builder.filter(Some(&"tokio_reactor"), LevelFilter::Error);
     log_builder.filter(Some(&"hyper"), LevelFilter::Error);
@@ -140,10 +198,16 @@ pub fn get_resolvers(_resolv_conf: &Path, resolvers: &[String]) -> Vec<String> {
     }
 }
 
+/// Check whether a `host:port` string names its host by a hostname rather
+/// than by an IP address, i.e., whether `parse_host_port` resolves it via DNS.
+pub fn is_hostname(input: &str) -> bool {
+    input.rfind(':').map_or(false, |n| IpAddr::from_str(&input[..n]).is_err())
+}
+
 pub fn parse_host_port(
     input: &str,
     resolvers: &[String],
-    require_dnssec: bool,
+    dns_options: dns::QueryOptions,
 ) -> anyhow::Result<Vec<SocketAddr>> {
     if let Some(n) = input.rfind(':') {
         let (ip, port) = input.split_at(n);
@@ -157,12 +221,16 @@ pub fn parse_host_port(
             Ok(vec![SocketAddr::new(ip, port)])
         } else {
             let resolver_addresses =
-                resolvers.iter().map(|x| IpAddr::from_str(x)).flatten().collect::<Vec<_>>();
+                resolvers.iter().filter_map(|x| dns::parse_resolver(x)).collect::<Vec<_>>();
             ensure!(!resolver_addresses.is_empty(), "No DNS resolvers available");
 
-            let a_record_resolver = if let Ok(res) =
-                dns::resolve_dns_a_record(&ip, &resolver_addresses, require_dnssec)
-            {
+            let a_records = dns::resolve_dns_a_record(&ip, &resolver_addresses, dns_options);
+            let aaaa_records = dns::resolve_dns_aaaa_record(&ip, &resolver_addresses, dns_options);
+            if let (Err(a_err), Err(aaaa_err)) = (&a_records, &aaaa_records) {
+                bail!("Can't resolve <{}>: {} (A), {} (AAAA)", ip, a_err, aaaa_err);
+            }
+
+            let a_record_resolver = if let Ok(res) = a_records {
                 res.into_iter()
                     .filter_map(|element| match IpAddr::from_str(&element) {
                         Ok(ip) => Some(SocketAddr::new(ip, port).to_owned()),
@@ -172,9 +240,7 @@ pub fn parse_host_port(
             } else {
                 vec![]
             };
-            let aaaa_record_resolver = if let Ok(res) =
-                dns::resolve_dns_aaaa_record(&ip, &resolver_addresses, require_dnssec)
-            {
+            let aaaa_record_resolver = if let Ok(res) = aaaa_records {
                 res.into_iter()
                     .filter_map(|element| IpAddr::from_str(&element).ok())
                     .map(|ip| SocketAddr::new(ip, port).to_owned())
@@ -193,21 +259,65 @@ pub fn parse_host_port(
     }
 }
 
+/// Split the DNSSEC requirement off a bootstrap node entry. Entries of the
+/// form `host:port#dnssec` are always resolved with DNSSEC validation, entries
+/// of the form `host:port#no-dnssec` never are, and entries without a suffix
+/// follow the global `--require-dnssec` flag.
+pub fn parse_bootstrap_entry(entry: &str) -> anyhow::Result<(&str, Option<bool>)> {
+    match entry.rsplit_once('#') {
+        Some((host_port, "dnssec")) => Ok((host_port, Some(true))),
+        Some((host_port, "no-dnssec")) => Ok((host_port, Some(false))),
+        Some((_, suffix)) => bail!(
+            "Unknown option <#{}> in bootstrap node <{}>. Accepted options are #dnssec and \
+             #no-dnssec.",
+            suffix,
+            entry
+        ),
+        None => Ok((entry, None)),
+    }
+}
+
+/// Resolve a single bootstrap node entry, honouring its DNSSEC requirement.
+fn resolve_bootstrap_entry(
+    entry: &str,
+    resolvers: &[String],
+    dns_options: dns::QueryOptions,
+) -> anyhow::Result<Vec<SocketAddr>> {
+    let (host_port, require_dnssec) = parse_bootstrap_entry(entry)?;
+    let dns_options = dns::QueryOptions {
+        require_dnssec: require_dnssec.unwrap_or(dns_options.require_dnssec),
+        ..dns_options
+    };
+    let addrs = parse_host_port(host_port, resolvers, dns_options)?;
+    let is_ip_literal =
+        host_port.rsplit_once(':').map_or(false, |(host, _)| IpAddr::from_str(host).is_ok());
+    if is_ip_literal {
+        debug!("Bootstrap node {} is an IP address, no DNS lookup needed", host_port);
+    } else if dns_options.require_dnssec {
+        info!("Resolved bootstrap node {} with DNSSEC validation", host_port);
+    } else {
+        info!("Resolved bootstrap node {} without DNSSEC validation", host_port);
+    }
+    Ok(addrs)
+}
+
+/// Resolve the given bootstrap nodes, returning the addresses of each of the
+/// ones that could be resolved.
 pub fn get_bootstrap_nodes(
     resolvers: &[String],
-    require_dnssec: bool,
+    dns_options: dns::QueryOptions,
     bootstrap_nodes: &[String],
-) -> Result<Vec<SocketAddr>, String> {
+) -> Result<Vec<(String, Vec<SocketAddr>)>, String> {
     if !bootstrap_nodes.is_empty() {
         debug!("Not using DNS for bootstrapping, we have nodes specified");
         let bootstrap_nodes = bootstrap_nodes
             .iter()
             .filter_map(|ip_port| {
-                parse_host_port(ip_port, resolvers, require_dnssec)
+                resolve_bootstrap_entry(ip_port, resolvers, dns_options)
+                    .map(|addrs| (ip_port.to_owned(), addrs))
                     .map_err(|err| error!("Invalid bootstrapper node received: {}", err))
                     .ok()
             })
-            .flatten()
             .collect::<Vec<_>>();
         Ok(bootstrap_nodes)
     } else {
@@ -215,6 +325,67 @@ pub fn get_bootstrap_nodes(
     }
 }
 
+/// The name of the file in the data directory holding the last successfully
+/// resolved bootstrapper addresses. It is only a fallback for when DNS is
+/// unavailable, so it can be deleted at any time.
+pub const BOOTSTRAP_CACHE_FILE: &str = "bootstrap-cache.txt";
+
+/// The cached addresses of each bootstrap node, along with the time at which
+/// they were resolved.
+pub type BootstrapCache = HashMap<String, (u64, Vec<SocketAddr>)>;
+
+/// Store the cached addresses of the given bootstrap nodes, one node per line.
+/// The file is replaced atomically, so an interrupted write can't leave a
+/// partial cache behind.
+pub fn write_bootstrap_cache(
+    path: &Path,
+    bootstrap_nodes: &[String],
+    cache: &BootstrapCache,
+) -> anyhow::Result<()> {
+    let mut contents = String::new();
+    for node in bootstrap_nodes {
+        if let Some((resolved_at, addrs)) = cache.get(node) {
+            let addrs = addrs.iter().map(ToString::to_string).collect::<Vec<_>>();
+            contents.push_str(&format!("{} {} {}\n", node, resolved_at, addrs.join(",")));
+        }
+    }
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, contents)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Read the cached addresses of the bootstrap nodes that were resolved at most
+/// `ttl` seconds ago. A missing cache yields an empty one.
+pub fn read_bootstrap_cache(path: &Path, ttl: u64) -> anyhow::Result<BootstrapCache> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Default::default()),
+        Err(e) => return Err(e.into()),
+    };
+    let now = get_current_stamp();
+    let mut cache = BootstrapCache::new();
+    for line in contents.lines() {
+        let mut fields = line.split(' ');
+        let (node, resolved_at, addrs) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(node), Some(resolved_at), Some(addrs)) => (node, resolved_at, addrs),
+            _ => bail!("The bootstrap cache contains a malformed entry."),
+        };
+        let resolved_at =
+            resolved_at.parse::<u64>().context("The bootstrap cache has a malformed timestamp.")?;
+        if now.saturating_sub(resolved_at) > ttl.saturating_mul(1000) {
+            continue;
+        }
+        let addrs = addrs
+            .split(',')
+            .map(SocketAddr::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .context("The bootstrap cache contains a malformed address.")?;
+        cache.insert(node.to_owned(), (resolved_at, addrs));
+    }
+    Ok(cache)
+}
+
 fn serialize_bootstrap_peers(peers: &[String]) -> Result<String, &'static str> {
     let mut buffer = format!("{:05}", peers.len());
 
@@ -280,13 +451,91 @@ pub fn generate_bootstrap_dns(
 
 pub fn generate_ed25519_key() -> SecretKey { SecretKey::generate(&mut OsRng::default()) }
 
-pub fn get_config_and_logging_setup() -> anyhow::Result<(config::Config, config::AppPreferences)> {
+/// The name of the file in the data directory holding the node's static noise
+/// private key.
+pub const NOISE_KEY_FILE: &str = "node-key.dat";
+
+const NOISE_KEY_LENGTH: usize = 32;
+
+/// Load the node's static noise keypair from the given file. If the file
+/// doesn't exist or `regenerate` is set, a fresh key is generated and written
+/// to the file, which is then only accessible by its owner.
+pub fn load_or_create_noise_keypair(path: &Path, regenerate: bool) -> anyhow::Result<NoiseKeypair> {
+    let mut key = [0u8; NOISE_KEY_LENGTH];
+    if !regenerate && path.exists() {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Could not read the noise key from {}.", path.display()))?;
+        ensure!(
+            bytes.len() == NOISE_KEY_LENGTH,
+            "The noise key in {} is malformed. Use `--regenerate-noise-key` to replace it.",
+            path.display()
+        );
+        key.copy_from_slice(&bytes);
+    } else {
+        OsRng.fill_bytes(&mut key);
+        write_private_file(path, &key)
+            .with_context(|| format!("Could not store the noise key in {}.", path.display()))?;
+    }
+    NoiseKeypair::from_private_key(NoisePrivateKey::from_bytes(key))
+        .map_err(|e| anyhow!("Invalid noise key: {:?}", e))
+}
+
+/// The resident memory of the node's process in bytes. It is only available on
+/// Linux.
+pub fn resident_memory() -> Option<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        std::fs::read_to_string("/proc/self/status").ok().and_then(|status| parse_vm_rss(&status))
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+/// Read the resident set size (in bytes) off the contents of a
+/// `/proc/<pid>/status` file.
+#[cfg(any(target_os = "linux", test))]
+fn parse_vm_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let mut fields = line["VmRSS:".len()..].split_whitespace();
+    let value = fields.next()?.parse::<u64>().ok()?;
+    match fields.next() {
+        Some("kB") => Some(value * 1024),
+        _ => None,
+    }
+}
+
+fn write_private_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // the mode only applies to new files, so also restrict an existing one
+        if path.exists() {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    options.open(path)?.write_all(contents)
+}
+
+/// Parse the configuration and set up the node's directories and logging.
+/// Returns `None` if the configuration was only meant to be checked; it is
+/// valid at that point.
+pub fn get_config_and_logging_setup(
+) -> anyhow::Result<Option<(config::Config, config::AppPreferences)>> {
     // Get config and app preferences
     let conf = config::parse_config()?;
+    if conf.common.check_config {
+        return Ok(None);
+    }
     let app_prefs = config::AppPreferences::new(
         conf.common.config_dir.to_owned(),
         conf.common.data_dir.to_owned(),
-    );
+    )
+    .context("Could not set up the node's directories.")?;
 
     // Prepare the logger
     let (env, log_lvl) = if conf.common.trace {
@@ -297,7 +546,7 @@ pub fn get_config_and_logging_setup() -> anyhow::Result<(config::Config, config:
         (Env::default().filter_or("LOG_LEVEL", "info"), "info")
     };
 
-    setup_logger_env(env, conf.common.no_log_timestamp);
+    setup_logger_env(env, conf.common.no_log_timestamp, conf.common.log_format);
 
     if conf.common.print_config {
         info!("Config:{:?}\n", conf);
@@ -316,7 +565,7 @@ pub fn get_config_and_logging_setup() -> anyhow::Result<(config::Config, config:
     );
     info!("Log level: {}", log_lvl);
 
-    Ok((conf, app_prefs))
+    Ok(Some((conf, app_prefs)))
 }
 
 #[cfg(test)]
@@ -338,6 +587,67 @@ mod tests {
         assert_eq!(EXPECTED, to_hex_string(PublicKey::from(&secret_key).as_bytes()));
     }
 
+    #[test]
+    pub fn test_noise_keypair_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(NOISE_KEY_FILE);
+        let created = load_or_create_noise_keypair(&path, false).unwrap();
+        let loaded = load_or_create_noise_keypair(&path, false).unwrap();
+        assert_eq!(created.get_public_key().as_bytes(), loaded.get_public_key().as_bytes());
+        let regenerated = load_or_create_noise_keypair(&path, true).unwrap();
+        assert_ne!(created.get_public_key().as_bytes(), regenerated.get_public_key().as_bytes());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    pub fn test_bootstrap_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(BOOTSTRAP_CACHE_FILE);
+        let nodes = vec!["bootstrap.example.com:8888".to_owned(), "10.0.0.2:8888".to_owned()];
+        let addrs: Vec<SocketAddr> =
+            vec!["10.0.0.1:8888".parse().unwrap(), "[2001:db8::1]:8888".parse().unwrap()];
+
+        let now = get_current_stamp();
+
+        assert!(read_bootstrap_cache(&path, 60).unwrap().is_empty());
+        let mut cache = BootstrapCache::new();
+        cache.insert(nodes[0].clone(), (now, addrs));
+        cache.insert(nodes[1].clone(), (now, vec!["10.0.0.2:8888".parse().unwrap()]));
+        // only the given bootstrap nodes are stored
+        cache.insert("other.example.com:8888".to_owned(), (now, vec![]));
+        write_bootstrap_cache(&path, &nodes, &cache).unwrap();
+        cache.remove("other.example.com:8888");
+        assert_eq!(read_bootstrap_cache(&path, 60).unwrap(), cache);
+        // and each of them expires on its own
+        let contents =
+            format!("{} 0 10.0.0.1:8888\n{} {} 10.0.0.2:8888\n", nodes[0], nodes[1], now);
+        std::fs::write(&path, contents).unwrap();
+        let read = read_bootstrap_cache(&path, 60).unwrap();
+        assert_eq!(read.keys().collect::<Vec<_>>(), vec![&nodes[1]]);
+    }
+
+    #[test]
+    pub fn test_bootstrap_entry_dnssec() {
+        assert_eq!(
+            parse_bootstrap_entry("bootstrap.example.com:8888").unwrap(),
+            ("bootstrap.example.com:8888", None)
+        );
+        assert_eq!(
+            parse_bootstrap_entry("bootstrap.example.com:8888#dnssec").unwrap(),
+            ("bootstrap.example.com:8888", Some(true))
+        );
+        assert_eq!(
+            parse_bootstrap_entry("10.0.0.1:8888#no-dnssec").unwrap(),
+            ("10.0.0.1:8888", Some(false))
+        );
+        assert!(parse_bootstrap_entry("bootstrap.example.com:8888#dnsec").is_err());
+    }
+
     #[test]
     pub fn test_sign_verify() {
         const INPUT: &str = "00002IP401001001001008888IP6deadbeaf00000000000000000000000009999";
@@ -359,4 +669,38 @@ mod tests {
             .verify(INPUT.as_bytes(), &Signature::try_from(&decoded_signature[..]).unwrap())
             .is_ok());
     }
+
+    #[test]
+    fn test_is_hostname() {
+        assert!(is_hostname("node.example.com:8888"));
+        assert!(is_hostname("localhost:8888"));
+        assert!(!is_hostname("10.0.0.1:8888"));
+        assert!(!is_hostname("::1:8888"));
+    }
+
+    #[test]
+    fn test_json_log_record() {
+        let record = log::Record::builder()
+            .level(log::Level::Warn)
+            .target("peer_drop")
+            .args(format_args!("Dropping the connection to 10.0.0.1:8888"))
+            .build();
+        let fields = json_log_record(&record, Some("2021-06-01T12:00:00.000000000Z".into()));
+        assert_eq!(fields["level"], "WARN");
+        assert_eq!(fields["target"], "peer_drop");
+        assert_eq!(fields["message"], "Dropping the connection to 10.0.0.1:8888");
+        assert_eq!(fields["timestamp"], "2021-06-01T12:00:00.000000000Z");
+        assert!(fields["thread_id"].is_u64());
+
+        // the timestamp can be left out
+        assert!(json_log_record(&record, None).get("timestamp").is_none());
+    }
+
+    #[test]
+    fn test_parse_vm_rss() {
+        let status = "Name:\tconcordium-node\nVmPeak:\t  204800 kB\nVmRSS:\t   51200 kB\n";
+        assert_eq!(parse_vm_rss(status), Some(51200 * 1024));
+        assert_eq!(parse_vm_rss("Name:\tconcordium-node\n"), None);
+        assert_eq!(parse_vm_rss("VmRSS:\tmany kB\n"), None);
+    }
 }